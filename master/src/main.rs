@@ -2,12 +2,22 @@ extern crate rcluster;
 extern crate structopt;
 #[macro_use] extern crate structopt_derive;
 
-use rcluster::{Master, utils};
+use rcluster::{ChangeItem, Compression, ConflictPolicy, ExecOptions, Job, JobStatus, Master, Progress};
+use rcluster::{CgroupLimits, ResourceUsage, SyncOptions};
+use rcluster::{FileInfo, FileKind, ProcessFilter, ProcessInfo, ProcessTarget, TwoWayOptions, Watcher};
+use rcluster::{signal_number, utils};
 use rcluster::errors::ClusterResult;
 use structopt::StructOpt;
 
 use std::error::Error;
+use std::io::{self, Cursor, Read, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// Width of the progress bars.
+const BAR_WIDTH: usize = 30;
 
 #[derive(StructOpt, Debug)]
 enum FileSync {
@@ -18,6 +28,73 @@ enum FileSync {
         source: String,
         #[structopt(long = "to")]
         dest: String,
+        #[structopt(long = "delete", help = "Delete files in destination which don't exist in source")]
+        delete: bool,
+        #[structopt(short = "n", long = "dry-run", help = "Only show what would've been changed")]
+        dry_run: bool,
+        #[structopt(long = "exclude", help = "Glob pattern for paths that should be left alone")]
+        excludes: Vec<String>,
+        #[structopt(long = "resume", help = "Resume partially sent files (and retry on failures)")]
+        resume: bool,
+        #[structopt(long = "retries", default_value = "3", help = "Number of retries for resuming")]
+        retries: usize,
+        #[structopt(long = "archive", help = "Stream the whole tree as a single archive (for lots of small files)")]
+        archive: bool,
+        #[structopt(long = "xattrs", help = "Sync extended attributes (including SELinux labels and capabilities)")]
+        xattrs: bool,
+        #[structopt(long = "acls", help = "Sync POSIX ACLs")]
+        acls: bool,
+        #[structopt(long = "watch", help = "Keep watching the source, and send the paths which change")]
+        watch: bool,
+        #[structopt(long = "debounce", default_value = "200", help = "Milliseconds to wait for changes to settle (when watching)")]
+        debounce: u64,
+    },
+    #[structopt(name = "push-archive")]
+    /// Send a tar archive (optionally gzipped) and extract it in slave machines
+    PushArchive {
+        #[structopt(long = "from")]
+        archive: String,
+        #[structopt(long = "to")]
+        dest: String,
+        #[structopt(long = "delete", help = "Delete files in destination which don't exist in the archive")]
+        delete: bool,
+        #[structopt(short = "n", long = "dry-run", help = "Only show what would've been changed")]
+        dry_run: bool,
+        #[structopt(long = "xattrs", help = "Apply extended attributes from the archive")]
+        xattrs: bool,
+        #[structopt(long = "acls", help = "Apply POSIX ACLs from the archive")]
+        acls: bool,
+    },
+    #[structopt(name = "pull-archive")]
+    /// Receive a tar archive (optionally gzipped) from slave machines and extract it here
+    PullArchive {
+        #[structopt(long = "from")]
+        archive: String,
+        #[structopt(long = "to")]
+        dest: String,
+        #[structopt(short = "n", long = "dry-run", help = "Only show what would've been changed")]
+        dry_run: bool,
+        #[structopt(long = "xattrs", help = "Apply extended attributes from the archive")]
+        xattrs: bool,
+        #[structopt(long = "acls", help = "Apply POSIX ACLs from the archive")]
+        acls: bool,
+    },
+    #[structopt(name = "sync")]
+    /// Sync a directory in both the directions (with the same directory in slave machines)
+    TwoWay {
+        #[structopt(long = "local")]
+        local: String,
+        #[structopt(long = "remote")]
+        remote: String,
+        #[structopt(long = "policy", default_value = "report",
+                    help = "Resolve conflicts with 'report' (leave both alone), 'master', 'slave' or 'newer'")]
+        policy: ConflictPolicy,
+        #[structopt(long = "state-dir", help = "Directory for the state of previous syncs")]
+        state_dir: Option<String>,
+        #[structopt(short = "n", long = "dry-run", help = "Only show what would've been changed")]
+        dry_run: bool,
+        #[structopt(long = "exclude", help = "Glob pattern for paths that should be left alone")]
+        excludes: Vec<String>,
     },
     #[structopt(name = "receive")]
     /// Receive file from slave machine
@@ -26,33 +103,824 @@ enum FileSync {
         source: String,
         #[structopt(long = "to")]
         dest: String,
+    },
+    #[structopt(name = "exec")]
+    /// Execute a command in slave machines
+    Exec {
+        #[structopt(long = "stdin", help = "Forward stdin to the command (in all slaves)")]
+        stdin: bool,
+        #[structopt(flatten)]
+        exec: ExecArgs,
+        #[structopt(help = "Command (along with its arguments)", raw(required = "true"))]
+        command: Vec<String>,
+    },
+    #[structopt(name = "ls")]
+    /// List a directory in slave machines
+    List {
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short = "r", long = "recursive", help = "List everything in the directory")]
+        recursive: bool,
+        #[structopt(long = "json", help = "Print the listing as JSON")]
+        json: bool,
+    },
+    #[structopt(name = "stat")]
+    /// Show the information of a path in slave machines
+    Stat {
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        path: PathBuf,
+    },
+    #[structopt(name = "rm")]
+    /// Remove a path in slave machines
+    Remove {
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short = "r", long = "recursive", help = "Remove directories along with their contents")]
+        recursive: bool,
+    },
+    #[structopt(name = "mv")]
+    /// Move (rename) a path in slave machines
+    Move {
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        source: PathBuf,
+        #[structopt(help = "New path in slave", parse(from_os_str))]
+        dest: PathBuf,
+    },
+    #[structopt(name = "mkdir")]
+    /// Create a directory in slave machines
+    MakeDir {
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short = "p", long = "parents", help = "Create the parents as needed")]
+        parents: bool,
+    },
+    #[structopt(name = "chmod")]
+    /// Change the permissions of a path in slave machines
+    ChangeMode {
+        #[structopt(help = "Permission bits (in octal)", parse(try_from_str = "parse_mode"))]
+        mode: u32,
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short = "R", long = "recursive", help = "Change everything in the directory")]
+        recursive: bool,
+    },
+    #[structopt(name = "tail")]
+    /// Show the last lines of a file in slave machines
+    Tail {
+        #[structopt(help = "Path in slave")]
+        path: String,
+        #[structopt(short = "f", long = "follow", help = "Keep showing the lines appended to the file")]
+        follow: bool,
+    },
+    #[structopt(name = "diff")]
+    /// Compare a file or directory across slave machines
+    Diff {
+        #[structopt(help = "Path in slaves")]
+        path: String,
+        #[structopt(long = "exclude", help = "Glob pattern for paths that should be left alone")]
+        excludes: Vec<String>,
+    },
+    #[structopt(name = "ps")]
+    /// List the processes in slave machines
+    Processes {
+        #[structopt(long = "name", help = "Glob pattern for the process names")]
+        name: Option<String>,
+        #[structopt(long = "user", help = "User (name or UID) owning the processes")]
+        user: Option<String>,
+        #[structopt(long = "spawned", help = "List only the commands spawned by the slave (and their children)")]
+        spawned: bool,
+        #[structopt(long = "json", help = "Print the listing as JSON")]
+        json: bool,
+    },
+    #[structopt(name = "kill")]
+    /// Send a signal to processes in slave machines
+    Kill {
+        #[structopt(short = "s", long = "signal", default_value = "TERM", parse(try_from_str = "parse_signal"),
+                    help = "Signal (name or number)")]
+        signal: i32,
+        #[structopt(long = "name", help = "Glob pattern for the process names")]
+        name: Option<String>,
+        #[structopt(long = "spawned", help = "Signal the commands spawned by the slave (and their children)")]
+        spawned: bool,
+        #[structopt(short = "g", long = "group", help = "Signal the process groups of the processes")]
+        group: bool,
+        #[structopt(help = "Process IDs")]
+        pids: Vec<u32>,
+    },
+    #[structopt(name = "job")]
+    /// Manage background jobs in slave machines
+    Job {
+        #[structopt(subcommand)]
+        command: JobCommand,
+    },
+    #[structopt(name = "du")]
+    /// Show the disk usage of a path in slave machines
+    Usage {
+        #[structopt(help = "Path in slave", parse(from_os_str))]
+        path: PathBuf,
+    },
+}
+
+/// Environment of the executed commands.
+#[derive(StructOpt, Debug)]
+struct ExecArgs {
+    #[structopt(long = "env", parse(try_from_str = "parse_env"), raw(number_of_values = "1"),
+                help = "Set an environment variable (NAME=VALUE)")]
+    env: Vec<(String, String)>,
+    #[structopt(long = "unset", raw(number_of_values = "1"), help = "Remove an environment variable")]
+    unset: Vec<String>,
+    #[structopt(long = "clear-env", help = "Start with an empty environment")]
+    clear_env: bool,
+    #[structopt(long = "cwd", help = "Working directory in slave")]
+    cwd: Option<String>,
+    #[structopt(long = "uid", help = "Run as this user ID")]
+    uid: Option<u32>,
+    #[structopt(long = "gid", help = "Run as this group ID")]
+    gid: Option<u32>,
+    #[structopt(long = "cpu-limit", help = "Limit the CPU time (in seconds)")]
+    cpu_limit: Option<u64>,
+    #[structopt(long = "memory-limit", help = "Limit the address space (in MiB)")]
+    memory_limit: Option<u64>,
+    #[structopt(long = "files-limit", help = "Limit the number of open files")]
+    files_limit: Option<u64>,
+    #[structopt(long = "timeout", help = "Kill the command (and its children) after these many seconds")]
+    timeout: Option<u64>,
+    #[structopt(long = "cgroup", help = "Run in a cgroup of its own (and report the resource usage)")]
+    cgroup: bool,
+    #[structopt(long = "cgroup-cpus", help = "Limit the CPU bandwidth of the cgroup (in CPUs, say, 1.5)")]
+    cgroup_cpus: Option<f64>,
+    #[structopt(long = "cgroup-memory", help = "Limit the memory of the cgroup (in MiB)")]
+    cgroup_memory: Option<u64>,
+    #[structopt(long = "cgroup-io-read", help = "Limit the reading rate of the cgroup (in KiB/s)")]
+    cgroup_io_read: Option<u64>,
+    #[structopt(long = "cgroup-io-write", help = "Limit the writing rate of the cgroup (in KiB/s)")]
+    cgroup_io_write: Option<u64>,
+}
+
+impl ExecArgs {
+    fn options(&self) -> ExecOptions {
+        ExecOptions {
+            clear_env: self.clear_env,
+            unset_env: self.unset.clone(),
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            cpu_limit: self.cpu_limit,
+            memory_limit: self.memory_limit.map(|mb| mb * 1024 * 1024),
+            open_files_limit: self.files_limit,
+            timeout: self.timeout.map(Duration::from_secs),
+            cgroup: self.cgroup_limits(),
+        }
+    }
+
+    /// Limits for the cgroup (any of them implies a cgroup).
+    fn cgroup_limits(&self) -> Option<CgroupLimits> {
+        let limits = CgroupLimits {
+            cpu_millis: self.cgroup_cpus.map(|c| (c * 1000.0).round() as u64),
+            memory: self.cgroup_memory.map(|mb| mb * 1024 * 1024),
+            io_read_bps: self.cgroup_io_read.map(|kb| kb * 1024),
+            io_write_bps: self.cgroup_io_write.map(|kb| kb * 1024),
+        };
+
+        match self.cgroup || limits != CgroupLimits::default() {
+            true => Some(limits),
+            false => None,
+        }
     }
 }
 
+#[derive(StructOpt, Debug)]
+enum JobCommand {
+    #[structopt(name = "start")]
+    /// Start a command in the background (it keeps running after disconnecting)
+    Start {
+        #[structopt(flatten)]
+        exec: ExecArgs,
+        #[structopt(help = "Command (along with its arguments)", raw(required = "true"))]
+        command: Vec<String>,
+    },
+    #[structopt(name = "list")]
+    /// List the jobs (including the finished ones)
+    List,
+    #[structopt(name = "attach")]
+    /// Show the output of a job (until it finishes)
+    Attach {
+        #[structopt(help = "Job ID")]
+        id: u64,
+    },
+    #[structopt(name = "wait")]
+    /// Wait for a job to finish
+    Wait {
+        #[structopt(help = "Job ID")]
+        id: u64,
+    },
+    #[structopt(name = "cancel")]
+    /// Cancel a running job
+    Cancel {
+        #[structopt(help = "Job ID")]
+        id: u64,
+    },
+}
+
 // Structure solely for obtaining the command-line arguments.
 #[derive(StructOpt)]
 struct Options {
-    #[structopt(help = "Address of slave machine")]
-    address: SocketAddr,
+    #[structopt(help = "Addresses of slave machines", raw(required = "true"))]
+    addresses: Vec<SocketAddr>,
     #[structopt(short = "p", long = "ping", help = "Ping the slave service")]
     ping: bool,
+    #[structopt(short = "z", long = "compress", help = "Compress the streamed content (if it's worth it)")]
+    compress: bool,
+    #[structopt(long = "bwlimit", help = "Limit the sending rate (in KiB per second)")]
+    bwlimit: Option<u64>,
     #[structopt(subcommand)]
     file: Option<FileSync>,
 }
 
+/// Writer which prefixes each line with the host, so that the output from many hosts
+/// can be interleaved. Incomplete lines are held back until they're complete.
+struct HostLines {
+    host: SocketAddr,
+    partial: Vec<u8>,
+}
+
+impl HostLines {
+    fn write_lines(&self, lines: &[u8]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for line in lines.split(|&b| b == b'\n') {
+            write!(out, "{}: ", self.host)?;
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+
+        out.flush()
+    }
+}
+
+impl Write for HostLines {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(bytes);
+        if let Some(i) = self.partial.iter().rposition(|&b| b == b'\n') {
+            let rest = self.partial.split_off(i + 1);
+            self.write_lines(&self.partial[..i])?;
+            self.partial = rest;
+        }
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for HostLines {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            let _ = self.write_lines(&self.partial);
+        }
+    }
+}
+
+fn parse_env(var: &str) -> Result<(String, String), String> {
+    match var.find('=') {
+        Some(i) => Ok((var[..i].to_owned(), var[i + 1..].to_owned())),
+        None => Err(format!("Invalid variable '{}' (expected NAME=VALUE)", var)),
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(m) if m <= 0o7777 => Ok(m),
+        _ => Err(format!("Invalid mode '{}' (expected octal permission bits)", mode)),
+    }
+}
+
+fn parse_signal(signal: &str) -> Result<i32, String> {
+    signal_number(signal).ok_or_else(|| format!("Unknown signal '{}'", signal))
+}
+
+/// Quote the string for JSON.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// JSON object for the information of a path.
+fn info_json(info: &FileInfo) -> String {
+    let kind = match info.kind {
+        FileKind::File => "file",
+        FileKind::Dir => "dir",
+        FileKind::Symlink => "symlink",
+        FileKind::Other => "other",
+    };
+
+    format!("{{\"path\":{},\"type\":\"{}\",\"size\":{},\"mode\":\"{:04o}\",\"mtime\":{},\"uid\":{},\"gid\":{}}}",
+            json_string(&info.path.to_string_lossy()), kind, info.size, info.mode, info.mtime,
+            info.uid, info.gid)
+}
+
+/// Line (like `ls -l`) for the information of a path.
+fn info_line(info: &FileInfo) -> String {
+    let kind = match info.kind {
+        FileKind::File => '-',
+        FileKind::Dir => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::Other => '?',
+    };
+
+    format!("{}{:04o} {:>5} {:>5} {:>12} {}", kind, info.mode, info.uid, info.gid, info.size,
+            info.path.display())
+}
+
+/// JSON object for a process.
+fn process_json(process: &ProcessInfo) -> String {
+    let args = process.args.iter().map(|a| json_string(a)).collect::<Vec<_>>();
+    format!("{{\"pid\":{},\"ppid\":{},\"pgid\":{},\"user\":{},\"state\":\"{}\",\"rss\":{},\
+             \"spawned\":{},\"name\":{},\"args\":[{}]}}",
+            process.pid, process.ppid, process.pgid, json_string(&process.user), process.state,
+            process.rss, process.spawned, json_string(&process.name), args.join(","))
+}
+
+/// Line (like `ps`) for a process. Kernel threads (which don't have arguments) are shown
+/// with their names in brackets.
+fn process_line(process: &ProcessInfo) -> String {
+    let command = match process.args.is_empty() {
+        true => format!("[{}]", process.name),
+        false => process.args.join(" "),
+    };
+
+    format!("{:>7} {:>7} {:<10} {} {:>10} {}{}", process.pid, process.ppid, process.user,
+            process.state, human_bytes(process.rss as f64), command,
+            if process.spawned { " (spawned)" } else { "" })
+}
+
+/// Line for a job.
+fn job_line(job: &Job) -> String {
+    let status = match job.status {
+        JobStatus::Running => format!("running (pid {})", job.pid),
+        JobStatus::Exited(code) => format!("exited with {}", code),
+        JobStatus::Cancelled(code) => format!("cancelled (exited with {})", code),
+    };
+
+    let usage = job.usage.as_ref().map(|u| format!(" [{}]", usage_line(u))).unwrap_or_default();
+    format!("[{}] {}{} (started at {}): {}", job.id, status, usage, job.started, job.args.join(" "))
+}
+
+/// Line for the resources used by a command.
+fn usage_line(usage: &ResourceUsage) -> String {
+    let seconds = |d: Duration| d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9;
+    let peak = usage.peak_memory.map(|m| format!("peak memory {}, ", human_bytes(m as f64)));
+    format!("{}cpu {:.2}s user, {:.2}s system", peak.unwrap_or_default(),
+            seconds(usage.cpu_user), seconds(usage.cpu_system))
+}
+
+/// Human-readable representation of the given number of bytes.
+fn human_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Format the progress bar of a host (along with the rate and ETA).
+fn progress_line(host: &SocketAddr, progress: &Progress) -> String {
+    let ratio = if progress.bytes_total == 0 {
+        1.0
+    } else {
+        progress.bytes_done as f64 / progress.bytes_total as f64
+    };
+
+    let filled = ((ratio * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+    let eta = match progress.eta() {
+        Some(ref d) if !progress.finished => format!("{:02}:{:02}", d.as_secs() / 60, d.as_secs() % 60),
+        _ => String::from("--:--"),
+    };
+
+    format!("{} [{}{}] {:>3}% {:>10}/s ETA {} {}", host, "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled), (ratio * 100.0) as u32, human_bytes(progress.rate),
+            eta, progress.current_file.display())
+}
+
+/// Show the progress of the transfers to the given hosts (which run at once), with a line
+/// for each host. The lines are reserved above the cursor, which stays below the last one.
+fn show_progress(master: &mut Master, ids: &[usize]) {
+    let hosts = master.addrs().to_vec();
+    let mut lines = vec![None; hosts.len()];
+    for (line, &id) in ids.iter().enumerate() {
+        lines[id] = Some(line);
+        println!("{}", hosts[id]);
+    }
+
+    let count = ids.len();
+    master.set_progress_handler(move |id, progress| {
+        if let Some(line) = lines[id] {
+            // Move up to the line of the host and clear the rest of it (since the previous
+            // render could've been longer), then move back down.
+            let up = count - line;
+            print!("\x1b[{}A\r{}\x1b[K\x1b[{}B\r", up, progress_line(&hosts[id], progress), up);
+            let _ = io::stdout().flush();
+        }
+    });
+}
+
+/// Print the changes made to a host (itemized like `rsync -i`).
+fn print_changes(host: &SocketAddr, changes: Vec<ChangeItem>, dry_run: bool) {
+    for item in changes {
+        println!("{}: {}", host, item);
+    }
+
+    if dry_run {
+        println!("{}: Dry run - nothing has been changed.", host);
+    }
+}
+
 fn handle_request() -> ClusterResult<()> {
     let options = Options::from_args();
     let mut master = Master::new();
-    let id = master.add_slave(options.address)?;
+    if options.compress {
+        master.set_compression(Compression::Deflate);
+    }
+
+    master.set_bwlimit(options.bwlimit.map(|kb| kb * 1024));
+    let mut ids = vec![];
+    for address in &options.addresses {
+        ids.push(master.add_slave(*address)?);
+    }
+
+    let hosts = master.addrs().to_vec();
+    master.set_warning_handler(move |id, warning| println!("{}: WARNING: {}", hosts[id], warning));
+
     if options.ping {
-        master.ping(id)?;
-        println!("Successfully pinged slave!");
+        for &id in &ids {
+            master.ping(id)?;
+            println!("Successfully pinged {}!", master.addrs()[id]);
+        }
     }
 
     match options.file {
-        Some(FileSync::SendOne { source, dest }) => {
-            master.send_file(id, source, dest)?;
-            println!("Successfully sent file!");
+        Some(FileSync::SendOne { source, dest, delete, dry_run, excludes, resume, retries, archive,
+                                 xattrs, acls, watch, debounce }) => {
+            let mut sync_options = SyncOptions { delete, dry_run, resume, archive, xattrs, acls,
+                                                 ..SyncOptions::default() };
+            for pattern in excludes {
+                sync_options.exclude(&pattern)?;
+            }
+
+            // Changes made during the initial send will be in the first batch.
+            let mut watcher = match watch {
+                true => Some(Watcher::new(&source, &sync_options, Duration::from_millis(debounce))?),
+                false => None,
+            };
+
+            // The hosts which failed are resumed together in the next attempt.
+            let mut pending = ids.clone();
+            let mut attempts = 0;
+            while !pending.is_empty() {
+                // Nothing is sent in a dry run.
+                if !dry_run {
+                    show_progress(&mut master, &pending);
+                }
+
+                let results = master.send_file_all(&pending, &source, &dest, &sync_options);
+                let mut failed = vec![];
+                for (id, result) in pending.into_iter().zip(results) {
+                    let host = master.addrs()[id];
+                    match result {
+                        Ok(changes) => {
+                            print_changes(&host, changes, dry_run);
+                            if !dry_run {
+                                println!("Successfully sent file to {}!", host);
+                            }
+                        },
+                        Err(e) => {
+                            if !resume || attempts == retries {
+                                return Err(e)
+                            }
+
+                            println!("Sending to {} failed ({}), resuming (attempt {} of {})...",
+                                     host, e.description(), attempts + 1, retries);
+                            failed.push(id);
+                        },
+                    }
+                }
+
+                if !failed.is_empty() {
+                    attempts += 1;
+                    for &id in &failed {
+                        master.reconnect(id)?;
+                    }
+                }
+
+                pending = failed;
+            }
+
+            if let Some(ref mut watcher) = watcher {
+                println!("Watching {} for changes...", source);
+                loop {
+                    let changes = watcher.next_batch()?;
+                    for &id in &ids {
+                        let host = master.addrs()[id];
+                        match master.send_changes(id, &changes, &dest, &sync_options) {
+                            Ok(changes) => print_changes(&host, changes, dry_run),
+                            // The changes will be sent again when the paths change next.
+                            Err(e) => {
+                                println!("\nSending changes to {} failed ({}), reconnecting...",
+                                         host, e.description());
+                                if let Err(e) = master.reconnect(id) {
+                                    println!("Cannot reconnect to {} ({})", host, e.description());
+                                }
+                            },
+                        }
+                    }
+                }
+            }
+        },
+        Some(FileSync::PushArchive { archive, dest, delete, dry_run, xattrs, acls }) => {
+            let sync_options = SyncOptions { delete, dry_run, xattrs, acls, ..SyncOptions::default() };
+            show_progress(&mut master, &ids);
+            let results = master.send_archive_all(&ids, &archive, &dest, &sync_options);
+            for (id, result) in ids.into_iter().zip(results) {
+                let host = master.addrs()[id];
+                let changes = result?;
+                print_changes(&host, changes, dry_run);
+                if !dry_run {
+                    println!("Successfully extracted archive in {}!", host);
+                }
+            }
+        },
+        Some(FileSync::PullArchive { archive, dest, dry_run, xattrs, acls }) => {
+            let sync_options = SyncOptions { dry_run, xattrs, acls, ..SyncOptions::default() };
+            for id in ids {
+                let host = master.addrs()[id];
+                let changes = master.receive_archive(id, &archive, &dest, &sync_options)?;
+                print_changes(&host, changes, dry_run);
+                if !dry_run {
+                    println!("Successfully extracted archive from {}!", host);
+                }
+            }
+        },
+        Some(FileSync::TwoWay { local, remote, policy, state_dir, dry_run, excludes }) => {
+            let mut sync_options = SyncOptions { dry_run, ..SyncOptions::default() };
+            for pattern in excludes {
+                sync_options.exclude(&pattern)?;
+            }
+
+            let options = TwoWayOptions {
+                policy,
+                state_dir: state_dir.map(PathBuf::from),
+                sync: sync_options,
+            };
+
+            for id in ids {
+                let host = master.addrs()[id];
+                let report = master.two_way_sync(id, &local, &remote, &options)?;
+                for path in &report.pushed {
+                    println!("{}: --> {}", host, path.display());
+                }
+
+                for path in &report.pulled {
+                    println!("{}: <-- {}", host, path.display());
+                }
+
+                for path in &report.removed_in_slave {
+                    println!("{}: --> *deleting {}", host, path.display());
+                }
+
+                for path in &report.removed_in_master {
+                    println!("{}: <-- *deleting {}", host, path.display());
+                }
+
+                for conflict in &report.conflicts {
+                    println!("{}: CONFLICT: {}", host, conflict);
+                }
+
+                if dry_run {
+                    println!("{}: Dry run - nothing has been changed.", host);
+                }
+            }
+        },
+        Some(FileSync::Exec { stdin, exec, command }) => {
+            // Input is read only once, and it's sent to all the slaves.
+            let mut input = vec![];
+            if stdin {
+                io::stdin().read_to_end(&mut input)?;
+            }
+
+            for id in ids {
+                let host = master.addrs()[id];
+                println!("==> {} <==", host);
+                let report = master.execute(id, &command, &exec.options(), Cursor::new(input.clone()),
+                                            io::stdout())?;
+                match report.usage {
+                    Some(usage) => println!("{}: Exited with {} ({})", host, report.code, usage_line(&usage)),
+                    None => println!("{}: Exited with {}", host, report.code),
+                }
+            }
+        },
+        Some(FileSync::List { path, recursive, json }) => {
+            let mut hosts = vec![];
+            for &id in &ids {
+                let host = master.addrs()[id];
+                let entries = master.list_path(id, &path, recursive)?;
+                if json {
+                    let entries = entries.iter().map(info_json).collect::<Vec<_>>();
+                    hosts.push(format!("{}:[{}]", json_string(&host.to_string()), entries.join(",")));
+                } else {
+                    for entry in &entries {
+                        println!("{}: {}", host, info_line(entry));
+                    }
+                }
+            }
+
+            if json {
+                println!("{{{}}}", hosts.join(","));
+            }
+        },
+        Some(FileSync::Stat { path }) => {
+            for &id in &ids {
+                let host = master.addrs()[id];
+                let info = master.stat_path(id, &path)?;
+                println!("{}: {} (modified at {})", host, info_line(&info), info.mtime);
+            }
+        },
+        Some(FileSync::Remove { path, recursive }) => {
+            for &id in &ids {
+                master.remove_path(id, &path, recursive)?;
+                println!("{}: Removed {}", master.addrs()[id], path.display());
+            }
+        },
+        Some(FileSync::Move { source, dest }) => {
+            for &id in &ids {
+                master.move_path(id, &source, &dest)?;
+                println!("{}: Moved {} to {}", master.addrs()[id], source.display(), dest.display());
+            }
+        },
+        Some(FileSync::MakeDir { path, parents }) => {
+            for &id in &ids {
+                master.create_dir(id, &path, parents)?;
+                println!("{}: Created {}", master.addrs()[id], path.display());
+            }
+        },
+        Some(FileSync::ChangeMode { mode, path, recursive }) => {
+            for &id in &ids {
+                master.set_mode(id, &path, mode, recursive)?;
+                println!("{}: Changed mode of {} to {:04o}", master.addrs()[id], path.display(), mode);
+            }
+        },
+        Some(FileSync::Tail { path, follow }) => {
+            // Each host is tailed in a thread of its own (since a followed file doesn't end).
+            let (compress, bwlimit) = (options.compress, options.bwlimit);
+            let threads = master.addrs().iter().map(|&host| {
+                let path = path.clone();
+                let handle = thread::spawn(move || -> ClusterResult<()> {
+                    let mut master = Master::new();
+                    if compress {
+                        master.set_compression(Compression::Deflate);
+                    }
+
+                    master.set_bwlimit(bwlimit.map(|kb| kb * 1024));
+                    let id = master.add_slave(host)?;
+                    master.tail(id, &path, follow, HostLines { host, partial: vec![] })
+                });
+
+                (host, handle)
+            }).collect::<Vec<_>>();
+
+            for (host, handle) in threads {
+                match handle.join() {
+                    Ok(Err(e)) => println!("{}: ERROR: {}", host, e),
+                    Err(_) => println!("{}: ERROR: tailing has panicked", host),
+                    Ok(Ok(())) => (),
+                }
+            }
+        },
+        Some(FileSync::Diff { path, excludes }) => {
+            let mut sync_options = SyncOptions::default();
+            for pattern in excludes {
+                sync_options.exclude(&pattern)?;
+            }
+
+            let groups = master.compare_path(&ids, &path, &sync_options)?;
+            let hosts = |ids: &[usize]| {
+                ids.iter().map(|&id| master.addrs()[id].to_string()).collect::<Vec<_>>().join(", ")
+            };
+
+            if groups.len() < 2 {
+                println!("{} is identical in all hosts.", path);
+            }
+
+            for (i, group) in groups.iter().enumerate() {
+                let (count, group_hosts) = (group.conn_ids.len(), hosts(&group.conn_ids));
+                match i {
+                    0 if groups.len() > 1 => println!("Majority ({} hosts): {}", count, group_hosts),
+                    0 => (),
+                    _ => println!("Differs in {} hosts: {}", count, group_hosts),
+                }
+
+                for file in &group.diffs {
+                    print!("{}", file.diff);
+                }
+            }
+        },
+        Some(FileSync::Processes { name, user, spawned, json }) => {
+            let filter = ProcessFilter { name, user, spawned };
+            let mut hosts = vec![];
+            for &id in &ids {
+                let host = master.addrs()[id];
+                let processes = master.list_processes(id, &filter)?;
+                if json {
+                    let processes = processes.iter().map(process_json).collect::<Vec<_>>();
+                    hosts.push(format!("{}:[{}]", json_string(&host.to_string()), processes.join(",")));
+                } else {
+                    for process in &processes {
+                        println!("{}: {}", host, process_line(process));
+                    }
+                }
+            }
+
+            if json {
+                println!("{{{}}}", hosts.join(","));
+            }
+        },
+        Some(FileSync::Kill { signal, name, spawned, group, pids }) => {
+            let target = match (name, spawned, pids.is_empty()) {
+                (Some(pattern), false, true) => ProcessTarget::Name(pattern),
+                (None, true, true) => ProcessTarget::Spawned,
+                (None, false, false) => ProcessTarget::Pids(pids),
+                _ => {
+                    println!("Specify either the process IDs, a name pattern, or --spawned");
+                    return Ok(())
+                },
+            };
+
+            for &id in &ids {
+                let host = master.addrs()[id];
+                let results = master.signal_processes(id, &target, signal, group)?;
+                if results.is_empty() {
+                    println!("{}: No matching processes", host);
+                }
+
+                for result in results {
+                    match result.error {
+                        Some(e) => println!("{}: Cannot signal {} ({})", host, result.pid, e),
+                        None => println!("{}: Sent signal {} to {}", host, signal, result.pid),
+                    }
+                }
+            }
+        },
+        Some(FileSync::Job { command }) => {
+            for &id in &ids {
+                let host = master.addrs()[id];
+                match command {
+                    JobCommand::Start { ref exec, ref command } => {
+                        let job = master.start_job(id, command, &exec.options())?;
+                        println!("{}: Started {}", host, job_line(&job));
+                    },
+                    JobCommand::List => {
+                        for job in master.list_jobs(id)? {
+                            println!("{}: {}", host, job_line(&job));
+                        }
+                    },
+                    JobCommand::Attach { id: job_id } => {
+                        let job = master.attach_job(id, job_id, HostLines { host, partial: vec![] })?;
+                        println!("{}: {}", host, job_line(&job));
+                    },
+                    JobCommand::Wait { id: job_id } => {
+                        let job = master.wait_job(id, job_id)?;
+                        println!("{}: {}", host, job_line(&job));
+                    },
+                    JobCommand::Cancel { id: job_id } => {
+                        let job = master.cancel_job(id, job_id)?;
+                        println!("{}: Cancelling {}", host, job_line(&job));
+                    },
+                }
+            }
+        },
+        Some(FileSync::Usage { path }) => {
+            for &id in &ids {
+                let usage = master.disk_usage(id, &path)?;
+                println!("{}: {} ({} on disk) in {} files and {} directories", master.addrs()[id],
+                         human_bytes(usage.size as f64), human_bytes(usage.allocated as f64),
+                         usage.files, usage.dirs);
+            }
         },
         _ => (),
     }
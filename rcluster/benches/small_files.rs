@@ -0,0 +1,76 @@
+#[macro_use] extern crate criterion;
+extern crate futures;
+extern crate rcluster;
+extern crate tokio_io;
+
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+use futures::{Async, Poll};
+use tokio_io::AsyncWrite;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+
+/// Number of files in the synced directory.
+const NUM_FILES: usize = 500;
+/// Size of each file.
+const FILE_SIZE: usize = 256;
+/// Default capacity of the connection's write buffer.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Socket whose other end is drained in a separate thread, so that every write
+/// to it is a syscall (like it'd be for a connection).
+struct Sink(UnixStream);
+
+impl Write for Sink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncWrite for Sink {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Create a directory with lots of small files.
+fn setup() -> PathBuf {
+    let dir = env::temp_dir().join("rcluster-bench-small-files");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for i in 0..NUM_FILES {
+        File::create(dir.join(format!("file-{}", i))).unwrap().write_all(&[42; FILE_SIZE]).unwrap();
+    }
+
+    dir
+}
+
+fn bench_small_files(c: &mut Criterion) {
+    let dir = setup();
+    let (sink, mut source) = UnixStream::pair().unwrap();
+    thread::spawn(move || io::copy(&mut source, &mut io::sink()));
+    let mut sink = Some(Sink(sink));
+
+    // Unbuffered writes behave like flushing every write (i.e., each flag, size and
+    // newline becomes its own write), while batched writes are flushed only at the end.
+    let capacities = vec![1, BUFFER_SIZE];
+    let benchmark = ParameterizedBenchmark::new("send", move |b, &capacity| {
+        b.iter(|| {
+            let writer = sink.take().unwrap();
+            sink = Some(rcluster::send_to_writer(&dir, writer, capacity).unwrap());
+        })
+    }, capacities).throughput(|_| Throughput::Elements(NUM_FILES as u32));
+
+    c.bench("small files (write buffer capacity)", benchmark);
+}
+
+criterion_group!(benches, bench_small_files);
+criterion_main!(benches);
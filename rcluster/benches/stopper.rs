@@ -0,0 +1,55 @@
+#[macro_use] extern crate criterion;
+extern crate rand;
+extern crate rcluster;
+
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+use rand::RngCore;
+
+/// Size of the content scanned in each iteration.
+const CONTENT_SIZE: usize = 1024 * 1024;
+const STOPPER_LENGTH: usize = 16;
+
+/// Random content (followed by the stopper) and the stopper. If `with_prefixes` is set,
+/// then every chunk of the given size ends with the first half of the stopper, so that
+/// the prefix is carried over to the next chunk every time.
+fn content(chunk_size: usize, with_prefixes: bool) -> (Vec<u8>, Vec<u8>) {
+    let mut rng = rand::thread_rng();
+    let mut stopper = vec![0; STOPPER_LENGTH];
+    rng.fill_bytes(&mut stopper);
+    let mut content = vec![0; CONTENT_SIZE];
+    rng.fill_bytes(&mut content);
+    if with_prefixes {
+        for chunk in content.chunks_mut(chunk_size) {
+            let len = chunk.len();
+            chunk[len - STOPPER_LENGTH / 2..].copy_from_slice(&stopper[..STOPPER_LENGTH / 2]);
+        }
+    }
+
+    content.extend_from_slice(&stopper);
+    (content, stopper)
+}
+
+fn bench_scan(c: &mut Criterion, name: &str, with_prefixes: bool) {
+    let sizes = vec![64, 1024, 8 * 1024, 64 * 1024];
+    let benchmark = ParameterizedBenchmark::new("scan", move |b, &size| {
+        let (content, stopper) = content(size, with_prefixes);
+        let chunks = content.chunks(size).collect::<Vec<_>>();
+        b.iter(|| rcluster::scan_for_stopper(&chunks, &stopper))
+    }, sizes).throughput(|_| Throughput::Bytes(CONTENT_SIZE as u32));
+
+    c.bench(name, benchmark);
+}
+
+/// Scanning random content.
+fn bench_random_scan(c: &mut Criterion) {
+    bench_scan(c, "stopper scan", false);
+}
+
+/// Scanning with the stopper's prefix at the end of every chunk, which is carried over
+/// (and fails to match) in the next chunk.
+fn bench_split_prefix_scan(c: &mut Criterion) {
+    bench_scan(c, "stopper split prefix scan", true);
+}
+
+criterion_group!(benches, bench_random_scan, bench_split_prefix_scan);
+criterion_main!(benches);
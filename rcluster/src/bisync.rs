@@ -0,0 +1,650 @@
+use checksum::{self, HASH_LENGTH};
+use connection::Connection;
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use filetime::FileTime;
+use fs_ops;
+use futures::Future;
+use itemize::{Change, ChangeItem, SyncReport};
+use path_sync::{self, SyncOptions};
+use pool;
+use sha2::{Digest, Sha256};
+use tokio_io::{AsyncRead, AsyncWrite};
+use walkdir::WalkDir;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// First line of the state files (so that older formats can be told apart).
+const STATE_HEADER: &str = "rcluster-state v2";
+/// Header of the older state files (whose paths aren't encoded).
+const STATE_HEADER_V1: &str = "rcluster-state v1";
+
+/// Wrapper for two-way syncs. Both the ends are compared with the state of the last sync
+/// (kept in master), so that the changes from either end can be propagated to the other.
+/// The master requests the manifest of its counterpart in slave, and once it decides what
+/// should be done, it sends and receives the files (using `PathSync`), and asks the slave
+/// to remove the files which have been removed in master.
+pub struct TwoWaySync<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
+
+/// State of a file in either end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileState {
+    pub hash: [u8; HASH_LENGTH],
+    /// Modification time (seconds since UNIX epoch).
+    pub mtime: i64,
+}
+
+/// Files (relative to the synced directory) along with their states. Only the files are
+/// synced - directories are created whenever they're needed.
+pub type Manifest = BTreeMap<PathBuf, FileState>;
+/// Hashes of the files (relative to the synced directory) after the last sync.
+pub type SyncState = BTreeMap<PathBuf, [u8; HASH_LENGTH]>;
+
+/// Policy for resolving conflicts (i.e., files which have been changed in both the ends
+/// since the last sync).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Leave both the ends alone, and only report the conflict (so that it can be fixed
+    /// manually). The conflict is reported in every sync until it's been fixed.
+    Report,
+    /// Version in master wins.
+    PreferMaster,
+    /// Version in slave wins.
+    PreferSlave,
+    /// Most recently modified version wins. Modifications win over removals.
+    PreferNewer,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Report
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "report" => Ok(ConflictPolicy::Report),
+            "master" => Ok(ConflictPolicy::PreferMaster),
+            "slave" => Ok(ConflictPolicy::PreferSlave),
+            "newer" => Ok(ConflictPolicy::PreferNewer),
+            _ => Err(format!("Unknown policy '{}' (expected report, master, slave or newer)", s)),
+        }
+    }
+}
+
+/// Change made to a file in one end since the last sync.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edit {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// File which has been changed in both the ends (differently) since the last sync.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    /// Path relative to the synced directory.
+    pub path: PathBuf,
+    pub master: Edit,
+    pub slave: Edit,
+    /// Whether the policy has resolved the conflict.
+    pub resolved: bool,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let edit = |e| match e {
+            Edit::Created => "created",
+            Edit::Modified => "modified",
+            Edit::Removed => "removed",
+        };
+
+        write!(f, "{} ({} in master, {} in slave){}", self.path.display(), edit(self.master),
+               edit(self.slave), if self.resolved { "" } else { " - unresolved" })
+    }
+}
+
+/// Options for a two-way sync.
+#[derive(Clone, Debug, Default)]
+pub struct TwoWayOptions {
+    pub policy: ConflictPolicy,
+    /// Directory for the state files (one for each pair of synced directories). If this
+    /// isn't set, then `~/.rcluster/state` is used.
+    pub state_dir: Option<PathBuf>,
+    /// Options for the sync. Exclude patterns apply to both the ends. In a dry run, nothing
+    /// is changed in either end (and the state isn't updated). Mirroring is ignored, since
+    /// removals are propagated anyway.
+    pub sync: SyncOptions,
+}
+
+/// Actions for bringing both the ends in sync, along with the conflicts.
+#[derive(Debug, Default, PartialEq)]
+pub struct TwoWayReport {
+    /// Files sent from master to slave.
+    pub pushed: Vec<PathBuf>,
+    /// Files received from slave.
+    pub pulled: Vec<PathBuf>,
+    /// Files removed from master (since they've been removed in slave).
+    pub removed_in_master: Vec<PathBuf>,
+    /// Files removed from slave (since they've been removed in master).
+    pub removed_in_slave: Vec<PathBuf>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl<R, W> TwoWaySync<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    /// (Master) Request the manifest of the directory in the other end.
+    pub fn request_manifest<P>(self, root: P,
+                               options: &SyncOptions) -> ClusterFuture<(Connection<R, W>, Manifest)>
+        where P: AsRef<Path>
+    {
+        let root = root.as_ref().to_path_buf();
+        let option_bytes = options.to_bytes();
+        let async_manifest = self.0.write_path(root)
+            .and_then(move |c| c.write_bytes(option_bytes))
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_lines())
+            .and_then(|(c, lines)| {
+                let mut manifest = Manifest::new();
+                for line in lines {
+                    let (path, state) = parse_manifest_line(&line).ok_or(ClusterError::InvalidManifest)?;
+                    path_sync::check_relative(&path)?;
+                    manifest.insert(path, state);
+                }
+
+                Ok((c, manifest))
+            });
+
+        Box::new(async_manifest) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request for a manifest, and send the manifest - hash, modification
+    /// time and relative path (encoded) of each file (one per line), followed by an empty line.
+    pub fn serve_manifest(self) -> ClusterFuture<Connection<R, W>> {
+        let roots = self.0.settings().roots.clone();
+        let async_serve = self.0.read_path()
+            .and_then(|(c, root)| SyncOptions::read_from(c).map(move |(c, opts)| (c, root, opts)))
+            .and_then(move |(c, root, options)| {
+                pool::run(move || -> ClusterResult<_> {
+                    manifest(&fs_ops::check_target(&roots, &root)?, &options)
+                }).map(move |m| (c, m))
+            }).and_then(|(c, manifest)| {
+                let mut bytes = vec![];
+                for (path, state) in &manifest {
+                    let line = format!("{} {} {}\n", checksum::to_hex(&state.hash), state.mtime,
+                                       fs_ops::encode_path(path));
+                    bytes.extend_from_slice(line.as_bytes());
+                }
+
+                bytes.push(b'\n');
+                c.write_bytes(bytes)
+            });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+
+    /// (Master) Remove the files (relative to the given directory) in the other end, along
+    /// with the directories if it's recursive. This resolves to the report of the removal.
+    pub fn remove_remote<P>(self, root: P, paths: &[PathBuf],
+                            recursive: bool) -> ClusterFuture<(Connection<R, W>, SyncReport)>
+        where P: AsRef<Path>
+    {
+        let mut bytes = fs_ops::encode_path(root.as_ref()).into_bytes();
+        bytes.push(b'\n');
+        bytes.push(recursive as u8);
+        for path in paths {
+            bytes.extend_from_slice(fs_ops::encode_path(path).as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes.push(b'\n');
+        let async_remove = self.0.write_bytes(bytes)
+            .and_then(|c| c.flush())
+            .and_then(|c| SyncReport::read_from(c));
+
+        Box::new(async_remove) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the paths to be removed (the directory, the flag for recursive removal,
+    /// and the relative paths (encoded) - one per line, followed by an empty line), remove
+    /// them, and send the report.
+    pub fn serve_removal(self) -> ClusterFuture<Connection<R, W>> {
+        let roots = self.0.settings().roots.clone();
+        let async_serve = self.0.read_path()
+            .and_then(|(c, root)| c.read_bytes([0; 1]).map(move |(c, flag)| (c, root, flag[0] != 0)))
+            .and_then(|(c, root, recursive)| {
+                c.read_lines().map(move |(c, paths)| (c, root, recursive, paths))
+            }).and_then(move |(c, root, recursive, paths)| {
+                pool::run(move || -> ClusterResult<_> {
+                    let paths = paths.iter().map(|p| fs_ops::decode_path(p))
+                                     .collect::<Option<Vec<_>>>().ok_or(ClusterError::InvalidPath)?;
+                    remove_files(&fs_ops::check_target(&roots, &root)?, &paths, recursive)
+                }).map(move |r| (c, r))
+            }).and_then(|(c, report)| c.write_bytes(report.to_bytes()));
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+}
+
+/// Parse a line of the manifest (hash, modification time and path).
+fn parse_manifest_line(line: &str) -> Option<(PathBuf, FileState)> {
+    let mut parts = line.splitn(3, ' ');
+    let hash = checksum::hash_from_hex(parts.next()?)?;
+    let mtime = parts.next()?.parse().ok()?;
+    let path = fs_ops::decode_path(parts.next()?).filter(|p| !p.as_os_str().is_empty())?;
+    Some((path, FileState { hash, mtime }))
+}
+
+/// Get the manifest of the files in the given directory (which haven't been excluded).
+/// Symlinks are ignored (like they are while syncing). If the root is a file, then the
+/// manifest has only that file (as `.`).
+pub fn manifest(root: &Path, options: &SyncOptions) -> ClusterResult<Manifest> {
+    let mut manifest = Manifest::new();
+    let metadata = match fs::symlink_metadata(root) {
+        Ok(m) => m,
+        Err(_) => return Ok(manifest),
+    };
+
+    if metadata.is_file() {
+        let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
+        let hash = checksum::file_hash(root)?;
+        manifest.insert(PathBuf::from("."), FileState { hash, mtime });
+        return Ok(manifest)
+    }
+
+    let mut entries = WalkDir::new(root).min_depth(1).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let entry_type = entry.file_type();
+        let rel_path = PathBuf::from(entry.path().strip_prefix(root).unwrap());
+        if options.is_excluded(&rel_path) {
+            if entry_type.is_dir() {
+                entries.skip_current_dir();
+            }
+
+            continue
+        }
+
+        if !entry_type.is_file() {
+            continue
+        }
+
+        let metadata = entry.metadata()?;
+        let mtime = FileTime::from_last_modification_time(&metadata).unix_seconds();
+        let hash = checksum::file_hash(entry.path())?;
+        manifest.insert(rel_path, FileState { hash, mtime });
+    }
+
+    Ok(manifest)
+}
+
+/// Remove the files (relative to the given directory), along with the directories (and
+/// everything in them) if it's recursive, and report them as deleted. The paths are checked
+/// (like the ones from a stream), and the ones which don't exist anymore are ignored.
+pub fn remove_files(root: &Path, paths: &[PathBuf], recursive: bool) -> ClusterResult<SyncReport> {
+    let mut report = SyncReport::default();
+    for path in paths {
+        path_sync::check_relative(path)?;
+        path_sync::check_parents(root, path)?;
+        let abs_path = root.join(path);
+        let is_dir = match fs::symlink_metadata(&abs_path) {
+            Ok(ref m) if m.is_file() => {
+                fs::remove_file(&abs_path)?;
+                false
+            },
+            Ok(ref m) if m.is_dir() && recursive => {
+                fs::remove_dir_all(&abs_path)?;
+                true
+            },
+            Ok(_) => {
+                report.warnings.push(format!("Not removing {} (it's not a file)", path.display()));
+                continue
+            },
+            Err(_) => continue,
+        };
+
+        info!("Removed {}", abs_path.display());
+        report.changes.push(ChangeItem { path: path.clone(), is_dir, change: Change::Deleted });
+    }
+
+    Ok(report)
+}
+
+/// Path of the state file for the given pair of directories (in this machine and in the
+/// slave with the given address).
+pub fn state_path(state_dir: Option<&Path>, addr: &SocketAddr, local: &Path,
+                  remote: &Path) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.input(format!("{}\0{}\0{}", addr, local.display(), remote.display()).as_bytes());
+    let name = checksum::to_hex(&hasher.result()[..16]);
+    match state_dir {
+        Some(dir) => dir.join(name),
+        None => {
+            let home = env::var("HOME").unwrap_or_else(|_| String::from("."));
+            Path::new(&home).join(".rcluster").join("state").join(name)
+        },
+    }
+}
+
+/// Load the state from the given file. If it doesn't exist, then this is the first sync,
+/// and so the state is empty.
+pub fn load_state(path: &Path) -> ClusterResult<SyncState> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(SyncState::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut lines = BufReader::new(file).lines();
+    let encoded = match lines.next() {
+        Some(Ok(ref l)) if l == STATE_HEADER => true,
+        Some(Ok(ref l)) if l == STATE_HEADER_V1 => false,
+        _ => return Err(ClusterError::InvalidManifest),
+    };
+
+    let mut state = SyncState::new();
+    for line in lines {
+        let line = line?;
+        let mut parts = line.splitn(2, ' ');
+        let hash = parts.next().and_then(checksum::hash_from_hex);
+        let path = match parts.next() {
+            Some(p) if encoded => fs_ops::decode_path(p),
+            p => p.map(PathBuf::from),
+        };
+
+        match (hash, path) {
+            (Some(hash), Some(path)) if !path.as_os_str().is_empty() => state.insert(path, hash),
+            _ => return Err(ClusterError::InvalidManifest),
+        };
+    }
+
+    Ok(state)
+}
+
+/// Save the state into the given file (atomically, so that an interrupted save doesn't
+/// leave a broken state behind).
+pub fn save_state(path: &Path, state: &SyncState) -> ClusterResult<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp)?;
+        writeln!(file, "{}", STATE_HEADER)?;
+        for (path, hash) in state {
+            writeln!(file, "{} {}", checksum::to_hex(hash), fs_ops::encode_path(path))?;
+        }
+
+        file.sync_all()?;
+    }
+
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Change made to a file in one end (compared to the last sync), if any.
+fn edit(current: Option<&FileState>, last: Option<&[u8; HASH_LENGTH]>) -> Option<Edit> {
+    match (current, last) {
+        (Some(c), Some(l)) if c.hash == *l => None,
+        (Some(_), Some(_)) => Some(Edit::Modified),
+        (Some(_), None) => Some(Edit::Created),
+        (None, Some(_)) => Some(Edit::Removed),
+        (None, None) => None,
+    }
+}
+
+/// Decide what should be done for bringing both the ends in sync. Changes in only one end
+/// are propagated to the other, while the changes in both the ends are resolved with the
+/// policy (or reported).
+pub fn plan(master: &Manifest, slave: &Manifest, state: &SyncState,
+            policy: ConflictPolicy) -> TwoWayReport {
+    let mut report = TwoWayReport::default();
+    let paths = master.keys().chain(slave.keys()).chain(state.keys()).collect::<BTreeSet<_>>();
+    for path in paths {
+        let (m, s) = (master.get(path), slave.get(path));
+        // Both the ends already agree.
+        if m.map(|f| f.hash) == s.map(|f| f.hash) {
+            continue
+        }
+
+        let last = state.get(path);
+        let master_wins = match (edit(m, last), edit(s, last)) {
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(master_edit), Some(slave_edit)) => {
+                let winner = match (policy, m, s) {
+                    (ConflictPolicy::Report, _, _) => None,
+                    (ConflictPolicy::PreferMaster, _, _) => Some(true),
+                    (ConflictPolicy::PreferSlave, _, _) => Some(false),
+                    (ConflictPolicy::PreferNewer, Some(m), Some(s)) if m.mtime != s.mtime => {
+                        Some(m.mtime > s.mtime)
+                    },
+                    (ConflictPolicy::PreferNewer, Some(_), None) => Some(true),
+                    (ConflictPolicy::PreferNewer, None, Some(_)) => Some(false),
+                    (ConflictPolicy::PreferNewer, _, _) => None,
+                };
+
+                report.conflicts.push(Conflict {
+                    path: path.clone(),
+                    master: master_edit,
+                    slave: slave_edit,
+                    resolved: winner.is_some(),
+                });
+
+                match winner {
+                    Some(w) => w,
+                    None => continue,
+                }
+            },
+            // Both the ends are the same as the last sync (which can't be, since they differ).
+            (None, None) => continue,
+        };
+
+        let path = path.clone();
+        match (master_wins, m.is_some(), s.is_some()) {
+            (true, true, _) => report.pushed.push(path),
+            (true, false, _) => report.removed_in_slave.push(path),
+            (false, _, true) => report.pulled.push(path),
+            (false, _, false) => report.removed_in_master.push(path),
+        }
+    }
+
+    report
+}
+
+/// State after the sync (once the actions in the report have been carried out). Unresolved
+/// conflicts retain their last state, so that they're reported again in the next sync.
+pub fn next_state(master: &Manifest, slave: &Manifest, state: &SyncState,
+                  report: &TwoWayReport) -> SyncState {
+    let mut next = state.clone();
+    let paths = master.keys().chain(slave.keys()).chain(state.keys()).collect::<BTreeSet<_>>();
+    for path in paths {
+        let (m, s) = (master.get(path), slave.get(path));
+        if report.pushed.contains(path) {
+            next.insert(path.clone(), m.unwrap().hash);
+        } else if report.pulled.contains(path) {
+            next.insert(path.clone(), s.unwrap().hash);
+        } else if report.removed_in_master.contains(path) || report.removed_in_slave.contains(path) {
+            next.remove(path);
+        } else {
+            match (m, s) {
+                (Some(m), Some(s)) if m.hash == s.hash => {
+                    next.insert(path.clone(), m.hash);
+                },
+                (None, None) => {
+                    next.remove(path);
+                },
+                _ => (),
+            }
+        }
+    }
+
+    next
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use checksum;
+    use connection::{loopback, response};
+    use fs_ops;
+    use futures::Future;
+    use path_sync::SyncOptions;
+    use rand::{self, RngCore};
+    use super::{ConflictPolicy, Edit, FileState, Manifest, SyncState, TwoWayReport, TwoWaySync};
+    use super::{load_state, manifest, next_state, plan, remove_files, save_state};
+
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    fn file(hash: u8, mtime: i64) -> FileState {
+        FileState { hash: [hash; 32], mtime }
+    }
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_plan_changes() {
+        let state = [("same", 1), ("edited", 1), ("gone", 1), ("both", 1), ("dropped", 1)]
+            .iter().map(|&(p, h)| (PathBuf::from(p), [h; 32])).collect::<SyncState>();
+        let master = [("same", file(1, 0)), ("edited", file(2, 0)), ("gone", file(1, 0)),
+                      ("both", file(2, 10)), ("new", file(3, 0))]
+            .iter().map(|&(p, f)| (PathBuf::from(p), f)).collect::<Manifest>();
+        let slave = [("same", file(1, 0)), ("edited", file(1, 0)), ("both", file(3, 20)),
+                     ("dropped", file(1, 0)), ("new", file(3, 0)), ("remote", file(4, 0))]
+            .iter().map(|&(p, f)| (PathBuf::from(p), f)).collect::<Manifest>();
+
+        let report = plan(&master, &slave, &state, ConflictPolicy::Report);
+        assert_eq!(report.pushed, paths(&["edited"]));
+        assert_eq!(report.pulled, paths(&["remote"]));
+        assert_eq!(report.removed_in_master, paths(&["gone"]));
+        assert_eq!(report.removed_in_slave, paths(&["dropped"]));
+        assert_eq!(report.conflicts.len(), 1);
+        let conflict = &report.conflicts[0];
+        assert_eq!((&*conflict.path, conflict.master, conflict.slave, conflict.resolved),
+                   (PathBuf::from("both").as_path(), Edit::Modified, Edit::Modified, false));
+
+        // Unresolved conflicts retain their state, while the rest take the new state.
+        let next = next_state(&master, &slave, &state, &report);
+        let expected = [("same", 1), ("edited", 2), ("both", 1), ("new", 3), ("remote", 4)]
+            .iter().map(|&(p, h)| (PathBuf::from(p), [h; 32])).collect::<SyncState>();
+        assert_eq!(next, expected);
+
+        // Policies resolve the conflicts.
+        let report = plan(&master, &slave, &state, ConflictPolicy::PreferMaster);
+        assert_eq!(report.pushed, paths(&["both", "edited"]));
+        assert!(report.conflicts[0].resolved);
+        let report = plan(&master, &slave, &state, ConflictPolicy::PreferSlave);
+        assert_eq!(report.pulled, paths(&["both", "remote"]));
+        let report = plan(&master, &slave, &state, ConflictPolicy::PreferNewer);
+        assert_eq!(report.pulled, paths(&["both", "remote"]));
+    }
+
+    #[test]
+    fn test_plan_removal_conflicts() {
+        let state = [(PathBuf::from("foo"), [1; 32])].iter().cloned().collect::<SyncState>();
+        let master = Manifest::new();
+        let slave = [(PathBuf::from("foo"), file(2, 0))].iter().cloned().collect::<Manifest>();
+
+        let report = plan(&master, &slave, &state, ConflictPolicy::PreferNewer);
+        assert_eq!(report.conflicts[0].master, Edit::Removed);
+        assert_eq!(report.pulled, paths(&["foo"]));
+        let report = plan(&master, &slave, &state, ConflictPolicy::PreferMaster);
+        assert_eq!(report.removed_in_slave, paths(&["foo"]));
+        assert_eq!(next_state(&master, &slave, &state, &report), SyncState::new());
+
+        // Nothing to do if both the ends have removed it.
+        let report = plan(&master, &Manifest::new(), &state, ConflictPolicy::Report);
+        assert_eq!(report, TwoWayReport::default());
+    }
+
+    #[test]
+    fn test_manifest_and_state() {
+        let root = env::temp_dir().join(format!("rcluster-bisync-{}", rand::thread_rng().next_u64()));
+        fs::create_dir_all(root.join("dir/nested")).unwrap();
+        File::create(root.join("dir/nested/foo")).unwrap().write_all(b"foo").unwrap();
+        File::create(root.join("bar")).unwrap().write_all(b"bar").unwrap();
+        File::create(root.join("dir/skipped.swp")).unwrap();
+        let mut options = SyncOptions::default();
+        options.exclude("*.swp").unwrap();
+
+        let files = manifest(&root, &options).unwrap();
+        assert_eq!(files.keys().cloned().collect::<Vec<_>>(), paths(&["bar", "dir/nested/foo"]));
+        // File has only itself.
+        let file = manifest(&root.join("bar"), &options).unwrap();
+        assert_eq!(file.get(&PathBuf::from(".")), files.get(&PathBuf::from("bar")));
+        let state = files.iter().map(|(p, f)| (p.clone(), f.hash)).collect::<SyncState>();
+        let state_path = root.join("state/pair");
+        assert_eq!(load_state(&state_path).unwrap(), SyncState::new());
+        save_state(&state_path, &state).unwrap();
+        assert_eq!(load_state(&state_path).unwrap(), state);
+        // Older state files (without encoded paths) can still be loaded.
+        let hash = checksum::to_hex(&state[&PathBuf::from("bar")]);
+        let old_state = format!("rcluster-state v1\n{} bar\n", hash);
+        File::create(&state_path).unwrap().write_all(old_state.as_bytes()).unwrap();
+        let loaded = load_state(&state_path).unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), [&PathBuf::from("bar")]);
+
+        let report = remove_files(&root, &paths(&["bar", "dir", "missing"]), false).unwrap();
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(!root.join("bar").exists());
+        assert!(remove_files(&root, &paths(&["../escaped"]), false).is_err());
+        let report = remove_files(&root, &paths(&["dir"]), true).unwrap();
+        assert!(report.changes[0].is_dir);
+        assert!(!root.join("dir").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_exchange() {
+        let root = env::temp_dir().join(format!("rcluster-manifest-{}", rand::thread_rng().next_u64()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        File::create(root.join("dir/foo bar")).unwrap().write_all(b"foo").unwrap();
+        // Names could have newlines, and they needn't be UTF-8.
+        let odd = PathBuf::from(OsStr::from_bytes(b"dir/new\n\nline\xff"));
+        File::create(root.join(&odd)).unwrap();
+        let options = SyncOptions::default();
+
+        // Request (as written by master) is served by the slave.
+        let mut request = format!("{}\n", fs_ops::encode_path(&root)).into_bytes();
+        request.extend(options.to_bytes());
+        let conn = TwoWaySync(loopback(request, [0; 16])).serve_manifest().wait().unwrap();
+        let (_, files) = TwoWaySync(loopback(response(conn), [0; 16]))
+            .request_manifest(&root, &options)
+            .wait().unwrap();
+        assert_eq!(files, manifest(&root, &options).unwrap());
+        let expected = [PathBuf::from("dir/foo bar"), odd.clone()];
+        assert_eq!(files.keys().cloned().collect::<Vec<_>>(), expected);
+
+        // Removal (as requested by master) removes the same file.
+        let empty_report = b"\n\n\n".to_vec();
+        let (conn, _) = TwoWaySync(loopback(empty_report, [0; 16]))
+            .remove_remote(&root, &[odd.clone()], false)
+            .wait().unwrap();
+        TwoWaySync(loopback(response(conn), [0; 16])).serve_removal().wait().unwrap();
+        assert!(!root.join(&odd).exists());
+        assert!(root.join("dir/foo bar").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
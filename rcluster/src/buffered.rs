@@ -1,228 +1,455 @@
+use checksum::{self, HASH_LENGTH};
+use compression::{Codec, CodecWriter};
 use errors::{ClusterError, ClusterFuture};
-use futures::future;
-
-use std::cell::Cell;
+use futures::{Async, Future, Poll};
+use matcher::StopperMatcher;
+use pool::{PoolReader, PoolWriter};
+use progress::ProgressTracker;
+use ratelimit::{Limiters, Throttled};
+use sha2::Sha256;
+use sparse::{Extent, ExtentReader, ExtentWriter};
+
+use std::cmp;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
 use std::path::Path;
 
 /// Buffer size used throughout the library.
 pub const BUFFER_SIZE: usize = 8 * 1024;
+/// Number of consecutive reads which should fill the buffer before an adaptive buffer grows.
+const FULL_READS_BEFORE_GROWING: usize = 4;
+
+/// Size of the buffers used for streaming.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferSize {
+    Fixed(usize),
+    /// Start with the initial size, and keep doubling the read buffer (until the maximum)
+    /// whenever the reads keep filling it (i.e., when the other end is faster than us).
+    Adaptive {
+        initial: usize,
+        max: usize,
+    },
+}
+
+impl Default for BufferSize {
+    fn default() -> Self { BufferSize::Fixed(BUFFER_SIZE) }
+}
+
+impl BufferSize {
+    /// Size of the buffers to begin with.
+    #[inline]
+    pub fn initial(&self) -> usize {
+        match *self {
+            BufferSize::Fixed(size) => size,
+            BufferSize::Adaptive { initial, .. } => initial,
+        }
+    }
+
+    /// Get the size to which a buffer of the given capacity should grow (if it can).
+    fn grow(&self, capacity: usize) -> Option<usize> {
+        match *self {
+            BufferSize::Adaptive { max, .. } if capacity < max =>
+                Some(cmp::min(capacity.saturating_mul(2), max)),
+            _ => None,
+        }
+    }
+}
+
+/// Writer (along with the transformations) used while streaming.
+type Sink<W> = CodecWriter<Throttled<BufWriter<W>>>;
 
 /// A "streamer" used on read/write halves for streaming stuff (like files, command output, etc.).
 /// It works much like multipart data. In the read end of the stream, this checks for magic
 /// bytes - once it encounters them, it consumes those bytes and stops reading. Hence, the reader's
 /// cursor will be positioned just after the magic bytes.
+///
+/// This is a future, which never blocks - if the reader or writer isn't ready
+/// (i.e., they fail with `WouldBlock`), it simply waits for them to be ready.
+///
+/// There's no zero-copy path (`sendfile` or `splice`) here. One end is always a connection,
+/// whose bytes are encrypted (or decrypted) by rustls in userspace, and rustls doesn't support
+/// kernel TLS offload. So, even the plain ends (files, pipes of child processes, stdout) have
+/// to go through the buffers.
 pub struct StreamingBuffer<R: Read, W: Write> {
     reader: Option<BufReader<R>>,
     writer: Option<BufWriter<W>>,
-    stop_bytes: Box<[u8]>,
-    prev_bytes_unwritten: Box<[u8]>,
-    status: Cell<StreamerStatus>,
+    matcher: StopperMatcher,
+    codec: Codec,
+    limiters: Limiters,
+    progress: ProgressTracker,
+    hasher: Option<Sha256>,
+    buffer_size: BufferSize,
+    /// Whether the writer should be flushed once the content has been streamed.
+    flush: bool,
+    /// Whether the writer should be flushed whenever the reader isn't ready.
+    idle_flush: bool,
+    /// Whether bytes have been written to the sink since it was last flushed.
+    dirty: bool,
+    /// Number of consecutive reads which have filled the read buffer.
+    full_reads: usize,
+    sink: Option<Sink<W>>,
+    /// Bytes which should be written to the sink before reading further.
+    pending: Vec<u8>,
+    pending_pos: usize,
+    content_ended: bool,
+}
+
+impl<R, W> StreamingBuffer<R, W>
+    where R: Read, W: Write
+{
+    /// Initialize this struct for streaming from the reader to the writer, until the given
+    /// magic bytes (or until EOF, if the magic bytes are empty). The reader and writer should
+    /// be non-blocking (say, async sources and sinks) or in-memory buffers - blocking ones
+    /// should go through the pool instead.
+    pub fn new(reader: BufReader<R>, writer: BufWriter<W>, stop_bytes: &[u8]) -> Self {
+        StreamingBuffer {
+            reader: Some(reader),
+            writer: Some(writer),
+            matcher: StopperMatcher::new(stop_bytes),
+            codec: Codec::Identity,
+            limiters: Limiters::default(),
+            progress: ProgressTracker::default(),
+            hasher: None,
+            buffer_size: BufferSize::default(),
+            flush: true,
+            idle_flush: false,
+            dirty: false,
+            full_reads: 0,
+            sink: None,
+            pending: Vec::with_capacity(BUFFER_SIZE),
+            pending_pos: 0,
+            content_ended: false,
+        }
+    }
+}
+
+impl<T, W> StreamingBuffer<PoolReader<T>, W>
+    where T: Read + Send + 'static, W: Write + 'static
+{
+    /// Initialize this struct for streaming from a blocking reader (say, a pipe of a child
+    /// process) onto a stream until EOF. The reads happen in a thread of its own.
+    pub fn blocking_to_stream(source: T, stream: BufWriter<W>, buffer_size: BufferSize) -> Self {
+        StreamingBuffer::pooled_to_stream(PoolReader::with_thread(source), stream, buffer_size)
+    }
+
+    fn pooled_to_stream(reader: PoolReader<T>, stream: BufWriter<W>,
+                        buffer_size: BufferSize) -> Self {
+        let reader = BufReader::with_capacity(buffer_size.initial(), reader);
+        let mut streamer = StreamingBuffer::new(reader, stream, &[]);
+        streamer.buffer_size = buffer_size;
+        streamer
+    }
 }
 
-impl<W> StreamingBuffer<File, W>
+impl<W> StreamingBuffer<PoolReader, W>
     where W: Write + 'static
 {
-    /// Initialize this struct for reading file onto a stream.
+    /// Initialize this struct for reading file (starting from the given offset) onto a stream.
+    /// The buffer size applies to reading the file.
     #[inline]
-    pub fn file_to_stream<P>(path: P, stream: BufWriter<W>)
+    pub fn file_to_stream<P>(path: P, offset: u64, stream: BufWriter<W>, buffer_size: BufferSize)
                             -> ClusterFuture<Self>
         where P: AsRef<Path>
     {
-        info!("Reading from {}", path.as_ref().display());
-        let reader = File::open(path).map(|f| BufReader::with_capacity(BUFFER_SIZE, f)).map(Some);
-        let async_streamer = reader.map(|reader| {
-            StreamingBuffer {
-                reader,
-                writer: Some(stream),
-                stop_bytes: Box::new([]),
-                prev_bytes_unwritten: Box::new([]),
-                status: Cell::new(StreamerStatus::StopperNotFound),
-            }
-        }).map_err(ClusterError::from);
+        info!("Reading from {} (offset: {})", path.as_ref().display(), offset);
+        let async_streamer = PoolReader::open(path.as_ref().to_owned(), offset).map(move |reader| {
+            StreamingBuffer::pooled_to_stream(reader, stream, buffer_size)
+        });
+
+        Box::new(async_streamer) as ClusterFuture<Self>
+    }
+}
+
+impl<W> StreamingBuffer<PoolReader<ExtentReader<File>>, W>
+    where W: Write + 'static
+{
+    /// Initialize this struct for reading the given extents of a (sparse) file onto a stream.
+    /// The buffer size applies to reading the file.
+    pub fn extents_to_stream<P>(path: P, extents: Vec<Extent>, stream: BufWriter<W>,
+                                buffer_size: BufferSize) -> ClusterFuture<Self>
+        where P: AsRef<Path>
+    {
+        info!("Reading {} extents from {}", extents.len(), path.as_ref().display());
+        let async_streamer = PoolReader::open_extents(path.as_ref().to_owned(), extents)
+            .map(move |reader| StreamingBuffer::pooled_to_stream(reader, stream, buffer_size));
 
-        Box::new(future::result(async_streamer)) as ClusterFuture<Self>
+        Box::new(async_streamer) as ClusterFuture<Self>
     }
 }
 
-impl<R> StreamingBuffer<R, File>
+impl<R, T> StreamingBuffer<R, PoolWriter<T>>
+    where R: Read + 'static, T: Write + Send + 'static
+{
+    /// Initialize this struct for streaming onto a blocking writer (say, stdout or a pipe of
+    /// a child process) until the given magic bytes (or until EOF, if they're empty). The
+    /// writes happen in a thread of its own.
+    pub fn stream_to_blocking(stream: BufReader<R>, stop_bytes: &[u8], sink: T,
+                              buffer_size: BufferSize) -> Self {
+        let writer = PoolWriter::with_thread(sink);
+        StreamingBuffer::stream_to_pooled(stream, stop_bytes, writer, buffer_size)
+    }
+
+    fn stream_to_pooled(stream: BufReader<R>, stop_bytes: &[u8], writer: PoolWriter<T>,
+                        buffer_size: BufferSize) -> Self {
+        let writer = BufWriter::with_capacity(buffer_size.initial(), writer);
+        let mut streamer = StreamingBuffer::new(stream, writer, stop_bytes);
+        streamer.buffer_size = buffer_size;
+        streamer
+    }
+}
+
+impl<R> StreamingBuffer<R, PoolWriter>
     where R: Read + 'static
 {
     /// Initialize this struct for writing to file from a stream. Note that this requires
     /// the magic bytes after which the streaming should be stopped. If the magic bytes are
-    /// empty, then the entire stream (until EOF) is written to file.
+    /// empty, then the entire stream (until EOF) is written to file. The file is truncated
+    /// to the given offset, and the stream is appended from there (the blocks up to the
+    /// expected size are allocated upfront). The buffer size applies to writing the file
+    /// (and growing the stream's buffer, if it's adaptive).
     #[inline]
-    pub fn stream_to_file<P>(stream: BufReader<R>, stop_bytes: &[u8], path: P)
-                            -> ClusterFuture<Self>
+    pub fn stream_to_file<P>(stream: BufReader<R>, stop_bytes: &[u8], path: P, offset: u64,
+                             size: u64, buffer_size: BufferSize) -> ClusterFuture<Self>
         where P: AsRef<Path>
     {
-        info!("Writing to {}", path.as_ref().display());
-        let writer = File::create(path).map(|f| BufWriter::with_capacity(BUFFER_SIZE, f)).map(Some);
-        let async_streamer = writer.map(|writer| {
-            StreamingBuffer {
-                reader: Some(stream),
-                writer,
-                stop_bytes: stop_bytes.into(),
-                prev_bytes_unwritten: Box::new([]),
-                status: Cell::new(StreamerStatus::StopperNotFound),
-            }
-        }).map_err(ClusterError::from);
+        info!("Writing to {} (offset: {})", path.as_ref().display(), offset);
+        let stop_bytes = Vec::from(stop_bytes);
+        let async_streamer = PoolWriter::open(path.as_ref().to_owned(), offset, size)
+            .map(move |writer| {
+                StreamingBuffer::stream_to_pooled(stream, &stop_bytes, writer, buffer_size)
+            });
+
+        Box::new(async_streamer) as ClusterFuture<Self>
+    }
+}
 
-        Box::new(future::result(async_streamer)) as ClusterFuture<Self>
+impl<R> StreamingBuffer<R, PoolWriter<ExtentWriter<File>>>
+    where R: Read + 'static
+{
+    /// Initialize this struct for writing to the given extents of a (sparse) file from
+    /// a stream, until the given magic bytes. The file is truncated to the given offset,
+    /// and the extents should come after it.
+    pub fn stream_to_extents<P>(stream: BufReader<R>, stop_bytes: &[u8], path: P, offset: u64,
+                                extents: Vec<Extent>, buffer_size: BufferSize) -> ClusterFuture<Self>
+        where P: AsRef<Path>
+    {
+        info!("Writing {} extents to {} (offset: {})", extents.len(),
+              path.as_ref().display(), offset);
+        let stop_bytes = Vec::from(stop_bytes);
+        let async_streamer = PoolWriter::open_extents(path.as_ref().to_owned(), offset, extents)
+            .map(move |writer| {
+                StreamingBuffer::stream_to_pooled(stream, &stop_bytes, writer, buffer_size)
+            });
+
+        Box::new(async_streamer) as ClusterFuture<Self>
     }
 }
 
-/// Status of this streamer. This determines whether the streaming should continue/stop.
-#[derive(Copy, Clone, Debug, PartialEq)]
-enum StreamerStatus {
-    /// Some of the bytes in the end of the current chunk match with the stopper's prefix.
-    /// This variant carries "N" - check the first 'N' bytes from the next chunk against
-    /// stopper's suffix.
-    StopperPrefixFound(usize),
-    /// Stopper byte found at Nth "index"
-    StopperFound(usize),
-    /// Stopper's suffix (of length "N" in this variant) matches with the first "N" bytes in chunk.
-    StopperExtendsFromPrevious(usize),
-    /// All bytes from previous chunk are wasted - they have to passed to the writer.
-    PreviousBytesWasted,
-    /// Stopper not found in this chunk.
-    StopperNotFound,
+/// Scan the chunks for the stopper (the way the streamer does while reading), and get the
+/// number of chunks scanned until the stopper was found. This only exists for benchmarks.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub fn scan_for_stopper(chunks: &[&[u8]], stopper: &[u8]) -> usize {
+    let mut matcher = StopperMatcher::new(stopper);
+    chunks.iter().position(|c| matcher.feed(c).is_some()).map(|i| i + 1).unwrap_or(chunks.len())
 }
 
 impl<R, W> StreamingBuffer<R, W>
-    where R: Read, W: Write
+    where R: Read + 'static, W: Write + 'static
 {
-    /// Check the previously unwritten bytes (with the new chunk) for possible stopper.
-    fn check_previous_bytes_with(&mut self, parent_bytes: &[u8]) {
-        let stop_len = self.stop_bytes.len();
-        if let StreamerStatus::StopperPrefixFound(len) = self.status.get() {
-            if parent_bytes.starts_with(&self.stop_bytes[(stop_len - len)..]) {
-                self.status.set(StreamerStatus::StopperExtendsFromPrevious(len));
-                return
-            }
-        }
+    /// Apply the given codec to the content while streaming.
+    #[inline]
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
 
-        self.status.set(StreamerStatus::PreviousBytesWasted);
+    /// Throttle the bytes written to the writer with the given rate limiters.
+    #[inline]
+    pub fn with_limiters(mut self, limiters: Limiters) -> Self {
+        self.limiters = limiters;
+        self
     }
 
-    /// Isolate some bytes for checking against magic during the incoming of next chunk from buffer.
-    fn check_suffix_bytes<'a>(&mut self, parent_bytes: &'a [u8]) {
-        if parent_bytes.is_empty() {
-            return
-        }
+    /// Report the bytes consumed from the reader to the given tracker.
+    #[inline]
+    pub fn with_progress(mut self, progress: ProgressTracker) -> Self {
+        self.progress = progress;
+        self
+    }
 
-        if let StreamerStatus::StopperExtendsFromPrevious(_) = self.status.get() {
-            // Stopper found - we no longer need to stream, so we don't care now!
-            return
-        }
+    /// Set whether the writer should be flushed once the content has been streamed (it's
+    /// flushed by default). Connections needn't be flushed here, since the content is
+    /// usually followed by more bytes, and they're flushed at the message boundaries.
+    #[inline]
+    pub fn with_flush(mut self, flush: bool) -> Self {
+        self.flush = flush;
+        self
+    }
 
-        let parent_len = parent_bytes.len();
-        let stop_len = self.stop_bytes.len();
-
-        self.prev_bytes_unwritten = if parent_bytes.len() >= stop_len {
-            parent_bytes[(parent_len - stop_len)..].into()
-        } else {
-            parent_bytes.into()
-        };
-
-        let prev_suffix = &self.prev_bytes_unwritten[..];
-        let stopper_prefix = &self.stop_bytes[..];
-        for i in 0..prev_suffix.len() {
-            let prev = &prev_suffix[i..];
-            let stopper = &stopper_prefix[..prev.len()];
-            if prev.starts_with(stopper) {
-                let remaining = stop_len - prev.len();
-                if remaining == 0 {
-                    self.status.set(StreamerStatus::StopperFound(parent_len - stop_len));
-                    return
-                } else {
-                    self.status.set(StreamerStatus::StopperPrefixFound(remaining));
-                    return
-                }
-            }
-        }
+    /// Flush the writer (along with the codec) whenever the reader has nothing to offer, so
+    /// that the content reaches the other end as soon as it's produced (say, for a file
+    /// that's being followed).
+    #[inline]
+    pub fn with_idle_flush(mut self, idle_flush: bool) -> Self {
+        self.idle_flush = idle_flush;
+        self
+    }
 
-        self.status.set(StreamerStatus::StopperNotFound);
+    /// Start streaming. This returns a future that resolves to the reader and writer.
+    pub fn stream(self) -> ClusterFuture<(BufReader<R>, BufWriter<W>)> {
+        Box::new(self.map(|(r, w, _)| (r, w))) as ClusterFuture<_>
     }
 
-    /// Get the suffix bytes from previous chunk (which haven't been written yet)
-    #[inline]
-    fn get_unwritten_bytes(&self) -> Option<&[u8]> {
-        match self.status.get() {
-            StreamerStatus::StopperExtendsFromPrevious(len) =>
-                Some(&self.prev_bytes_unwritten[..len]),
-            StreamerStatus::PreviousBytesWasted =>
-                Some(&self.prev_bytes_unwritten),
-            _ => None,
-        }
+    /// Start streaming, while hashing the streamed content (i.e., the uncompressed bytes, in
+    /// case there's a codec) with the given hasher. The hasher could already have some content
+    /// (say, the prefix of a file that's being resumed). This resolves to the reader, writer
+    /// and the final hash.
+    pub fn stream_hashed(mut self, hasher: Sha256)
+                        -> ClusterFuture<(BufReader<R>, BufWriter<W>, [u8; HASH_LENGTH])>
+    {
+        self.hasher = Some(hasher);
+        let async_stream = self.map(|(r, w, h)| (r, w, checksum::finish(h.expect("hasher"))));
+        Box::new(async_stream) as ClusterFuture<_>
+    }
+}
+
+/// Convert the result of a non-blocking operation to a `Poll`.
+fn poll_io<T>(result: io::Result<T>) -> Poll<T, ClusterError> {
+    match result {
+        Ok(t) => Ok(Async::Ready(t)),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(ClusterError::from(e)),
     }
 }
 
 impl<R, W> StreamingBuffer<R, W>
-    where R: Read + 'static, W: Write + 'static
+    where R: Read, W: Write
 {
-    /// Start streaming. This returns a future that resolves to the reader and writer.
-    pub fn stream(mut self) -> ClusterFuture<(BufReader<R>, BufWriter<W>)> {
-        let (mut r, mut w) = (self.reader.take().unwrap(), self.writer.take().unwrap());
+    /// Write the pending bytes to the sink.
+    fn poll_pending(&mut self) -> Poll<(), ClusterError> {
+        let sink = self.sink.as_mut().expect("sink");
+        while self.pending_pos < self.pending.len() {
+            match try_ready!(poll_io(sink.write(&self.pending[self.pending_pos..]))) {
+                0 => return Err(ClusterError::from(io::Error::from(ErrorKind::WriteZero))),
+                n => self.pending_pos += n,
+            }
+
+            self.dirty = true;
+        }
+
+        self.pending.clear();
+        self.pending_pos = 0;
+        Ok(Async::Ready(()))
+    }
 
-        let mut content_ended = false;
+    /// Stream from the reader until the content ends. This stops midway (with the new size)
+    /// if the reader's buffer should grow.
+    fn poll_content(&mut self, r: &mut BufReader<R>) -> Poll<Option<usize>, ClusterError> {
         loop {
-            let consumed = {
-                let mut call = || -> Result<_, _> {
-                    let bytes = r.fill_buf()?;
-
-                    let (consume_amt, write_amt) = if self.stop_bytes.is_empty() {
-                        // If there's no stopper, then we consume and write everything.
-                        (bytes.len(), bytes.len())
-                    } else {
-                        self.check_previous_bytes_with(bytes);
-                        if let Some(prev_bytes) = self.get_unwritten_bytes() {
-                            w.write_all(&prev_bytes)?;
-                        }
+            try_ready!(self.poll_pending());
+            if self.content_ended {
+                return Ok(Async::Ready(None))
+            }
 
-                        self.check_suffix_bytes(bytes);
-                        match self.status.get() {
-                            StreamerStatus::StopperFound(idx) => {
-                                content_ended = true;
-                                (idx + self.stop_bytes.len(), idx)
-                            },
-                            StreamerStatus::StopperExtendsFromPrevious(suffix_len) => {
-                                content_ended = true;
-                                (suffix_len, 0)
-                            },
-                            _ => (bytes.len(), bytes.len().saturating_sub(self.stop_bytes.len())),
+            let capacity = r.capacity();
+            let (consume_amt, filled) = {
+                let bytes = match r.fill_buf() {
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock && self.idle_flush => {
+                        if self.dirty {
+                            try_ready!(poll_io(self.sink.as_mut().expect("sink").flush()));
+                            self.dirty = false;
                         }
-                    };
 
-                    if bytes.is_empty() {
-                        content_ended = true;
-                        return Ok(0)
+                        return Ok(Async::NotReady)
+                    },
+                    result => try_ready!(poll_io(result)),
+                };
+                let filled = bytes.len() == capacity;
+                if bytes.is_empty() {
+                    if !self.matcher.stopper().is_empty() {
+                        // Stream has ended before the stopper (connection has been dropped?)
+                        let err = io::Error::new(ErrorKind::UnexpectedEof, "stopper not found");
+                        return Err(ClusterError::from(err))
                     }
 
-                    if write_amt > 0 {
-                        w.write_all(&bytes[..write_amt])?;
-                    }
+                    self.content_ended = true;
+                    continue
+                }
 
-                    Ok(consume_amt)
+                // Bytes which could be the beginning of the stopper are held back (until
+                // the next chunk tells otherwise). These are the same as the stopper's prefix,
+                // so the content is whatever precedes the stopper in (held bytes + chunk).
+                let held = self.matcher.matched();
+                let (consume_amt, content_len) = match self.matcher.feed(bytes) {
+                    Some(end) => {
+                        self.content_ended = true;
+                        (end, held + end - self.matcher.stopper().len())
+                    },
+                    None => (bytes.len(), held + bytes.len() - self.matcher.matched()),
                 };
 
-                call()
+                let from_held = cmp::min(content_len, held);
+                self.pending.extend_from_slice(&self.matcher.stopper()[..from_held]);
+                self.pending.extend_from_slice(&bytes[..content_len - from_held]);
+                (consume_amt, filled)
             };
 
-            match consumed {
-                Ok(len) => {
-                    r.consume(len);
-                    if content_ended {
-                        break
-                    }
+            r.consume(consume_amt);
+            self.progress.advance(consume_amt as u64);
+
+            self.full_reads = if filled { self.full_reads + 1 } else { 0 };
+            if self.full_reads >= FULL_READS_BEFORE_GROWING && r.buffer().is_empty() {
+                self.full_reads = 0;
+                if let Some(size) = self.buffer_size.grow(r.capacity()) {
+                    return Ok(Async::Ready(Some(size)))
+                }
+            }
+        }
+    }
+}
+
+impl<R, W> Future for StreamingBuffer<R, W>
+    where R: Read, W: Write
+{
+    type Item = (BufReader<R>, BufWriter<W>, Option<Sha256>);
+    type Error = ClusterError;
+
+    fn poll(&mut self) -> Poll<Self::Item, ClusterError> {
+        if self.sink.is_none() {
+            let writer = self.writer.take().expect("polling after completion");
+            let throttled = Throttled::new(writer, self.limiters.clone());
+            self.sink = Some(CodecWriter::new(throttled, self.codec, self.hasher.take()));
+        }
+
+        loop {
+            let mut r = self.reader.take().expect("polling after completion");
+            let result = self.poll_content(&mut r);
+            match result {
+                // Buffer is empty at this point, so nothing's lost.
+                Ok(Async::Ready(Some(size))) => {
+                    debug!("Growing read buffer to {} bytes", size);
+                    self.reader = Some(BufReader::with_capacity(size, r.into_inner()));
+                },
+                _ => {
+                    self.reader = Some(r);
+                    try_ready!(result);
+                    break
                 },
-                Err(ClusterError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock => (),
-                Err(e) => return Box::new(future::err(e)) as ClusterFuture<_>,
             }
         }
 
-        Box::new(future::ok((r, w))) as ClusterFuture<_>
+        {
+            let sink = self.sink.as_mut().unwrap();
+            try_ready!(poll_io(sink.try_finish()));
+            // Flushing the codec could write more stuff, and so we flush only the inner writer.
+            if self.flush {
+                try_ready!(poll_io(sink.get_mut().flush()));
+            }
+        }
+
+        let (throttled, hasher) = self.sink.take().unwrap().into_inner()?;
+        Ok(Async::Ready((self.reader.take().unwrap(), throttled.into_inner(), hasher)))
     }
 }
 
@@ -231,21 +458,17 @@ impl<R, W> StreamingBuffer<R, W>
 #[cfg(test)]
 mod tests {
     use futures::Future;
-    use rand::{self, Rng};
-    use super::{StreamingBuffer, StreamerStatus};
+    use rand::{self, RngCore};
+    use sha2::{Digest, Sha256};
+    use compression::Codec;
+    use super::{BufferSize, StreamingBuffer};
 
-    use std::cell::Cell;
-    use std::io::{BufRead, BufReader, BufWriter, Cursor};
+    use std::io::{BufRead, BufReader, BufWriter, Cursor, Read};
 
     impl StreamingBuffer<Cursor<Vec<u8>>, Vec<u8>> {
-        fn new(bytes: Vec<u8>, write_bytes: Vec<u8>, cap: usize, stop: &[u8]) -> Self {
-            StreamingBuffer {
-                reader: Some(BufReader::with_capacity(cap, Cursor::new(bytes))),
-                writer: Some(BufWriter::with_capacity(cap, write_bytes)),
-                stop_bytes: stop.into(),
-                prev_bytes_unwritten: Box::new([]),
-                status: Cell::new(StreamerStatus::StopperNotFound),
-            }
+        fn from_bytes(bytes: Vec<u8>, write_bytes: Vec<u8>, cap: usize, stop: &[u8]) -> Self {
+            StreamingBuffer::new(BufReader::with_capacity(cap, Cursor::new(bytes)),
+                                 BufWriter::with_capacity(cap, write_bytes), stop)
         }
     }
 
@@ -257,12 +480,35 @@ mod tests {
         rng.fill_bytes(&mut buf);
 
         let buf = Vec::from(&buf[..]);
-        let streamer = StreamingBuffer::new(buf, Vec::new(), 256, &[]);     // 256 byte buffer
+        let streamer = StreamingBuffer::from_bytes(buf, Vec::new(), 256, &[]);     // 256 byte buffer
         let (r, w) = streamer.stream().wait().unwrap();
         let (buf, out) = (r.into_inner().into_inner(), w.into_inner().unwrap());
         assert_eq!(&buf[..], &out[..]);
     }
 
+    /// An adaptive buffer should keep growing (until the maximum) while the reads fill it.
+    #[test]
+    fn test_adaptive_buffer_grows() {
+        let mut buf = vec![0; 64 * 1024];
+        rand::thread_rng().fill_bytes(&mut buf);
+
+        let mut streamer = StreamingBuffer::from_bytes(buf.clone(), Vec::new(), 16, &[]);
+        streamer.buffer_size = BufferSize::Adaptive { initial: 16, max: 1000 };
+        let (r, w) = streamer.stream().wait().unwrap();
+        assert_eq!(r.capacity(), 1000);
+        assert_eq!(w.into_inner().unwrap(), buf);
+
+        let mut stopper = [0; 16];
+        rand::thread_rng().fill_bytes(&mut stopper);
+        let mut input = buf.clone();
+        input.extend_from_slice(&stopper);
+        let mut streamer = StreamingBuffer::from_bytes(input, Vec::new(), 16, &stopper);
+        streamer.buffer_size = BufferSize::Adaptive { initial: 16, max: 4096 };
+        let (r, w) = streamer.stream().wait().unwrap();
+        assert_eq!(r.capacity(), 4096);
+        assert_eq!(w.into_inner().unwrap(), buf);
+    }
+
     /// Test that the streamer stops once it encounters the "stopper" bytes at EOF - when it
     /// flushes everything other than the stopper to the writer.
     #[test]
@@ -273,7 +519,7 @@ mod tests {
 
         let stop_bytes = &buf[248..];       // assume that last 8 bytes indicate stopper bytes.
         let buf = Vec::from(&buf[..]);
-        let streamer = StreamingBuffer::new(buf, Vec::new(), 16, stop_bytes);
+        let streamer = StreamingBuffer::from_bytes(buf, Vec::new(), 16, stop_bytes);
         let (r, w) = streamer.stream().wait().unwrap();
         let (buf, out) = (r.into_inner().into_inner(), w.into_inner().unwrap());
         assert_eq!(&buf[..248], &out[..]);      // last 8 bytes have been ignored
@@ -291,7 +537,7 @@ mod tests {
         // and the remaining will be in the next.
         let stop_bytes = &buf[14..22];
         let input = Vec::from(&buf[..]);
-        let streamer = StreamingBuffer::new(input, Vec::new(), 16, stop_bytes);
+        let streamer = StreamingBuffer::from_bytes(input, Vec::new(), 16, stop_bytes);
         let (mut r, w) = streamer.stream().wait().unwrap();
         {   // Check that `BufReader` cursor is at 22 (i.e., after consuming stopper)
             let remaining = r.fill_buf().unwrap();
@@ -303,4 +549,95 @@ mod tests {
         let out = w.into_inner().unwrap();
         assert_eq!(&buf[..14], &out[..]);       // Writer has everything until the stopper
     }
+
+    /// Stream the content (followed by the stopper and some trailing bytes) with the given
+    /// buffer size, and check that the writer gets only the content, and the reader is left
+    /// right after the stopper.
+    fn stream_with_stopper(content: &[u8], trailing: &[u8], cap: usize) -> bool {
+        let mut stopper = [0; 16];
+        rand::thread_rng().fill_bytes(&mut stopper);
+        let mut input = content.to_vec();
+        input.extend_from_slice(&stopper);
+        input.extend_from_slice(trailing);
+
+        let streamer = StreamingBuffer::from_bytes(input, Vec::new(), cap, &stopper);
+        let (mut r, w) = streamer.stream().wait().unwrap();
+        let mut remaining = vec![];
+        r.read_to_end(&mut remaining).unwrap();
+        w.into_inner().unwrap() == content && remaining == trailing
+    }
+
+    /// The stopper should be found wherever it is in the chunks (with any bytes following it).
+    #[test]
+    fn test_stopper_at_every_boundary() {
+        let mut buf = [0; 64];
+        rand::thread_rng().fill_bytes(&mut buf);
+        for len in 0..buf.len() + 1 {
+            assert!(stream_with_stopper(&buf[..len], &buf[len..], 16));
+        }
+    }
+
+    quickcheck! {
+        fn prop_stream_stops_at_stopper(content: Vec<u8>, trailing: Vec<u8>, cap: usize) -> bool {
+            stream_with_stopper(&content, &trailing, cap % 64 + 1)
+        }
+    }
+
+    /// The streamer should fail if the stream ends before the stopper bytes (for example,
+    /// when the connection has been dropped midway).
+    #[test]
+    fn test_stream_ending_before_stopper() {
+        let mut buf = [0; 256];
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut buf);
+
+        let input = Vec::from(&buf[..200]);
+        let streamer = StreamingBuffer::from_bytes(input, Vec::new(), 16, &buf[248..]);
+        assert!(streamer.stream().wait().is_err());
+    }
+
+    /// The hash should cover only the streamed content (i.e., without the stopper), along with
+    /// whatever the hasher had been fed already.
+    #[test]
+    fn test_stream_hashed() {
+        let mut buf = [0; 256];
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut buf);
+
+        let input = Vec::from(&buf[64..]);
+        let streamer = StreamingBuffer::from_bytes(input, Vec::new(), 16, &buf[248..]);
+        let mut hasher = Sha256::new();
+        hasher.input(&buf[..64]);
+        let (_, w, hash) = streamer.stream_hashed(hasher).wait().unwrap();
+        assert_eq!(&w.into_inner().unwrap()[..], &buf[64..248]);
+        assert_eq!(&hash[..], &Sha256::digest(&buf[..248])[..]);
+    }
+
+    /// Test that compressed content (followed by a stopper) is decompressed back by the streamer.
+    #[test]
+    fn test_stream_compressed() {
+        use compression::Compression;
+
+        let mut content = vec![];
+        for i in 0..2048 {
+            content.extend_from_slice(format!("{} bottles of beer\n", i % 99).as_bytes());
+        }
+
+        let encoder = StreamingBuffer::from_bytes(content.clone(), Vec::new(), 64, &[])
+            .with_codec(Codec::Encode(Compression::Deflate));
+        let (_, w, hash) = encoder.stream_hashed(Sha256::new()).wait().unwrap();
+        let mut compressed = w.into_inner().unwrap();
+        assert!(compressed.len() < content.len() / 10);
+        assert_eq!(&hash[..], &Sha256::digest(&content)[..]);
+
+        let mut stopper = [0; 16];
+        rand::thread_rng().fill_bytes(&mut stopper);
+        compressed.extend_from_slice(&stopper);
+        compressed.extend_from_slice(b"next entry");
+        let decoder = StreamingBuffer::from_bytes(compressed, Vec::new(), 64, &stopper)
+            .with_codec(Codec::Decode(Compression::Deflate));
+        let (_, w, hash) = decoder.stream_hashed(Sha256::new()).wait().unwrap();
+        assert_eq!(w.into_inner().unwrap(), content);
+        assert_eq!(&hash[..], &Sha256::digest(&content)[..]);
+    }
 }
@@ -0,0 +1,268 @@
+use libc;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Period for the CPU bandwidth (in microseconds).
+const CPU_PERIOD_US: u64 = 100_000;
+/// Number of attempts for removing a cgroup (its processes could take a while to die).
+const REMOVE_ATTEMPTS: u32 = 50;
+/// Interval between the attempts.
+const REMOVE_INTERVAL_MS: u64 = 20;
+
+lazy_static! {
+    /// Counter for the names of the cgroups created by this slave.
+    static ref NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Limits for the cgroup (v2) of a command.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CgroupLimits {
+    /// CPU bandwidth (in thousandths of a CPU, so 1500 is one and a half CPUs).
+    pub cpu_millis: Option<u64>,
+    /// Memory (in bytes), beyond which the command is OOM-killed.
+    pub memory: Option<u64>,
+    /// Reading and writing rates (in bytes per second) for each block device.
+    pub io_read_bps: Option<u64>,
+    pub io_write_bps: Option<u64>,
+}
+
+/// Resources used by a command (and its descendants) in its cgroup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceUsage {
+    /// Peak memory (in bytes), if the kernel tracks it.
+    pub peak_memory: Option<u64>,
+    pub cpu_user: Duration,
+    pub cpu_system: Duration,
+}
+
+impl ResourceUsage {
+    /// Field for the usage (without spaces) in the lines sent to the master.
+    pub fn to_field(usage: Option<&ResourceUsage>) -> String {
+        match usage {
+            Some(u) => format!("{}:{}:{}", u.peak_memory.map(|m| m.to_string()).unwrap_or_default(),
+                               micros(u.cpu_user), micros(u.cpu_system)),
+            None => String::from("-"),
+        }
+    }
+
+    /// Parse the usage from its field (this is `Some(None)` for commands without cgroups).
+    pub fn from_field(field: &str) -> Option<Option<Self>> {
+        if field == "-" {
+            return Some(None)
+        }
+
+        let mut parts = field.split(':');
+        let peak_memory = match parts.next()? {
+            "" => None,
+            m => Some(m.parse().ok()?),
+        };
+
+        let (user, system) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+        Some(Some(ResourceUsage {
+            peak_memory,
+            cpu_user: Duration::from_micros(user),
+            cpu_system: Duration::from_micros(system),
+        }))
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64
+}
+
+/// Cgroup created for a command. Once it's dropped, the processes left in it are killed,
+/// and it's removed.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Create a cgroup (with the given limits) in the root, which should be a cgroup v2
+    /// directory delegated to the slave (i.e., writable, and without processes of its own).
+    pub fn create(root: &Path, limits: &CgroupLimits) -> io::Result<Cgroup> {
+        // Controllers are enabled for the children only if they're needed (the others
+        // may not be available).
+        let controllers = [("cpu", limits.cpu_millis.is_some()), ("memory", limits.memory.is_some()),
+                           ("io", limits.io_read_bps.is_some() || limits.io_write_bps.is_some())];
+        let available = fs::read_to_string(root.join("cgroup.controllers"))?;
+        for &(name, needed) in &controllers {
+            let is_available = available.split_whitespace().any(|c| c == name);
+            if needed && !is_available {
+                let msg = format!("{} controller isn't available in {}", name, root.display());
+                return Err(io::Error::new(ErrorKind::Other, msg))
+            }
+
+            let control = root.join("cgroup.subtree_control");
+            if needed {
+                write_file(&control, &format!("+{}", name))?;
+            } else if name == "memory" && is_available {
+                // Memory controller gives the peak memory (even without a limit).
+                let _ = write_file(&control, "+memory");
+            }
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path = root.join(format!("rcluster-{}-{}", process::id(), id));
+        fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+
+        if let Some(millis) = limits.cpu_millis {
+            let quota = (millis * CPU_PERIOD_US / 1000).max(1000);
+            cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD_US))?;
+        }
+
+        if let Some(memory) = limits.memory {
+            cgroup.write("memory.max", &memory.to_string())?;
+            // Otherwise, the command would be swapping instead of being killed.
+            let _ = cgroup.write("memory.swap.max", "0");
+        }
+
+        if limits.io_read_bps.is_some() || limits.io_write_bps.is_some() {
+            cgroup.limit_io(limits)?;
+        }
+
+        Ok(cgroup)
+    }
+
+    fn write(&self, name: &str, value: &str) -> io::Result<()> {
+        write_file(&self.path.join(name), value)
+    }
+
+    /// Set the IO limits for each block device.
+    fn limit_io(&self, limits: &CgroupLimits) -> io::Result<()> {
+        let mut value = String::new();
+        if let Some(bps) = limits.io_read_bps {
+            value.push_str(&format!(" rbps={}", bps));
+        }
+
+        if let Some(bps) = limits.io_write_bps {
+            value.push_str(&format!(" wbps={}", bps));
+        }
+
+        let mut limited = 0;
+        for entry in fs::read_dir("/sys/block")? {
+            let device = fs::read_to_string(entry?.path().join("dev"))?;
+            // Some devices (say, RAM disks) can't be limited.
+            match self.write("io.max", &format!("{}{}", device.trim(), value)) {
+                Ok(()) => limited += 1,
+                Err(e) => debug!("Cannot limit IO of {}: {}", device.trim(), e),
+            }
+        }
+
+        match limited {
+            0 => Err(io::Error::new(ErrorKind::Other, "cannot limit IO of any block device")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open the file for moving processes into this cgroup (writing `0` to it moves
+    /// the writer).
+    pub fn procs_file(&self) -> io::Result<File> {
+        OpenOptions::new().write(true).open(self.path.join("cgroup.procs"))
+    }
+
+    /// Get the resources used so far.
+    pub fn usage(&self) -> io::Result<ResourceUsage> {
+        let stat = fs::read_to_string(self.path.join("cpu.stat"))?;
+        let (user, system) = parse_cpu_stat(&stat).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, "malformed cpu.stat")
+        })?;
+
+        // Peak memory needs the memory controller (and a recent kernel).
+        let peak_memory = fs::read_to_string(self.path.join("memory.peak")).ok()
+                             .and_then(|m| m.trim().parse().ok());
+        Ok(ResourceUsage { peak_memory, cpu_user: user, cpu_system: system })
+    }
+
+    /// Kill the processes in this cgroup.
+    fn kill(&self) -> io::Result<()> {
+        if self.write("cgroup.kill", "1").is_ok() {
+            return Ok(())
+        }
+
+        // Older kernels don't have `cgroup.kill`.
+        for pid in fs::read_to_string(self.path.join("cgroup.procs"))?.lines() {
+            if let Ok(pid) = pid.parse::<i32>() {
+                unsafe { libc::kill(pid, libc::SIGKILL); }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = self.kill() {
+            info!("Cannot kill the processes in {}: {}", self.path.display(), e);
+        }
+
+        for _ in 0..REMOVE_ATTEMPTS {
+            match fs::remove_dir(&self.path) {
+                Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    thread::sleep(Duration::from_millis(REMOVE_INTERVAL_MS));
+                },
+                Err(e) => {
+                    info!("Cannot remove {}: {}", self.path.display(), e);
+                    return
+                },
+                Ok(()) => return,
+            }
+        }
+
+        info!("Cannot remove {} (it's still busy)", self.path.display());
+    }
+}
+
+fn write_file(path: &Path, value: &str) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.write_all(value.as_bytes()).map_err(|e| {
+        io::Error::new(e.kind(), format!("cannot write '{}' to {}: {}", value, path.display(), e))
+    })
+}
+
+/// Parse the user and system CPU time from the content of `cpu.stat`.
+fn parse_cpu_stat(stat: &str) -> Option<(Duration, Duration)> {
+    let field = |name: &str| {
+        stat.lines().filter_map(|l| {
+            let mut parts = l.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(n), Some(v)) if n == name => v.parse().ok(),
+                _ => None,
+            }
+        }).next().map(Duration::from_micros)
+    };
+
+    Some((field("user_usec")?, field("system_usec")?))
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use super::{ResourceUsage, parse_cpu_stat};
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_usage() {
+        let stat = "usage_usec 1500042\nuser_usec 1000001\nsystem_usec 500041\nnr_periods 0\n";
+        let (user, system) = parse_cpu_stat(stat).unwrap();
+        assert_eq!((user, system), (Duration::from_micros(1000001), Duration::from_micros(500041)));
+        assert_eq!(parse_cpu_stat("usage_usec 42\n"), None);
+
+        let usage = ResourceUsage { peak_memory: Some(4096), cpu_user: user, cpu_system: system };
+        assert_eq!(ResourceUsage::to_field(Some(&usage)), "4096:1000001:500041");
+        assert_eq!(ResourceUsage::from_field("4096:1000001:500041"), Some(Some(usage.clone())));
+        let usage = ResourceUsage { peak_memory: None, ..usage };
+        assert_eq!(ResourceUsage::from_field(&ResourceUsage::to_field(Some(&usage))),
+                   Some(Some(usage)));
+        assert_eq!(ResourceUsage::from_field("-"), Some(None));
+        assert_eq!(ResourceUsage::from_field("foo"), None);
+    }
+}
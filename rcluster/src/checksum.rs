@@ -0,0 +1,96 @@
+use buffered::BUFFER_SIZE;
+use sha2::{Digest, Sha256};
+
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+/// Length of the hashes used throughout the library (SHA-256).
+pub const HASH_LENGTH: usize = 32;
+
+/// Create a hasher which has been fed with the first `len` bytes of the file at the given
+/// path (so that it can continue hashing the rest). This fails if the file has fewer bytes.
+pub fn prefix_hasher<P>(path: P, len: u64) -> io::Result<Sha256>
+    where P: AsRef<Path>
+{
+    let mut reader = File::open(path)?.take(len);
+    let mut hasher = Sha256::new();
+    let mut buf = [0; BUFFER_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "file is shorter than prefix"))
+        }
+
+        hasher.input(&buf[..read]);
+        remaining -= read as u64;
+    }
+
+    Ok(hasher)
+}
+
+/// Hash of the contents of the file at the given path.
+pub fn file_hash<P>(path: P) -> io::Result<[u8; HASH_LENGTH]>
+    where P: AsRef<Path>
+{
+    let mut reader = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(finish(hasher)),
+            read => hasher.input(&buf[..read]),
+        }
+    }
+}
+
+/// Consume the hasher and get the final hash.
+pub fn finish(hasher: Sha256) -> [u8; HASH_LENGTH] {
+    let mut hash = [0; HASH_LENGTH];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// Transfer ID of a file. This is derived from the (relative) path, size and modification
+/// time, so that both the ends can agree on it without exchanging it, and so that it
+/// changes whenever the file in the source changes.
+pub fn transfer_id(path: &Path, size: u64, mtime: i64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(path.to_string_lossy().as_bytes());
+    hasher.input(format!("\0{}\0{}", size, mtime).as_bytes());
+
+    to_hex(&hasher.result()[..8])
+}
+
+/// Lowercase hex representation of the given bytes.
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+
+    hex
+}
+
+/// Parse the bytes from their hex representation.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None
+    }
+
+    (0..hex.len() / 2).map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()).collect()
+}
+
+/// Parse the hash from its hex representation.
+pub fn hash_from_hex(hex: &str) -> Option<[u8; HASH_LENGTH]> {
+    let bytes = from_hex(hex)?;
+    if bytes.len() != HASH_LENGTH {
+        return None
+    }
+
+    let mut hash = [0; HASH_LENGTH];
+    hash.copy_from_slice(&bytes);
+    Some(hash)
+}
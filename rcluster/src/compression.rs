@@ -0,0 +1,230 @@
+use buffered::BUFFER_SIZE;
+use flate2::{self, Compression as Level};
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Size of the sample (from the beginning of a file) used for checking whether
+/// compression is worth it.
+const SAMPLE_SIZE: usize = 8 * BUFFER_SIZE;
+/// Files whose compressed sample is larger than this ratio of the actual sample
+/// are streamed uncompressed.
+const MAX_SAMPLE_RATIO: f64 = 0.9;
+/// Extensions of files which have already been compressed.
+const COMPRESSED_EXTENSIONS: &'static [&'static str] = &[
+    "7z", "avi", "bz2", "flac", "gif", "gz", "jpeg", "jpg", "lz4", "lzma", "mkv", "mov",
+    "mp3", "mp4", "ogg", "png", "rar", "tbz", "tgz", "txz", "webm", "webp", "xz", "zip", "zst",
+];
+
+enum_from_primitive! {
+    /// Compression used for streaming content. This is negotiated for a connection,
+    /// and it's specified for each streamed file.
+    #[repr(u8)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Compression {
+        None    = 0,
+        Deflate = 1,
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self { Compression::None }
+}
+
+impl Into<u8> for Compression {
+    fn into(self) -> u8 { self as u8 }
+}
+
+/// Transformation applied to the content while streaming.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Identity,
+    /// Compress the content before writing.
+    Encode(Compression),
+    /// Decompress the content before writing.
+    Decode(Compression),
+}
+
+/// Check whether it's worth compressing the file at the given path - the file shouldn't
+/// have an extension of compressed files, and a sample of it should compress well.
+pub fn should_compress<P>(path: P) -> bool
+    where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    if let Some(ext) = extension {
+        if COMPRESSED_EXTENSIONS.contains(&&*ext) {
+            return false
+        }
+    }
+
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    let read = File::open(path).and_then(|f| f.take(SAMPLE_SIZE as u64).read_to_end(&mut sample));
+    if read.is_err() || sample.is_empty() {
+        return false
+    }
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Level::fast());
+    let compressed = encoder.write_all(&sample).and_then(|_| encoder.finish());
+    compressed.map(|c| (c.len() as f64) < MAX_SAMPLE_RATIO * sample.len() as f64)
+              .unwrap_or(false)
+}
+
+/// Writer which hashes the content that's being written (if it has a hasher).
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(bytes)?;
+        if let Some(ref mut h) = self.hasher {
+            h.input(&bytes[..written]);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer which applies a codec to the content. The hash (if any) is always
+/// computed for the uncompressed content.
+pub enum CodecWriter<W: Write> {
+    Identity(HashWriter<W>),
+    Deflate(HashWriter<DeflateEncoder<W>>),
+    Inflate(DeflateDecoder<HashWriter<W>>),
+}
+
+impl<W: Write> CodecWriter<W> {
+    pub fn new(inner: W, codec: Codec, hasher: Option<Sha256>) -> Self {
+        match codec {
+            Codec::Encode(Compression::Deflate) => {
+                let encoder = DeflateEncoder::new(inner, flate2::Compression::default());
+                CodecWriter::Deflate(HashWriter { inner: encoder, hasher })
+            },
+            Codec::Decode(Compression::Deflate) =>
+                CodecWriter::Inflate(DeflateDecoder::new(HashWriter { inner, hasher })),
+            _ => CodecWriter::Identity(HashWriter { inner, hasher }),
+        }
+    }
+
+    /// Write the remaining content (if any) to the inner writer. This can be retried
+    /// if it fails with `WouldBlock`.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        match *self {
+            CodecWriter::Identity(_) => Ok(()),
+            CodecWriter::Deflate(ref mut w) => w.inner.try_finish(),
+            CodecWriter::Inflate(ref mut w) => w.try_finish(),
+        }
+    }
+
+    /// Get a mutable reference to the inner writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        match *self {
+            CodecWriter::Identity(ref mut w) => &mut w.inner,
+            CodecWriter::Deflate(ref mut w) => w.inner.get_mut(),
+            CodecWriter::Inflate(ref mut w) => &mut w.get_mut().inner,
+        }
+    }
+
+    /// Get the inner writer and the hasher back. This should be called only after
+    /// `try_finish` has succeeded.
+    pub fn into_inner(self) -> io::Result<(W, Option<Sha256>)> {
+        match self {
+            CodecWriter::Identity(HashWriter { inner, hasher }) => Ok((inner, hasher)),
+            CodecWriter::Deflate(HashWriter { inner, hasher }) => inner.finish().map(|w| (w, hasher)),
+            CodecWriter::Inflate(w) => w.finish().map(|w| (w.inner, w.hasher)),
+        }
+    }
+}
+
+impl<W: Write> Write for CodecWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match *self {
+            CodecWriter::Identity(ref mut w) => w.write(bytes),
+            CodecWriter::Deflate(ref mut w) => w.write(bytes),
+            CodecWriter::Inflate(ref mut w) => w.write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            CodecWriter::Identity(ref mut w) => w.flush(),
+            CodecWriter::Deflate(ref mut w) => w.flush(),
+            CodecWriter::Inflate(ref mut w) => w.flush(),
+        }
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, RngCore};
+    use sha2::{Digest, Sha256};
+    use super::{Codec, CodecWriter, Compression};
+
+    use std::io::Write;
+
+    #[test]
+    fn test_codec_roundtrip_with_hash() {
+        let mut content = vec![];
+        for i in 0..4096 {
+            content.extend_from_slice(format!("line {}\n", i % 64).as_bytes());
+        }
+
+        let mut encoder = CodecWriter::new(vec![], Codec::Encode(Compression::Deflate),
+                                           Some(Sha256::new()));
+        encoder.write_all(&content).unwrap();
+        encoder.try_finish().unwrap();
+        let (compressed, hasher) = encoder.into_inner().unwrap();
+
+        assert!(compressed.len() < content.len() / 10);
+        let expected = Sha256::digest(&content);
+        assert_eq!(&hasher.unwrap().result()[..], &expected[..]);
+
+        let mut out = vec![];
+        {
+            let mut decoder = CodecWriter::new(&mut out, Codec::Decode(Compression::Deflate),
+                                               Some(Sha256::new()));
+            for chunk in compressed.chunks(48) {
+                decoder.write_all(chunk).unwrap();
+            }
+
+            decoder.try_finish().unwrap();
+            let (_, hasher) = decoder.into_inner().unwrap();
+            let hasher = hasher.unwrap();
+            assert_eq!(&hasher.result()[..], &expected[..]);
+        }
+
+        assert_eq!(out, content);
+    }
+
+    #[test]
+    fn test_random_content_is_not_compressed() {
+        use std::env;
+        use std::fs::{self, File};
+
+        let mut rng = rand::thread_rng();
+        let mut buf = vec![0; 64 * 1024];
+        rng.fill_bytes(&mut buf);
+        let dir = env::temp_dir().join(format!("rcluster-compress-{}", rng.next_u64()));
+        fs::create_dir_all(&dir).unwrap();
+
+        File::create(dir.join("random")).unwrap().write_all(&buf).unwrap();
+        File::create(dir.join("text.log")).unwrap().write_all(&[b'a'; 4096]).unwrap();
+        File::create(dir.join("text.gz")).unwrap().write_all(&[b'a'; 4096]).unwrap();
+        assert!(!super::should_compress(dir.join("random")));
+        assert!(super::should_compress(dir.join("text.log")));
+        assert!(!super::should_compress(dir.join("text.gz")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
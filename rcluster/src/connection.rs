@@ -1,12 +1,23 @@
-use buffered::BUFFER_SIZE;
+use bisync::TwoWaySync;
+use buffered::BufferSize;
+use compression::Compression;
 use errors::{ClusterError, ClusterFuture};
+use exec::Execution;
+use fs_ops::{self, FsOps};
+use jobs::Jobs;
 use futures::{Future, future};
+use futures::future::Loop;
 use num::FromPrimitive;
+use path_sync::PathSync;
+use procs::Processes;
+use ratelimit::Limiters;
 use rand::{self, RngCore};
+use tail::Tail;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::io::{self as async_io, ReadHalf, WriteHalf};
 
-use std::io::{BufReader, BufWriter};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
 
 /// Length of the random separator used in a connection for boundaries.
 ///
@@ -24,6 +35,24 @@ enum_from_primitive! {
         MasterWantsPath,
         MasterSendsPath,
         MasterWantsExecution,
+        MasterWantsManifest,
+        MasterRemovesPaths,
+        MasterPullsPath,
+        MasterListsPath,
+        MasterStatsPath,
+        MasterDeletesPath,
+        MasterMovesPath,
+        MasterCreatesDir,
+        MasterChangesMode,
+        MasterWantsUsage,
+        MasterTailsPath,
+        MasterListsProcesses,
+        MasterSignalsProcesses,
+        MasterStartsJob,
+        MasterListsJobs,
+        MasterAttachesJob,
+        MasterWaitsJob,
+        MasterCancelsJob,
     }
 }
 
@@ -35,7 +64,26 @@ impl Into<u8> for ConnectionFlag {
 pub type StreamingConnection<S> = Connection<ReadHalf<S>, WriteHalf<S>>;
 /// Deconstructed version of a connection. This exists so that we can deconstruct
 /// the struct, pass the necessary values for executing a future and reconstruct it back.
-pub type ConnectionParts<R, W> = (BufReader<R>, BufWriter<W>, [u8; MAGIC_LENGTH]);
+pub type ConnectionParts<R, W> = (BufReader<R>, BufWriter<W>, [u8; MAGIC_LENGTH],
+                                  ConnectionSettings);
+
+/// Settings of a connection. Some of these are negotiated while creating the connection.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionSettings {
+    /// Compression used for streaming content (whenever it's worth compressing).
+    pub compression: Compression,
+    /// Rate limiters for the bytes written to this connection (local to this end).
+    pub limiters: Limiters,
+    /// Size of the buffers for reading from and writing to this connection, and
+    /// the default for the transfers over it (local to this end).
+    pub buffer_size: BufferSize,
+    /// Directories (canonical) to which the filesystem operations from the other end are
+    /// restricted. Empty means that there are no restrictions (slave only).
+    pub roots: Vec<PathBuf>,
+    /// Cgroup (v2) directory in which the commands from the other end can get cgroups
+    /// of their own (slave only).
+    pub cgroup_root: Option<PathBuf>,
+}
 
 /// Represents a connection (for master/slave). This is called immediately after
 /// `connect_async` or `accept_async` (from TLS). All methods of this struct resolve
@@ -44,6 +92,7 @@ pub struct Connection<R: AsyncRead, W: AsyncWrite> {
     reader: BufReader<R>,
     writer: BufWriter<W>,
     magic: [u8; MAGIC_LENGTH],
+    settings: ConnectionSettings,
 }
 
 impl<R, W> From<ConnectionParts<R, W>> for Connection<R, W>
@@ -54,6 +103,7 @@ impl<R, W> From<ConnectionParts<R, W>> for Connection<R, W>
             reader: v.0,
             writer: v.1,
             magic: v.2,
+            settings: v.3,
         }
     }
 }
@@ -63,7 +113,7 @@ impl<R, W> Into<ConnectionParts<R, W>> for Connection<R, W>
 {
     #[inline]
     fn into(self) -> ConnectionParts<R, W> {
-        (self.reader, self.writer, self.magic)
+        (self.reader, self.writer, self.magic, self.settings)
     }
 }
 
@@ -74,46 +124,172 @@ impl<S> StreamingConnection<S>
     /// to `true`, then this assumes that the connection is incoming and expects a
     /// a set of bytes (which I call "magic") which begins the connection. If it's `false`,
     /// then this assumes that the connection is outgoing, and so it writes the "magic" bytes.
-    pub fn create_for_stream(stream: S, expect_magic: bool) -> ClusterFuture<Self> {
+    ///
+    /// Once the magic has been exchanged, the outgoing end proposes the compression from
+    /// the given settings, and the incoming end either accepts it (if it's supported)
+    /// or falls back to no compression. Both the ends use the accepted compression.
+    pub fn create_for_stream(stream: S, expect_magic: bool,
+                             settings: ConnectionSettings) -> ClusterFuture<Self> {
         let (r, w) = stream.split();
-        let (reader, writer) = (BufReader::with_capacity(BUFFER_SIZE, r),
-                                BufWriter::with_capacity(BUFFER_SIZE, w));
+        let size = settings.buffer_size.initial();
+        let (reader, writer) = (BufReader::with_capacity(size, r),
+                                BufWriter::with_capacity(size, w));
         let mut magic = [0; MAGIC_LENGTH];
 
-        if expect_magic {
-            Connection { reader, writer, magic }.read_magic()
+        let async_conn = if expect_magic {
+            let async_accept = Connection { reader, writer, magic, settings }.read_magic()
+                .and_then(|c| c.read_bytes([0; 1]))
+                .and_then(|(c, proposed)| {
+                    let compression = Compression::from_u8(proposed[0]).unwrap_or_default();
+                    c.write_flag(compression)
+                     .and_then(|c| c.flush())
+                     .map(move |c| (c, compression))
+                });
+
+            Box::new(async_accept) as ClusterFuture<(Self, Compression)>
         } else {
             let mut rng = rand::thread_rng();
             rng.fill_bytes(&mut magic);
-            Connection { reader, writer, magic }.write_magic()
-        }
+            let proposed = settings.compression;
+            let async_propose = Connection { reader, writer, magic, settings }.write_magic()
+                .and_then(move |c| c.write_flag(proposed))
+                .and_then(|c| c.flush())
+                .and_then(|c| c.read_bytes([0; 1]))
+                .map(|(c, accepted)| (c, Compression::from_u8(accepted[0]).unwrap_or_default()));
+
+            Box::new(async_propose) as ClusterFuture<(Self, Compression)>
+        };
+
+        let async_conn = async_conn.map(|(mut conn, compression)| {
+            info!("Using compression: {:?}", compression);
+            conn.settings.compression = compression;
+            conn
+        });
+
+        Box::new(async_conn) as ClusterFuture<Self>
     }
 }
 
 impl<R, W> Connection<R, W>
     where R: AsyncRead + 'static, W: AsyncWrite + 'static
 {
-    /// Write bytes to the "writable half" of this connection and flush the stream.
+    /// Settings of this connection.
+    #[inline]
+    pub fn settings(&self) -> &ConnectionSettings {
+        &self.settings
+    }
+
+    /// Write bytes to the "writable half" of this connection. The bytes are written once
+    /// the rate limiters (if any) allow them. Writes are buffered, and so the connection
+    /// should be flushed at the message boundaries (i.e., before waiting for the other end).
     #[inline]
     pub fn write_bytes<B>(self, bytes: B) -> ClusterFuture<Self>
         where B: AsRef<[u8]> + 'static
     {
-        let (r, w, m) = self.into();
-        let async_write = async_io::write_all(w, bytes)
-            .and_then(|(w, _)| async_io::flush(w))
-            .map(move |w| Connection::from((r, w, m)))
-            .map_err(ClusterError::from);
+        let delay = self.settings.limiters.delay(bytes.as_ref().len());
+        let (r, w, m, s) = self.into();
+        let async_write = delay.and_then(move |_| {
+            async_io::write_all(w, bytes)
+                .map(move |(w, _)| Connection::from((r, w, m, s)))
+                .map_err(ClusterError::from)
+        });
         Box::new(async_write) as ClusterFuture<Self>
     }
 
+    /// Flush the buffered writes to the stream.
+    #[inline]
+    pub fn flush(self) -> ClusterFuture<Self> {
+        let (r, w, m, s) = self.into();
+        let async_flush = async_io::flush(w)
+            .map(move |w| Connection::from((r, w, m, s)))
+            .map_err(ClusterError::from);
+        Box::new(async_flush) as ClusterFuture<Self>
+    }
+
+    /// Write the given string (followed by a newline) to this connection.
+    #[inline]
+    pub fn write_line<S>(self, line: S) -> ClusterFuture<Self>
+        where S: Into<String>
+    {
+        let mut line = line.into();
+        line.push('\n');
+        self.write_bytes(line.into_bytes())
+    }
+
+    /// Fill the given buffer with bytes from the "readable half" of this connection.
+    #[inline]
+    pub fn read_bytes<B>(self, buf: B) -> ClusterFuture<(Self, B)>
+        where B: AsMut<[u8]> + 'static
+    {
+        let (r, w, m, s) = self.into();
+        let async_read = async_io::read_exact(r, buf)
+            .map(move |(r, buf)| (Connection::from((r, w, m, s)), buf))
+            .map_err(ClusterError::from);
+        Box::new(async_read) as ClusterFuture<(Self, B)>
+    }
+
+    // FIXME: DoS is possible on every usage of `read_until` with `Vec::new`
+
+    /// Read a line (terminated by newline) from this connection. The newline
+    /// is stripped from the resulting string.
+    pub fn read_line(self) -> ClusterFuture<(Self, String)> {
+        let (r, w, m, s) = self.into();
+        let async_read = async_io::read_until(r, b'\n', Vec::new())
+            .map_err(ClusterError::from)
+            .and_then(move |(r, mut bytes)| {
+                if bytes.pop() != Some(b'\n') {
+                    let err = io::Error::new(ErrorKind::UnexpectedEof, "stream ended before newline");
+                    return Err(ClusterError::from(err))
+                }
+
+                let line = String::from_utf8_lossy(&bytes).into_owned();
+                Ok((Connection::from((r, w, m, s)), line))
+            });
+
+        Box::new(async_read) as ClusterFuture<(Self, String)>
+    }
+
+    /// Read lines from this connection until an empty line is encountered.
+    pub fn read_lines(self) -> ClusterFuture<(Self, Vec<String>)> {
+        let async_read = future::loop_fn((self, vec![]), |(conn, mut lines)| {
+            conn.read_line().map(|(conn, line)| {
+                if line.is_empty() {
+                    return Loop::Break((conn, lines))
+                }
+
+                lines.push(line);
+                Loop::Continue((conn, lines))
+            })
+        });
+
+        Box::new(async_read) as ClusterFuture<(Self, Vec<String>)>
+    }
+
+    /// Write a path as a line (encoded, since it could have newlines, and it may not be UTF-8).
+    #[inline]
+    pub fn write_path<P>(self, path: P) -> ClusterFuture<Self>
+        where P: AsRef<Path>
+    {
+        self.write_line(fs_ops::encode_path(path.as_ref()))
+    }
+
+    /// Read a path written by `write_path`.
+    pub fn read_path(self) -> ClusterFuture<(Self, PathBuf)> {
+        let async_read = self.read_line().and_then(|(c, line)| {
+            fs_ops::decode_path(&line).map(|p| (c, p)).ok_or(ClusterError::InvalidPath)
+        });
+
+        Box::new(async_read) as ClusterFuture<(Self, PathBuf)>
+    }
+
     /// Read the magic bytes from this connection. Note that this changes
     /// the magic bytes that already exist in `self` (because we use only one
     /// set of bytes throughout a connection).
     #[inline]
     pub fn read_magic(self) -> ClusterFuture<Self> {
-        let (reader, writer, _) = self.into();
+        let (reader, writer, _, settings) = self.into();
         let async_read = async_io::read_exact(reader, [0; MAGIC_LENGTH])
-            .map(|(reader, magic)| Connection { reader, writer, magic })
+            .map(|(reader, magic)| Connection { reader, writer, magic, settings })
             .map_err(ClusterError::from);
         Box::new(async_read) as ClusterFuture<Self>
     }
@@ -130,13 +306,13 @@ impl<R, W> Connection<R, W>
     pub fn read_flag<F>(self) -> ClusterFuture<(Self, ConnectionFlag)>
         where F: FromPrimitive
     {
-        let (r, w, m) = self.into();
+        let (r, w, m, s) = self.into();
         let async_handle = async_io::read_exact(r, [0; 1])
             .map_err(ClusterError::from)
             .and_then(move |(r, flag_byte)| {
                 let flag = ConnectionFlag::from_u8(flag_byte[0])
                                           .ok_or(ClusterError::UnknownFlag);
-                flag.map(move |f| ((r, w, m).into(), f))
+                flag.map(move |f| ((r, w, m, s).into(), f))
             });
         Box::new(async_handle) as ClusterFuture<(Self, ConnectionFlag)>
     }
@@ -150,36 +326,97 @@ impl<R, W> Connection<R, W>
         self.write_bytes(flag)
     }
 
-    fn buffered_file_write(self) -> ClusterFuture<Self> {
-        let (r, w, m) = self.into();
-        let async_handle = async_io::read_until(r, b'\n', Vec::new())
-            .map_err(ClusterError::from)
-            .and_then(move |(r, bytes)| {
-                let path_str = String::from_utf8_lossy(&bytes[..bytes.len() - 1]);
-                StreamingBuffer::stream_to_file(r, &m, &*path_str)
-                                .and_then(|s| s.stream())
-                                .map(move |(r, _fd)| (r, w, m))
-            }).and_then(|(r, w, m)| {
-                Connection::from((r, w, m)).write_flag(ConnectionFlag::SlaveOk)
-            });
+    /// Keep handling the requests in this connection until the other end closes it.
+    /// This is meant for the slave. The response is flushed once a request has been handled.
+    pub fn serve(self) -> ClusterFuture<()> {
+        let async_serve = future::loop_fn(self, |conn| {
+            let (r, w, m, s) = conn.into();
+            async_io::read(r, [0; 1])
+                .map_err(ClusterError::from)
+                .and_then(move |(r, flag_byte, len)| -> ClusterFuture<Loop<(), Self>> {
+                    if len == 0 {
+                        return Box::new(future::ok(Loop::Break(())))
+                    }
 
-        Box::new(async_handle) as ClusterFuture<Self>
+                    let flag = future_try!(ConnectionFlag::from_u8(flag_byte[0])
+                                                          .ok_or(ClusterError::UnknownFlag));
+                    let async_handle = Connection::from((r, w, m, s)).handle_flag(flag)
+                                                                     .and_then(|c| c.flush());
+                    Box::new(async_handle.map(Loop::Continue))
+                })
+        });
+
+        Box::new(async_serve) as ClusterFuture<()>
     }
 
-    /// The next byte in the `IncomingStream` is a flag. Read it and use
-    /// appropriate methods to handle it. This is meant for the slave.
-    #[inline]
-    pub fn handle_flags(self) -> ClusterFuture<Self> {
-        let async_handle = self.read_flag::<ConnectionFlag>().and_then(|(conn, flag)| {
-            conn.write_magic().and_then(move |conn| match flag {
-                ConnectionFlag::MasterPing => conn.write_flag(ConnectionFlag::SlaveOk),
-                _ => {
-                    error!("Dunno how to handle {:?}", flag);
-                    Box::new(future::ok(conn)) as ClusterFuture<Self>
-                },
-            })
+    /// Handle the given flag (which has been read from this connection).
+    fn handle_flag(self, flag: ConnectionFlag) -> ClusterFuture<Self> {
+        let async_magic = self.write_magic().and_then(|c| c.flush());
+        let async_handle = async_magic.and_then(move |conn| match flag {
+            ConnectionFlag::MasterPing => conn.write_flag(ConnectionFlag::SlaveOk),
+            ConnectionFlag::MasterSendsPath => {
+                let async_sync = PathSync(conn).stream_to_source()
+                    .and_then(|(c, report)| {
+                        c.write_flag(ConnectionFlag::SlaveOk).map(move |c| (c, report))
+                    }).and_then(|(c, report)| c.write_bytes(report.to_bytes()));
+
+                Box::new(async_sync) as ClusterFuture<Self>
+            },
+            ConnectionFlag::MasterWantsPath => PathSync(conn).serve_archive(),
+            ConnectionFlag::MasterWantsExecution => Execution(conn).run_local(),
+            ConnectionFlag::MasterWantsManifest => TwoWaySync(conn).serve_manifest(),
+            ConnectionFlag::MasterRemovesPaths => TwoWaySync(conn).serve_removal(),
+            ConnectionFlag::MasterPullsPath => PathSync(conn).serve_path(),
+            ConnectionFlag::MasterListsPath |
+            ConnectionFlag::MasterStatsPath |
+            ConnectionFlag::MasterDeletesPath |
+            ConnectionFlag::MasterMovesPath |
+            ConnectionFlag::MasterCreatesDir |
+            ConnectionFlag::MasterChangesMode |
+            ConnectionFlag::MasterWantsUsage => FsOps(conn).serve(flag),
+            ConnectionFlag::MasterTailsPath => Tail(conn).serve(),
+            ConnectionFlag::MasterListsProcesses |
+            ConnectionFlag::MasterSignalsProcesses => Processes(conn).serve(flag),
+            ConnectionFlag::MasterStartsJob |
+            ConnectionFlag::MasterListsJobs |
+            ConnectionFlag::MasterWaitsJob |
+            ConnectionFlag::MasterCancelsJob => Jobs(conn).serve(flag),
+            ConnectionFlag::MasterAttachesJob => Jobs(conn).serve_attach(),
+            _ => {
+                error!("Dunno how to handle {:?}", flag);
+                Box::new(future::ok(conn)) as ClusterFuture<Self>
+            },
         });
 
         Box::new(async_handle) as ClusterFuture<Self>
     }
 }
+
+/* Test helpers */
+
+/// Connection (for tests) which reads the given bytes, and writes to a buffer.
+#[cfg(test)]
+pub type Loopback = Connection<io::Cursor<Vec<u8>>, io::Cursor<Vec<u8>>>;
+
+/// Create a connection (for tests) which reads the given bytes (as written by the other end),
+/// with the default settings.
+#[cfg(test)]
+pub fn loopback(input: Vec<u8>, magic: [u8; MAGIC_LENGTH]) -> Loopback {
+    loopback_with(input, magic, ConnectionSettings::default())
+}
+
+/// Same as `loopback`, but with the given settings.
+#[cfg(test)]
+pub fn loopback_with(input: Vec<u8>, magic: [u8; MAGIC_LENGTH],
+                     settings: ConnectionSettings) -> Loopback {
+    let (reader, writer) = (BufReader::new(io::Cursor::new(input)),
+                            BufWriter::new(io::Cursor::new(vec![])));
+    Connection::from((reader, writer, magic, settings))
+}
+
+/// Get the bytes written to the connection (so that they can be read by the other end).
+#[cfg(test)]
+pub fn response(conn: Loopback) -> Vec<u8> {
+    let (_, writer, _, _) = conn.into();
+    writer.into_inner().unwrap().into_inner()
+}
@@ -0,0 +1,306 @@
+use bisync::Manifest;
+use checksum::HASH_LENGTH;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of unchanged lines shown around the changes.
+const CONTEXT_LINES: usize = 3;
+/// Files larger than this aren't diffed.
+const MAX_DIFF_SIZE: u64 = 4 * 1024 * 1024;
+/// Maximum number of edits (i.e., added and removed lines) for diffing. Files which differ
+/// more than this aren't worth showing line by line anyway.
+const MAX_EDITS: usize = 2000;
+
+/// Slaves whose content (of the compared path) is identical.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffGroup {
+    pub conn_ids: Vec<usize>,
+    /// Hashes of the files relative to the compared path (`.` if the path is a file).
+    pub files: BTreeMap<PathBuf, [u8; HASH_LENGTH]>,
+    /// Differences from the majority (i.e., the first group).
+    pub diffs: Vec<FileDiff>,
+}
+
+/// Difference in a file between the majority and another group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    /// Unified diff from the majority's version of the file (or a note, if the file can't
+    /// be diffed line by line).
+    pub diff: String,
+}
+
+/// Group the slaves by their manifests (ignoring the modification times). Groups are sorted
+/// by their size, and so the first one is the majority (ties go to the earlier slaves).
+pub fn group(manifests: Vec<(usize, Manifest)>) -> Vec<DiffGroup> {
+    let mut groups: Vec<DiffGroup> = vec![];
+    for (id, manifest) in manifests {
+        let files = manifest.into_iter().map(|(p, s)| (p, s.hash)).collect();
+        match groups.iter_mut().position(|g| g.files == files) {
+            Some(i) => groups[i].conn_ids.push(id),
+            None => groups.push(DiffGroup { conn_ids: vec![id], files, diffs: vec![] }),
+        }
+    }
+
+    // Sort is stable.
+    groups.sort_by(|a, b| b.conn_ids.len().cmp(&a.conn_ids.len()));
+    groups
+}
+
+/// Paths of the files which differ between the groups (including the ones missing in either).
+pub fn differing_files(majority: &DiffGroup, other: &DiffGroup) -> Vec<PathBuf> {
+    let mut paths = majority.files.keys().chain(other.files.keys())
+                            .filter(|p| majority.files.get(*p) != other.files.get(*p))
+                            .cloned().collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Unified diff between the files (missing files are treated as empty, and they're labelled
+/// as `/dev/null`).
+pub fn diff_files(old: Option<&Path>, new: Option<&Path>, old_label: &str,
+                  new_label: &str) -> io::Result<String> {
+    let read = |path: Option<&Path>| -> io::Result<Option<Vec<u8>>> {
+        let path = match path {
+            Some(p) => p,
+            None => return Ok(Some(vec![])),
+        };
+
+        match fs::metadata(path)?.len() {
+            len if len > MAX_DIFF_SIZE => Ok(None),
+            _ => fs::read(path).map(Some),
+        }
+    };
+
+    let (old_label, new_label) = (old.map(|_| old_label).unwrap_or("/dev/null"),
+                                  new.map(|_| new_label).unwrap_or("/dev/null"));
+    let (old, new) = match (read(old)?, read(new)?) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return Ok(format!("Files {} and {} differ (too large to diff)\n", old_label, new_label)),
+    };
+
+    if old.contains(&0) || new.contains(&0) {
+        return Ok(format!("Binary files {} and {} differ\n", old_label, new_label))
+    }
+
+    match unified_diff(&old, &new, old_label, new_label) {
+        Some(diff) => Ok(diff),
+        None => Ok(format!("Files {} and {} differ (too many changes to show)\n", old_label, new_label)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Split the content into lines (along with their newlines).
+fn lines(content: &[u8]) -> Vec<&[u8]> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, &b) in content.iter().enumerate() {
+        if b == b'\n' {
+            lines.push(&content[start..i + 1]);
+            start = i + 1;
+        }
+    }
+
+    if start < content.len() {
+        lines.push(&content[start..]);
+    }
+
+    lines
+}
+
+/// Shortest edit script for turning the old lines into the new lines (Myers' algorithm),
+/// unless it needs more than the maximum number of edits.
+fn edit_script(old: &[&[u8]], new: &[&[u8]]) -> Option<Vec<Op>> {
+    // Common prefix and suffix are left out of the search.
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+                              .take_while(|&(a, b)| a == b).count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let (n, m) = (a.len() as isize, b.len() as isize);
+
+    // Furthest reaching paths (indexed by diagonal) before each round, for backtracking.
+    // Round `d` reaches the diagonals from `-d` to `d`, and so only those are kept.
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = vec![];
+    let mut found = n == 0 && m == 0;
+    let mut d = 0;
+    while !found {
+        if d as usize > MAX_EDITS {
+            return None
+        }
+
+        trace.push(v[(offset - d) as usize..(offset + d + 1) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) { v[i + 1] } else { v[i - 1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+
+            v[i] = x;
+            if x >= n && y >= m {
+                found = true;
+                break
+            }
+
+            k += 2;
+        }
+
+        d += 1;
+    }
+
+    let mut ops = vec![Op::Equal; suffix];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().skip(1).rev() {
+        let d = d as isize;
+        let k = x - y;
+        // Diagonal `k` of the previous round is at `k + d` in its (trimmed) paths.
+        let at = |k: isize| v[(k + d) as usize];
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            ops.push(Op::Equal);
+            x -= 1;
+            y -= 1;
+        }
+
+        if x == prev_x {
+            ops.push(Op::Insert);
+            y -= 1;
+        } else {
+            ops.push(Op::Delete);
+            x -= 1;
+        }
+    }
+
+    // Whatever's left is the common snake of the first round.
+    ops.extend((0..x as usize + prefix).map(|_| Op::Equal));
+    ops.reverse();
+    Some(ops)
+}
+
+/// Unified diff between the contents (with the labels in the header), unless there are
+/// too many changes. This is empty if the contents are the same.
+pub fn unified_diff(old: &[u8], new: &[u8], old_label: &str, new_label: &str) -> Option<String> {
+    let (old, new) = (lines(old), lines(new));
+    let ops = edit_script(&old, &new)?;
+
+    // Positions (in both the old and new lines) before each operation.
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for op in &ops {
+        positions.push((i, j));
+        match *op {
+            Op::Equal => { i += 1; j += 1; },
+            Op::Delete => i += 1,
+            Op::Insert => j += 1,
+        }
+    }
+
+    positions.push((i, j));
+    let changes = ops.iter().enumerate().filter(|&(_, op)| *op != Op::Equal)
+                     .map(|(i, _)| i).collect::<Vec<_>>();
+    if changes.is_empty() {
+        return Some(String::new())
+    }
+
+    // Hunks are merged if their context would overlap.
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for &c in &changes {
+        let (start, end) = (c.saturating_sub(CONTEXT_LINES), (c + 1 + CONTEXT_LINES).min(ops.len()));
+        match hunks.last_mut() {
+            Some(ref mut h) if start <= h.1 => h.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_label, new_label);
+    for (start, end) in hunks {
+        let ((old_start, new_start), (old_end, new_end)) = (positions[start], positions[end]);
+        // Empty ranges start at the line before them.
+        let range = |start: usize, len: usize| match len {
+            0 => format!("{},0", start),
+            1 => format!("{}", start + 1),
+            _ => format!("{},{}", start + 1, len),
+        };
+
+        diff.push_str(&format!("@@ -{} +{} @@\n", range(old_start, old_end - old_start),
+                               range(new_start, new_end - new_start)));
+        for (op, &(i, j)) in ops[start..end].iter().zip(&positions[start..end]) {
+            let (prefix, line) = match *op {
+                Op::Equal => (' ', old[i]),
+                Op::Delete => ('-', old[i]),
+                Op::Insert => ('+', new[j]),
+            };
+
+            diff.push(prefix);
+            diff.push_str(&String::from_utf8_lossy(line));
+            if !line.ends_with(b"\n") {
+                diff.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    Some(diff)
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use bisync::{FileState, Manifest};
+    use super::{differing_files, group, unified_diff};
+
+    use std::path::PathBuf;
+
+    fn manifest(files: &[(&str, u8)]) -> Manifest {
+        files.iter().map(|&(p, h)| (PathBuf::from(p), FileState { hash: [h; 32], mtime: h as i64 }))
+             .collect()
+    }
+
+    #[test]
+    fn test_grouping() {
+        let groups = group(vec![(0, manifest(&[("foo", 1)])),
+                                (1, manifest(&[("foo", 2), ("bar", 3)])),
+                                (2, manifest(&[("foo", 1)])),
+                                (3, manifest(&[("foo", 4)]))]);
+        let ids = groups.iter().map(|g| g.conn_ids.clone()).collect::<Vec<_>>();
+        assert_eq!(ids, [vec![0, 2], vec![1], vec![3]]);
+        assert_eq!(differing_files(&groups[0], &groups[1]),
+                   [PathBuf::from("bar"), PathBuf::from("foo")]);
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = (1..=12).map(|i| format!("line {}\n", i)).collect::<String>();
+        let new = old.replace("line 2\n", "line two\n").replace("line 11\n", "")
+                     .replace("line 12\n", "line 12");
+        let diff = unified_diff(old.as_bytes(), new.as_bytes(), "a/foo", "b/foo").unwrap();
+        assert_eq!(diff, "--- a/foo\n+++ b/foo\n\
+                          @@ -1,5 +1,5 @@\n line 1\n-line 2\n+line two\n line 3\n line 4\n line 5\n\
+                          @@ -8,5 +8,4 @@\n line 8\n line 9\n line 10\n-line 11\n-line 12\n+line 12\n\
+                          \\ No newline at end of file\n");
+
+        assert_eq!(unified_diff(b"same\n", b"same\n", "a", "b").unwrap(), "");
+        assert_eq!(unified_diff(b"", b"new\n", "/dev/null", "b").unwrap(),
+                   "--- /dev/null\n+++ b\n@@ -0,0 +1 @@\n+new\n");
+        assert_eq!(unified_diff(b"a\nb\nc\n", b"c\nb\na\n", "a", "b").unwrap(),
+                   "--- a\n+++ b\n@@ -1,3 +1,3 @@\n-a\n-b\n c\n+b\n+a\n");
+    }
+}
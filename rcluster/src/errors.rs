@@ -1,4 +1,5 @@
 use futures::Future;
+use glob::PatternError;
 use walkdir::Error as WalkError;
 
 use std::io;
@@ -13,12 +14,6 @@ macro_rules! future_try {
     };
 }
 
-macro_rules! future_try_wait {
-    ($res:expr) => {
-        future_try!($res.wait())
-    };
-}
-
 /// Future type used throughout the library.
 pub type ClusterFuture<T> = Box<Future<Item=T, Error=ClusterError>>;
 /// Result type used throughout the library.
@@ -29,8 +24,30 @@ pub enum ClusterError {
     Io(io::Error),
     AddrParse(AddrParseError),
     Walk(WalkError),
+    Pattern(PatternError),
     /// Unknown flag in stream.
     UnknownFlag,
     /// No such connection exists for ID.
     InvalidConnectionId,
+    /// Connection has been lost (due to an earlier failure).
+    ConnectionLost,
+    /// Path escapes the destination.
+    InvalidPath,
+    /// Malformed line in change report.
+    InvalidReport,
+    /// Received content doesn't match the checksum from sender.
+    ChecksumMismatch,
+    /// Extents of a sparse file are out of order or out of bounds.
+    InvalidExtents,
+    /// Extended attributes in the header are malformed.
+    InvalidAttributes,
+    /// Malformed line in manifest (or in the state of a two-way sync).
+    InvalidManifest,
+    /// Path is outside the slave's roots.
+    OutsideRoots,
+    /// No such job exists in the slave.
+    UnknownJob,
+    /// Operation has failed in the slave.
+    #[error(msg_embedded, no_from, non_std)]
+    Remote(String),
 }
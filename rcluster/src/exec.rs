@@ -0,0 +1,602 @@
+use buffered::StreamingBuffer;
+use byteorder::{BigEndian, ByteOrder};
+use cgroup::{Cgroup, CgroupLimits, ResourceUsage};
+use compression::Codec;
+use connection::{Connection, ConnectionFlag};
+use errors::{ClusterError, ClusterFuture};
+use futures::{Future, future};
+use futures_cpupool::CpuPool;
+use libc;
+use pool;
+use procs;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io as async_io;
+
+use std::fs::File;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Exit code reported when the command couldn't be spawned.
+const SPAWN_FAILURE_CODE: i32 = 127;
+
+/// Wrapper for executing commands. The master sends the command (one argument per line,
+/// followed by an empty line) and its options (see `ExecOptions`), and then both ends
+/// stream concurrently - the master streams the input (stdin) of the command, while the
+/// slave streams its output (both stdout and stderr), each followed by the magic. Finally,
+/// the slave sends the exit code, and the resources used by the command (a line).
+pub struct Execution<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
+
+/// Environment of a command executed in the slave (everything else is inherited from
+/// the slave). These are sent as lines (one for each setting, ending with an empty line).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecOptions {
+    /// Clear the environment (before unsetting or setting the variables).
+    pub clear_env: bool,
+    /// Variables to be removed from the environment.
+    pub unset_env: Vec<String>,
+    /// Variables to be set (after clearing or unsetting).
+    pub env: Vec<(String, String)>,
+    /// Working directory.
+    pub cwd: Option<String>,
+    /// User and group to run the command as (the slave should be privileged for this).
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Limit for the CPU time (in seconds).
+    pub cpu_limit: Option<u64>,
+    /// Limit for the address space (in bytes).
+    pub memory_limit: Option<u64>,
+    /// Limit for the number of open files.
+    pub open_files_limit: Option<u64>,
+    /// Wall-clock limit, after which the command's process group is killed.
+    pub timeout: Option<Duration>,
+    /// Run the command in a cgroup of its own, with the given limits (the slave should have
+    /// a cgroup root for this). Its resource usage is reported along with the exit code.
+    pub cgroup: Option<CgroupLimits>,
+}
+
+/// Exit code of a command, along with the resources used by it (if it's been run in a cgroup).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecReport {
+    /// Exit code (128 + signal, if it's been killed, and 127 if it couldn't be spawned).
+    pub code: i32,
+    pub usage: Option<ResourceUsage>,
+}
+
+impl ExecOptions {
+    fn to_lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if self.clear_env {
+            lines.push(String::from("clear-env"));
+        }
+
+        lines.extend(self.unset_env.iter().map(|n| format!("unset {}", n)));
+        lines.extend(self.env.iter().map(|&(ref n, ref v)| format!("env {}={}", n, v)));
+        lines.extend(self.cwd.iter().map(|d| format!("cwd {}", d)));
+        lines.extend(self.uid.iter().map(|u| format!("uid {}", u)));
+        lines.extend(self.gid.iter().map(|g| format!("gid {}", g)));
+        lines.extend(self.cpu_limit.iter().map(|l| format!("cpu {}", l)));
+        lines.extend(self.memory_limit.iter().map(|l| format!("memory {}", l)));
+        lines.extend(self.open_files_limit.iter().map(|l| format!("files {}", l)));
+        lines.extend(self.timeout.iter().map(|t| {
+            format!("timeout {}", t.as_secs() * 1000 + t.subsec_nanos() as u64 / 1_000_000)
+        }));
+
+        if let Some(ref limits) = self.cgroup {
+            lines.push(String::from("cgroup"));
+            lines.extend(limits.cpu_millis.iter().map(|l| format!("cgroup-cpu {}", l)));
+            lines.extend(limits.memory.iter().map(|l| format!("cgroup-memory {}", l)));
+            lines.extend(limits.io_read_bps.iter().map(|l| format!("cgroup-io-read {}", l)));
+            lines.extend(limits.io_write_bps.iter().map(|l| format!("cgroup-io-write {}", l)));
+        }
+
+        lines
+    }
+
+    fn from_lines(lines: &[String]) -> io::Result<Self> {
+        let mut options = ExecOptions::default();
+        for line in lines {
+            let mut parts = line.splitn(2, ' ');
+            let (key, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            let valid = match key {
+                "clear-env" => {
+                    options.clear_env = true;
+                    true
+                },
+                "unset" => {
+                    options.unset_env.push(value.to_owned());
+                    true
+                },
+                "env" => match value.find('=') {
+                    Some(i) => {
+                        options.env.push((value[..i].to_owned(), value[i + 1..].to_owned()));
+                        true
+                    },
+                    None => false,
+                },
+                "cwd" => {
+                    options.cwd = Some(value.to_owned());
+                    true
+                },
+                "uid" => {
+                    options.uid = value.parse().ok();
+                    options.uid.is_some()
+                },
+                "gid" => {
+                    options.gid = value.parse().ok();
+                    options.gid.is_some()
+                },
+                "cpu" => {
+                    options.cpu_limit = value.parse().ok();
+                    options.cpu_limit.is_some()
+                },
+                "memory" => {
+                    options.memory_limit = value.parse().ok();
+                    options.memory_limit.is_some()
+                },
+                "files" => {
+                    options.open_files_limit = value.parse().ok();
+                    options.open_files_limit.is_some()
+                },
+                "timeout" => {
+                    options.timeout = value.parse().ok().map(Duration::from_millis);
+                    options.timeout.is_some()
+                },
+                "cgroup" => {
+                    options.cgroup = Some(CgroupLimits::default());
+                    true
+                },
+                // Limits come after the cgroup.
+                key if key.starts_with("cgroup-") => {
+                    let (limits, limit) = (options.cgroup.as_mut(), value.parse().ok());
+                    match (limits, limit, key) {
+                        (Some(l), Some(v), "cgroup-cpu") => l.cpu_millis = Some(v),
+                        (Some(l), Some(v), "cgroup-memory") => l.memory = Some(v),
+                        (Some(l), Some(v), "cgroup-io-read") => l.io_read_bps = Some(v),
+                        (Some(l), Some(v), "cgroup-io-write") => l.io_write_bps = Some(v),
+                        _ => return Err(invalid(line)),
+                    }
+
+                    true
+                },
+                _ => false,
+            };
+
+            if !valid {
+                return Err(invalid(line))
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, format!("invalid execution option '{}'", line))
+}
+
+/// Bytes for the command and its options (as expected by `read_command`).
+pub fn command_bytes(args: &[String], options: &ExecOptions) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for lines in &[args.to_vec(), options.to_lines()] {
+        for line in lines {
+            if line.contains('\n') {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "newline in command"))
+            }
+
+            bytes.extend_from_slice(line.as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes.push(b'\n');
+    }
+
+    Ok(bytes)
+}
+
+/// Read the command and its options (from `command_bytes`). Invalid options are reported
+/// when the command is spawned.
+pub fn read_command<R, W>(conn: Connection<R, W>)
+                         -> ClusterFuture<(Connection<R, W>, Vec<String>, io::Result<ExecOptions>)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let async_read = conn.read_lines().and_then(|(c, args)| {
+        c.read_lines().map(move |(c, lines)| (c, args, ExecOptions::from_lines(&lines)))
+    });
+
+    Box::new(async_read) as ClusterFuture<_>
+}
+
+/// Child spawned for the master's command.
+pub struct SpawnedChild {
+    pub child: Child,
+    /// Dropping this stops the timer for the command.
+    timer: Option<Sender<()>>,
+    cgroup: Option<Cgroup>,
+}
+
+impl SpawnedChild {
+    /// Wait for the child to exit (it's no longer tracked after this), and get its exit code
+    /// along with its resource usage. The processes left in its cgroup (if any) are killed.
+    pub fn wait(mut self) -> io::Result<ExecReport> {
+        let status = self.child.wait();
+        procs::untrack(self.child.id());
+        self.timer.take();
+        let usage = self.cgroup.take().and_then(|cgroup| match cgroup.usage() {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                info!("Cannot get the resource usage of {}: {}", self.child.id(), e);
+                None
+            },
+        });
+
+        Ok(ExecReport { code: exit_code(status?), usage })
+    }
+}
+
+/// Kill the process group once the timeout elapses, unless the returned sender has been
+/// dropped by then.
+fn kill_after(pgid: u32, timeout: Duration) -> Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(timeout) {
+            info!("Process group {} has timed out, killing it", pgid);
+            unsafe { libc::kill(-(pgid as i32), libc::SIGKILL); }
+        }
+    });
+
+    sender
+}
+
+/// Stdin of a child process. Once the child stops reading, this discards the bytes
+/// (so that the rest of the input can still be drained from the stream).
+struct ChildInput(Option<ChildStdin>);
+
+impl Write for ChildInput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let result = match self.0 {
+            Some(ref mut stdin) => stdin.write(bytes),
+            None => return Ok(bytes.len()),
+        };
+
+        match result {
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+                self.0 = None;
+                Ok(bytes.len())
+            },
+            r => r,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {
+            Some(ref mut stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Spawn the command with the given options and stdio. The child leads a new process group
+/// (so that it can be signalled along with its descendants), and it's tracked until it's
+/// been awaited. If the options ask for a cgroup, then it's created in the given root.
+pub fn spawn_command(args: &[String], options: &ExecOptions, cgroup_root: Option<&Path>,
+                     stdin: Stdio, stdout: Stdio, stderr: Stdio) -> io::Result<SpawnedChild> {
+    let (program, rest) = args.split_first().ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidInput, "empty command")
+    })?;
+
+    let mut command = Command::new(program);
+    command.args(rest).stdin(stdin).stdout(stdout).stderr(stderr);
+    if options.clear_env {
+        command.env_clear();
+    }
+
+    for name in &options.unset_env {
+        command.env_remove(name);
+    }
+
+    command.envs(options.env.iter().map(|&(ref n, ref v)| (n, v)));
+    if let Some(ref dir) = options.cwd {
+        command.current_dir(dir);
+    }
+
+    let cgroup = match (options.cgroup.as_ref(), cgroup_root) {
+        (Some(limits), Some(root)) => Some(Cgroup::create(root, limits)?),
+        (Some(_), None) => {
+            return Err(io::Error::new(ErrorKind::Other, "slave doesn't have a cgroup root"))
+        },
+        _ => None,
+    };
+
+    // Closed (in the child) once it executes the command.
+    let procs_file = match cgroup {
+        Some(ref c) => Some(c.procs_file()?),
+        None => None,
+    };
+
+    let procs_fd = procs_file.as_ref().map(|f| f.as_raw_fd());
+    let (uid, gid) = (options.uid, options.gid);
+    let limits = [(libc::RLIMIT_CPU, options.cpu_limit), (libc::RLIMIT_AS, options.memory_limit),
+                  (libc::RLIMIT_NOFILE, options.open_files_limit)];
+    // The user is changed in the end, since the child may not be able to join the cgroup
+    // (or raise the limits) after that.
+    unsafe {
+        command.pre_exec(move || {
+            let check = |ret: libc::c_int| match ret {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            };
+
+            check(libc::setpgid(0, 0))?;
+            if let Some(fd) = procs_fd {
+                check(libc::write(fd, b"0".as_ptr() as *const _, 1) as libc::c_int)?;
+            }
+
+            for &(resource, limit) in &limits {
+                let limit = match limit {
+                    Some(l) => l as libc::rlim_t,
+                    None => continue,
+                };
+
+                let rlimit = libc::rlimit { rlim_cur: limit, rlim_max: limit };
+                check(libc::setrlimit(resource, &rlimit))?;
+            }
+
+            if (uid.is_some() || gid.is_some()) && libc::geteuid() == 0 {
+                check(libc::setgroups(0, ::std::ptr::null()))?;
+            }
+
+            if let Some(gid) = gid {
+                check(libc::setgid(gid))?;
+            }
+
+            if let Some(uid) = uid {
+                check(libc::setuid(uid))?;
+            }
+
+            Ok(())
+        });
+    }
+
+    let child = command.spawn()?;
+    procs::track(child.id(), args);
+    let timer = options.timeout.map(|t| kill_after(child.id(), t));
+    Ok(SpawnedChild { child, timer, cgroup })
+}
+
+/// Spawn the command, whose stdout and stderr go to the same pipe. This returns the child
+/// and the reading end of that pipe.
+fn spawn(args: &[String], options: &ExecOptions,
+         cgroup_root: Option<&Path>) -> io::Result<(SpawnedChild, File)> {
+    let (reader, writer) = pool::pipe()?;
+    let child = spawn_command(args, options, cgroup_root, Stdio::piped(),
+                              Stdio::from(writer.try_clone()?), Stdio::from(writer))?;
+    // The command (along with our copies of the writing end) has been dropped by now,
+    // and so the output ends once the child (and its children) exit.
+    Ok((child, reader))
+}
+
+/// Exit code of the process (or 128 + signal, like shells do, if it's been killed).
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(-1)
+}
+
+impl<R, W> Execution<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    /// (Master) Execute the given command in the slave (with the given options and input),
+    /// and stream its output to the given writer. This resolves to the connection
+    /// and the exit code (along with the resource usage) of the command.
+    pub fn run_remote<I, O>(self, args: &[String], options: &ExecOptions, input: I,
+                            output: O) -> ClusterFuture<(Connection<R, W>, ExecReport)>
+        where I: Read + Send + 'static, O: Write + Send + 'static
+    {
+        let command = future_try!(command_bytes(args, options));
+        let async_exec = self.0.write_flag(ConnectionFlag::MasterWantsExecution)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_magic())
+            .and_then(move |c| c.write_bytes(command))
+            // The input could take a while, and the command should be run right away.
+            .and_then(|c| c.flush())
+            .and_then(move |c| {
+                let (r, w, m, s) = c.into();
+                let async_input = StreamingBuffer::blocking_to_stream(input, w, s.buffer_size)
+                    .with_codec(Codec::Encode(s.compression))
+                    .with_limiters(s.limiters.clone())
+                    .stream()
+                    .and_then(move |(_, w)| {
+                        // Magic ends the input (we can't use the connection yet, because
+                        // its reader is still busy with the output).
+                        async_io::write_all(w, m)
+                            .and_then(|(w, _)| async_io::flush(w))
+                            .map_err(ClusterError::from)
+                    });
+
+                let async_output = StreamingBuffer::stream_to_blocking(r, &m, output, s.buffer_size)
+                    .with_codec(Codec::Decode(s.compression))
+                    .stream()
+                    .map(|(r, _)| r);
+
+                async_input.join(async_output).and_then(move |(w, r)| {
+                    Connection::from((r, w, m, s)).read_bytes([0; 4])
+                }).and_then(|(c, code)| {
+                    let code = BigEndian::read_i32(&code);
+                    c.read_line().and_then(move |(c, line)| match ResourceUsage::from_field(&line) {
+                        Some(usage) => Ok((c, ExecReport { code, usage })),
+                        None => Err(ClusterError::InvalidReport),
+                    })
+                })
+            });
+
+        Box::new(async_exec) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the command from the stream and execute it, forwarding the input from
+    /// the stream to the command, and streaming its output (along with the exit code).
+    pub fn run_local(self) -> ClusterFuture<Connection<R, W>> {
+        let async_exec = read_command(self.0).and_then(|(c, args, options)| {
+            let spawned = options.and_then(|o| {
+                spawn(&args, &o, c.settings().cgroup_root.as_ref().map(|p| p.as_path()))
+            });
+            let (stdin, output, child) = match spawned {
+                Ok((mut child, reader)) => {
+                    info!("Executing {:?}", args);
+                    let stdin = child.child.stdin.take();
+                    (stdin, Box::new(reader) as Box<Read + Send>, Some(child))
+                },
+                Err(e) => {
+                    // The input should still be drained, and the error is the output.
+                    error!("Cannot execute {:?}: {}", args, e);
+                    let message = format!("{}: {}\n", args.join(" "), e).into_bytes();
+                    (None, Box::new(Cursor::new(message)) as Box<Read + Send>, None)
+                },
+            };
+
+            let (r, w, m, s) = c.into();
+            let async_input = StreamingBuffer::stream_to_blocking(r, &m, ChildInput(stdin),
+                                                                 s.buffer_size)
+                .with_codec(Codec::Decode(s.compression))
+                .stream()
+                // Dropping the writer closes the child's stdin.
+                .map(|(r, _)| r);
+
+            let async_output = StreamingBuffer::blocking_to_stream(output, w, s.buffer_size)
+                .with_codec(Codec::Encode(s.compression))
+                .with_limiters(s.limiters.clone())
+                .stream()
+                .and_then(move |(_, w)| {
+                    let async_wait = match child {
+                        Some(child) => {
+                            // The child could still be running (after closing its output),
+                            // and so it's awaited in a thread of its own.
+                            let async_wait = CpuPool::new(1).spawn_fn(move || child.wait());
+
+                            Box::new(async_wait.map_err(ClusterError::from)) as ClusterFuture<ExecReport>
+                        },
+                        None => {
+                            let report = ExecReport { code: SPAWN_FAILURE_CODE, usage: None };
+                            Box::new(future::ok(report)) as ClusterFuture<_>
+                        },
+                    };
+
+                    async_wait.map(move |report| (w, report))
+                });
+
+            async_input.join(async_output).and_then(move |(r, (w, report))| {
+                info!("Command exited with {}", report.code);
+                let mut code_bytes = [0; 4];
+                BigEndian::write_i32(&mut code_bytes, report.code);
+                let usage = ResourceUsage::to_field(report.usage.as_ref());
+                Connection::from((r, w, m, s)).write_magic()
+                                              .and_then(move |c| c.write_bytes(code_bytes))
+                                              .and_then(move |c| c.write_line(usage))
+            })
+        });
+
+        Box::new(async_exec) as ClusterFuture<_>
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ByteOrder};
+    use cgroup::CgroupLimits;
+    use connection::{loopback, response};
+    use futures::Future;
+    use rand::{self, RngCore};
+    use super::{ExecOptions, Execution, command_bytes};
+
+    use std::env;
+    use std::time::{Duration, Instant};
+
+    fn run_local(args: &[&str], input: &[u8]) -> (Vec<u8>, i32) {
+        run_with(args, &ExecOptions::default(), input)
+    }
+
+    /// Run the command locally (as the slave would) with the given options and input, and
+    /// get the output along with the exit code.
+    fn run_with(args: &[&str], options: &ExecOptions, input: &[u8]) -> (Vec<u8>, i32) {
+        let mut magic = [0; 16];
+        rand::thread_rng().fill_bytes(&mut magic);
+        let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let mut stream = command_bytes(&args, options).unwrap();
+        stream.extend_from_slice(input);
+        stream.extend_from_slice(&magic);
+
+        let conn = Execution(loopback(stream, magic)).run_local().wait().unwrap();
+        let out = response(conn);
+
+        // Commands aren't run in cgroups here, and so there's no usage.
+        assert!(out.ends_with(b"-\n"));
+        let (rest, code) = out[..out.len() - 2].split_at(out.len() - 6);
+        assert!(rest.ends_with(&magic));
+        (rest[..rest.len() - magic.len()].to_vec(), BigEndian::read_i32(code))
+    }
+
+    #[test]
+    fn test_execution_forwards_input() {
+        let mut input = vec![0; 200 * 1024];
+        rand::thread_rng().fill_bytes(&mut input);
+        let (output, code) = run_local(&["cat"], &input);
+        assert_eq!(code, 0);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_execution_output_and_exit_code() {
+        let (output, code) = run_local(&["sh", "-c", "echo out; echo err >&2; exit 3"], b"");
+        assert_eq!(code, 3);
+        assert_eq!(&output[..], b"out\nerr\n");
+
+        // Input which isn't read by the command is discarded.
+        let (output, code) = run_local(&["true"], &[b'x'; 300 * 1024]);
+        assert_eq!((&output[..], code), (&b""[..], 0));
+
+        let (output, code) = run_local(&["/nonexistent/command"], b"");
+        assert_eq!(code, 127);
+        assert!(output.starts_with(b"/nonexistent/command: "));
+    }
+
+    #[test]
+    fn test_execution_options() {
+        let dir = env::temp_dir().canonicalize().unwrap();
+        let options = ExecOptions {
+            clear_env: true,
+            env: vec![(String::from("FOO"), String::from("bar=baz"))],
+            cwd: Some(dir.to_string_lossy().into_owned()),
+            open_files_limit: Some(42),
+            ..ExecOptions::default()
+        };
+
+        let script = "echo \"$FOO:${HOME:-none}:$(pwd)\"; ulimit -n";
+        let (output, code) = run_with(&["/bin/sh", "-c", script], &options, b"");
+        assert_eq!(code, 0);
+        assert_eq!(String::from_utf8(output).unwrap(), format!("bar=baz:none:{}\n42\n", dir.display()));
+
+        // Timeout kills the whole process group.
+        let options = ExecOptions { timeout: Some(Duration::from_millis(200)), ..ExecOptions::default() };
+        let start = Instant::now();
+        let (_, code) = run_with(&["sh", "-c", "sleep 30 & sleep 30"], &options, b"");
+        assert_eq!(code, 128 + 9);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let options = ExecOptions { cwd: Some(String::from("/nonexistent")), ..ExecOptions::default() };
+        assert_eq!(run_with(&["true"], &options, b"").1, 127);
+
+        // Cgroups need the slave's root.
+        let limits = CgroupLimits { cpu_millis: Some(500), memory: Some(1 << 20), ..CgroupLimits::default() };
+        let options = ExecOptions { cgroup: Some(limits), ..ExecOptions::default() };
+        assert_eq!(ExecOptions::from_lines(&options.to_lines()).unwrap(), options);
+        let (output, code) = run_with(&["true"], &options, b"");
+        assert_eq!(code, 127);
+        assert!(String::from_utf8(output).unwrap().contains("cgroup root"));
+        assert!(ExecOptions::from_lines(&[String::from("cgroup-cpu 500")]).is_err());
+    }
+}
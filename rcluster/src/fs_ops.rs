@@ -0,0 +1,629 @@
+use checksum;
+use connection::{Connection, ConnectionFlag};
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use futures::{Future, future};
+use futures::future::Loop;
+use pool;
+use tokio_io::{AsyncRead, AsyncWrite};
+use walkdir::WalkDir;
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, Metadata, Permissions};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Status byte for an operation which has succeeded (followed by its result lines).
+const STATUS_OK: u8 = 0;
+/// Status byte for an operation which has failed (followed by the error message).
+const STATUS_FAILED: u8 = 1;
+
+/// Wrapper for small filesystem operations in the slave. The master sends the operation's
+/// flag, followed by its arguments - paths (or the mode, for `chmod`) one per line, and a
+/// byte for its option (recursive, or creating the parents). The slave replies with a
+/// status byte, followed by the result (lines, ending with an empty line) or the error
+/// message (a line). Paths are in hex both ways (see `encode_path`). They should be
+/// absolute, and they should be inside the slave's roots (if it has any).
+///
+/// Futures in master resolve to the connection along with the operation's result, so that
+/// the connection isn't lost when an operation fails in the slave.
+pub struct FsOps<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
+
+/// Type of a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl FileKind {
+    fn to_char(&self) -> char {
+        match *self {
+            FileKind::File => 'f',
+            FileKind::Dir => 'd',
+            FileKind::Symlink => 'l',
+            FileKind::Other => 'o',
+        }
+    }
+
+    fn from_char(c: &str) -> Option<Self> {
+        match c {
+            "f" => Some(FileKind::File),
+            "d" => Some(FileKind::Dir),
+            "l" => Some(FileKind::Symlink),
+            "o" => Some(FileKind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// Information about a path in the slave.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileInfo {
+    /// Path relative to the listed directory (or the requested path, for `stat`).
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Permission bits (including setuid, setgid and sticky bits).
+    pub mode: u32,
+    /// Modification time (seconds since UNIX epoch).
+    pub mtime: i64,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl FileInfo {
+    fn new(path: PathBuf, meta: &Metadata) -> Self {
+        let file_type = meta.file_type();
+        let kind = if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        };
+
+        FileInfo {
+            path, kind,
+            size: meta.len(),
+            mode: meta.mode() & 0o7777,
+            mtime: meta.mtime(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!("{} {:o} {} {} {} {} {}", self.kind.to_char(), self.mode, self.size,
+                self.mtime, self.uid, self.gid, encode_path(&self.path))
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(7, ' ');
+        Some(FileInfo {
+            kind: FileKind::from_char(parts.next()?)?,
+            mode: u32::from_str_radix(parts.next()?, 8).ok()?,
+            size: parts.next()?.parse().ok()?,
+            mtime: parts.next()?.parse().ok()?,
+            uid: parts.next()?.parse().ok()?,
+            gid: parts.next()?.parse().ok()?,
+            path: decode_path(parts.next()?)?,
+        })
+    }
+}
+
+/// Disk usage of a path (and everything in it). Hard links are counted once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiskUsage {
+    /// Total size of the files.
+    pub size: u64,
+    /// Bytes actually allocated for them (less than the size for sparse files).
+    pub allocated: u64,
+    pub files: u64,
+    pub dirs: u64,
+}
+
+impl<R, W> FsOps<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    /// (Master) List the path in the other end - the entries of a directory (everything in
+    /// it, if it's recursive), or the path itself (if it's not a directory).
+    pub fn list<P>(self, path: P,
+                   recursive: bool) -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<FileInfo>>)>
+        where P: AsRef<Path>
+    {
+        let async_list = self.request(&[path_arg(path)], recursive).map(|(c, result)| {
+            let entries = result.and_then(|lines| {
+                lines.iter().map(|l| FileInfo::from_line(l)).collect::<Option<Vec<_>>>()
+                     .ok_or(ClusterError::InvalidReport)
+            });
+
+            (c, entries)
+        });
+
+        Box::new(async_list) as ClusterFuture<_>
+    }
+
+    /// (Master) Get the information of the path (without following symlinks) in the other end.
+    pub fn stat<P>(self, path: P) -> ClusterFuture<(Connection<R, W>, ClusterResult<FileInfo>)>
+        where P: AsRef<Path>
+    {
+        let async_stat = self.request(&[path_arg(path)], false).map(|(c, result)| {
+            let info = result.and_then(|lines| {
+                lines.first().and_then(|l| FileInfo::from_line(l)).ok_or(ClusterError::InvalidReport)
+            });
+
+            (c, info)
+        });
+
+        Box::new(async_stat) as ClusterFuture<_>
+    }
+
+    /// (Master) Get the disk usage of the path in the other end.
+    pub fn disk_usage<P>(self, path: P) -> ClusterFuture<(Connection<R, W>, ClusterResult<DiskUsage>)>
+        where P: AsRef<Path>
+    {
+        let async_usage = self.request(&[path_arg(path)], false).map(|(c, result)| {
+            let usage = result.and_then(|lines| {
+                let numbers = lines.first().map(|l| l.split(' ').filter_map(|n| n.parse().ok())
+                                                                .collect::<Vec<u64>>());
+                match numbers {
+                    Some(ref n) if n.len() == 4 => {
+                        Ok(DiskUsage { size: n[0], allocated: n[1], files: n[2], dirs: n[3] })
+                    },
+                    _ => Err(ClusterError::InvalidReport),
+                }
+            });
+
+            (c, usage)
+        });
+
+        Box::new(async_usage) as ClusterFuture<_>
+    }
+
+    /// (Master) Send the arguments and the option of an operation (whose flag has already
+    /// been sent), and get the result lines (or the error from the other end).
+    pub fn request(self, args: &[String],
+                   option: bool) -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<String>>)> {
+        let mut bytes = vec![];
+        for arg in args {
+            bytes.extend_from_slice(arg.as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes.push(option as u8);
+        let async_request = self.0.write_bytes(bytes)
+            .and_then(|c| c.flush())
+            .and_then(read_result);
+
+        Box::new(async_request) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request for the operation (with the given flag), perform it (only
+    /// if its paths are inside the roots), and send the result (or the error).
+    pub fn serve(self, flag: ConnectionFlag) -> ClusterFuture<Connection<R, W>> {
+        let num_args = match flag {
+            ConnectionFlag::MasterMovesPath | ConnectionFlag::MasterChangesMode => 2,
+            _ => 1,
+        };
+
+        let roots = self.0.settings().roots.clone();
+        let async_read = future::loop_fn((self.0, vec![]), move |(c, mut args)| {
+            c.read_line().map(move |(c, line)| {
+                args.push(line);
+                match args.len() < num_args {
+                    true => Loop::Continue((c, args)),
+                    false => Loop::Break((c, args)),
+                }
+            })
+        }).and_then(|(c, args)| c.read_bytes([0; 1]).map(move |(c, option)| (c, args, option[0] != 0)));
+
+        let async_serve = async_read.and_then(move |(c, args, option)| {
+            pool::run(move || perform(flag, &roots, &args, option)).then(|result| {
+                if let Err(ref e) = result {
+                    info!("Filesystem operation failed: {}", e);
+                }
+
+                write_result(c, result)
+            })
+        });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+}
+
+/// Encode the path (which could have newlines, or which may not be UTF-8) for the lines
+/// sent to the other end.
+pub fn encode_path(path: &Path) -> String {
+    checksum::to_hex(path.as_os_str().as_bytes())
+}
+
+/// Decode the path (encoded with `encode_path`).
+pub fn decode_path(encoded: &str) -> Option<PathBuf> {
+    checksum::from_hex(encoded).map(|b| PathBuf::from(OsString::from_vec(b)))
+}
+
+fn path_arg<P: AsRef<Path>>(path: P) -> String {
+    encode_path(path.as_ref())
+}
+
+/// Send the result of an operation - a status byte, followed by the result lines (ending
+/// with an empty line) or the error message (a line).
+pub fn write_result<R, W>(conn: Connection<R, W>,
+                          result: ClusterResult<Vec<String>>) -> ClusterFuture<Connection<R, W>>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let mut bytes = vec![];
+    match result {
+        Ok(lines) => {
+            bytes.push(STATUS_OK);
+            for line in lines {
+                bytes.extend_from_slice(line.as_bytes());
+                bytes.push(b'\n');
+            }
+
+            bytes.push(b'\n');
+        },
+        Err(e) => {
+            bytes.push(STATUS_FAILED);
+            bytes.extend_from_slice(error_message(&e).as_bytes());
+            bytes.push(b'\n');
+        },
+    }
+
+    conn.write_bytes(bytes)
+}
+
+/// Read the result of an operation (sent with `write_result`). Failures in the other end
+/// are `ClusterError::Remote`.
+pub fn read_result<R, W>(conn: Connection<R, W>)
+                        -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<String>>)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let async_read = conn.read_bytes([0; 1]).and_then(|(c, status)| -> ClusterFuture<_> {
+        match status[0] {
+            STATUS_OK => Box::new(c.read_lines().map(|(c, lines)| (c, Ok(lines)))),
+            _ => Box::new(c.read_line().map(|(c, msg)| (c, Err(ClusterError::Remote(msg))))),
+        }
+    });
+
+    Box::new(async_read) as ClusterFuture<_>
+}
+
+/// Message for an error (in a single line), which is sent to the master.
+pub fn error_message(err: &ClusterError) -> String {
+    let msg = match *err {
+        ClusterError::Io(ref e) => e.to_string(),
+        ClusterError::Walk(ref e) => e.to_string(),
+        ref e => e.description().to_owned(),
+    };
+
+    msg.replace('\n', " ")
+}
+
+/// Perform the operation (with the given flag), and get its result lines.
+fn perform(flag: ConnectionFlag, roots: &[PathBuf], args: &[String],
+           option: bool) -> ClusterResult<Vec<String>> {
+    let arg_path = |arg: &str| decode_path(arg).ok_or(ClusterError::InvalidPath);
+    let requested = arg_path(&args[0])?;
+    let path = check_roots(roots, &requested)?;
+    match flag {
+        ConnectionFlag::MasterListsPath => Ok(list(&path, option)?.iter().map(|i| i.to_line()).collect()),
+        ConnectionFlag::MasterStatsPath => {
+            let meta = fs::symlink_metadata(&path)?;
+            Ok(vec![FileInfo::new(requested, &meta).to_line()])
+        },
+        ConnectionFlag::MasterWantsUsage => {
+            let usage = disk_usage(&path)?;
+            Ok(vec![format!("{} {} {} {}", usage.size, usage.allocated, usage.files, usage.dirs)])
+        },
+        ConnectionFlag::MasterDeletesPath => {
+            check_not_root(roots, &path)?;
+            let meta = fs::symlink_metadata(&path)?;
+            match meta.is_dir() {
+                true if option => fs::remove_dir_all(&path)?,
+                true => fs::remove_dir(&path)?,
+                false => fs::remove_file(&path)?,
+            }
+
+            info!("Removed {}", path.display());
+            Ok(vec![])
+        },
+        ConnectionFlag::MasterMovesPath => {
+            check_not_root(roots, &path)?;
+            let dest = check_roots(roots, &arg_path(&args[1])?)?;
+            fs::rename(&path, &dest)?;
+            info!("Moved {} to {}", path.display(), dest.display());
+            Ok(vec![])
+        },
+        ConnectionFlag::MasterCreatesDir => {
+            match option {
+                true => fs::create_dir_all(&path)?,
+                false => fs::create_dir(&path)?,
+            }
+
+            Ok(vec![])
+        },
+        ConnectionFlag::MasterChangesMode => {
+            // Mode (in octal) follows the path.
+            let mode = u32::from_str_radix(&args[1], 8).map_err(|_| ClusterError::InvalidReport)?;
+            set_mode(&path, mode & 0o7777, option)?;
+            Ok(vec![])
+        },
+        _ => Err(ClusterError::UnknownFlag),
+    }
+}
+
+/// Resolve the (absolute) path, and make sure that it's inside one of the roots (if there
+/// are any). Symlinks in the parents are resolved, but the path itself isn't followed (so
+/// that symlinks can be removed or moved), and the path needn't exist.
+pub fn check_roots(roots: &[PathBuf], path: &Path) -> ClusterResult<PathBuf> {
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(ClusterError::InvalidPath)
+    }
+
+    let name = match path.file_name() {
+        Some(name) => name,
+        // Root directory.
+        None => return check_inside(roots, path.to_path_buf()),
+    };
+
+    // Resolve the nearest ancestor that exists, and add the rest.
+    let parent = path.parent().unwrap();
+    let mut existing = parent;
+    let mut rest = vec![];
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(p) => break p,
+            Err(_) => {
+                rest.push(existing.file_name().unwrap());
+                existing = existing.parent().unwrap();
+            },
+        }
+    };
+
+    let resolved = rest.iter().rev().fold(resolved, |p, c| p.join(c)).join(name);
+    check_inside(roots, resolved)
+}
+
+/// Check that the path whose contents are read or written (say, the source or destination
+/// of a sync) is inside the roots, along with its target (if it's a symlink). Unlike
+/// `check_roots`, nothing is checked if there aren't any roots (since the path could be
+/// relative, in the master).
+pub fn check_target(roots: &[PathBuf], path: &Path) -> ClusterResult<PathBuf> {
+    if roots.is_empty() {
+        return Ok(path.to_path_buf())
+    }
+
+    let path = check_roots(roots, path)?;
+    if let Ok(target) = path.canonicalize() {
+        check_inside(roots, target)?;
+    }
+
+    Ok(path)
+}
+
+fn check_inside(roots: &[PathBuf], path: PathBuf) -> ClusterResult<PathBuf> {
+    if roots.is_empty() || roots.iter().any(|r| path.starts_with(r)) {
+        Ok(path)
+    } else {
+        Err(ClusterError::OutsideRoots)
+    }
+}
+
+/// Roots themselves can't be removed (or moved).
+fn check_not_root(roots: &[PathBuf], path: &Path) -> ClusterResult<()> {
+    match roots.iter().any(|r| r == path) {
+        true => Err(ClusterError::OutsideRoots),
+        false => Ok(()),
+    }
+}
+
+/// List the entries of a directory (relative to it), or the path itself.
+fn list(path: &Path, recursive: bool) -> ClusterResult<Vec<FileInfo>> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        let name = path.file_name().map(PathBuf::from).unwrap_or_default();
+        return Ok(vec![FileInfo::new(name, &meta)])
+    }
+
+    let max_depth = if recursive { ::std::usize::MAX } else { 1 };
+    let mut entries = vec![];
+    let walker = WalkDir::new(path).min_depth(1).max_depth(max_depth)
+                                   .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in walker {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(path).unwrap().to_path_buf();
+        entries.push(FileInfo::new(rel_path, &entry.metadata()?));
+    }
+
+    Ok(entries)
+}
+
+/// Get the disk usage of the path (without following symlinks, including the path itself,
+/// since it could point outside the roots).
+fn disk_usage(path: &Path) -> ClusterResult<DiskUsage> {
+    let mut usage = DiskUsage::default();
+    let mut links = HashSet::new();
+    for entry in WalkDir::new(path).follow_root_links(false) {
+        let meta = entry?.metadata()?;
+        if meta.is_dir() {
+            usage.dirs += 1;
+            continue
+        }
+
+        if meta.nlink() > 1 && !links.insert((meta.dev(), meta.ino())) {
+            continue
+        }
+
+        usage.files += 1;
+        usage.size += meta.len();
+        usage.allocated += meta.blocks() * 512;
+    }
+
+    Ok(usage)
+}
+
+/// Set the permission bits of the path (and everything in it, if it's recursive).
+/// Symlinks (including the path itself) are left alone.
+fn set_mode(path: &Path, mode: u32, recursive: bool) -> ClusterResult<()> {
+    let max_depth = if recursive { ::std::usize::MAX } else { 0 };
+    for entry in WalkDir::new(path).follow_root_links(false).max_depth(max_depth) {
+        let entry = entry?;
+        if !entry.file_type().is_symlink() {
+            fs::set_permissions(entry.path(), Permissions::from_mode(mode))?;
+        }
+    }
+
+    Ok(())
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use connection::{ConnectionFlag, loopback, response};
+    use errors::ClusterError;
+    use futures::Future;
+    use rand::{self, RngCore};
+    use super::{FileInfo, FileKind, FsOps, check_roots, check_target, encode_path, path_arg, perform};
+
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs::{self, File, Permissions};
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    use std::path::{Path, PathBuf};
+
+    fn args(paths: &[&Path]) -> Vec<String> {
+        paths.iter().map(|p| encode_path(p)).collect()
+    }
+
+    fn mode_args(path: &Path, mode: &str) -> Vec<String> {
+        vec![encode_path(path), String::from(mode)]
+    }
+
+    #[test]
+    fn test_roots() {
+        let root = env::temp_dir().canonicalize().unwrap()
+                                  .join(format!("rcluster-roots-{}", rand::thread_rng().next_u64()));
+        let (inside, outside) = (root.join("inside"), root.join("outside"));
+        fs::create_dir_all(&inside).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, inside.join("escape")).unwrap();
+        let roots = vec![inside.clone()];
+
+        assert_eq!(check_roots(&roots, &inside.join("new/dir")).unwrap(), inside.join("new/dir"));
+        assert!(check_roots(&[], &outside).is_ok());
+        assert!(check_roots(&roots, Path::new("relative")).is_err());
+        assert!(check_roots(&roots, &inside.join("../outside")).is_err());
+        assert!(check_roots(&roots, &outside.join("foo")).is_err());
+        // Symlinks in parents are resolved, but the path itself isn't followed.
+        assert!(check_roots(&roots, &inside.join("escape/foo")).is_err());
+        assert_eq!(check_roots(&roots, &inside.join("escape")).unwrap(), inside.join("escape"));
+        // ... unless its contents are read or written.
+        assert!(check_target(&roots, &inside.join("escape")).is_err());
+        assert_eq!(check_target(&roots, &inside.join("new")).unwrap(), inside.join("new"));
+        assert_eq!(check_target(&[], Path::new("relative")).unwrap(), Path::new("relative"));
+
+        let rename = |from: &Path, to: &Path| {
+            perform(ConnectionFlag::MasterMovesPath, &roots, &args(&[from, to]), false)
+        };
+        match rename(&inside.join("escape"), &outside.join("escape")) {
+            Err(ClusterError::OutsideRoots) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // Symlinked paths aren't followed (by `chmod` and `du`) outside the roots.
+        File::create(outside.join("foo")).unwrap().write_all(b"foo").unwrap();
+        fs::set_permissions(&outside, Permissions::from_mode(0o755)).unwrap();
+        let escape = inside.join("escape");
+        perform(ConnectionFlag::MasterChangesMode, &roots, &mode_args(&escape, "777"), true).unwrap();
+        assert_eq!(fs::metadata(&outside).unwrap().permissions().mode() & 0o7777, 0o755);
+        assert_ne!(fs::metadata(outside.join("foo")).unwrap().permissions().mode() & 0o7777, 0o777);
+        let usage = perform(ConnectionFlag::MasterWantsUsage, &roots, &args(&[&escape]), false).unwrap();
+        assert!(usage[0].ends_with(" 1 0"));
+
+        assert!(rename(&inside, &inside.join("foo")).is_err());
+        rename(&inside.join("escape"), &inside.join("link")).unwrap();
+        assert!(fs::symlink_metadata(inside.join("link")).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_operations() {
+        let root = env::temp_dir().canonicalize().unwrap()
+                                  .join(format!("rcluster-fs-ops-{}", rand::thread_rng().next_u64()));
+        let roots = vec![];
+        let run = |flag, paths: &[&Path], option| perform(flag, &roots, &args(paths), option);
+
+        run(ConnectionFlag::MasterCreatesDir, &[&root.join("a/b")], true).unwrap();
+        assert!(run(ConnectionFlag::MasterCreatesDir, &[&root.join("c/d")], false).is_err());
+        File::create(root.join("a/b/foo")).unwrap().write_all(b"foobar").unwrap();
+        File::create(root.join("a/bar")).unwrap().write_all(b"bar").unwrap();
+        perform(ConnectionFlag::MasterChangesMode, &roots, &mode_args(&root.join("a"), "700"), true)
+            .unwrap();
+        let usage = run(ConnectionFlag::MasterWantsUsage, &[&root], false).unwrap();
+        // Allocated size depends on the filesystem.
+        assert!(usage[0].starts_with("9 ") && usage[0].ends_with(" 2 3"));
+        // Names with newlines don't end the listing.
+        File::create(root.join("a/b\n\nc")).unwrap();
+
+        // Master parses the listing (as written by the slave).
+        let mut request = format!("{}\n", encode_path(&root)).into_bytes();
+        request.push(1);
+        let conn = FsOps(loopback(request, [0; 16])).serve(ConnectionFlag::MasterListsPath)
+                                                    .wait().unwrap();
+        let (_, entries) = FsOps(loopback(response(conn), [0; 16])).list(&root, true)
+                                                                   .wait().unwrap();
+        let entries = entries.unwrap();
+        let paths = entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+        let expected = ["a", "a/b", "a/b/foo", "a/b\n\nc", "a/bar"];
+        assert_eq!(paths, expected.iter().map(PathBuf::from).collect::<Vec<_>>());
+        assert_eq!((entries[2].kind, entries[2].mode, entries[2].size), (FileKind::File, 0o700, 6));
+        assert_eq!(entries[0].kind, FileKind::Dir);
+
+        // ... and they needn't be UTF-8.
+        let path = PathBuf::from(OsStr::from_bytes(b"foo\xff\n"));
+        let info = FileInfo { path, ..entries[0].clone() };
+        assert_eq!(FileInfo::from_line(&info.to_line()), Some(info));
+
+        // Neither do the paths in requests (as sent by the master).
+        let odd = root.join(OsStr::from_bytes(b"a/b\xff\nd"));
+        File::create(&odd).unwrap();
+        let mut request = format!("{}\n", path_arg(&odd)).into_bytes();
+        request.push(0);
+        let conn = FsOps(loopback(request, [0; 16])).serve(ConnectionFlag::MasterStatsPath)
+                                                    .wait().unwrap();
+        let (_, info) = FsOps(loopback(response(conn), [0; 16])).stat(&odd).wait().unwrap();
+        assert_eq!(info.unwrap().path, odd);
+        run(ConnectionFlag::MasterDeletesPath, &[&odd], false).unwrap();
+
+        assert!(run(ConnectionFlag::MasterDeletesPath, &[&root.join("a")], false).is_err());
+        run(ConnectionFlag::MasterDeletesPath, &[&root.join("a/bar")], false).unwrap();
+        run(ConnectionFlag::MasterDeletesPath, &[&root], true).unwrap();
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn test_failure_keeps_connection() {
+        let missing = env::temp_dir().join(format!("rcluster-missing-{}", rand::thread_rng().next_u64()));
+        let mut request = format!("{}\n", encode_path(&missing)).into_bytes();
+        request.push(0);
+        let conn = FsOps(loopback(request, [0; 16])).serve(ConnectionFlag::MasterStatsPath)
+                                                    .wait().unwrap();
+        let (_, result) = FsOps(loopback(response(conn), [0; 16])).stat(&missing).wait().unwrap();
+        match result {
+            Err(ClusterError::Remote(ref msg)) => assert!(msg.contains("No such file")),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
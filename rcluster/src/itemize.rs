@@ -0,0 +1,212 @@
+use connection::Connection;
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use futures::Future;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Length of the change code in an itemized line (like `rsync -i`).
+const CODE_LENGTH: usize = 11;
+
+/// Change made (or would be made, in case of a dry run) to a path in the destination.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    /// Path didn't exist in the destination (or it had a different type).
+    Created,
+    /// Path exists in the destination, but its size, modification time, permissions, ACLs
+    /// or extended attributes differ (the last two are compared only if they're synced).
+    Updated {
+        size: bool,
+        time: bool,
+        perms: bool,
+        acls: bool,
+        xattrs: bool,
+    },
+    /// Path doesn't exist in the source, and it's been removed from the destination.
+    Deleted,
+}
+
+/// An item in the change report of a sync.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeItem {
+    /// Path relative to the destination.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub change: Change,
+}
+
+impl Display for ChangeItem {
+    /// Format this item like `rsync -i` does - an 11-character code (`YXcstpoguax`),
+    /// followed by the path (directories have a trailing slash).
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let file_type = if self.is_dir { 'd' } else { 'f' };
+        match self.change {
+            Change::Created => {
+                let update_type = if self.is_dir { 'c' } else { '>' };
+                write!(f, "{}{}+++++++++", update_type, file_type)?;
+            },
+            Change::Updated { size, time, perms, acls, xattrs } => {
+                let flag = |set, c| if set { c } else { '.' };
+                let update_type = flag(!self.is_dir && (size || time), '>');
+                write!(f, "{}{}.{}{}{}...{}{}", update_type, file_type,
+                       flag(size, 's'), flag(time, 't'), flag(perms, 'p'),
+                       flag(acls, 'a'), flag(xattrs, 'x'))?;
+            },
+            Change::Deleted => write!(f, "{:<width$}", "*deleting", width = CODE_LENGTH)?,
+        }
+
+        write!(f, " {}", self.path.display())?;
+        if self.is_dir {
+            write!(f, "/")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ChangeItem {
+    type Err = ClusterError;
+
+    /// Parse an itemized line (as formatted by `Display`).
+    fn from_str(line: &str) -> Result<Self, ClusterError> {
+        if line.len() < CODE_LENGTH + 2 || !line.is_char_boundary(CODE_LENGTH) {
+            return Err(ClusterError::InvalidReport)
+        }
+
+        let (code, path) = line.split_at(CODE_LENGTH);
+        let mut path = &path[1..];
+        let is_dir = path.ends_with('/');
+        if is_dir {
+            path = &path[..path.len() - 1];
+        }
+
+        let code = code.as_bytes();
+        let change = if code.starts_with(b"*deleting") {
+            Change::Deleted
+        } else if &code[2..] == b"+++++++++" {
+            Change::Created
+        } else {
+            Change::Updated {
+                size: code[3] == b's',
+                time: code[4] == b't',
+                perms: code[5] == b'p',
+                acls: code[9] == b'a',
+                xattrs: code[10] == b'x',
+            }
+        };
+
+        Ok(ChangeItem {
+            path: PathBuf::from(path),
+            is_dir,
+            change,
+        })
+    }
+}
+
+/// Report sent by the receiver at the end of a sync.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Changes made to the destination.
+    pub changes: Vec<ChangeItem>,
+    /// Files (relative to destination) whose contents didn't match the checksum
+    /// from the sender. These have been left alone in the destination.
+    pub mismatched: Vec<PathBuf>,
+    /// Things which the receiver couldn't do, but which didn't fail the sync
+    /// (for example, attributes which couldn't be applied).
+    pub warnings: Vec<String>,
+}
+
+impl SyncReport {
+    /// Itemized changes, the mismatched paths and the warnings (one per line),
+    /// with each section ending in an empty line.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut report = String::new();
+        for item in &self.changes {
+            report.push_str(&item.to_string());
+            report.push('\n');
+        }
+
+        report.push('\n');
+        for path in &self.mismatched {
+            report.push_str(&path.to_string_lossy());
+            report.push('\n');
+        }
+
+        report.push('\n');
+        for warning in &self.warnings {
+            // Warnings could have paths, and so they shouldn't break the lines.
+            report.push_str(&warning.replace('\n', " "));
+            report.push('\n');
+        }
+
+        report.push('\n');
+        report.into_bytes()
+    }
+
+    /// Read the report from the given connection.
+    pub fn read_from<R, W>(conn: Connection<R, W>) -> ClusterFuture<(Connection<R, W>, Self)>
+        where R: AsyncRead + 'static, W: AsyncWrite + 'static
+    {
+        let async_read = conn.read_lines()
+            .and_then(|(c, lines)| c.read_lines().map(move |(c, paths)| (c, lines, paths)))
+            .and_then(|(c, lines, paths)| {
+                c.read_lines().map(move |(c, warnings)| (c, lines, paths, warnings))
+            }).and_then(|(c, lines, paths, warnings)| {
+                let changes = lines.iter().map(|l| l.parse()).collect::<ClusterResult<_>>()?;
+                Ok((c, SyncReport {
+                    changes,
+                    mismatched: paths.into_iter().map(PathBuf::from).collect(),
+                    warnings,
+                }))
+            });
+
+        Box::new(async_read) as ClusterFuture<_>
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, ChangeItem};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_itemized_lines() {
+        let items = [
+            (ChangeItem { path: PathBuf::from("foo/bar"), is_dir: false, change: Change::Created },
+             ">f+++++++++ foo/bar"),
+            (ChangeItem { path: PathBuf::from("foo"), is_dir: true, change: Change::Created },
+             "cd+++++++++ foo/"),
+            (ChangeItem {
+                path: PathBuf::from("foo/baz"),
+                is_dir: false,
+                change: Change::Updated { size: true, time: true, perms: false,
+                                          acls: false, xattrs: false },
+             }, ">f.st...... foo/baz"),
+            (ChangeItem {
+                path: PathBuf::from("foobar"),
+                is_dir: false,
+                change: Change::Updated { size: false, time: false, perms: true,
+                                          acls: false, xattrs: false },
+             }, ".f...p..... foobar"),
+            (ChangeItem {
+                path: PathBuf::from("bin"),
+                is_dir: true,
+                change: Change::Updated { size: false, time: false, perms: false,
+                                          acls: true, xattrs: true },
+             }, ".d.......ax bin/"),
+            (ChangeItem { path: PathBuf::from("old"), is_dir: true, change: Change::Deleted },
+             "*deleting   old/"),
+        ];
+
+        for &(ref item, line) in items.iter() {
+            assert_eq!(item.to_string(), line);
+            assert_eq!(&line.parse::<ChangeItem>().unwrap(), item);
+        }
+
+        assert!("*deleting".parse::<ChangeItem>().is_err());
+    }
+}
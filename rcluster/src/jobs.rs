@@ -0,0 +1,485 @@
+use buffered::StreamingBuffer;
+use cgroup::ResourceUsage;
+use compression::Codec;
+use connection::{Connection, ConnectionFlag};
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use exec::{self, ExecOptions};
+use fs_ops;
+use futures::{Future, future};
+use futures_cpupool::CpuPool;
+use libc;
+use pool;
+use tail::StopOnDrop;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Finished jobs (along with their output) are forgotten once there are more than these.
+const MAX_FINISHED_JOBS: usize = 64;
+/// Interval for checking a running job for more output.
+const POLL_INTERVAL_MS: u64 = 250;
+/// Status byte for a job whose output follows.
+const STATUS_OK: u8 = 0;
+/// Status byte for a job which couldn't be attached (followed by the error message).
+const STATUS_FAILED: u8 = 1;
+
+lazy_static! {
+    /// Jobs started in this slave (which outlive the connections that started them).
+    static ref JOBS: Mutex<JobTable> = Mutex::new(JobTable::default());
+    /// Notified whenever a job finishes.
+    static ref JOB_FINISHED: Condvar = Condvar::new();
+}
+
+#[derive(Default)]
+struct JobTable {
+    last_id: u64,
+    jobs: BTreeMap<u64, JobEntry>,
+}
+
+struct JobEntry {
+    job: Job,
+    /// File which has the job's output (both stdout and stderr).
+    log: PathBuf,
+    cancelled: bool,
+}
+
+/// Status of a job.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    Running,
+    /// Exited with the code (or 128 + signal, if it's been killed).
+    Exited(i32),
+    /// Exited with the code after it's been cancelled.
+    Cancelled(i32),
+}
+
+/// Background job in the slave. Its ID is unique only within the slave (until it restarts).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub id: u64,
+    pub pid: u32,
+    /// Start time (seconds since UNIX epoch).
+    pub started: i64,
+    pub status: JobStatus,
+    /// Resources used by the job (once it's finished, if it's been run in a cgroup).
+    pub usage: Option<ResourceUsage>,
+    pub args: Vec<String>,
+}
+
+impl Job {
+    fn to_line(&self) -> String {
+        let (status, code) = match self.status {
+            JobStatus::Running => ("running", 0),
+            JobStatus::Exited(c) => ("exited", c),
+            JobStatus::Cancelled(c) => ("cancelled", c),
+        };
+
+        // Arguments could have spaces, but they can't have nulls.
+        let mut line = format!("{} {} {} {} {} {}", self.id, self.pid, self.started, status, code,
+                               ResourceUsage::to_field(self.usage.as_ref()));
+        for arg in &self.args {
+            line.push('\0');
+            line.push_str(arg);
+        }
+
+        line.replace('\n', " ")
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut args = line.split('\0');
+        let mut parts = args.next()?.split(' ');
+        let (id, pid, started) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?,
+                                  parts.next()?.parse().ok()?);
+        let (status, code) = (parts.next()?, parts.next()?.parse().ok()?);
+        let usage = ResourceUsage::from_field(parts.next()?)?;
+        let status = match status {
+            "running" => JobStatus::Running,
+            "exited" => JobStatus::Exited(code),
+            "cancelled" => JobStatus::Cancelled(code),
+            _ => return None,
+        };
+
+        Some(Job { id, pid, started, status, usage, args: args.map(String::from).collect() })
+    }
+}
+
+/// Directory for the output of the jobs in this slave.
+fn log_dir() -> PathBuf {
+    env::temp_dir().join(format!("rcluster-jobs-{}", process::id()))
+}
+
+/// Start the command (with the given options) as a job in the background. Its output goes
+/// to a file, and its input is empty. Its cgroup (if any) is created in the given root.
+pub fn start(args: Vec<String>, options: &ExecOptions,
+             cgroup_root: Option<&Path>) -> ClusterResult<Job> {
+    let dir = log_dir();
+    fs::create_dir_all(&dir)?;
+    let mut table = JOBS.lock().unwrap();
+    forget_finished(&mut table);
+
+    let id = table.last_id + 1;
+    let log = dir.join(format!("{}.log", id));
+    let file = File::create(&log)?;
+    let child = exec::spawn_command(&args, options, cgroup_root, Stdio::null(),
+                                    Stdio::from(file.try_clone()?), Stdio::from(file))?;
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64)
+                                   .unwrap_or(0);
+    let job = Job {
+        id, started, args,
+        pid: child.child.id(),
+        status: JobStatus::Running,
+        usage: None,
+    };
+
+    info!("Started job {} ({:?})", id, job.args);
+    table.last_id = id;
+    table.jobs.insert(id, JobEntry { job: job.clone(), log, cancelled: false });
+
+    thread::spawn(move || {
+        let (code, usage) = match child.wait() {
+            Ok(report) => (report.code, report.usage),
+            Err(_) => (-1, None),
+        };
+
+        let mut table = JOBS.lock().unwrap();
+        if let Some(entry) = table.jobs.get_mut(&id) {
+            info!("Job {} exited with {}", id, code);
+            entry.job.status = match entry.cancelled {
+                true => JobStatus::Cancelled(code),
+                false => JobStatus::Exited(code),
+            };
+            entry.job.usage = usage;
+        }
+
+        JOB_FINISHED.notify_all();
+    });
+
+    Ok(job)
+}
+
+/// Remove the oldest finished jobs (along with their output) beyond the limit.
+fn forget_finished(table: &mut JobTable) {
+    let finished = table.jobs.values().filter(|e| e.job.status != JobStatus::Running)
+                                      .map(|e| e.job.id).collect::<Vec<_>>();
+    for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+        let entry = table.jobs.remove(id).unwrap();
+        let _ = fs::remove_file(&entry.log);
+    }
+}
+
+/// Get all the jobs in this slave (sorted by their IDs).
+pub fn list() -> Vec<Job> {
+    JOBS.lock().unwrap().jobs.values().map(|e| e.job.clone()).collect()
+}
+
+/// Get the job with the given ID.
+pub fn get(id: u64) -> ClusterResult<Job> {
+    JOBS.lock().unwrap().jobs.get(&id).map(|e| e.job.clone()).ok_or(ClusterError::UnknownJob)
+}
+
+/// Wait for the job to finish.
+pub fn wait(id: u64) -> ClusterResult<Job> {
+    let mut table = JOBS.lock().unwrap();
+    loop {
+        match table.jobs.get(&id) {
+            Some(e) if e.job.status != JobStatus::Running => return Ok(e.job.clone()),
+            Some(_) => table = JOB_FINISHED.wait(table).unwrap(),
+            None => return Err(ClusterError::UnknownJob),
+        }
+    }
+}
+
+/// Cancel the job (if it's still running) by terminating its process group.
+pub fn cancel(id: u64) -> ClusterResult<Job> {
+    let mut table = JOBS.lock().unwrap();
+    let entry = table.jobs.get_mut(&id).ok_or(ClusterError::UnknownJob)?;
+    if entry.job.status == JobStatus::Running {
+        if unsafe { libc::kill(-(entry.job.pid as i32), libc::SIGTERM) } != 0 {
+            return Err(io::Error::last_os_error().into())
+        }
+
+        info!("Cancelled job {}", id);
+        entry.cancelled = true;
+    }
+
+    Ok(entry.job.clone())
+}
+
+/// Reader for the output of a job, which waits for more output until the job has finished.
+struct JobOutput {
+    id: u64,
+    file: File,
+    /// Set once nobody's interested in the output anymore.
+    stopped: Arc<AtomicBool>,
+}
+
+impl JobOutput {
+    fn open(id: u64) -> ClusterResult<Self> {
+        let log = JOBS.lock().unwrap().jobs.get(&id).map(|e| e.log.clone())
+                                              .ok_or(ClusterError::UnknownJob)?;
+        Ok(JobOutput { id, file: File::open(log)?, stopped: Arc::new(AtomicBool::new(false)) })
+    }
+}
+
+impl Read for JobOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.file.read(buf)?;
+            if len > 0 {
+                return Ok(len)
+            }
+
+            // Output could've been written after the previous read (before it finished).
+            let running = get(self.id).map(|j| j.status == JobStatus::Running).unwrap_or(false);
+            if !running {
+                return self.file.read(buf)
+            }
+
+            if self.stopped.load(Ordering::SeqCst) {
+                return Err(io::Error::new(ErrorKind::Other, "stopped attaching"))
+            }
+
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+}
+
+/// Wrapper for managing the background jobs in the slave. The master sends the operation's
+/// flag, followed by its argument - the command and its options (like `Execution`) for
+/// starting a job, nothing for listing the jobs, or the job's ID (a line). The slave
+/// replies like it does for the filesystem operations (see `FsOps`), with a line for each job.
+///
+/// For attaching to a job, the slave replies with a status byte, followed by the error message
+/// (a line) or the job's output (everything so far, and the rest as it's produced) ending with
+/// the magic once the job finishes, and finally the job (a line).
+pub struct Jobs<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
+
+impl<R, W> Jobs<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    /// (Master) Start the command (with the given options) as a job in the other end.
+    pub fn start(self, args: &[String],
+                 options: &ExecOptions) -> ClusterFuture<(Connection<R, W>, ClusterResult<Job>)> {
+        let bytes = future_try!(exec::command_bytes(args, options));
+        Box::new(self.request(bytes).map(|(c, r)| (c, r.and_then(single_job)))) as ClusterFuture<_>
+    }
+
+    /// (Master) List the jobs in the other end.
+    pub fn list(self) -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<Job>>)> {
+        let async_list = self.request(vec![]).map(|(c, result)| {
+            let jobs = result.and_then(|lines| {
+                lines.iter().map(|l| Job::from_line(l)).collect::<Option<Vec<_>>>()
+                     .ok_or(ClusterError::InvalidReport)
+            });
+
+            (c, jobs)
+        });
+
+        Box::new(async_list) as ClusterFuture<_>
+    }
+
+    /// (Master) Wait for the job (whose flag has already been sent) to finish, or cancel it.
+    pub fn job_request(self, id: u64) -> ClusterFuture<(Connection<R, W>, ClusterResult<Job>)> {
+        let bytes = format!("{}\n", id).into_bytes();
+        Box::new(self.request(bytes).map(|(c, r)| (c, r.and_then(single_job)))) as ClusterFuture<_>
+    }
+
+    fn request(self, bytes: Vec<u8>) -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<String>>)> {
+        let async_request = self.0.write_bytes(bytes)
+            .and_then(|c| c.flush())
+            .and_then(fs_ops::read_result);
+
+        Box::new(async_request) as ClusterFuture<_>
+    }
+
+    /// (Master) Attach to the job, and stream its output to the given writer. This resolves
+    /// once the job has finished.
+    pub fn attach<O>(self, id: u64, output: O) -> ClusterFuture<(Connection<R, W>, ClusterResult<Job>)>
+        where O: io::Write + Send + 'static
+    {
+        let async_attach = self.0.write_line(id.to_string())
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_bytes([0; 1]))
+            .and_then(move |(c, status)| -> ClusterFuture<_> {
+                if status[0] != STATUS_OK {
+                    return Box::new(c.read_line().map(|(c, msg)| (c, Err(ClusterError::Remote(msg)))))
+                }
+
+                let (r, w, m, s) = c.into();
+                let async_stream = StreamingBuffer::stream_to_blocking(r, &m, output, s.buffer_size)
+                    .with_codec(Codec::Decode(s.compression))
+                    .stream()
+                    .and_then(move |(r, _)| Connection::from((r, w, m, s)).read_line())
+                    .map(|(c, line)| (c, Job::from_line(&line).ok_or(ClusterError::InvalidReport)));
+                Box::new(async_stream)
+            });
+
+        Box::new(async_attach) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request for the operation (with the given flag), perform it, and send
+    /// the result (or the error).
+    pub fn serve(self, flag: ConnectionFlag) -> ClusterFuture<Connection<R, W>> {
+        let async_serve = match flag {
+            ConnectionFlag::MasterStartsJob => {
+                let async_start = exec::read_command(self.0).and_then(|(c, args, options)| {
+                    let cgroup_root = c.settings().cgroup_root.clone();
+                    pool::run(move || -> ClusterResult<_> {
+                        let job = start(args, &options?, cgroup_root.as_ref().map(|p| p.as_path()))?;
+                        Ok(vec![job.to_line()])
+                    }).then(|r| Ok((c, r)))
+                });
+
+                Box::new(async_start) as ClusterFuture<_>
+            },
+            ConnectionFlag::MasterListsJobs => {
+                let lines = list().iter().map(|j| j.to_line()).collect();
+                Box::new(future::ok((self.0, Ok(lines)))) as ClusterFuture<_>
+            },
+            _ => {
+                let async_job = self.0.read_line().and_then(move |(c, id)| {
+                    let id = match id.parse() {
+                        Ok(id) => id,
+                        Err(_) => return Box::new(future::ok((c, Err(ClusterError::InvalidReport))))
+                                      as ClusterFuture<_>,
+                    };
+
+                    let result = match flag {
+                        // Jobs could take a while, and so they're awaited in a thread of their own.
+                        ConnectionFlag::MasterWaitsJob => {
+                            let async_wait = CpuPool::new(1).spawn_fn(move || wait(id));
+                            Box::new(async_wait) as ClusterFuture<_>
+                        },
+                        ConnectionFlag::MasterCancelsJob => Box::new(future::result(cancel(id))),
+                        _ => Box::new(future::err(ClusterError::UnknownFlag)),
+                    };
+
+                    Box::new(result.then(move |r| Ok((c, r.map(|j| vec![j.to_line()])))))
+                });
+
+                Box::new(async_job)
+            },
+        };
+
+        let async_reply = async_serve.and_then(|(c, result)| {
+            if let Err(ref e) = result {
+                info!("Job operation failed: {}", e);
+            }
+
+            fs_ops::write_result(c, result)
+        });
+
+        Box::new(async_reply) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the job's ID, and stream its output until it finishes.
+    pub fn serve_attach(self) -> ClusterFuture<Connection<R, W>> {
+        let async_serve = self.0.read_line().and_then(|(c, id)| -> ClusterFuture<_> {
+            let output = id.parse().map_err(|_| ClusterError::InvalidReport)
+                                   .and_then(JobOutput::open);
+            let output = match output {
+                Ok(o) => o,
+                Err(e) => {
+                    info!("Cannot attach to job {}: {}", id, e);
+                    let mut bytes = vec![STATUS_FAILED];
+                    bytes.extend_from_slice(fs_ops::error_message(&e).as_bytes());
+                    bytes.push(b'\n');
+                    return c.write_bytes(bytes)
+                },
+            };
+
+            info!("Attaching to job {}", id);
+            let (id, guard) = (output.id, StopOnDrop(output.stopped.clone()));
+            let async_stream = c.write_bytes([STATUS_OK]).and_then(move |c| {
+                let (r, w, m, s) = c.into();
+                StreamingBuffer::blocking_to_stream(output, w, s.buffer_size)
+                    .with_codec(Codec::Encode(s.compression))
+                    .with_limiters(s.limiters.clone())
+                    .with_idle_flush(true)
+                    .stream()
+                    .and_then(move |(_, w)| Connection::from((r, w, m, s)).write_magic())
+                    .and_then(move |c| {
+                        let job = future_try!(get(id));
+                        c.write_line(job.to_line())
+                    })
+            });
+
+            // The reader is stopped once the connection is dropped.
+            Box::new(async_stream.then(move |result| {
+                drop(guard);
+                future::result(result)
+            }))
+        });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+}
+
+fn single_job(lines: Vec<String>) -> ClusterResult<Job> {
+    lines.first().and_then(|l| Job::from_line(l)).ok_or(ClusterError::InvalidReport)
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use connection::{loopback, response};
+    use errors::ClusterError;
+    use futures::Future;
+    use exec::ExecOptions;
+    use super::{Job, JobOutput, JobStatus, Jobs, cancel, get, list, wait};
+
+    use std::io::Read;
+
+    fn start(args: &[&str]) -> Job {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        super::start(args, &ExecOptions::default(), None).unwrap()
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        let job = start(&["sh", "-c", "echo foo; sleep 0.3; echo 'bar baz'; exit 3"]);
+        assert_eq!(job.status, JobStatus::Running);
+        assert_eq!(Job::from_line(&job.to_line()), Some(job.clone()));
+        assert!(list().iter().any(|j| j.id == job.id));
+
+        // Attach while it's running, and get the rest of the output as it's produced.
+        let magic = [42; 16];
+        let request = format!("{}\n", job.id).into_bytes();
+        let conn = Jobs(loopback(request, magic)).serve_attach().wait().unwrap();
+        let reply = response(conn);
+        assert!(String::from_utf8_lossy(&reply).contains("foo\nbar baz\n"));
+
+        let (_, result) = Jobs(loopback(reply, magic)).attach(job.id, vec![]).wait().unwrap();
+        let finished = result.unwrap();
+        assert_eq!(finished.status, JobStatus::Exited(3));
+        assert_eq!(wait(job.id).unwrap(), finished);
+
+        match get(0) {
+            Err(ClusterError::UnknownJob) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_job_output_and_cancel() {
+        let job = start(&["sh", "-c", "echo started; exec sleep 30"]);
+        cancel(job.id).unwrap();
+        assert_eq!(wait(job.id).unwrap().status, JobStatus::Cancelled(128 + 15));
+
+        let job = start(&["sh", "-c", "echo out; echo err >&2"]);
+        wait(job.id).unwrap();
+        let mut output = String::new();
+        JobOutput::open(job.id).unwrap().read_to_string(&mut output).unwrap();
+        assert_eq!(output, "out\nerr\n");
+    }
+}
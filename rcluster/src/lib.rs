@@ -3,27 +3,70 @@ extern crate chrono;
 #[macro_use] extern crate derive_error;
 #[macro_use] extern crate enum_primitive;
 extern crate env_logger;
-extern crate futures;
+extern crate filetime;
+extern crate flate2;
+#[macro_use] extern crate futures;
+extern crate futures_cpupool;
+extern crate glob;
+extern crate inotify;
 #[macro_use] extern crate lazy_static;
+extern crate libc;
 #[macro_use] extern crate log;
 extern crate num;
+#[cfg(test)] #[macro_use] extern crate quickcheck;
 extern crate rand;
 extern crate rustls;
+extern crate sha2;
+extern crate tar;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_rustls;
+extern crate tokio_timer;
 extern crate walkdir;
 extern crate webpki;
 
 include!(concat!(env!("OUT_DIR"), "/config.rs"));
 
 #[macro_use] pub mod errors;
+mod bisync;
 mod buffered;
+mod cgroup;
+mod checksum;
+mod compression;
 mod connection;
+mod diff;
+mod exec;
+mod fs_ops;
+mod itemize;
+mod jobs;
 mod master;
+mod matcher;
 mod path_sync;
+mod pool;
+mod procs;
+mod progress;
+mod ratelimit;
 mod slave;
+mod sparse;
+mod tail;
 pub mod utils;
+mod watch;
+mod xattrs;
 
+pub use bisync::{Conflict, ConflictPolicy, Edit, TwoWayOptions, TwoWayReport};
+pub use buffered::BufferSize;
+#[cfg(feature = "bench")] #[doc(hidden)] pub use buffered::scan_for_stopper;
+pub use cgroup::{CgroupLimits, ResourceUsage};
+pub use compression::Compression;
+pub use diff::{DiffGroup, FileDiff};
+pub use exec::{ExecOptions, ExecReport};
+pub use fs_ops::{DiskUsage, FileInfo, FileKind};
+pub use itemize::{Change, ChangeItem};
+pub use jobs::{Job, JobStatus};
 pub use master::Master;
+#[cfg(feature = "bench")] #[doc(hidden)] pub use path_sync::send_to_writer;
+pub use path_sync::SyncOptions;
+pub use procs::{ProcessFilter, ProcessInfo, ProcessTarget, SignalResult, signal_number};
+pub use progress::Progress;
 pub use slave::Slave;
+pub use watch::{Changes, Watcher};
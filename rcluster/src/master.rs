@@ -1,18 +1,41 @@
+use bisync::{self, Manifest, TwoWayOptions, TwoWayReport, TwoWaySync};
+use buffered::BufferSize;
 use config::CLIENT_CONFIG;
-use connection::{Connection, ConnectionFlag, StreamingConnection};
-use errors::{ClusterError, ClusterResult};
-use futures::Future;
-use path_sync::PathSync;
+use compression::Compression;
+use connection::{Connection, ConnectionFlag, ConnectionSettings, StreamingConnection};
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use diff::{self, DiffGroup, FileDiff};
+use exec::{ExecOptions, ExecReport, Execution};
+use fs_ops::{self, DiskUsage, FileInfo, FsOps};
+use futures::{future, Future};
+use itemize::{ChangeItem, SyncReport};
+use jobs::{Job, Jobs};
+use path_sync::{PathSync, SyncOptions};
+use procs::{ProcessFilter, ProcessInfo, ProcessTarget, Processes, SignalResult};
+use progress::{Progress, ProgressTracker};
+use rand::{self, RngCore};
+use ratelimit::{Limiters, RateLimiter};
 use rustls::ClientSession;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::Core;
+use tail::Tail;
 use tokio_rustls::{ClientConfigExt, TlsStream};
 use utils::DOMAIN;
+use watch::Changes;
 
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 /// Outgoing stream from master (i.e., client)
 type OutgoingStream = TlsStream<TcpStream, ClientSession>;
+/// Handler which gets the progress of transfers (along with the connection ID).
+type ProgressHandler = Arc<Mutex<Box<FnMut(usize, &Progress) + Send>>>;
+/// Handler which gets the warnings from syncs (along with the connection ID).
+type WarningHandler = Box<FnMut(usize, &str)>;
 
 /// Master (i.e., client) which connects to slave machines. As long as this struct exists,
 /// the sockets added will be kept alive, and so we can re-use it for further messages.
@@ -20,18 +43,74 @@ pub struct Master {
     event_loop: Core,
     slaves: Vec<Option<StreamingConnection<OutgoingStream>>>,
     addrs: Vec<SocketAddr>,
+    /// Rate limiters for each slave.
+    limiters: Vec<RateLimiter>,
+    /// Rate limiter shared by all the slaves.
+    global_limiter: RateLimiter,
+    settings: ConnectionSettings,
+    progress_handler: Option<ProgressHandler>,
+    warning_handler: Option<WarningHandler>,
 }
 
 impl Master {
     /// Create a new instance of master.
     pub fn new() -> Self {
+        let global_limiter = RateLimiter::default();
         Master {
             event_loop: Core::new().expect("event loop creation"),
             slaves: vec![],
             addrs: vec![],
+            limiters: vec![],
+            settings: ConnectionSettings {
+                limiters: Limiters::default().with(global_limiter.clone()),
+                ..ConnectionSettings::default()
+            },
+            global_limiter,
+            progress_handler: None,
+            warning_handler: None,
         }
     }
 
+    /// Set the compression to be proposed for further connections. Slaves which don't
+    /// support the compression will fall back to no compression.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.settings.compression = compression;
+    }
+
+    /// Set the size of the buffers for further connections (and the transfers over them).
+    pub fn set_buffer_size(&mut self, size: BufferSize) {
+        self.settings.buffer_size = size;
+    }
+
+    /// Limit the rate (bytes per second) of sending bytes to all slaves combined.
+    pub fn set_bwlimit(&mut self, rate: Option<u64>) {
+        self.global_limiter.set_rate(rate);
+    }
+
+    /// Limit the rate (bytes per second) of sending bytes to the slave with the given ID.
+    pub fn set_slave_bwlimit(&mut self, conn_id: usize, rate: Option<u64>) -> ClusterResult<()> {
+        let limiter = self.limiters.get(conn_id).ok_or(ClusterError::InvalidConnectionId)?;
+        limiter.set_rate(rate);
+        Ok(())
+    }
+
+    /// Set the handler for the progress of transfers. It gets the ID of the connection
+    /// along with the progress.
+    pub fn set_progress_handler<F>(&mut self, handler: F)
+        where F: FnMut(usize, &Progress) + Send + 'static
+    {
+        self.progress_handler = Some(Arc::new(Mutex::new(Box::new(handler))));
+    }
+
+    /// Set the handler for the warnings from syncs (for example, attributes which couldn't be
+    /// applied). It gets the ID of the connection along with the warning. If there's no handler,
+    /// then the warnings are only logged.
+    pub fn set_warning_handler<F>(&mut self, handler: F)
+        where F: FnMut(usize, &str) + 'static
+    {
+        self.warning_handler = Some(Box::new(handler));
+    }
+
     /// List of addresses to which we've successfully connected.
     pub fn addrs(&self) -> &[SocketAddr] {
         &self.addrs
@@ -41,22 +120,29 @@ impl Master {
     /// Once the connection has been established, this returns an ID for the connection,
     /// which should be used for future actions.
     pub fn add_slave(&mut self, addr: SocketAddr) -> ClusterResult<usize> {
-        let handle = self.event_loop.handle();
-        let stream_async = TcpStream::connect(&addr, &handle)
-            .and_then(|stream| CLIENT_CONFIG.connect_async(DOMAIN.clone(), stream))
-            .map_err(ClusterError::from)
-            .and_then(|stream| Connection::create_for_stream(stream, false));
-
-        let stream = self.event_loop.run(stream_async)?;
+        let limiter = RateLimiter::default();
+        let stream = self.connect(addr, limiter.clone())?;
         self.slaves.push(Some(stream));
         self.addrs.push(addr);
+        self.limiters.push(limiter);
         Ok(self.slaves.len() - 1)
     }
 
+    /// Connect again to the address belonging to a given ID (for replacing a dropped
+    /// connection). The ID remains the same.
+    pub fn reconnect(&mut self, conn_id: usize) -> ClusterResult<()> {
+        let addr = *self.addrs.get(conn_id).ok_or(ClusterError::InvalidConnectionId)?;
+        let limiter = self.limiters[conn_id].clone();
+        let stream = self.connect(addr, limiter)?;
+        self.slaves[conn_id] = Some(stream);
+        Ok(())
+    }
+
     /// Ping the connection belonging to a given ID (if it exists).
     pub fn ping(&mut self, conn_id: usize) -> ClusterResult<()> {
         let conn = self.get_conn(conn_id)?;
         let async_conn = conn.write_flag(ConnectionFlag::MasterPing)
+            .and_then(|c| c.flush())
             .and_then(|c| c.read_magic())
             .and_then(|c| c.read_flag::<ConnectionFlag>());
 
@@ -69,36 +155,558 @@ impl Master {
         Ok(())
     }
 
-    /// Stream file from `source_path` in this machine to `dest_path` in slave.
-    pub fn send_file<P>(&mut self, conn_id: usize,
-                        source_path: P, dest_path: P) -> ClusterResult<()>
-        where P: AsRef<str>
+    /// Execute the command (program, followed by its arguments) in the slave, with the given
+    /// options and input (stdin). The output (both stdout and stderr) is written to the given
+    /// writer. This returns the exit code of the command (128 + signal, if it's been killed,
+    /// and 127 if the command couldn't be spawned), along with its resource usage (if it's
+    /// been run in a cgroup).
+    pub fn execute<I, O>(&mut self, conn_id: usize, args: &[String], options: &ExecOptions,
+                         input: I, output: O) -> ClusterResult<ExecReport>
+        where I: Read + Send + 'static, O: Write + Send + 'static
     {
         let conn = self.get_conn(conn_id)?;
-        let source = String::from(source_path.as_ref());
-        let dest = String::from(dest_path.as_ref());
+        let async_exec = Execution(conn).run_remote(args, options, input, output);
+        let (conn, report) = self.event_loop.run(async_exec)?;
+        self.slaves[conn_id] = Some(conn);
+        Ok(report)
+    }
+
+    /// Stream file from `source_path` in this machine to `dest_path` in slave. This returns
+    /// the changes made to the slave (or the changes that would be made, in case of a dry run).
+    pub fn send_file<P>(&mut self, conn_id: usize, source_path: P, dest_path: P,
+                        options: &SyncOptions) -> ClusterResult<Vec<ChangeItem>>
+        where P: AsRef<Path>
+    {
+        self.send_file_all(&[conn_id], source_path, dest_path, options).remove(0)
+    }
+
+    /// Same as `send_file`, but the file is sent to all the given slaves at once (so their
+    /// progress is reported together). This returns the result for each of the slaves
+    /// (in the same order), and a failure in one of them doesn't affect the others.
+    pub fn send_file_all<P>(&mut self, conn_ids: &[usize], source_path: P, dest_path: P,
+                            options: &SyncOptions) -> Vec<ClusterResult<Vec<ChangeItem>>>
+        where P: AsRef<Path>
+    {
+        let source = source_path.as_ref().to_path_buf();
+        let dest = dest_path.as_ref().to_path_buf();
+        let options = options.clone();
+        self.send_paths(conn_ids, move |c, progress| {
+            PathSync(c).source_to_stream(source, dest, &options, progress)
+        })
+    }
 
-        let async_conn = conn.write_flag(ConnectionFlag::MasterSendsPath)
-            .and_then(|c| PathSync(c).source_to_stream(source, dest))
+    /// Stream an existing tar archive (optionally gzipped) from `archive_path` in this machine,
+    /// and extract it into `dest_path` in slave. This returns the changes made to the slave
+    /// (or the changes that would be made, in case of a dry run).
+    pub fn send_archive<P>(&mut self, conn_id: usize, archive_path: P, dest_path: P,
+                           options: &SyncOptions) -> ClusterResult<Vec<ChangeItem>>
+        where P: AsRef<Path>
+    {
+        self.send_archive_all(&[conn_id], archive_path, dest_path, options).remove(0)
+    }
+
+    /// Same as `send_archive`, but the archive is streamed to all the given slaves at once.
+    /// This returns the result for each of the slaves (in the same order).
+    pub fn send_archive_all<P>(&mut self, conn_ids: &[usize], archive_path: P, dest_path: P,
+                               options: &SyncOptions) -> Vec<ClusterResult<Vec<ChangeItem>>>
+        where P: AsRef<Path>
+    {
+        let archive = archive_path.as_ref().to_path_buf();
+        let dest = dest_path.as_ref().to_path_buf();
+        let options = options.clone();
+        self.send_paths(conn_ids, move |c, progress| {
+            PathSync(c).archive_to_stream(archive, dest, &options, progress)
+        })
+    }
+
+    /// Fetch an existing tar archive (optionally gzipped) from `archive_path` in slave, and
+    /// extract it into `dest_path` in this machine. This returns the changes made to this
+    /// machine (or the changes that would be made, in case of a dry run).
+    pub fn receive_archive<P>(&mut self, conn_id: usize, archive_path: P, dest_path: P,
+                              options: &SyncOptions) -> ClusterResult<Vec<ChangeItem>>
+        where P: AsRef<Path>
+    {
+        let conn = self.get_conn(conn_id)?;
+        let archive = archive_path.as_ref().to_path_buf();
+        let dest = dest_path.as_ref().to_path_buf();
+        let options = options.clone();
+        let async_conn = conn.write_flag(ConnectionFlag::MasterWantsPath)
+            .and_then(|c| c.flush())
             .and_then(|c| c.read_magic())
-            .and_then(|c| c.read_flag::<ConnectionFlag>());
+            .and_then(move |c| PathSync(c).pull_archive(archive, dest, &options));
 
-        let (conn, flag) = self.event_loop.run(async_conn)?;
-        if flag != ConnectionFlag::SlaveOk {
-            info!("Error sending file!");
+        let (conn, report) = self.event_loop.run(async_conn)?;
+        self.slaves[conn_id] = Some(conn);
+        self.check_report(conn_id, report)
+    }
+
+    /// Fetch the file or directory at `source_path` in slave into `dest_path` in this machine.
+    /// This returns the changes made to this machine (or the changes that would be made, in
+    /// case of a dry run).
+    pub fn receive_path<P>(&mut self, conn_id: usize, source_path: P, dest_path: P,
+                           options: &SyncOptions) -> ClusterResult<Vec<ChangeItem>>
+        where P: AsRef<Path>
+    {
+        let conn = self.get_conn(conn_id)?;
+        let source = source_path.as_ref().to_path_buf();
+        let dest = dest_path.as_ref().to_path_buf();
+        let options = options.clone();
+        let async_conn = conn.write_flag(ConnectionFlag::MasterPullsPath)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_magic())
+            .and_then(move |c| PathSync(c).pull_path(source, dest, &options));
+
+        let (conn, report) = self.event_loop.run(async_conn)?;
+        self.slaves[conn_id] = Some(conn);
+        self.check_report(conn_id, report)
+    }
+
+    /// Sync the directory at `local_path` in this machine with the directory at `remote_path`
+    /// in slave, in both the directions. Changes since the last sync (of this pair) are
+    /// propagated from either end to the other, and the files which have been changed in both
+    /// the ends are resolved with the policy (or reported). This returns what's been done (or
+    /// what would be done, in case of a dry run).
+    pub fn two_way_sync<P>(&mut self, conn_id: usize, local_path: P, remote_path: P,
+                           options: &TwoWayOptions) -> ClusterResult<TwoWayReport>
+        where P: AsRef<str>
+    {
+        let (local, remote) = (Path::new(local_path.as_ref()), Path::new(remote_path.as_ref()));
+        let addr = *self.addrs.get(conn_id).ok_or(ClusterError::InvalidConnectionId)?;
+        let state_path = bisync::state_path(options.state_dir.as_ref().map(|p| p.as_path()),
+                                            &addr, local, remote);
+        let state = bisync::load_state(&state_path)?;
+        let master_files = bisync::manifest(local, &options.sync)?;
+
+        let slave_files = self.remote_manifest(conn_id, remote, &options.sync)?;
+        let report = bisync::plan(&master_files, &slave_files, &state, options.policy);
+        if options.sync.dry_run {
+            return Ok(report)
+        }
+
+        // Files are transferred one at a time (into their parents), and so there's nothing
+        // to mirror.
+        let transfer = SyncOptions { delete: false, archive: false, ..options.sync.clone() };
+        let parent = |root: &Path, path: &Path| {
+            root.join(path).parent().unwrap_or(root).to_path_buf()
+        };
+
+        for path in &report.pushed {
+            self.send_file(conn_id, local.join(path), parent(remote, path), &transfer)?;
+        }
+
+        for path in &report.pulled {
+            self.receive_path(conn_id, remote.join(path), parent(local, path), &transfer)?;
+        }
+
+        let removed = bisync::remove_files(local, &report.removed_in_master, false)?;
+        self.check_report(conn_id, removed)?;
+        if !report.removed_in_slave.is_empty() {
+            let conn = self.get_conn(conn_id)?;
+            let (remote_root, paths) = (remote.to_path_buf(), report.removed_in_slave.clone());
+            let async_remove = conn.write_flag(ConnectionFlag::MasterRemovesPaths)
+                .and_then(|c| c.flush())
+                .and_then(|c| c.read_magic())
+                .and_then(move |c| TwoWaySync(c).remove_remote(remote_root, &paths, false));
+            let (conn, removed) = self.event_loop.run(async_remove)?;
+            self.slaves[conn_id] = Some(conn);
+            self.check_report(conn_id, removed)?;
+        }
+
+        let next = bisync::next_state(&master_files, &slave_files, &state, &report);
+        bisync::save_state(&state_path, &next)?;
+        Ok(report)
+    }
+
+    /// List the path in slave - the entries of a directory (everything in it, if it's
+    /// recursive) relative to it, or the path itself (if it's not a directory).
+    pub fn list_path<P>(&mut self, conn_id: usize, path: P,
+                        recursive: bool) -> ClusterResult<Vec<FileInfo>>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref().to_path_buf();
+        self.remote_op(conn_id, ConnectionFlag::MasterListsPath, move |c| {
+            FsOps(c).list(path, recursive)
+        })
+    }
+
+    /// Get the information of the path in slave (without following symlinks).
+    pub fn stat_path<P>(&mut self, conn_id: usize, path: P) -> ClusterResult<FileInfo>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref().to_path_buf();
+        self.remote_op(conn_id, ConnectionFlag::MasterStatsPath, move |c| FsOps(c).stat(path))
+    }
+
+    /// Remove the path in slave. Directories are removed only if they're empty, unless
+    /// it's recursive.
+    pub fn remove_path<P>(&mut self, conn_id: usize, path: P, recursive: bool) -> ClusterResult<()>
+        where P: AsRef<Path>
+    {
+        let args = [fs_ops::encode_path(path.as_ref())];
+        self.remote_op(conn_id, ConnectionFlag::MasterDeletesPath, move |c| {
+            Box::new(FsOps(c).request(&args, recursive).map(|(c, r)| (c, r.map(|_| ()))))
+        })
+    }
+
+    /// Move (rename) the path in slave.
+    pub fn move_path<P>(&mut self, conn_id: usize, source_path: P, dest_path: P) -> ClusterResult<()>
+        where P: AsRef<Path>
+    {
+        let args = [fs_ops::encode_path(source_path.as_ref()),
+                    fs_ops::encode_path(dest_path.as_ref())];
+        self.remote_op(conn_id, ConnectionFlag::MasterMovesPath, move |c| {
+            Box::new(FsOps(c).request(&args, false).map(|(c, r)| (c, r.map(|_| ()))))
+        })
+    }
+
+    /// Create a directory in slave (along with its parents, like `mkdir -p`).
+    pub fn create_dir<P>(&mut self, conn_id: usize, path: P, parents: bool) -> ClusterResult<()>
+        where P: AsRef<Path>
+    {
+        let args = [fs_ops::encode_path(path.as_ref())];
+        self.remote_op(conn_id, ConnectionFlag::MasterCreatesDir, move |c| {
+            Box::new(FsOps(c).request(&args, parents).map(|(c, r)| (c, r.map(|_| ()))))
+        })
+    }
+
+    /// Set the permission bits of the path in slave (and everything in it, if it's recursive).
+    pub fn set_mode<P>(&mut self, conn_id: usize, path: P, mode: u32,
+                       recursive: bool) -> ClusterResult<()>
+        where P: AsRef<Path>
+    {
+        let args = [fs_ops::encode_path(path.as_ref()), format!("{:o}", mode)];
+        self.remote_op(conn_id, ConnectionFlag::MasterChangesMode, move |c| {
+            Box::new(FsOps(c).request(&args, recursive).map(|(c, r)| (c, r.map(|_| ()))))
+        })
+    }
+
+    /// Get the disk usage of the path in slave.
+    pub fn disk_usage<P>(&mut self, conn_id: usize, path: P) -> ClusterResult<DiskUsage>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref().to_path_buf();
+        self.remote_op(conn_id, ConnectionFlag::MasterWantsUsage, move |c| {
+            FsOps(c).disk_usage(path)
+        })
+    }
+
+    /// Stream the last few lines of the file at `path` in slave to the given writer. If it's
+    /// followed, then the bytes appended to the file are streamed as well (even if the file
+    /// is rotated or truncated), and this returns only when the connection fails.
+    pub fn tail<P, O>(&mut self, conn_id: usize, path: P, follow: bool, output: O) -> ClusterResult<()>
+        where P: AsRef<str>, O: Write + Send + 'static
+    {
+        let conn = self.get_conn(conn_id)?;
+        let path = String::from(path.as_ref());
+        let async_tail = conn.write_flag(ConnectionFlag::MasterTailsPath)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_magic())
+            .and_then(move |c| Tail(c).request(path, follow, output));
+
+        let (conn, result) = self.event_loop.run(async_tail)?;
+        self.slaves[conn_id] = Some(conn);
+        result
+    }
+
+    /// Send the changes (from watching a source) to `dest_path` in slave - the changed paths
+    /// are sent into their parents, and the removed paths are removed from the slave (only
+    /// if the options say so). This returns the changes made to the slave.
+    pub fn send_changes<P>(&mut self, conn_id: usize, changes: &Changes, dest_path: P,
+                           options: &SyncOptions) -> ClusterResult<Vec<ChangeItem>>
+        where P: AsRef<str>
+    {
+        let dest = Path::new(dest_path.as_ref());
+        let mut items = vec![];
+        for path in &changes.changed {
+            let source = changes.root.join(path);
+            // It's been removed after the change (which will be in the next batch).
+            if fs::symlink_metadata(&source).is_err() {
+                continue
+            }
+
+            let parent = path.parent().unwrap_or(Path::new(""));
+            let changed = self.send_file(conn_id, source, dest.join(parent), options)?;
+            items.extend(changed.into_iter().map(|mut item| {
+                item.path = parent.join(&item.path);
+                item
+            }));
+        }
+
+        if options.delete && !options.dry_run && !changes.removed.is_empty() {
+            let conn = self.get_conn(conn_id)?;
+            let (root, paths) = (dest.to_path_buf(), changes.removed.clone());
+            let async_remove = conn.write_flag(ConnectionFlag::MasterRemovesPaths)
+                .and_then(|c| c.flush())
+                .and_then(|c| c.read_magic())
+                .and_then(move |c| TwoWaySync(c).remove_remote(root, &paths, true));
+            let (conn, removed) = self.event_loop.run(async_remove)?;
+            self.slaves[conn_id] = Some(conn);
+            items.extend(self.check_report(conn_id, removed)?);
         }
 
+        Ok(items)
+    }
+
+    /// List the processes in slave which match the filter.
+    pub fn list_processes(&mut self, conn_id: usize,
+                          filter: &ProcessFilter) -> ClusterResult<Vec<ProcessInfo>> {
+        let filter = filter.clone();
+        self.remote_op(conn_id, ConnectionFlag::MasterListsProcesses, move |c| {
+            Processes(c).list(&filter)
+        })
+    }
+
+    /// Send the signal to the target processes in slave (or to their process groups, if
+    /// `group` is set). Commands spawned by the slave are always signalled along with their
+    /// descendants. This returns the result for each process.
+    pub fn signal_processes(&mut self, conn_id: usize, target: &ProcessTarget, signal: i32,
+                            group: bool) -> ClusterResult<Vec<SignalResult>> {
+        let target = target.clone();
+        self.remote_op(conn_id, ConnectionFlag::MasterSignalsProcesses, move |c| {
+            Processes(c).signal(&target, signal, group)
+        })
+    }
+
+    /// Start the command (with the given options) as a background job in slave. The job
+    /// outlives this connection (its output is kept in the slave), and so it can be attached
+    /// or awaited later.
+    pub fn start_job<S>(&mut self, conn_id: usize, args: &[S],
+                        options: &ExecOptions) -> ClusterResult<Job>
+        where S: AsRef<str>
+    {
+        let args = args.iter().map(|a| String::from(a.as_ref())).collect::<Vec<_>>();
+        let options = options.clone();
+        self.remote_op(conn_id, ConnectionFlag::MasterStartsJob, move |c| {
+            Jobs(c).start(&args, &options)
+        })
+    }
+
+    /// List the jobs in slave (including the finished ones).
+    pub fn list_jobs(&mut self, conn_id: usize) -> ClusterResult<Vec<Job>> {
+        self.remote_op(conn_id, ConnectionFlag::MasterListsJobs, |c| Jobs(c).list())
+    }
+
+    /// Wait for the job in slave to finish.
+    pub fn wait_job(&mut self, conn_id: usize, job_id: u64) -> ClusterResult<Job> {
+        self.remote_op(conn_id, ConnectionFlag::MasterWaitsJob, move |c| Jobs(c).job_request(job_id))
+    }
+
+    /// Cancel the job in slave (by terminating its process group). This returns without
+    /// waiting for it to exit.
+    pub fn cancel_job(&mut self, conn_id: usize, job_id: u64) -> ClusterResult<Job> {
+        self.remote_op(conn_id, ConnectionFlag::MasterCancelsJob, move |c| Jobs(c).job_request(job_id))
+    }
+
+    /// Stream the output of the job in slave (everything so far, and the rest as it's
+    /// produced) to the given writer. This returns once the job has finished.
+    pub fn attach_job<O>(&mut self, conn_id: usize, job_id: u64, output: O) -> ClusterResult<Job>
+        where O: Write + Send + 'static
+    {
+        self.remote_op(conn_id, ConnectionFlag::MasterAttachesJob, move |c| {
+            Jobs(c).attach(job_id, output)
+        })
+    }
+
+    /// Perform an operation (with the given flag) in the slave. The connection remains
+    /// usable even if the operation fails.
+    fn remote_op<T, F>(&mut self, conn_id: usize, flag: ConnectionFlag, op: F) -> ClusterResult<T>
+        where F: FnOnce(StreamingConnection<OutgoingStream>)
+                        -> ClusterFuture<(StreamingConnection<OutgoingStream>, ClusterResult<T>)> + 'static,
+              T: 'static
+    {
+        let conn = self.get_conn(conn_id)?;
+        let async_op = conn.write_flag(flag)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_magic())
+            .and_then(op);
+
+        let (conn, result) = self.event_loop.run(async_op)?;
         self.slaves[conn_id] = Some(conn);
+        result
+    }
+
+    /// Compare the file or directory at `path` across the slaves - the slaves are grouped by
+    /// the hashes of their files, and the files in the other groups are diffed against the
+    /// majority (i.e., the largest group, which comes first). Exclude patterns in the options
+    /// apply to the comparison.
+    pub fn compare_path<P>(&mut self, conn_ids: &[usize], path: P,
+                           options: &SyncOptions) -> ClusterResult<Vec<DiffGroup>>
+        where P: AsRef<str>
+    {
+        let root = Path::new(path.as_ref());
+        let mut manifests = vec![];
+        for &id in conn_ids {
+            manifests.push((id, self.remote_manifest(id, root, options)?));
+        }
+
+        let mut groups = diff::group(manifests);
+        if groups.len() < 2 {
+            return Ok(groups)
+        }
+
+        let temp = env::temp_dir().join(format!("rcluster-diff-{}", rand::thread_rng().next_u64()));
+        let result = self.diff_groups(root, &mut groups, &temp);
+        let _ = fs::remove_dir_all(&temp);
+        result.map(|_| groups)
+    }
+
+    /// Fetch the differing files of each group (and the majority) into the temporary
+    /// directory, and diff them.
+    fn diff_groups(&mut self, root: &Path, groups: &mut [DiffGroup], temp: &Path) -> ClusterResult<()> {
+        let options = SyncOptions::default();
+        let majority_id = groups[0].conn_ids[0];
+        let (majority, others) = groups.split_first_mut().unwrap();
+        for group in others {
+            let id = group.conn_ids[0];
+            for path in diff::differing_files(majority, group) {
+                let (remote, rel) = match path == Path::new(".") {
+                    true => (root.to_path_buf(), PathBuf::from(root.file_name().unwrap_or_default())),
+                    false => (root.join(&path), path.clone()),
+                };
+
+                // Files are fetched one at a time (into their parents), and the majority's
+                // files are fetched only once.
+                let mut fetched = vec![];
+                for &(conn_id, files) in &[(majority_id, &majority.files), (id, &group.files)] {
+                    if !files.contains_key(&path) {
+                        fetched.push(None);
+                        continue
+                    }
+
+                    let local = temp.join(conn_id.to_string()).join(&rel);
+                    if !local.exists() {
+                        let parent = local.parent().unwrap();
+                        fs::create_dir_all(parent)?;
+                        self.receive_path(conn_id, remote.clone(), parent.to_path_buf(), &options)?;
+                    }
+
+                    fetched.push(Some(local));
+                }
+
+                let (old_label, new_label) = (format!("{}:{}", self.addrs[majority_id], remote.display()),
+                                              format!("{}:{}", self.addrs[id], remote.display()));
+                let diff = diff::diff_files(fetched[0].as_ref().map(|p| p.as_path()),
+                                            fetched[1].as_ref().map(|p| p.as_path()),
+                                            &old_label, &new_label)?;
+                group.diffs.push(FileDiff { path, diff });
+            }
+        }
+
         Ok(())
     }
 
-    /// Get the connection corresponding to the given ID. Panics if this has been
-    /// done before and the connection hasn't been set.
+    /// Get the manifest of the path in slave.
+    fn remote_manifest(&mut self, conn_id: usize, root: &Path,
+                       options: &SyncOptions) -> ClusterResult<Manifest> {
+        let conn = self.get_conn(conn_id)?;
+        let options = options.clone();
+        let remote_root = root.to_path_buf();
+        let async_manifest = conn.write_flag(ConnectionFlag::MasterWantsManifest)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_magic())
+            .and_then(move |c| TwoWaySync(c).request_manifest(remote_root, &options));
+        let (conn, manifest) = self.event_loop.run(async_manifest)?;
+        self.slaves[conn_id] = Some(conn);
+        Ok(manifest)
+    }
+
+    /// Send a path to the slaves with the given function (which streams it to a connection,
+    /// while reporting the progress to the tracker), and get the changes made to each slave.
+    /// The transfers run together in the event loop, and a failed one doesn't stop the rest.
+    fn send_paths<F>(&mut self, conn_ids: &[usize], stream: F) -> Vec<ClusterResult<Vec<ChangeItem>>>
+        where F: FnOnce(StreamingConnection<OutgoingStream>, ProgressTracker)
+                        -> ClusterFuture<StreamingConnection<OutgoingStream>> + Clone + 'static
+    {
+        let mut sends = vec![];
+        for &conn_id in conn_ids {
+            let conn = match self.get_conn(conn_id) {
+                Ok(c) => c,
+                Err(e) => {
+                    sends.push(Box::new(future::ok(Err(e))) as Box<Future<Item=_, Error=()>>);
+                    continue
+                },
+            };
+
+            let progress = match self.progress_handler {
+                Some(ref handler) => {
+                    let handler = handler.clone();
+                    ProgressTracker::new(move |p| {
+                        let mut handler = handler.lock().unwrap();
+                        (&mut *handler)(conn_id, p)
+                    })
+                },
+                None => ProgressTracker::default(),
+            };
+
+            let stream = stream.clone();
+            let async_conn = conn.write_flag(ConnectionFlag::MasterSendsPath)
+                .and_then(|c| c.flush())
+                .and_then(|c| c.read_magic())
+                .and_then(move |c| stream(c, progress))
+                .and_then(|c| c.flush())
+                .and_then(|c| c.read_flag::<ConnectionFlag>())
+                .and_then(|(c, flag)| SyncReport::read_from(c).map(move |(c, r)| (c, flag, r)))
+                // Keep the error of this slave, so that the others can still finish.
+                .then(|result| Ok(result));
+            sends.push(Box::new(async_conn));
+        }
+
+        let results = self.event_loop.run(future::join_all(sends)).expect("sends never fail");
+
+        conn_ids.iter().zip(results).map(|(&conn_id, result)| {
+            let (conn, flag, report) = result?;
+            if flag != ConnectionFlag::SlaveOk {
+                info!("Error sending file!");
+            }
+
+            self.slaves[conn_id] = Some(conn);
+            self.check_report(conn_id, report)
+        }).collect()
+    }
+
+    /// Get the changes from the report of a sync (after passing its warnings to the handler),
+    /// unless some of the files didn't match their checksums.
+    fn check_report(&mut self, conn_id: usize, report: SyncReport) -> ClusterResult<Vec<ChangeItem>> {
+        for warning in &report.warnings {
+            warn!("{}", warning);
+            if let Some(ref mut handler) = self.warning_handler {
+                handler(conn_id, warning);
+            }
+        }
+
+        if !report.mismatched.is_empty() {
+            for path in &report.mismatched {
+                error!("Checksum mismatch for {}", path.display());
+            }
+
+            return Err(ClusterError::ChecksumMismatch)
+        }
+
+        Ok(report.changes)
+    }
+
+    /// Establish a TLS connection with the given address. Bytes written to the connection
+    /// are limited by the given limiter (along with the global limiter).
+    fn connect(&mut self, addr: SocketAddr,
+               limiter: RateLimiter) -> ClusterResult<StreamingConnection<OutgoingStream>> {
+        let handle = self.event_loop.handle();
+        let mut settings = self.settings.clone();
+        settings.limiters = settings.limiters.with(limiter);
+        let stream_async = TcpStream::connect(&addr, &handle)
+            .and_then(|stream| CLIENT_CONFIG.connect_async(DOMAIN.clone(), stream))
+            .map_err(ClusterError::from)
+            .and_then(move |stream| Connection::create_for_stream(stream, false, settings));
+
+        self.event_loop.run(stream_async)
+    }
+
+    /// Get the connection corresponding to the given ID. If an earlier action on the
+    /// connection had failed, then the connection is lost and it should be reconnected.
     fn get_conn(&mut self, id: usize) -> ClusterResult<StreamingConnection<OutgoingStream>> {
-        if id > self.slaves.len() {
+        if id >= self.slaves.len() {
             return Err(ClusterError::InvalidConnectionId)
         }
 
-        Ok(self.slaves[id].take().expect("connection has been robbed"))
+        self.slaves[id].take().ok_or(ClusterError::ConnectionLost)
     }
 }
@@ -0,0 +1,157 @@
+/// Streaming matcher for the stopper (i.e., the magic bytes which end the streamed content).
+/// This is Knuth-Morris-Pratt, whose state is carried across chunks - so, the stopper is
+/// found wherever it is, even if it's split between any number of chunks.
+pub struct StopperMatcher {
+    stopper: Box<[u8]>,
+    /// Length of the longest proper prefix of `stopper[..i + 1]` which is also its suffix.
+    failure: Box<[usize]>,
+    /// Number of bytes (at the end of the bytes fed so far) which match the stopper's prefix.
+    matched: usize,
+}
+
+impl StopperMatcher {
+    pub fn new(stopper: &[u8]) -> Self {
+        let mut failure = vec![0; stopper.len()];
+        let mut len = 0;
+        for i in 1..stopper.len() {
+            while len > 0 && stopper[i] != stopper[len] {
+                len = failure[len - 1];
+            }
+
+            if stopper[i] == stopper[len] {
+                len += 1;
+            }
+
+            failure[i] = len;
+        }
+
+        StopperMatcher {
+            stopper: stopper.into(),
+            failure: failure.into_boxed_slice(),
+            matched: 0,
+        }
+    }
+
+    /// The stopper being matched.
+    #[inline]
+    pub fn stopper(&self) -> &[u8] {
+        &self.stopper
+    }
+
+    /// Number of bytes at the end of the bytes fed so far which could be the beginning of
+    /// the stopper. These are always the same as the stopper's prefix of that length.
+    #[inline]
+    pub fn matched(&self) -> usize {
+        self.matched
+    }
+
+    /// Feed the next chunk, and get the index (in that chunk) right after the stopper, if
+    /// it ends in this chunk. Once the stopper has been found, the matcher starts over.
+    pub fn feed(&mut self, bytes: &[u8]) -> Option<usize> {
+        if self.stopper.is_empty() {
+            return None
+        }
+
+        let mut i = 0;
+        while i < bytes.len() {
+            if self.matched == 0 {
+                // Skip to the next occurrence of the stopper's first byte.
+                match bytes[i..].iter().position(|&b| b == self.stopper[0]) {
+                    Some(pos) => i += pos,
+                    None => return None,
+                }
+            }
+
+            let byte = bytes[i];
+            while self.matched > 0 && byte != self.stopper[self.matched] {
+                self.matched = self.failure[self.matched - 1];
+            }
+
+            if byte == self.stopper[self.matched] {
+                self.matched += 1;
+            }
+
+            i += 1;
+            if self.matched == self.stopper.len() {
+                self.matched = 0;
+                return Some(i)
+            }
+        }
+
+        None
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::TestResult;
+    use super::StopperMatcher;
+
+    /// Find the stopper by feeding the content in chunks of the given size, and get the
+    /// position (in the content) right after the stopper.
+    fn find_in_chunks(content: &[u8], stopper: &[u8], chunk_size: usize) -> Option<usize> {
+        let mut matcher = StopperMatcher::new(stopper);
+        let mut offset = 0;
+        for chunk in content.chunks(chunk_size) {
+            if let Some(end) = matcher.feed(chunk) {
+                return Some(offset + end)
+            }
+
+            offset += chunk.len();
+        }
+
+        None
+    }
+
+    /// Position right after the first occurrence of the stopper (the naive way).
+    fn find_naive(content: &[u8], stopper: &[u8]) -> Option<usize> {
+        content.windows(stopper.len()).position(|w| w == stopper).map(|i| i + stopper.len())
+    }
+
+    #[test]
+    fn test_stopper_at_every_boundary() {
+        // Self-overlapping stopper, and content which ends with a partial match
+        // (which requires falling back once the next byte doesn't match).
+        let stopper = b"abaabab";
+        let mut content = b"babaaabbbaabaaba".to_vec();
+        content.extend_from_slice(stopper);
+        content.extend_from_slice(b"ab");
+        let expected = find_naive(&content, stopper);
+        assert_eq!(expected, Some(content.len() - 2));
+
+        for chunk_size in 1..content.len() + 1 {
+            assert_eq!(find_in_chunks(&content, stopper, chunk_size), expected);
+        }
+    }
+
+    quickcheck! {
+        fn prop_matches_naive_search(content: Vec<bool>, stopper: Vec<bool>,
+                                     chunk_size: usize) -> TestResult {
+            if stopper.is_empty() {
+                return TestResult::discard()
+            }
+
+            // Small alphabet, so that there are lots of partial matches.
+            let content = content.into_iter().map(|b| b as u8).collect::<Vec<_>>();
+            let stopper = stopper.into_iter().map(|b| b as u8).collect::<Vec<_>>();
+            let chunk_size = chunk_size % 64 + 1;
+            let found = find_in_chunks(&content, &stopper, chunk_size);
+            TestResult::from_bool(found == find_naive(&content, &stopper))
+        }
+
+        fn prop_matched_bytes_are_prefix(content: Vec<u8>, stopper: Vec<u8>) -> TestResult {
+            if stopper.is_empty() || find_naive(&content, &stopper).is_some() {
+                return TestResult::discard()
+            }
+
+            let mut matcher = StopperMatcher::new(&stopper);
+            matcher.feed(&content);
+            let matched = matcher.matched();
+            // It should be the longest prefix that the content ends with.
+            let longest = (matched + 1..stopper.len()).all(|l| !content.ends_with(&stopper[..l]));
+            TestResult::from_bool(content.ends_with(&stopper[..matched]) && longest)
+        }
+    }
+}
@@ -1,15 +1,57 @@
-use buffered::StreamingBuffer;
+use buffered::{BufferSize, StreamingBuffer};
 use byteorder::{BigEndian, ByteOrder};
+use checksum::{self, HASH_LENGTH};
+use compression::{self, Codec, Compression};
 use connection::Connection;
-use errors::ClusterFuture;
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use filetime::{self, FileTime};
+use flate2::read::GzDecoder;
+use fs_ops;
 use futures::{Future, future};
-use std::fs;
-use std::io::{self, ErrorKind};
+use futures::future::Loop;
+use futures_cpupool::CpuPool;
+use glob::Pattern;
+use itemize::{Change, ChangeItem, SyncReport};
+use num::FromPrimitive;
+use pool;
+use progress::ProgressTracker;
+use rand::{self, RngCore};
+use ratelimit::{Limiters, RateLimiter};
+use sha2::{Digest, Sha256};
+use sparse::{self, Extent};
+use tar::{self, EntryType};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::io::{self as async_io};
-use walkdir::WalkDir;
+use walkdir::{self, WalkDir};
+use xattrs::{self, Xattr};
 
-use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use std::fs::{self, File, Metadata, OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Remove the files in destination that don't exist in source.
+const OPTION_DELETE: u8 = 1 << 0;
+/// Don't write anything to the destination.
+const OPTION_DRY_RUN: u8 = 1 << 1;
+/// Resume the partially received files.
+const OPTION_RESUME: u8 = 1 << 2;
+/// Entries are streamed as a single tar archive.
+const OPTION_ARCHIVE: u8 = 1 << 3;
+/// Extended attributes are synced.
+const OPTION_XATTRS: u8 = 1 << 4;
+/// POSIX ACLs are synced.
+const OPTION_ACLS: u8 = 1 << 5;
+/// Permission bits that are synced.
+const MODE_MASK: u32 = 0o7777;
+/// Length of the fixed-size part of an entry header.
+const HEADER_LENGTH: usize = 22;
+/// Magic bytes at the beginning of gzipped archives.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Prefix of the PAX records which hold the extended attributes in archives.
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+/// Maximum length of an attribute's name.
+const MAX_XATTR_NAME: usize = 255;
 
 pub struct PathSync<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
 
@@ -20,6 +62,10 @@ enum_from_primitive! {
     pub enum FileType {
         Directory = 0,
         File      = 1,
+        /// Marks the end of entries in the stream.
+        End       = 2,
+        /// File with holes, whose contents are preceded by the extents which have data.
+        SparseFile = 3,
     }
 }
 
@@ -27,126 +73,1554 @@ impl Into<u8> for FileType {
     fn into(self) -> u8 { self as u8 }
 }
 
-// FIXME: DoS is possible on every usage of `read_until` with `Vec::new`
+/// Options for syncing a path. These are sent along with the destination path,
+/// so that the receiver also knows how it should behave.
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    /// Mirror the source - remove the paths in destination which weren't sent.
+    pub delete: bool,
+    /// Don't create, overwrite or delete anything in destination - only report the changes.
+    pub dry_run: bool,
+    /// Resume files from the partial contents in destination (left behind by a dropped
+    /// connection) once the sender verifies them.
+    pub resume: bool,
+    /// Stream the entire tree as a single tar archive (instead of one entry at a time),
+    /// which is much faster for lots of small files. Files aren't resumed in this mode,
+    /// and it's ignored in a dry run (since the contents aren't sent anyway).
+    pub archive: bool,
+    /// Sync the extended attributes in the `user`, `security` (SELinux labels, capabilities)
+    /// and `trusted` namespaces. If the receiver can't apply them, then it reports warnings
+    /// instead of failing.
+    pub xattrs: bool,
+    /// Sync the POSIX ACLs (access and default). Like attributes, the receiver reports
+    /// warnings if it can't apply them.
+    pub acls: bool,
+    /// Glob patterns for paths that should be left alone. These are matched against
+    /// the relative path (starting from the tip of source) and the file name.
+    pub excludes: Vec<Pattern>,
+    /// Maximum rate (bytes per second) for sending the contents. This is only
+    /// used by the sender (in addition to the limits of the connection).
+    pub bwlimit: Option<u64>,
+    /// Size of the buffers for streaming the contents (local to this end). If this
+    /// isn't set, then the buffer size of the connection is used.
+    pub buffer_size: Option<BufferSize>,
+}
+
+impl SyncOptions {
+    /// Add a glob pattern for excluding paths.
+    pub fn exclude(&mut self, pattern: &str) -> ClusterResult<()> {
+        self.excludes.push(Pattern::new(pattern)?);
+        Ok(())
+    }
+
+    /// Check whether the attributes (or ACLs) are synced.
+    fn has_xattrs(&self) -> bool {
+        self.xattrs || self.acls
+    }
+
+    /// Check whether the attribute with the given name should be synced.
+    fn wants_xattr(&self, name: &str) -> bool {
+        if xattrs::is_acl(name) {
+            self.acls
+        } else {
+            self.xattrs && xattrs::is_syncable(name)
+        }
+    }
+
+    /// Check whether the given relative path has been excluded.
+    pub(crate) fn is_excluded(&self, rel_path: &Path) -> bool {
+        let name = rel_path.file_name().map(|n| n.to_string_lossy());
+        self.excludes.iter().any(|p| {
+            p.matches_path(rel_path) || name.as_ref().map(|n| p.matches(n)).unwrap_or(false)
+        })
+    }
+
+    /// Serialize the options - flag byte, followed by the exclude patterns
+    /// (one per line) and an empty line.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.delete {
+            flags |= OPTION_DELETE;
+        }
+
+        if self.dry_run {
+            flags |= OPTION_DRY_RUN;
+        }
+
+        if self.resume {
+            flags |= OPTION_RESUME;
+        }
+
+        if self.archive {
+            flags |= OPTION_ARCHIVE;
+        }
+
+        if self.xattrs {
+            flags |= OPTION_XATTRS;
+        }
+
+        if self.acls {
+            flags |= OPTION_ACLS;
+        }
+
+        let mut bytes = vec![flags];
+        for pattern in &self.excludes {
+            bytes.extend_from_slice(pattern.as_str().as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes.push(b'\n');
+        bytes
+    }
+
+    /// Read the options from the given connection.
+    pub(crate) fn read_from<R, W>(conn: Connection<R, W>) -> ClusterFuture<(Connection<R, W>, Self)>
+        where R: AsyncRead + 'static, W: AsyncWrite + 'static
+    {
+        let async_read = conn.read_bytes([0; 1])
+            .and_then(|(c, flags)| c.read_lines().map(move |(c, lines)| (c, flags[0], lines)))
+            .and_then(|(c, flags, lines)| {
+                let mut options = SyncOptions {
+                    delete: flags & OPTION_DELETE != 0,
+                    dry_run: flags & OPTION_DRY_RUN != 0,
+                    resume: flags & OPTION_RESUME != 0,
+                    archive: flags & OPTION_ARCHIVE != 0,
+                    xattrs: flags & OPTION_XATTRS != 0,
+                    acls: flags & OPTION_ACLS != 0,
+                    excludes: vec![],
+                    bwlimit: None,
+                    buffer_size: None,
+                };
+
+                for line in lines {
+                    options.exclude(&line)?;
+                }
+
+                Ok((c, options))
+            });
+
+        Box::new(async_read) as ClusterFuture<_>
+    }
+}
+
+/// Header of an entry in the stream.
+#[derive(Clone)]
+struct EntryHeader {
+    size: u64,
+    file_type: FileType,
+    /// Compression used for the file contents.
+    compression: Compression,
+    /// Permission bits.
+    mode: u32,
+    /// Modification time (seconds since UNIX epoch).
+    mtime: i64,
+    path: PathBuf,
+    /// Extended attributes (and ACLs), if they're synced.
+    xattrs: Option<Vec<Xattr>>,
+}
+
+impl EntryHeader {
+    /// Create a header for the given file or directory.
+    fn new(rel_path: PathBuf, metadata: &Metadata) -> Self {
+        let file_type = if metadata.is_dir() { FileType::Directory } else { FileType::File };
+        EntryHeader {
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            file_type,
+            compression: Compression::None,
+            mode: metadata.permissions().mode() & MODE_MASK,
+            mtime: FileTime::from_last_modification_time(metadata).unix_seconds(),
+            path: rel_path,
+            xattrs: None,
+        }
+    }
+
+    /// Header which marks the end of entries.
+    fn end() -> Self {
+        EntryHeader {
+            size: 0,
+            file_type: FileType::End,
+            compression: Compression::None,
+            mode: 0,
+            mtime: 0,
+            path: PathBuf::new(),
+            xattrs: None,
+        }
+    }
+
+    /// File size, file type flag, compression flag, permission bits, modification time
+    /// (all in big endian), relative path (encoded) and newline - in that order. If the attributes
+    /// are synced, then they follow - number of attributes, followed by the length of the
+    /// name and the value, the name and the value of each attribute (lengths in big endian).
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_LENGTH];
+        BigEndian::write_u64(&mut bytes[..8], self.size);
+        bytes[8] = self.file_type.into();
+        bytes[9] = self.compression.into();
+        BigEndian::write_u32(&mut bytes[10..14], self.mode);
+        BigEndian::write_i64(&mut bytes[14..], self.mtime);
+        bytes.extend_from_slice(fs_ops::encode_path(&self.path).as_bytes());
+        bytes.push(b'\n');
+        if let Some(ref attrs) = self.xattrs {
+            let mut buf = [0; 5];
+            BigEndian::write_u16(&mut buf[..2], attrs.len() as u16);
+            bytes.extend_from_slice(&buf[..2]);
+            for attr in attrs {
+                buf[0] = attr.name.len() as u8;
+                BigEndian::write_u32(&mut buf[1..], attr.value.len() as u32);
+                bytes.extend_from_slice(&buf);
+                bytes.extend_from_slice(attr.name.as_bytes());
+                bytes.extend_from_slice(&attr.value);
+            }
+        }
+
+        bytes
+    }
+
+    /// Read a header from the given connection. Attributes are read only if they're
+    /// synced (the end of entries doesn't have them).
+    fn read_from<R, W>(conn: Connection<R, W>,
+                       with_xattrs: bool) -> ClusterFuture<(Connection<R, W>, Self)>
+        where R: AsyncRead + 'static, W: AsyncWrite + 'static
+    {
+        let async_read = conn.read_bytes([0; HEADER_LENGTH])
+            .and_then(move |(c, buf)| {
+                let size = BigEndian::read_u64(&buf[..8]);
+                let file_type = future_try!(FileType::from_u8(buf[8]).ok_or(ClusterError::UnknownFlag));
+                let compression = future_try!(Compression::from_u8(buf[9])
+                                                          .ok_or(ClusterError::UnknownFlag));
+                let mode = BigEndian::read_u32(&buf[10..14]);
+                let mtime = BigEndian::read_i64(&buf[14..]);
+                let async_path = c.read_path().and_then(move |(c, path)| {
+                    let async_xattrs = if with_xattrs && file_type != FileType::End {
+                        Box::new(read_xattrs(c).map(|(c, attrs)| (c, Some(attrs)))) as ClusterFuture<_>
+                    } else {
+                        Box::new(future::ok((c, None))) as ClusterFuture<_>
+                    };
+
+                    async_xattrs.map(move |(c, xattrs)| {
+                        (c, EntryHeader { size, file_type, compression, mode, mtime, path, xattrs })
+                    })
+                });
+
+                Box::new(async_path) as ClusterFuture<_>
+            });
+
+        Box::new(async_read) as ClusterFuture<_>
+    }
+}
+
+/// Read the attributes (as serialized in the header) from the given connection.
+fn read_xattrs<R, W>(conn: Connection<R, W>) -> ClusterFuture<(Connection<R, W>, Vec<Xattr>)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let async_read = conn.read_bytes([0; 2]).and_then(|(c, count)| {
+        let count = BigEndian::read_u16(&count) as usize;
+        if count > xattrs::MAX_XATTRS {
+            return Box::new(future::err(ClusterError::InvalidAttributes)) as ClusterFuture<_>
+        }
+
+        let async_attrs = future::loop_fn((c, vec![]), move |(c, mut attrs)| {
+            if attrs.len() == count {
+                return Box::new(future::ok(Loop::Break((c, attrs)))) as ClusterFuture<_>
+            }
+
+            let async_attr = c.read_bytes([0; 5]).and_then(|(c, lengths)| {
+                let name_len = lengths[0] as usize;
+                let value_len = BigEndian::read_u32(&lengths[1..]) as usize;
+                if value_len > xattrs::MAX_VALUE_SIZE {
+                    return Box::new(future::err(ClusterError::InvalidAttributes)) as ClusterFuture<_>
+                }
+
+                let async_bytes = c.read_bytes(vec![0; name_len + value_len])
+                                   .map(move |(c, bytes)| (c, bytes, name_len));
+                Box::new(async_bytes) as ClusterFuture<_>
+            }).and_then(move |(c, mut name, name_len): (Connection<R, W>, Vec<u8>, usize)| {
+                let value = name.split_off(name_len);
+                let name = String::from_utf8(name).map_err(|_| ClusterError::InvalidAttributes)?;
+                attrs.push(Xattr { name, value });
+                Ok(Loop::Continue((c, attrs)))
+            });
+
+            Box::new(async_attr) as ClusterFuture<_>
+        });
+
+        Box::new(async_attrs) as ClusterFuture<_>
+    });
+
+    Box::new(async_read) as ClusterFuture<_>
+}
+
+/// Iterator over the entries in source which should be sent (i.e., files and directories
+/// which haven't been excluded), along with their paths relative to the parent of source.
+struct SourceEntries {
+    entries: walkdir::IntoIter,
+    parent: PathBuf,
+    options: SyncOptions,
+}
+
+impl SourceEntries {
+    fn new(source: &Path, options: &SyncOptions) -> Self {
+        let mut parent = PathBuf::from(source);
+        // Since all paths are relative to the tip of source, don't trim the tip.
+        parent.pop();
+        SourceEntries {
+            entries: WalkDir::new(source).into_iter(),
+            parent,
+            options: options.clone(),
+        }
+    }
+}
+
+impl Iterator for SourceEntries {
+    /// Absolute path, relative path and metadata of the entry.
+    type Item = ClusterResult<(PathBuf, PathBuf, Metadata)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(ClusterError::from(e))),
+            };
+
+            let path = PathBuf::from(entry.path());
+            let entry_type = entry.file_type();
+
+            // Allow only files and dirs - no symlinks
+            if entry_type.is_symlink() {
+                info!("Ignoring symlink: {}", path.display());
+                continue
+            }
+
+            let rel_path = PathBuf::from(path.strip_prefix(&self.parent).unwrap());
+            if self.options.is_excluded(&rel_path) {
+                info!("Excluding {}", rel_path.display());
+                if entry_type.is_dir() {
+                    self.entries.skip_current_dir();
+                }
+
+                continue
+            }
+
+            return Some(entry.metadata().map(|m| (path, rel_path, m)).map_err(ClusterError::from))
+        }
+    }
+}
 
 impl<R, W> PathSync<R, W>
     where R: AsyncRead + 'static, W: AsyncWrite + 'static
 {
-    pub fn source_to_stream<P, Q>(self, source: P, dest: Q) -> ClusterFuture<Connection<R, W>>
+    /// Stream the source path (file or directory) to the destination in the other end.
+    /// The progress (of the contents being sent) is reported to the given tracker.
+    pub fn source_to_stream<P, Q>(self, source: P, dest: Q, options: &SyncOptions,
+                                  progress: ProgressTracker) -> ClusterFuture<Connection<R, W>>
+        where P: AsRef<Path>, Q: AsRef<Path>
+    {
+        let entries = SourceEntries::new(source.as_ref(), options);
+        let dest = dest.as_ref().to_path_buf();
+        let mut options = options.clone();
+        // Nothing's sent in a dry run, so there's no point in archiving.
+        options.archive &= !options.dry_run;
+
+        let async_total = if progress.is_active() && !options.dry_run {
+            let (source, opts) = (source.as_ref().to_path_buf(), options.clone());
+            let tracker = progress.clone();
+            let async_total = pool::run(move || total_size(&source, &opts)).map(move |total| {
+                tracker.set_total(total);
+            });
+
+            Box::new(async_total) as ClusterFuture<_>
+        } else {
+            Box::new(future::ok(())) as ClusterFuture<_>
+        };
+
+        let option_bytes = options.to_bytes();
+        let limiters = self.0.settings().limiters.with(RateLimiter::new(options.bwlimit));
+        let conn = self.0;
+        let async_options = async_total.and_then(move |_| conn.write_path(dest))
+                                       .and_then(move |c| c.write_bytes(option_bytes));
+
+        if options.archive {
+            let source = PathBuf::from(source.as_ref());
+            let async_stream = async_options.and_then(move |c| {
+                let (reader, writer) = future_try!(pool::pipe());
+                // Archive is built in a thread of its own, while it's being streamed.
+                let (tracker, buffer_size) = (progress.clone(), options.buffer_size);
+                let async_pack = CpuPool::new(1).spawn_fn(move || {
+                    pack_archive(entries, &tracker, writer)
+                });
+
+                let compression = c.settings().compression;
+                let async_send = send_archive(c, reader, compression, buffer_size, limiters,
+                                              ProgressTracker::default());
+                let async_archive = async_send.join(async_pack).map(move |(c, _)| {
+                    info!("Sent {} as archive", source.display());
+                    progress.finish();
+                    c
+                });
+
+                Box::new(async_archive) as ClusterFuture<_>
+            });
+
+            return Box::new(async_stream) as ClusterFuture<_>
+        }
+
+        let async_stream = async_options
+            .and_then(move |c| future::loop_fn((c, entries), move |(conn, entries)| {
+                let (options, opts) = (options.clone(), options.clone());
+                let (limiters, progress) = (limiters.clone(), progress.clone());
+                // Find the next entry that should be sent (walking the source blocks).
+                let async_next = pool::run(move || -> ClusterResult<_> {
+                    let mut entries = entries;
+                    let next = match entries.next() {
+                        Some(entry) => Some(with_xattrs(entry?, &opts)?),
+                        None => None,
+                    };
+
+                    Ok((entries, next))
+                });
+
+                async_next.and_then(move |(entries, next)| {
+                    let (path, rel_path, metadata, xattrs) = match next {
+                        Some(entry) => entry,
+                        None => {
+                            progress.finish();
+                            let async_end = conn.write_bytes(EntryHeader::end().to_bytes());
+                            return Box::new(async_end.map(Loop::Break)) as ClusterFuture<_>
+                        },
+                    };
+
+                    let async_send = send_entry(conn, path, rel_path, &metadata, xattrs, &options,
+                                                limiters, progress);
+                    Box::new(async_send.map(move |c| Loop::Continue((c, entries))))
+                })
+            }));
+
+        Box::new(async_stream) as ClusterFuture<_>
+    }
+
+    /// Stream an existing tar archive (optionally gzipped) in this machine, so that it's
+    /// extracted into the destination in the other end. The archive is streamed as it is,
+    /// and the progress (of the bytes being sent) is reported to the given tracker.
+    pub fn archive_to_stream<P, Q>(self, archive: P, dest: Q, options: &SyncOptions,
+                                   progress: ProgressTracker) -> ClusterFuture<Connection<R, W>>
+        where P: AsRef<Path>, Q: AsRef<Path>
+    {
+        let archive = PathBuf::from(archive.as_ref());
+        let dest = dest.as_ref().to_path_buf();
+        let options = SyncOptions { archive: true, ..options.clone() };
+        let option_bytes = options.to_bytes();
+        let limiters = self.0.settings().limiters.with(RateLimiter::new(options.bwlimit));
+
+        let async_open = pool::run(move || -> io::Result<_> {
+            let mut file = File::open(&archive)?;
+            let mut magic = [0; 2];
+            let is_gzip = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+            file.seek(SeekFrom::Start(0))?;
+            let size = file.metadata()?.len();
+            Ok((file, archive, is_gzip, size))
+        });
+
+        let async_stream = async_open.and_then(move |(file, archive, is_gzip, size)| {
+            info!("Streaming archive {}", archive.display());
+            progress.set_total(size);
+            progress.start_file(&archive, 0);
+            // Gzipped archives aren't worth compressing again.
+            let compression = if is_gzip { Compression::None } else { self.0.settings().compression };
+            self.0.write_path(dest)
+                  .and_then(move |c| c.write_bytes(option_bytes))
+                  .and_then(move |c| {
+                      send_archive(c, file, compression, options.buffer_size, limiters,
+                                   progress.clone()).map(move |c| {
+                          progress.finish();
+                          c
+                      })
+                  })
+        });
+
+        Box::new(async_stream) as ClusterFuture<_>
+    }
+
+    /// (Master) Request the archive at the given path in the other end, and extract it into
+    /// the destination in this machine. This resolves to the connection and the changes made
+    /// to the destination (or the changes which would've been made, in case of a dry run).
+    pub fn pull_archive<P, Q>(self, archive: P, dest: Q,
+                              options: &SyncOptions) -> ClusterFuture<(Connection<R, W>, SyncReport)>
+        where P: AsRef<Path>, Q: AsRef<Path>
+    {
+        // The other end streams the archive as it would for extracting it remotely.
+        self.pull(archive.as_ref(), dest.as_ref(), options)
+    }
+
+    /// (Master) Request the source path (file or directory) in the other end, and receive it
+    /// into the destination in this machine. This resolves to the connection and the changes
+    /// made to the destination (or the changes which would've been made, in case of a dry run).
+    pub fn pull_path<P, Q>(self, source: P, dest: Q,
+                           options: &SyncOptions) -> ClusterFuture<(Connection<R, W>, SyncReport)>
         where P: AsRef<Path>, Q: AsRef<Path>
     {
-        let walker = WalkDir::new(source.as_ref());
-        let source_path = PathBuf::from(source.as_ref());
-        let dest = dest.as_ref().to_string_lossy().into_owned();
-
-        let async_stream = self.0.write_bytes(dest.into_bytes())
-            .and_then(|c| c.write_bytes(&[b'\n']))
-            .and_then(move |c| -> ClusterFuture<Connection<R, W>> {
-                let mut conn = c;
-                let mut parent = source_path;
-                // Since all paths are relative to the tip of source, don't trim the tip.
-                parent.pop();
-
-                for entry in walker {
-                    let entry = future_try!(entry);
-                    let path = PathBuf::from(entry.path());
-                    let entry_type = entry.file_type();
-
-                    // Allow only files and dirs - no symlinks
-                    if entry_type.is_symlink() {
-                        info!("Ignoring symlink: {}", path.display());
-                        continue
+        self.pull(source.as_ref(), dest.as_ref(), options)
+    }
+
+    /// (Slave) Read the request for an archive (from `pull_archive`), and stream the archive.
+    pub fn serve_archive(self) -> ClusterFuture<Connection<R, W>> {
+        let async_serve = self.read_request().and_then(|(c, archive, dest, options)| {
+            PathSync(c).archive_to_stream(archive, dest, &options, ProgressTracker::default())
+        });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request for a path (from `pull_path`), and stream the path.
+    pub fn serve_path(self) -> ClusterFuture<Connection<R, W>> {
+        let async_serve = self.read_request().and_then(|(c, source, dest, options)| {
+            PathSync(c).source_to_stream(source, dest, &options, ProgressTracker::default())
+        });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+
+    /// (Master) Request the path in the other end (to be streamed into the destination), and
+    /// receive the stream.
+    fn pull(self, source: &Path, dest: &Path,
+            options: &SyncOptions) -> ClusterFuture<(Connection<R, W>, SyncReport)> {
+        let (source, dest) = (source.to_path_buf(), dest.to_path_buf());
+        let option_bytes = options.to_bytes();
+        let async_pull = self.0.write_path(source)
+            .and_then(move |c| c.write_path(dest))
+            .and_then(move |c| c.write_bytes(option_bytes))
+            .and_then(|c| c.flush())
+            .and_then(|c| PathSync(c).stream_to_source());
+
+        Box::new(async_pull) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request from `pull` - the requested path (which should be inside
+    /// the roots), the destination (in the other end) and the options.
+    fn read_request(self) -> ClusterFuture<(Connection<R, W>, PathBuf, PathBuf, SyncOptions)> {
+        let roots = self.0.settings().roots.clone();
+        let async_read = self.0.read_path()
+            .and_then(move |(c, source)| {
+                pool::run(move || fs_ops::check_target(&roots, &source)).map(|s| (c, s))
+            }).and_then(|(c, source)| c.read_path().map(move |(c, dest)| (c, source, dest)))
+            .and_then(|(c, source, dest)| {
+                SyncOptions::read_from(c).map(move |(c, options)| (c, source, dest, options))
+            });
+
+        Box::new(async_read) as ClusterFuture<_>
+    }
+
+    /// Receive the entries from stream and write them to the destination. This resolves
+    /// to the connection and the changes made to the destination (or the changes which
+    /// would've been made, in case of a dry run).
+    pub fn stream_to_source(self) -> ClusterFuture<(Connection<R, W>, SyncReport)> {
+        let roots = self.0.settings().roots.clone();
+        let async_stream = self.0.read_path()
+            .and_then(|(c, dest)| SyncOptions::read_from(c).map(move |(c, opts)| (c, dest, opts)))
+            .and_then(move |(c, dest, options)| {
+                let dry_run = options.dry_run;
+                pool::run(move || prepare_dest(&roots, &dest, dry_run))
+                    .map(move |dest| (c, dest, options))
+            }).and_then(|(c, dest_path, options)| {
+                let (dest, opts) = (dest_path.clone(), options.clone());
+                let async_receive = if options.archive {
+                    receive_archive(c, dest_path, options)
+                } else {
+                    receive_entries(c, dest_path, options)
+                };
+
+                let async_delete = async_receive.and_then(move |(c, received, mut report)| {
+                    if !opts.delete {
+                        return Box::new(future::ok((c, report))) as ClusterFuture<_>
                     }
 
-                    // Write file size, file type flag, relative path, newline,
-                    // (optional) file contents (with trailing magic bytes) - in that order.
-
-                    let rel_path = PathBuf::from(path.strip_prefix(&parent).unwrap());
-                    let rel_path_str = rel_path.to_string_lossy().into_owned();
-                    let mut size_buf = [0; 8];
-                    if entry_type.is_dir() {
-                        let async_conn = conn.write_bytes(size_buf)
-                            .and_then(|c| c.write_flag(FileType::Directory))
-                            .and_then(move |c| c.write_bytes(rel_path_str.into_bytes()))
-                            .and_then(|c| c.write_bytes(&[b'\n']));
-                        conn = future_try_wait!(async_conn);
-                        println!("{}", rel_path.display());
-                        continue
+                    // Received paths can't be trusted if some of them couldn't be verified.
+                    if !report.mismatched.is_empty() {
+                        let warning = String::from("Some files couldn't be verified, \
+                                                    so extraneous files haven't been deleted");
+                        warn!("{}", warning);
+                        report.warnings.push(warning);
+                        return Box::new(future::ok((c, report))) as ClusterFuture<_>
                     }
 
-                    let metadata = future_try!(entry.metadata());
-                    BigEndian::write_u64(&mut size_buf, metadata.len());
-                    let async_conn = conn.write_bytes(size_buf)
-                        .and_then(|c| c.write_flag(FileType::File))
-                        .and_then(move |c| c.write_bytes(rel_path_str.into_bytes()))
-                        .and_then(|c| c.write_bytes(&[b'\n']))
-                        .and_then(move |c| {
-                            let (r, w, m) = c.into();
-                            StreamingBuffer::file_to_stream(path, w)
-                                            .and_then(|s| s.stream())
-                                            .map(move |(_fd, w)| Connection::from((r, w, m)))
-                        }).and_then(|c| c.write_magic());
-
-                    conn = future_try_wait!(async_conn);
-                    println!("{}: {}", rel_path.display(), metadata.len());
-                }
+                    let async_delete = pool::run(move || delete_extraneous(&dest, &received, &opts))
+                        .map(move |deleted| {
+                            report.changes.extend(deleted);
+                            (c, report)
+                        });
 
-                Box::new(future::ok(conn))
+                    Box::new(async_delete) as ClusterFuture<_>
+                });
+
+                Box::new(async_delete) as ClusterFuture<_>
             });
 
         Box::new(async_stream) as ClusterFuture<_>
     }
+}
+
+/// (Receiver) Check the destination (which should be inside the roots, if there are any),
+/// and create it if it doesn't exist (unless it's a dry run).
+fn prepare_dest(roots: &[PathBuf], dest: &Path, dry_run: bool) -> ClusterResult<PathBuf> {
+    let dest = fs_ops::check_target(roots, dest)?;
+    if dest.is_file() {
+        // If destination exists and it's a file, then bail out.
+        let err = io::Error::new(ErrorKind::AlreadyExists, "Destination is a file!");
+        return Err(ClusterError::from(err))
+    } else if !dest.exists() && !dry_run {
+        // If destination doesn't exist, then try to create dirs recursively.
+        fs::create_dir_all(&dest)?;
+    }
+
+    Ok(dest)
+}
 
-    pub fn stream_to_source(self) -> ClusterFuture<Connection<R, W>> {
-        let (r, w, m) = self.0.into();
-        let async_stream = async_io::read_until(r, b'\n', Vec::new())
-            .map_err(ClusterError::from)
-            .and_then(move |(r, bytes)| {
-                let path_str = String::from_utf8_lossy(&bytes[..bytes.len() - 1]);
-                let dest_path = PathBuf::from(path_str);
-
-                if dest_path.is_file() {
-                    // If destination exists and it's a file, then bail out.
-                    return future::err(ClusterError::Io(io::Error::new(ErrorKind::AlreadyExists, "Destination is a file!")))
-                } else if !dest_path.exists() {
-                    // If destination doesn't exist, then try to create dirs recursively.
-                    future_try!(fs::create_dir_all(&dest_path).map_err(ClusterError::from));
+/// (Receiver) Receive the entries (one at a time) from the stream and write them to the
+/// destination. This resolves to the connection, the paths which have been received, and
+/// the report.
+fn receive_entries<R, W>(conn: Connection<R, W>, dest_path: PathBuf, options: SyncOptions)
+                        -> ClusterFuture<(Connection<R, W>, HashSet<PathBuf>, SyncReport)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let (dest, opts) = (dest_path.clone(), options.clone());
+    let async_check = pool::run(move || -> ClusterResult<_> {
+        let mut report = SyncReport::default();
+        let apply_xattrs = check_xattrs(&dest, &opts, &mut report);
+        Ok((apply_xattrs, report))
+    });
+
+    let async_receive = async_check.and_then(move |(apply_xattrs, report)| {
+        future::loop_fn((conn, HashSet::new(), report), move |(conn, mut received, mut report)| {
+            let (dest_path, options) = (dest_path.clone(), options.clone());
+            EntryHeader::read_from(conn, options.has_xattrs()).and_then(move |(c, mut header)| {
+                if header.file_type == FileType::End {
+                    let done = Loop::Break((c, received, report));
+                    return Box::new(future::ok(done)) as ClusterFuture<_>
+                }
+
+                future_try!(check_relative(&header.path));
+                if !apply_xattrs {
+                    header.xattrs = None;
                 }
 
-                let mut conn = Connection::from((r, w, m));
-                loop {
-                    let (r, w, m) = conn.into();
-                    let async_meta = async_io::read_exact(r, [0; 8])
-                        .map(ClusterError::from)
-                        .and_then(|(r, size_buf)| (r, BigEndian::read_u64(&size_buf)))
-                        .and_then(move |(r, size)| {
-                            Connection::from((r, w, m)).read_flag().map(|(c, f)| {
-                                let (r, w, m) = c.into();
-                                (r, w, m, size, f)
-                            })
+                received.insert(header.path.clone());
+                let (entry, opts) = (header.clone(), options.clone());
+                let async_prepare = pool::run(move || prepare_entry(&dest_path, &entry, &opts));
+                let async_entry = async_prepare.and_then(move |(abs_path, item, done)| {
+                    // Nothing else comes for directories (or for anything in a dry run).
+                    if let Some(warnings) = done {
+                        report.warnings.extend(warnings);
+                        report.changes.extend(item);
+                        let next = Loop::Continue((c, received, report));
+                        return Box::new(future::ok(next)) as ClusterFuture<_>
+                    }
+
+                    let async_file = receive_file(c, &header, abs_path, &options)
+                        .map(move |(c, matched, warnings)| {
+                            report.warnings.extend(warnings);
+                            if matched {
+                                report.changes.extend(item);
+                            } else {
+                                report.mismatched.push(header.path);
+                            }
+
+                            Loop::Continue((c, received, report))
                         });
 
-                    let (r, w, m, file_size, file_type) = future_try_wait!(async_meta);
-                    let async_path = async_io::read_until(r, b'\n', Vec::new())
-                        .map_err(ClusterError::from)
-                        .and_then(move |(r, bytes)| (r, String::from_utf8_lossy(&bytes[..bytes.len() - 1])));
+                    Box::new(async_file) as ClusterFuture<_>
+                });
+
+                Box::new(async_entry) as ClusterFuture<_>
+            })
+        })
+    });
+
+    Box::new(async_receive) as ClusterFuture<_>
+}
+
+/// (Receiver) Itemize the entry against the destination, and prepare the path for it
+/// (unless it's a dry run). This returns the absolute path, the change (if any), and
+/// the warnings (for the attributes which couldn't be applied) if there's nothing more
+/// to be received for the entry.
+fn prepare_entry(dest: &Path, header: &EntryHeader, options: &SyncOptions)
+                 -> ClusterResult<(PathBuf, Option<ChangeItem>, Option<Vec<String>>)> {
+    check_parents(dest, &header.path)?;
+    let abs_path = dest.join(&header.path);
+    let item = itemize(header, &abs_path, options);
+    // The sender doesn't send the contents in a dry run.
+    if options.dry_run {
+        return Ok((abs_path, item, Some(vec![])))
+    }
+
+    if let Some(ChangeItem { change: Change::Created, .. }) = item {
+        // Remove existing paths of different type (or symlinks) before writing.
+        remove_path(&abs_path)?;
+    }
+
+    if header.file_type != FileType::Directory {
+        return Ok((abs_path, item, None))
+    }
+
+    fs::create_dir_all(&abs_path)?;
+    fs::set_permissions(&abs_path, Permissions::from_mode(header.mode))?;
+    let warnings = apply_header_xattrs(header, &abs_path, options)?;
+    Ok((abs_path, item, Some(warnings)))
+}
+
+/// (Sender) Write the header of an entry, followed by the contents (if it's a file, and if
+/// it's not a dry run) with trailing magic bytes and the checksum - in that order.
+fn send_entry<R, W>(conn: Connection<R, W>, path: PathBuf, rel_path: PathBuf, metadata: &Metadata,
+                    xattrs: Option<Vec<Xattr>>, options: &SyncOptions, limiters: Limiters,
+                    progress: ProgressTracker) -> ClusterFuture<Connection<R, W>>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let mut header = EntryHeader::new(rel_path.clone(), metadata);
+    header.xattrs = xattrs;
+
+    // The receiver doesn't need the contents in a dry run.
+    if metadata.is_dir() || options.dry_run {
+        progress.start_file(&rel_path, 0);
+        return conn.write_bytes(header.to_bytes())
+    }
+
+    // Compress the contents only if the connection allows it, and if it's worth it. Files
+    // which could have holes are checked for their data extents.
+    let compression = conn.settings().compression;
+    let async_check = if compression == Compression::None && !sparse::maybe_sparse(metadata) {
+        Box::new(future::ok((false, None))) as ClusterFuture<_>
+    } else {
+        let path = path.clone();
+        pool::run(move || -> io::Result<_> {
+            let extents = sparse::data_extents(&File::open(&path)?)?;
+            let compress = compression != Compression::None && compression::should_compress(path);
+            Ok((compress, extents))
+        })
+    };
+
+    let resume = options.resume;
+    let size = header.size;
+    let buffer_size = options.buffer_size.unwrap_or(conn.settings().buffer_size);
+    let async_send = async_check.and_then(move |(compress, extents)| {
+        if compress {
+            header.compression = compression;
+        }
+
+        if extents.is_some() {
+            header.file_type = FileType::SparseFile;
+        }
+
+        let codec = Codec::Encode(header.compression);
+        conn.write_bytes(header.to_bytes()).map(move |c| (c, codec, extents))
+    }).and_then(move |(c, codec, extents)| {
+        let async_offset = if resume {
+            negotiate_offset(c, path)
+        } else {
+            Box::new(future::ok((c, path, 0u64, Sha256::new()))) as ClusterFuture<_>
+        };
+
+        async_offset.and_then(move |(c, path, offset, hasher)| {
+            // Extents are sent once the offset is known, so that only the rest are sent.
+            let extents = extents.map(|e| sparse::clip(&e, offset));
+            let async_extents = match extents {
+                Some(ref e) => c.write_bytes(extents_to_bytes(e)),
+                None => Box::new(future::ok(c)) as ClusterFuture<_>,
+            };
+
+            async_extents.map(move |c| (c, path, offset, hasher, codec, extents))
+        })
+    }).and_then(move |(c, path, offset, hasher, codec, extents)| {
+        // Holes don't have to be sent, and so they're accounted as skipped.
+        let data_len = match extents {
+            Some(ref e) => e.iter().map(|e| e.len).sum(),
+            None => size.saturating_sub(offset),
+        };
+
+        progress.start_file(&rel_path, size.saturating_sub(data_len));
+        let (r, w, m, s) = c.into();
+        let async_stream = match extents {
+            Some(extents) => {
+                let async_stream = StreamingBuffer::extents_to_stream(path, extents, w, buffer_size)
+                    .and_then(move |b| {
+                        b.with_codec(codec)
+                         .with_limiters(limiters)
+                         .with_progress(progress)
+                         .with_flush(false)
+                         .stream_hashed(hasher)
+                    }).map(|(_, w, h)| (w, h));
+
+                Box::new(async_stream) as ClusterFuture<_>
+            },
+            None => {
+                let async_stream = StreamingBuffer::file_to_stream(path, offset, w, buffer_size)
+                    .and_then(move |b| {
+                        b.with_codec(codec)
+                         .with_limiters(limiters)
+                         .with_progress(progress)
+                         .with_flush(false)
+                         .stream_hashed(hasher)
+                    }).map(|(_fd, w, h)| (w, h));
+
+                Box::new(async_stream) as ClusterFuture<_>
+            },
+        };
+
+        async_stream.map(move |(w, h)| (Connection::from((r, w, m, s)), h))
+    }).and_then(|(c, hash)| {
+        // Magic ends the contents, and it's followed by the checksum.
+        c.write_magic().and_then(move |c| c.write_bytes(hash))
+    });
+
+    Box::new(async_send) as ClusterFuture<_>
+}
+
+/// (Receiver) Receive the contents of a file into a partial file, and move it to the given
+/// path once its checksum has been verified. This resolves to the connection, whether
+/// the checksum has matched, and the warnings for the attributes which couldn't be applied.
+fn receive_file<R, W>(conn: Connection<R, W>, header: &EntryHeader, abs_path: PathBuf,
+                      options: &SyncOptions) -> ClusterFuture<(Connection<R, W>, bool, Vec<String>)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let buffer_size = options.buffer_size.unwrap_or(conn.settings().buffer_size);
+    // Contents are written to a partial file, which is renamed once
+    // the file has been received completely.
+    let partial = partial_path(&abs_path, header);
+    let async_offset = if options.resume {
+        offer_offset(conn, partial.clone(), header.size)
+    } else {
+        Box::new(future::ok((conn, 0u64, Sha256::new()))) as ClusterFuture<_>
+    };
+
+    let codec = Codec::Decode(header.compression);
+    let (size, mode, mtime) = (header.size, header.mode, header.mtime);
+    let is_sparse = header.file_type == FileType::SparseFile;
+    let (xattr_header, options) = (header.clone(), options.clone());
+    let async_receive = async_offset.and_then(move |(c, offset, hasher)| {
+        let async_extents = if is_sparse {
+            read_extents(c, offset, size)
+        } else {
+            Box::new(future::ok((c, None))) as ClusterFuture<_>
+        };
+
+        async_extents.map(move |(c, extents)| (c, offset, hasher, extents))
+    }).and_then(move |(c, offset, hasher, extents)| {
+        let (r, w, m, s) = c.into();
+        let async_stream = match extents {
+            Some(extents) => {
+                let async_stream = StreamingBuffer::stream_to_extents(r, &m, &partial, offset,
+                                                                      extents, buffer_size)
+                    .and_then(move |b| b.with_codec(codec).stream_hashed(hasher))
+                    .map(|(r, _, hash)| (r, hash));
+
+                Box::new(async_stream) as ClusterFuture<_>
+            },
+            None => {
+                let async_stream = StreamingBuffer::stream_to_file(r, &m, &partial, offset,
+                                                                   size, buffer_size)
+                    .and_then(move |b| b.with_codec(codec).stream_hashed(hasher))
+                    .map(|(r, _fd, hash)| (r, hash));
+
+                Box::new(async_stream) as ClusterFuture<_>
+            },
+        };
+
+        async_stream.and_then(move |(r, hash)| {
+            Connection::from((r, w, m, s)).read_bytes([0; HASH_LENGTH])
+                                          .map(move |(c, expected)| (c, hash == expected, partial))
+        })
+    }).and_then(move |(c, matched, partial)| {
+        let async_finish = pool::run(move || -> io::Result<_> {
+            if !matched {
+                // Remove the partial file, so that it's not resumed later.
+                error!("Checksum mismatch for {}", abs_path.display());
+                fs::remove_file(&partial)?;
+                return Ok(vec![])
+            }
+
+            if is_sparse {
+                // Trailing hole (if any) isn't there until the file is extended.
+                OpenOptions::new().write(true).open(&partial)?.set_len(size)?;
+            }
+
+            fs::rename(&partial, &abs_path)?;
+            let mtime = FileTime::from_unix_time(mtime, 0);
+            filetime::set_file_times(&abs_path, mtime, mtime)?;
+            fs::set_permissions(&abs_path, Permissions::from_mode(mode))?;
+            apply_header_xattrs(&xattr_header, &abs_path, &options)
+        });
+
+        async_finish.map(move |warnings| (c, matched, warnings))
+    });
+
+    Box::new(async_receive) as ClusterFuture<_>
+}
+
+/// Attributes of the path which should be synced (none, if the filesystem doesn't
+/// support them).
+fn local_xattrs(path: &Path, options: &SyncOptions) -> io::Result<Vec<Xattr>> {
+    match xattrs::read(path) {
+        Ok(attrs) => Ok(attrs.into_iter().filter(|a| {
+            options.wants_xattr(&a.name) && a.name.len() <= MAX_XATTR_NAME &&
+                a.value.len() <= xattrs::MAX_VALUE_SIZE
+        }).collect()),
+        Err(ref e) if xattrs::is_unsupported(e) => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Add the attributes (if they're synced) to the entry from `SourceEntries`.
+fn with_xattrs(entry: (PathBuf, PathBuf, Metadata), options: &SyncOptions)
+               -> io::Result<(PathBuf, PathBuf, Metadata, Option<Vec<Xattr>>)> {
+    let (path, rel_path, metadata) = entry;
+    let xattrs = match options.has_xattrs() {
+        true => Some(local_xattrs(&path, options)?),
+        false => None,
+    };
+
+    Ok((path, rel_path, metadata, xattrs))
+}
+
+/// (Receiver) Check whether the attributes can be applied in the destination (if they're
+/// synced at all). If they can't be, then it's reported as a warning.
+fn check_xattrs(dest: &Path, options: &SyncOptions, report: &mut SyncReport) -> bool {
+    if !options.has_xattrs() {
+        return false
+    }
+
+    let supported = xattrs::is_supported(dest);
+    if !supported {
+        let warning = format!("{} doesn't support extended attributes (or ACLs), \
+                               so they won't be applied", dest.display());
+        warn!("{}", warning);
+        report.warnings.push(warning);
+    }
+
+    supported
+}
+
+/// (Receiver) Apply the attributes from the header (if any) to the path in destination.
+/// Attributes which couldn't be applied (permissions, unsupported namespaces, etc.)
+/// are returned as warnings.
+fn apply_header_xattrs(header: &EntryHeader, abs_path: &Path,
+                       options: &SyncOptions) -> io::Result<Vec<String>> {
+    let attrs = match header.xattrs {
+        Some(ref attrs) => attrs,
+        None => return Ok(vec![]),
+    };
+
+    let failed = xattrs::apply(abs_path, attrs, |name| options.wants_xattr(name))?;
+    Ok(failed.into_iter().map(|(name, e)| {
+        let warning = format!("Cannot apply {} to {}: {}", name, header.path.display(), e);
+        warn!("{}", warning);
+        warning
+    }).collect())
+}
+
+/// Serialize the extents - number of extents, followed by the offset and length of
+/// each extent (all in big endian).
+fn extents_to_bytes(extents: &[Extent]) -> Vec<u8> {
+    let mut bytes = vec![0; 4 + 16 * extents.len()];
+    BigEndian::write_u32(&mut bytes[..4], extents.len() as u32);
+    for (e, buf) in extents.iter().zip(bytes[4..].chunks_mut(16)) {
+        BigEndian::write_u64(&mut buf[..8], e.offset);
+        BigEndian::write_u64(&mut buf[8..], e.len);
+    }
+
+    bytes
+}
+
+/// (Receiver) Read the extents of a sparse file from the connection, and check that
+/// they're within the file (after the given offset).
+fn read_extents<R, W>(conn: Connection<R, W>, offset: u64,
+                      size: u64) -> ClusterFuture<(Connection<R, W>, Option<Vec<Extent>>)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let async_read = conn.read_bytes([0; 4]).and_then(move |(c, count)| {
+        let count = BigEndian::read_u32(&count) as usize;
+        if count > sparse::MAX_EXTENTS {
+            return Box::new(future::err(ClusterError::InvalidExtents)) as ClusterFuture<_>
+        }
+
+        let async_extents = c.read_bytes(vec![0; 16 * count]).and_then(move |(c, bytes)| {
+            let extents = bytes.chunks(16).map(|buf| Extent {
+                offset: BigEndian::read_u64(&buf[..8]),
+                len: BigEndian::read_u64(&buf[8..]),
+            }).collect::<Vec<_>>();
+
+            if !sparse::are_valid(&extents, offset, size) {
+                return Err(ClusterError::InvalidExtents)
+            }
+
+            Ok((c, Some(extents)))
+        });
+
+        Box::new(async_extents) as ClusterFuture<_>
+    });
+
+    Box::new(async_read) as ClusterFuture<_>
+}
+
+/// Total size of the files (which aren't excluded) in the source.
+fn total_size(source: &Path, options: &SyncOptions) -> ClusterResult<u64> {
+    let parent = source.parent().unwrap_or(source);
+    let mut total = 0;
+    let mut entries = WalkDir::new(source).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let entry_type = entry.file_type();
+        if entry_type.is_symlink() {
+            continue
+        }
+
+        if options.is_excluded(entry.path().strip_prefix(parent).unwrap()) {
+            if entry_type.is_dir() {
+                entries.skip_current_dir();
+            }
+
+            continue
+        }
+
+        if entry_type.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+
+    Ok(total)
+}
+
+/// Check that the relative path (from stream) doesn't escape the destination
+/// (i.e., it shouldn't have a root, prefix or parent directory components).
+pub fn check_relative(path: &Path) -> ClusterResult<()> {
+    let mut components = path.components().peekable();
+    if components.peek().is_none() {
+        return Err(ClusterError::InvalidPath)
+    }
+
+    for component in components {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            _ => return Err(ClusterError::InvalidPath),
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that none of the parents of the relative path (in destination) is a symlink,
+/// so that nothing can be written outside the destination through them.
+pub fn check_parents(dest: &Path, rel_path: &Path) -> ClusterResult<()> {
+    let mut path = PathBuf::from(dest);
+    let mut components = rel_path.components();
+    components.next_back();
+    for component in components {
+        path.push(component);
+        if fs::symlink_metadata(&path).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+            return Err(ClusterError::InvalidPath)
+        }
+    }
+
+    Ok(())
+}
+
+/// Path of the partial file (for the given path in destination) into which the contents
+/// are received. This is in the same directory, so that it can be atomically renamed.
+fn partial_path(abs_path: &Path, header: &EntryHeader) -> PathBuf {
+    let id = checksum::transfer_id(&header.path, header.size, header.mtime);
+    let name = abs_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    abs_path.with_file_name(format!(".{}.{}.part", name, id))
+}
+
+/// (Sender) Write the compression flag, followed by the archive (read from the given
+/// blocking reader) with trailing magic bytes and the checksum - in that order.
+fn send_archive<R, W, T>(conn: Connection<R, W>, archive: T, compression: Compression,
+                         buffer_size: Option<BufferSize>, limiters: Limiters,
+                         progress: ProgressTracker) -> ClusterFuture<Connection<R, W>>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static, T: Read + Send + 'static
+{
+    let buffer_size = buffer_size.unwrap_or(conn.settings().buffer_size);
+    let flag: u8 = compression.into();
+    let async_send = conn.write_bytes([flag]).and_then(move |c| {
+        let (r, w, m, s) = c.into();
+        StreamingBuffer::blocking_to_stream(archive, w, buffer_size)
+                        .with_codec(Codec::Encode(compression))
+                        .with_limiters(limiters)
+                        .with_progress(progress)
+                        .with_flush(false)
+                        .stream_hashed(Sha256::new())
+                        .map(move |(_, w, h)| (Connection::from((r, w, m, s)), h))
+    }).and_then(|(c, hash)| {
+        // Magic ends the archive, and it's followed by the checksum.
+        c.write_magic().and_then(move |c| c.write_bytes(hash))
+    });
+
+    Box::new(async_send) as ClusterFuture<_>
+}
+
+/// (Receiver) Receive the archive from the stream and extract it (while it's being received)
+/// into a staging directory. Once its checksum has been verified, the entries are moved into
+/// the destination. This resolves to the connection, the paths which have been received,
+/// and the report.
+fn receive_archive<R, W>(conn: Connection<R, W>, dest_path: PathBuf, options: SyncOptions)
+                        -> ClusterFuture<(Connection<R, W>, HashSet<PathBuf>, SyncReport)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let buffer_size = options.buffer_size.unwrap_or(conn.settings().buffer_size);
+    let async_receive = conn.read_bytes([0; 1]).and_then(move |(c, flag)| {
+        let compression = future_try!(Compression::from_u8(flag[0]).ok_or(ClusterError::UnknownFlag));
+        let (reader, writer) = future_try!(pool::pipe());
+        // Archive is extracted in a thread of its own, while it's being received.
+        let (dest, opts) = (dest_path.clone(), options.clone());
+        let async_unpack = CpuPool::new(1).spawn_fn(move || {
+            unpack_archive(reader, &dest, &opts)
+        });
+
+        let (r, w, m, s) = c.into();
+        let async_stream = StreamingBuffer::stream_to_blocking(r, &m, writer, buffer_size)
+            .with_codec(Codec::Decode(compression))
+            .stream_hashed(Sha256::new())
+            // Dropping the writer ends the archive for the unpacker.
+            .and_then(move |(r, _, hash)| {
+                Connection::from((r, w, m, s)).read_bytes([0; HASH_LENGTH])
+                                              .map(move |(c, expected)| (c, hash == expected))
+            });
+
+        let async_archive = async_stream.join(async_unpack)
+            .and_then(move |((c, matched), (received, mut report, staged))| {
+                if !matched {
+                    // Whatever has been extracted can't be trusted (and it's dropped
+                    // along with the staging directory).
+                    error!("Checksum mismatch for the archive");
+                    report.changes.clear();
+                    report.mismatched = received.iter().cloned().collect();
+                    report.mismatched.sort();
+                    return Box::new(future::ok((c, received, report))) as ClusterFuture<_>
+                }
+
+                let async_apply = pool::run(move || -> ClusterResult<_> {
+                    staged.apply(&dest_path, &options, &mut report)?;
+                    Ok((received, report))
+                });
+
+                Box::new(async_apply.map(move |(received, report)| (c, received, report)))
+            });
+
+        Box::new(async_archive) as ClusterFuture<_>
+    });
+
+    Box::new(async_receive) as ClusterFuture<_>
+}
+
+/// (Sender) Write the entries into a tar archive (on the given writer). The progress
+/// is reported as the entries are written. Attributes (if they're synced) are written
+/// as PAX records before their entries (the way GNU tar does).
+fn pack_archive<T: Write>(entries: SourceEntries, progress: &ProgressTracker,
+                          writer: T) -> ClusterResult<()> {
+    let options = entries.options.clone();
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for entry in entries {
+        let (path, rel_path, metadata) = entry?;
+        progress.start_file(&rel_path, 0);
+        if options.has_xattrs() {
+            let records = local_xattrs(&path, &options)?.into_iter().map(|a| {
+                (format!("{}{}", PAX_XATTR_PREFIX, a.name), a.value)
+            }).collect::<Vec<_>>();
+            builder.append_pax_extensions(records.iter().map(|&(ref k, ref v)| (&**k, &**v)))?;
+        }
+
+        if metadata.is_dir() {
+            builder.append_dir(&rel_path, &path)?;
+        } else {
+            builder.append_path_with_name(&path, &rel_path)?;
+            progress.advance(metadata.len());
+        }
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
 
-                    let (r, rel_path) = future_try_wait!(async_path);
-                    let abs_path = dest_path.join(rel_path);
-                    if file_type == FileType::Directory {
-                        future_try!(fs::create_dir_all(&abs_path).map_err(ClusterError::from));
-                        continue
+/// Archive which has been extracted into a staging directory (in the destination). It's
+/// removed once this is dropped.
+struct StagedArchive {
+    dir: PathBuf,
+    entries: Vec<StagedEntry>,
+}
+
+/// Entry of an archive, which is yet to be moved into the destination.
+struct StagedEntry {
+    header: EntryHeader,
+    item: Option<ChangeItem>,
+    /// Extracted contents (for files).
+    file: Option<PathBuf>,
+}
+
+impl StagedArchive {
+    /// Move the extracted entries into the destination, and apply their attributes.
+    /// The changes (and the warnings) are added to the report.
+    fn apply(mut self, dest: &Path, options: &SyncOptions,
+             report: &mut SyncReport) -> ClusterResult<()> {
+        for entry in self.entries.drain(..) {
+            let (header, item) = (entry.header, entry.item);
+            let abs_path = dest.join(&header.path);
+            if let Some(ChangeItem { change: Change::Created, .. }) = item {
+                // Remove existing paths of different type (or symlinks) before writing.
+                remove_path(&abs_path)?;
+            }
+
+            match entry.file {
+                Some(file) => {
+                    // Archives don't necessarily have entries for the parent directories.
+                    if let Some(parent) = abs_path.parent() {
+                        fs::create_dir_all(parent)?;
                     }
 
-                    let async_read = StreamingBuffer::stream_to_file(r, &m, &abs_path)
-                        .and_then(|s| s.stream())
-                        .map(move |(r, _fd)| Connection::from((r, w, m)));
-                    conn = future_try_wait!(async_read);
+                    fs::rename(&file, &abs_path)?;
+                    let mtime = FileTime::from_unix_time(header.mtime, 0);
+                    filetime::set_file_times(&abs_path, mtime, mtime)?;
+                },
+                None => fs::create_dir_all(&abs_path)?,
+            }
+
+            fs::set_permissions(&abs_path, Permissions::from_mode(header.mode))?;
+            report.warnings.extend(apply_header_xattrs(&header, &abs_path, options)?);
+            report.changes.extend(item);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for StagedArchive {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// (Receiver) Extract the tar archive (gzipped or not) from the given reader into a staging
+/// directory in the destination. This returns the received paths along with the report (of
+/// the changes which would be made, in case of a dry run) and the staged entries, which should
+/// be applied once the archive has been verified. The reader is always drained, so that
+/// the streamer can finish, even if the archive has been rejected.
+fn unpack_archive<T: Read>(reader: T, dest: &Path, options: &SyncOptions)
+                          -> ClusterResult<(HashSet<PathBuf>, SyncReport, StagedArchive)> {
+    let mut reader = BufReader::new(reader);
+    let is_gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+    let dir = dest.join(format!(".rcluster-archive-{}.part", rand::thread_rng().next_u64()));
+    let mut staged = StagedArchive { dir, entries: vec![] };
+    let result = if is_gzip {
+        extract_entries(GzDecoder::new(&mut reader), dest, options, &mut staged)
+    } else {
+        extract_entries(&mut reader, dest, options, &mut staged)
+    };
+
+    io::copy(&mut reader, &mut io::sink())?;
+    result.map(|(received, report)| (received, report, staged))
+}
+
+/// Extract the entries of the tar archive into the staging directory, with the same rules as
+/// the entries from the stream - only files and directories are allowed, and they shouldn't
+/// escape the destination.
+fn extract_entries<T: Read>(reader: T, dest: &Path, options: &SyncOptions,
+                            staged: &mut StagedArchive)
+                           -> ClusterResult<(HashSet<PathBuf>, SyncReport)> {
+    let mut archive = tar::Archive::new(reader);
+    let (mut received, mut report) = (HashSet::new(), SyncReport::default());
+    let apply_xattrs = check_xattrs(dest, options, &mut report);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let mut header = match archive_header(&mut entry, apply_xattrs)? {
+            Some(header) => header,
+            None => continue,
+        };
+
+        // Archives could have any attributes, but only the wanted ones are compared and applied.
+        if let Some(ref mut attrs) = header.xattrs {
+            attrs.retain(|a| options.wants_xattr(&a.name));
+        }
+
+        // Archives (which haven't been built by us) could have entries under excluded paths.
+        if header.path.ancestors().any(|p| options.is_excluded(p)) {
+            info!("Excluding {}", header.path.display());
+            continue
+        }
+
+        check_parents(dest, &header.path)?;
+        let item = itemize(&header, &dest.join(&header.path), options);
+        received.insert(header.path.clone());
+        if options.dry_run {
+            report.changes.extend(item);
+            continue
+        }
+
+        let file = match header.file_type {
+            FileType::Directory => None,
+            _ => {
+                fs::create_dir_all(&staged.dir)?;
+                let path = staged.dir.join(staged.entries.len().to_string());
+                let mut file = File::create(&path)?;
+                if header.file_type == FileType::SparseFile {
+                    sparse::copy_sparse(&mut entry, &mut file)?;
+                } else {
+                    io::copy(&mut entry, &mut file)?;
                 }
+
+                Some(path)
+            },
+        };
+
+        staged.entries.push(StagedEntry { header, item, file });
+    }
+
+    Ok((received, report))
+}
+
+/// Header for the given entry of an archive (if it's a file or directory). The path
+/// is checked (so that it doesn't escape the destination) and normalized. Attributes
+/// (from the PAX records) are included if they're wanted.
+fn archive_header<T: Read>(entry: &mut tar::Entry<T>,
+                           with_xattrs: bool) -> ClusterResult<Option<EntryHeader>> {
+    let mut xattrs = None;
+    if with_xattrs {
+        let mut attrs = vec![];
+        for record in entry.pax_extensions()?.into_iter().flat_map(|e| e) {
+            let record = record?;
+            let name = match record.key() {
+                Ok(key) if key.starts_with(PAX_XATTR_PREFIX) => &key[PAX_XATTR_PREFIX.len()..],
+                _ => continue,
+            };
+
+            attrs.push(Xattr { name: name.to_owned(), value: record.value_bytes().to_vec() });
+        }
+
+        attrs.sort_by(|a, b| a.name.cmp(&b.name));
+        xattrs = Some(attrs);
+    }
+
+    let raw_path = entry.path()?;
+    let file_type = match entry.header().entry_type() {
+        EntryType::Directory => FileType::Directory,
+        EntryType::Regular | EntryType::Continuous => FileType::File,
+        // Holes are filled with zeros by the reader, and they're punched again while writing.
+        EntryType::GNUSparse => FileType::SparseFile,
+        other => {
+            info!("Ignoring {:?} entry in archive: {}", other, raw_path.display());
+            return Ok(None)
+        },
+    };
+
+    check_relative(&raw_path)?;
+    // Archives often have paths starting with "./" (which is the destination itself).
+    let path = raw_path.components().filter(|c| *c != Component::CurDir).collect::<PathBuf>();
+    if path.as_os_str().is_empty() {
+        return Ok(None)
+    }
+
+    let header = entry.header();
+    Ok(Some(EntryHeader {
+        size: if file_type == FileType::Directory { 0 } else { entry.size() },
+        file_type,
+        compression: Compression::None,
+        mode: header.mode()? & MODE_MASK,
+        mtime: header.mtime()? as i64,
+        path,
+        xattrs,
+    }))
+}
+
+/// (Sender) Read the offset (along with the hash of the prefix) which the receiver already
+/// has for a file, and verify it against the local file. The receiver is told whether it can
+/// resume, and this resolves to the offset from which the file should be streamed (along with
+/// the hasher which has been fed with the prefix).
+fn negotiate_offset<R, W>(conn: Connection<R, W>, path: PathBuf)
+                         -> ClusterFuture<(Connection<R, W>, PathBuf, u64, Sha256)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    // Receiver responds to the header, and so it should've been flushed.
+    let async_offset = conn.flush()
+        .and_then(|c| c.read_bytes([0; 8 + HASH_LENGTH]))
+        .and_then(move |(c, buf)| {
+            let offset = BigEndian::read_u64(&buf[..8]);
+            let async_hasher = pool::run(move || -> io::Result<_> {
+                let hasher = match checksum::prefix_hasher(&path, offset) {
+                    Ok(ref h) if offset > 0 && checksum::finish(h.clone())[..] == buf[8..] => {
+                        info!("Resuming {} from offset {}", path.display(), offset);
+                        Some(h.clone())
+                    },
+                    _ => None,
+                };
+
+                Ok((path, hasher))
             });
 
-        Box::new(async_stream) as ClusterFuture<_>
+            async_hasher.and_then(move |(path, hasher)| {
+                let verified = hasher.is_some();
+                let offset = if verified { offset } else { 0 };
+                let hasher = hasher.unwrap_or_else(Sha256::new);
+                c.write_bytes([verified as u8]).map(move |c| (c, path, offset, hasher))
+            })
+        });
+
+    Box::new(async_offset) as ClusterFuture<_>
+}
+
+/// (Receiver) Send the length of the partial file (along with the hash of its contents),
+/// and resolve to the offset from which the sender will stream the file (along with the
+/// hasher which has been fed with the prefix).
+fn offer_offset<R, W>(conn: Connection<R, W>, partial: PathBuf,
+                      size: u64) -> ClusterFuture<(Connection<R, W>, u64, Sha256)>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    let async_prefix = pool::run(move || -> io::Result<_> {
+        let offset = partial.metadata().map(|m| m.len()).unwrap_or(0);
+        if offset == 0 || offset > size {
+            return Ok(None)
+        }
+
+        Ok(checksum::prefix_hasher(&partial, offset).ok().map(|h| (offset, h)))
+    });
+
+    let async_offset = async_prefix.and_then(|prefix| {
+        let mut buf = vec![0; 8 + HASH_LENGTH];
+        if let Some((offset, ref h)) = prefix {
+            BigEndian::write_u64(&mut buf[..8], offset);
+            buf[8..].copy_from_slice(&checksum::finish(h.clone()));
+        }
+
+        conn.write_bytes(buf)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_bytes([0; 1]))
+            .map(move |(c, accepted)| match prefix {
+                Some((offset, h)) if accepted[0] == 1 => (c, offset, h),
+                _ => (c, 0, Sha256::new()),
+            })
+    });
+
+    Box::new(async_offset) as ClusterFuture<_>
+}
+
+/// Compare the header against the local state of the path in destination,
+/// and itemize the change (if any).
+fn itemize(header: &EntryHeader, abs_path: &Path, options: &SyncOptions) -> Option<ChangeItem> {
+    let is_dir = header.file_type == FileType::Directory;
+    let change = match fs::symlink_metadata(abs_path) {
+        Ok(ref m) if !m.file_type().is_symlink() && m.is_dir() == is_dir => {
+            let mtime = FileTime::from_last_modification_time(m).unix_seconds();
+            let size = !is_dir && m.len() != header.size;
+            let time = !is_dir && mtime != header.mtime;
+            let perms = m.permissions().mode() & MODE_MASK != header.mode;
+            let (mut acls, mut xattrs) = (false, false);
+            if let Some(ref attrs) = header.xattrs {
+                let local = local_xattrs(abs_path, options).unwrap_or_default();
+                let differ = |acl| {
+                    let filter = |a: &&Xattr| xattrs::is_acl(&a.name) == acl;
+                    !attrs.iter().filter(&filter).eq(local.iter().filter(&filter))
+                };
+
+                acls = differ(true);
+                xattrs = differ(false);
+            }
+
+            if !(size || time || perms || acls || xattrs) {
+                return None
+            }
+
+            Change::Updated { size, time, perms, acls, xattrs }
+        },
+        _ => Change::Created,
+    };
+
+    Some(ChangeItem {
+        path: header.path.clone(),
+        is_dir,
+        change,
+    })
+}
+
+/// Remove the file or directory (if it exists) at the given path.
+fn remove_path(path: &Path) -> ClusterResult<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => (),
     }
+
+    Ok(())
+}
+
+/// Remove the paths under the received roots (in destination) which weren't received
+/// from the stream, and itemize them (parents before children). Excluded paths
+/// (and their parents) are left alone. In a dry run, nothing is removed.
+fn delete_extraneous(dest: &Path, received: &HashSet<PathBuf>,
+                     options: &SyncOptions) -> ClusterResult<Vec<ChangeItem>>
+{
+    let mut extraneous = vec![];
+    let mut protected = HashSet::new();
+
+    // Roots are the tips of the source (i.e., paths with a single component).
+    for root in received.iter().filter(|p| p.components().count() == 1) {
+        let mut entries = WalkDir::new(dest.join(root)).min_depth(1).into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            let rel_path = PathBuf::from(entry.path().strip_prefix(dest).unwrap());
+            let is_dir = entry.file_type().is_dir();
+
+            if options.is_excluded(&rel_path) {
+                if is_dir {
+                    entries.skip_current_dir();
+                }
+
+                let mut parent = rel_path.parent();
+                while let Some(p) = parent {
+                    protected.insert(PathBuf::from(p));
+                    parent = p.parent();
+                }
+            } else if !received.contains(&rel_path) {
+                extraneous.push((rel_path, is_dir));
+            }
+        }
+    }
+
+    extraneous.retain(|&(ref p, _)| !protected.contains(p));
+    if !options.dry_run {
+        // Children come after their parents, so remove them in reverse.
+        for &(ref rel_path, is_dir) in extraneous.iter().rev() {
+            let path = dest.join(rel_path);
+            info!("Deleting {}", path.display());
+            if is_dir {
+                fs::remove_dir(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+
+    Ok(extraneous.into_iter().map(|(path, is_dir)| ChangeItem {
+        path,
+        is_dir,
+        change: Change::Deleted,
+    }).collect())
+}
+
+/// Send the source path to the given writer (the way it's sent to a slave), with the writes
+/// buffered by the given capacity, and get the writer back. The destination in the stream
+/// is a placeholder (nothing receives it). This only exists for benchmarks.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub fn send_to_writer<W>(source: &Path, writer: W, write_capacity: usize) -> ClusterResult<W>
+    where W: AsyncWrite + 'static
+{
+    use connection::{ConnectionSettings, MAGIC_LENGTH};
+    use std::io::BufWriter;
+
+    let parts = (BufReader::new(io::Cursor::new(vec![])),
+                 BufWriter::with_capacity(write_capacity, writer),
+                 [0; MAGIC_LENGTH], ConnectionSettings::default());
+    let options = SyncOptions::default();
+    let conn = PathSync(Connection::from(parts))
+        .source_to_stream(source, "/tmp", &options, ProgressTracker::default())
+        .and_then(|c| c.flush())
+        .wait()?;
+    let (_, writer, _, _) = conn.into();
+    writer.into_inner().map_err(|e| ClusterError::from(io::Error::from(e)))
 }
 
 /* Tests */
@@ -154,50 +1628,92 @@ impl<R, W> PathSync<R, W>
 #[cfg(test)]
 mod tests {
     use byteorder::{BigEndian, ByteOrder};
-    use connection::Connection;
+    use connection::{ConnectionSettings, loopback, loopback_with, response};
+    use filetime::FileTime;
+    use flate2::Compression as Level;
+    use flate2::write::GzEncoder;
+    use fs_ops;
     use futures::Future;
-    use rand::{self, Rng};
-    use super::{FileType, PathSync};
+    use itemize::{Change, ChangeItem};
+    use progress::{Progress, ProgressTracker};
+    use rand::{self, RngCore};
+    use sha2::{Digest, Sha256};
+    use tar;
+    use super::{EntryHeader, FileType, PathSync, SyncOptions};
+    use super::{check_relative, delete_extraneous, itemize, unpack_archive};
+    use xattrs::{self, Xattr};
     use walkdir::WalkDir;
 
-    use std::fs::File;
-    use std::io::{BufReader, BufWriter, Cursor, Read};
-    use std::path::PathBuf;
+    use std::collections::HashSet;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs::{self, File, Metadata, Permissions};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    /// Header marking the end of entries.
+    const END_HEADER: [u8; 23] = [0, 0, 0, 0, 0, 0, 0, 0, FileType::End as u8, 0,
+                                  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, b'\n'];
+
+    /// Header bytes for the given path - size (zero for directories), flag, compression
+    /// (none), permission bits, modification time, path (encoded) and newline.
+    fn header_bytes(metadata: &Metadata, path: &str) -> Vec<u8> {
+        let mut out = vec![0; 22];
+        let flag = if metadata.is_dir() {
+            FileType::Directory
+        } else {
+            BigEndian::write_u64(&mut out[..8], metadata.len());
+            FileType::File
+        };
+
+        out[8] = flag as u8;
+        BigEndian::write_u32(&mut out[10..14], metadata.permissions().mode() & 0o7777);
+        let mtime = FileTime::from_last_modification_time(metadata).unix_seconds();
+        BigEndian::write_i64(&mut out[14..], mtime);
+        out.extend(path_line(path));
+        out
+    }
+
+    /// Line for the given path (as written by `Connection::write_path`).
+    fn path_line(path: &str) -> Vec<u8> {
+        format!("{}\n", fs_ops::encode_path(Path::new(path))).into_bytes()
+    }
 
     #[test]
     fn test_single_file_to_stream() {
         let mut magic = [0; 16];
-        let mut rng = rand::thread_rng();
-        let buf = Cursor::new(vec![]);
-        rng.fill_bytes(&mut magic);
-
-        let parts = (BufReader::new(buf.clone()), BufWriter::new(buf), magic);
-        let sync = PathSync(Connection::from(parts));
+        rand::thread_rng().fill_bytes(&mut magic);
+        let sync = PathSync(loopback(vec![], magic));
         let mut test_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         test_path.push("..");
         test_path.push("tests");
         test_path.push("test_path");
         test_path.push("foobar");
 
-        let conn = sync.source_to_stream(&test_path, "/tmp/foo").wait().unwrap();
-        let (_, writer, _) = conn.into();
-        let buf = writer.into_inner().unwrap().into_inner();
+        let options = SyncOptions::default();
+        let conn = sync.source_to_stream(&test_path, "/tmp/foo", &options, ProgressTracker::default())
+                       .wait().unwrap();
+        let buf = response(conn);
 
         let mut out = vec![];
         // begins with destination path
-        out.extend_from_slice(&b"/tmp/foo\n"[..]);
+        out.extend(path_line("/tmp/foo"));
+        // ... followed by options (no flags and no exclude patterns)
+        out.extend_from_slice(&[0, b'\n'][..]);
 
         let mut fd = File::open(&test_path).unwrap();
         let metadata = fd.metadata().unwrap();
-        let mut size_buf = [0; 8];
-        BigEndian::write_u64(&mut size_buf, metadata.len());
-        out.extend_from_slice(&size_buf[..]);   // file size in big endian
-
-        out.push(FileType::File as u8);         // flag for file
-        out.extend_from_slice(&b"foobar"[..]);  // file path (in this case, just the name)
-        out.push(10);
-        fd.read_to_end(&mut out).unwrap();      // file contents
+        // file path is just the name in this case.
+        out.extend(header_bytes(&metadata, "foobar"));
+        let mut contents = vec![];
+        fd.read_to_end(&mut contents).unwrap();
+        out.extend_from_slice(&contents);       // file contents
         out.extend_from_slice(&magic[..]);      // magic ends the stream
+        out.extend_from_slice(&Sha256::digest(&contents));      // ... followed by checksum
+        out.extend_from_slice(&END_HEADER[..]);
 
         assert_eq!(buf, out);
     }
@@ -205,24 +1721,28 @@ mod tests {
     #[test]
     fn test_recursive_path_to_stream() {
         let mut magic = [0; 16];
-        let mut rng = rand::thread_rng();
-        let buf = Cursor::new(vec![]);
-        rng.fill_bytes(&mut magic);
-
-        let parts = (BufReader::new(buf.clone()), BufWriter::new(buf), magic);
-        let sync = PathSync(Connection::from(parts));
+        rand::thread_rng().fill_bytes(&mut magic);
+        let sync = PathSync(loopback(vec![], magic));
         let mut test_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         test_dir_path.push("..");
         test_dir_path.push("tests");
         let test_parent = test_dir_path.clone();
         test_dir_path.push("test_path");
 
-        let conn = sync.source_to_stream(&test_dir_path, "/tmp/foo").wait().unwrap();
-        let (_, writer, _) = conn.into();
-        let buf = writer.into_inner().unwrap().into_inner();
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reports_clone = reports.clone();
+        let progress = ProgressTracker::new(move |p: &Progress| {
+            reports_clone.lock().unwrap().push(p.clone());
+        });
+
+        let options = SyncOptions::default();
+        let conn = sync.source_to_stream(&test_dir_path, "/tmp/foo", &options, progress)
+                       .wait().unwrap();
+        let buf = response(conn);
 
         let mut out = vec![];
-        out.extend_from_slice(&b"/tmp/foo\n"[..]);      // destination path
+        out.extend(path_line("/tmp/foo"));      // destination path
+        out.extend_from_slice(&[0, b'\n'][..]);         // options
 
         let walker = WalkDir::new(&test_dir_path);
         for entry in walker {
@@ -234,31 +1754,439 @@ mod tests {
 
             let metadata = entry.metadata().expect("metadata");
             let path = entry.path().strip_prefix(&test_parent).unwrap();
-            let path_str = path.to_string_lossy();
-
-            let mut size_buf = [0; 8];
-            let flag = if entry_type.is_file() {
-                BigEndian::write_u64(&mut size_buf, metadata.len());
-                FileType::File
-            } else {
-                FileType::Directory
-            };
-
-            // size of entry node
-            out.extend_from_slice(&size_buf[..]);
-            out.push(flag as u8);
-            // finally, the path itself
-            out.extend(path_str.as_bytes());
-            // ... terminated by newline.
-            out.push(10);
+            out.extend(header_bytes(&metadata, &path.to_string_lossy()));
 
             if entry_type.is_file() {   // write contents if it's a file.
                 let mut fd = File::open(entry.path()).unwrap();
-                fd.read_to_end(&mut out).unwrap();
+                let mut contents = vec![];
+                fd.read_to_end(&mut contents).unwrap();
+                out.extend_from_slice(&contents);
                 out.extend_from_slice(&magic[..]);
+                out.extend_from_slice(&Sha256::digest(&contents));
             }
         }
 
+        out.extend_from_slice(&END_HEADER[..]);
+        assert_eq!(buf, out);
+
+        // Every entry has been reported, and the last report marks the end.
+        let reports = reports.lock().unwrap();
+        let last = reports.last().unwrap();
+        assert!(reports.len() > WalkDir::new(&test_dir_path).into_iter().count());
+        assert!(last.finished);
+        assert!(last.bytes_total > 0);
+        assert_eq!(last.bytes_done, last.bytes_total);
+    }
+
+    #[test]
+    fn test_excluded_paths_in_stream() {
+        let magic = [0; 16];
+        let sync = PathSync(loopback(vec![], magic));
+        let mut test_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_dir_path.push("..");
+        test_dir_path.push("tests");
+        test_dir_path.push("test_path");
+
+        let mut options = SyncOptions { dry_run: true, ..SyncOptions::default() };
+        options.exclude("foo").unwrap();
+        let conn = sync.source_to_stream(&test_dir_path, "/tmp/foo", &options,
+                                         ProgressTracker::default()).wait().unwrap();
+        let buf = response(conn);
+
+        let mut out = vec![];
+        out.extend(path_line("/tmp/foo"));
+        out.extend_from_slice(&b"\x02foo\n\n"[..]);      // dry run flag and the pattern
+        // Nothing from the excluded directory, and no contents (since it's a dry run).
+        for path in &["test_path", "test_path/foobar"] {
+            let metadata = test_dir_path.join("..").join(path).metadata().unwrap();
+            out.extend(header_bytes(&metadata, path));
+        }
+
+        out.extend_from_slice(&END_HEADER[..]);
         assert_eq!(buf, out);
     }
+
+    #[test]
+    fn test_relative_paths() {
+        assert!(check_relative(Path::new("foo/bar")).is_ok());
+        assert!(check_relative(Path::new("./foo")).is_ok());
+        assert!(check_relative(Path::new("")).is_err());
+        assert!(check_relative(Path::new("/etc/passwd")).is_err());
+        assert!(check_relative(Path::new("foo/../../bar")).is_err());
+    }
+
+    #[test]
+    fn test_delete_extraneous_paths() {
+        let mut rng = rand::thread_rng();
+        let dest = env::temp_dir().join(format!("rcluster-delete-{}", rng.next_u64()));
+        let root = dest.join("root");
+        for dir in &["stale_dir", "logs"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        for file in &["keep", "stale", "stale_dir/foo", "logs/debug.log"] {
+            File::create(root.join(file)).unwrap();
+        }
+
+        let received = ["root", "root/keep"].iter().map(PathBuf::from).collect::<HashSet<_>>();
+        let mut options = SyncOptions { dry_run: true, ..SyncOptions::default() };
+        options.exclude("*.log").unwrap();
+
+        let expected = vec![PathBuf::from("root/stale"), PathBuf::from("root/stale_dir"),
+                            PathBuf::from("root/stale_dir/foo")];
+        let deleted_paths = |items: Vec<ChangeItem>| {
+            assert!(items.iter().all(|i| i.change == Change::Deleted));
+            let mut paths = items.into_iter().map(|i| i.path).collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        let deleted = delete_extraneous(&dest, &received, &options).unwrap();
+        assert_eq!(deleted_paths(deleted), expected);
+        assert!(root.join("stale_dir/foo").exists());     // nothing removed in a dry run
+
+        options.dry_run = false;
+        let deleted = delete_extraneous(&dest, &received, &options).unwrap();
+        assert_eq!(deleted_paths(deleted), expected);
+        assert!(root.join("keep").exists());
+        assert!(!root.join("stale").exists());
+        assert!(!root.join("stale_dir").exists());
+        // excluded file and its parent have been left alone.
+        assert!(root.join("logs/debug.log").exists());
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_itemize_local_state() {
+        let mut rng = rand::thread_rng();
+        let dest = env::temp_dir().join(format!("rcluster-itemize-{}", rng.next_u64()));
+        fs::create_dir_all(&dest).unwrap();
+        let path = dest.join("foo");
+        let header_for = |path: &Path| {
+            EntryHeader::new(PathBuf::from("foo"), &path.metadata().unwrap())
+        };
+
+        let options = SyncOptions::default();
+        File::create(&path).unwrap().write_all(b"foobar").unwrap();
+        let header = header_for(&path);
+        assert_eq!(itemize(&header, &path, &options), None);      // nothing has changed
+
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        let mut header = header_for(&path);
+        header.mode = 0o644;
+        header.size += 1;
+        let item = itemize(&header, &path, &options).unwrap();
+        assert_eq!(item.change, Change::Updated { size: true, time: false, perms: true,
+                                                  acls: false, xattrs: false });
+
+        // Attributes are compared only if they're synced.
+        if xattrs::is_supported(&dest) {
+            let options = SyncOptions { xattrs: true, ..SyncOptions::default() };
+            let mut header = header_for(&path);
+            header.xattrs = Some(vec![Xattr { name: "user.foo".into(), value: b"bar".to_vec() }]);
+            let item = itemize(&header, &path, &options).unwrap();
+            assert_eq!(item.change, Change::Updated { size: false, time: false, perms: false,
+                                                      acls: false, xattrs: true });
+            xattrs::set(&path, &header.xattrs.as_ref().unwrap()[0]).unwrap();
+            assert_eq!(itemize(&header, &path, &options), None);
+        }
+
+        let item = itemize(&header, &dest.join("bar"), &options).unwrap();
+        assert_eq!(item.change, Change::Created);
+
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();     // type has changed
+        let item = itemize(&header, &path, &options).unwrap();
+        assert_eq!(item.change, Change::Created);
+
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_archive_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().join(format!("rcluster-archive-{}", rng.next_u64()));
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("nested")).unwrap();
+        let mut contents = vec![0; 100 * 1024];
+        rng.fill_bytes(&mut contents);
+        File::create(source.join("big")).unwrap().write_all(&contents).unwrap();
+        File::create(source.join("nested/small")).unwrap().write_all(b"foobar").unwrap();
+        File::create(source.join("skipped.log")).unwrap();
+        fs::set_permissions(source.join("nested/small"), Permissions::from_mode(0o600)).unwrap();
+        ::std::os::unix::fs::symlink("/etc/passwd", source.join("link")).unwrap();
+
+        let mut magic = [0; 16];
+        rng.fill_bytes(&mut magic);
+        let mut options = SyncOptions { archive: true, ..SyncOptions::default() };
+        options.exclude("*.log").unwrap();
+        let conn = PathSync(loopback(vec![], magic))
+            .source_to_stream(&source, &dest, &options, ProgressTracker::default())
+            .wait().unwrap();
+        let stream = response(conn);
+
+        let (_, report) = PathSync(loopback(stream, magic)).stream_to_source().wait().unwrap();
+        assert!(report.mismatched.is_empty());
+        let mut created = report.changes.iter().map(|i| {
+            assert_eq!(i.change, Change::Created);
+            i.path.to_string_lossy().into_owned()
+        }).collect::<Vec<_>>();
+        created.sort();
+        assert_eq!(created, ["source", "source/big", "source/nested", "source/nested/small"]);
+
+        let mut out = vec![];
+        File::open(dest.join("source/big")).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, contents);
+        let metadata = dest.join("source/nested/small").metadata().unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        // Symlinks and excluded paths are never sent.
+        assert!(fs::symlink_metadata(dest.join("source/link")).is_err());
+        assert!(!dest.join("source/skipped.log").exists());
+
+        // Corrupted archive neither replaces the files, nor deletes the extraneous ones.
+        File::create(source.join("big")).unwrap().write_all(b"changed").unwrap();
+        File::create(dest.join("source/extra")).unwrap();
+        let options = SyncOptions { delete: true, ..options };
+        let conn = PathSync(loopback(vec![], magic))
+            .source_to_stream(&source, &dest, &options, ProgressTracker::default())
+            .wait().unwrap();
+        let mut stream = response(conn);
+        *stream.last_mut().unwrap() ^= 1;
+
+        let (_, report) = PathSync(loopback(stream, magic)).stream_to_source().wait().unwrap();
+        assert!(report.changes.is_empty());
+        assert_eq!(report.mismatched.len(), 4);
+        let mut out = vec![];
+        File::open(dest.join("source/big")).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, contents);
+        assert!(dest.join("source/extra").exists());
+        // Staging directory is gone.
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_stream_through_symlinks() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().join(format!("rcluster-stream-links-{}", rng.next_u64()));
+        let (dest, outside) = (root.join("dest"), root.join("outside"));
+        fs::create_dir_all(&dest).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        ::std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+        File::create(root.join("evil")).unwrap().write_all(b"evil").unwrap();
+
+        // Sender skips the entry for the symlinked directory.
+        let mut magic = [0; 16];
+        rng.fill_bytes(&mut magic);
+        let mut stream = path_line(&dest.to_string_lossy());
+        stream.extend_from_slice(&[0, b'\n'][..]);
+        stream.extend(header_bytes(&root.join("evil").metadata().unwrap(), "link/evil"));
+        stream.extend_from_slice(b"evil");
+        stream.extend_from_slice(&magic[..]);
+        stream.extend_from_slice(&Sha256::digest(b"evil"));
+        stream.extend_from_slice(&END_HEADER[..]);
+
+        assert!(PathSync(loopback(stream, magic)).stream_to_source().wait().is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_stream_outside_roots() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().canonicalize().unwrap()
+                                  .join(format!("rcluster-stream-roots-{}", rng.next_u64()));
+        let (source, inside) = (root.join("source"), root.join("inside"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&inside).unwrap();
+        File::create(source.join("foo")).unwrap();
+        ::std::os::unix::fs::symlink(&root, inside.join("escape")).unwrap();
+
+        let settings = ConnectionSettings { roots: vec![inside.clone()], ..Default::default() };
+        for dest in &[root.join("outside"), inside.join("escape")] {
+            let conn = PathSync(loopback(vec![], [0; 16]))
+                .source_to_stream(&source, dest, &SyncOptions::default(), ProgressTracker::default())
+                .wait().unwrap();
+            let conn = loopback_with(response(conn), [0; 16], settings.clone());
+            assert!(PathSync(conn).stream_to_source().wait().is_err());
+        }
+
+        assert!(!root.join("outside").exists());
+        assert!(!root.join("source/source").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_odd_names_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().join(format!("rcluster-odd-names-{}", rng.next_u64()));
+        // Names could have newlines, and they needn't be UTF-8.
+        let source = root.join(OsStr::from_bytes(b"source\n\xff"));
+        let name = OsStr::from_bytes(b"new\n\nline\xfe");
+        fs::create_dir_all(source.join(name)).unwrap();
+        File::create(source.join(name).join(name)).unwrap().write_all(b"foobar").unwrap();
+
+        for &archive in &[false, true] {
+            let dest = root.join(OsStr::from_bytes(format!("dest\n{}", archive).as_bytes()));
+            let mut magic = [0; 16];
+            rng.fill_bytes(&mut magic);
+            let options = SyncOptions { archive, ..SyncOptions::default() };
+            let conn = PathSync(loopback(vec![], magic))
+                .source_to_stream(&source, &dest, &options, ProgressTracker::default())
+                .wait().unwrap();
+            let (_, report) = PathSync(loopback(response(conn), magic)).stream_to_source()
+                                                                       .wait().unwrap();
+            assert!(report.mismatched.is_empty());
+            assert_eq!(report.changes.len(), 3);
+
+            let path = dest.join(source.file_name().unwrap()).join(name).join(name);
+            let mut out = vec![];
+            File::open(&path).unwrap().read_to_end(&mut out).unwrap();
+            assert_eq!(out, b"foobar");
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_sparse_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().join(format!("rcluster-sparse-{}", rng.next_u64()));
+        let source = root.join("source");
+        fs::create_dir_all(&source).unwrap();
+        // Data in the middle, with holes on either side.
+        let mut data = vec![0; 64 * 1024];
+        rng.fill_bytes(&mut data);
+        let size = 16 * 1024 * 1024;
+        let mut fd = File::create(source.join("sparse")).unwrap();
+        fd.seek(SeekFrom::Start(4 * 1024 * 1024)).unwrap();
+        fd.write_all(&data).unwrap();
+        fd.set_len(size).unwrap();
+        drop(fd);
+
+        for &archive in &[false, true] {
+            let dest = root.join(format!("dest-{}", archive));
+            let mut magic = [0; 16];
+            rng.fill_bytes(&mut magic);
+            let options = SyncOptions { archive, ..SyncOptions::default() };
+            let conn = PathSync(loopback(vec![], magic))
+                .source_to_stream(&source, &dest, &options, ProgressTracker::default())
+                .wait().unwrap();
+            let stream = response(conn);
+            // Holes aren't in the stream.
+            assert!((stream.len() as u64) < size / 4);
+
+            let (_, report) = PathSync(loopback(stream, magic)).stream_to_source().wait().unwrap();
+            assert!(report.mismatched.is_empty());
+
+            let path = dest.join("source/sparse");
+            let metadata = path.metadata().unwrap();
+            assert_eq!(metadata.len(), size);
+            assert!(metadata.blocks() * 512 < size);
+            let mut out = vec![];
+            File::open(&path).unwrap().read_to_end(&mut out).unwrap();
+            assert!(out[..4 * 1024 * 1024].iter().all(|&b| b == 0));
+            assert_eq!(&out[4 * 1024 * 1024..][..data.len()], &data[..]);
+            assert!(out[4 * 1024 * 1024 + data.len()..].iter().all(|&b| b == 0));
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_xattrs_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().join(format!("rcluster-xattrs-{}", rng.next_u64()));
+        let source = root.join("source");
+        fs::create_dir_all(&source).unwrap();
+        if !xattrs::is_supported(&root) {
+            return fs::remove_dir_all(&root).unwrap()
+        }
+
+        let attr = |name: &str, value: &[u8]| Xattr { name: name.into(), value: value.to_vec() };
+        File::create(source.join("foo")).unwrap();
+        xattrs::set(&source.join("foo"), &attr("user.foo", b"bar")).unwrap();
+        xattrs::set(&source, &attr("user.baz", b"")).unwrap();
+
+        for &archive in &[false, true] {
+            let dest = root.join(format!("dest-{}", archive));
+            fs::create_dir_all(dest.join("source")).unwrap();
+            // Extra attributes in destination are removed.
+            xattrs::set(&dest.join("source"), &attr("user.extra", b"1")).unwrap();
+
+            let mut magic = [0; 16];
+            rng.fill_bytes(&mut magic);
+            let options = SyncOptions { archive, xattrs: true, ..SyncOptions::default() };
+            let conn = PathSync(loopback(vec![], magic))
+                .source_to_stream(&source, &dest, &options, ProgressTracker::default())
+                .wait().unwrap();
+            let stream = response(conn);
+
+            let (_, report) = PathSync(loopback(stream, magic)).stream_to_source().wait().unwrap();
+            assert!(report.warnings.is_empty());
+            let dir_change = report.changes.iter().find(|i| i.is_dir).unwrap().change;
+            match dir_change {
+                Change::Updated { xattrs: true, .. } => (),
+                _ => panic!("Unexpected change for directory: {:?}", dir_change),
+            }
+
+            assert_eq!(xattrs::read(&dest.join("source")).unwrap(), [attr("user.baz", b"")]);
+            assert_eq!(xattrs::read(&dest.join("source/foo")).unwrap(), [attr("user.foo", b"bar")]);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Tar archive (with a single file) whose path is set as it is, without any checks.
+    fn raw_archive(path: &str, contents: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append(&header, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_unpack_foreign_archives() {
+        let mut rng = rand::thread_rng();
+        let root = env::temp_dir().join(format!("rcluster-unpack-{}", rng.next_u64()));
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+        let options = SyncOptions::default();
+
+        // Gzipped archive with "./" paths (and without entries for the parent directories).
+        let mut encoder = GzEncoder::new(vec![], Level::default());
+        encoder.write_all(&raw_archive("./foo/bar", b"foobar")).unwrap();
+        let archive = encoder.finish().unwrap();
+        let (received, mut report, staged) = unpack_archive(Cursor::new(archive), &dest, &options)
+                                                 .unwrap();
+        assert_eq!(received, [PathBuf::from("foo/bar")].iter().cloned().collect());
+        // Nothing is in the destination until the staged entries are applied.
+        assert!(!dest.join("foo").exists());
+        staged.apply(&dest, &options, &mut report).unwrap();
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 1);
+        let mut out = vec![];
+        File::open(dest.join("foo/bar")).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"foobar");
+
+        // Paths can't escape the destination (not even through symlinks in destination).
+        let archive = raw_archive("../escaped", b"evil");
+        assert!(unpack_archive(Cursor::new(archive), &dest, &options).is_err());
+        assert!(!root.join("escaped").exists());
+
+        ::std::os::unix::fs::symlink(&root, dest.join("link")).unwrap();
+        let archive = raw_archive("link/escaped", b"evil");
+        assert!(unpack_archive(Cursor::new(archive), &dest, &options).is_err());
+        assert!(!root.join("escaped").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
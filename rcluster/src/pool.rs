@@ -0,0 +1,312 @@
+use buffered::BUFFER_SIZE;
+use errors::{ClusterError, ClusterFuture};
+use futures::{Async, Future};
+use futures_cpupool::{CpuFuture, CpuPool};
+use libc;
+use sparse::{self, Extent, ExtentReader, ExtentWriter};
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+
+lazy_static! {
+    /// Thread pool for the blocking file IO, so that it doesn't block the event loop.
+    static ref FILE_POOL: CpuPool = CpuPool::new_num_cpus();
+}
+
+/// Run the given (blocking) function in the file IO pool.
+pub fn run<F, T, E>(f: F) -> ClusterFuture<T>
+    where F: FnOnce() -> Result<T, E> + Send + 'static, T: Send + 'static, E: Send + 'static,
+          ClusterError: From<E>
+{
+    Box::new(FILE_POOL.spawn_fn(f).map_err(ClusterError::from)) as ClusterFuture<T>
+}
+
+/// Create a pipe (whose ends aren't inherited by other processes). This returns the
+/// reading and writing ends, in that order.
+pub fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Error returned by the pooled readers and writers when an operation is in progress
+/// in the pool. The current task will be notified once the operation has completed.
+fn would_block() -> io::Error {
+    io::Error::new(ErrorKind::WouldBlock, "waiting for file IO")
+}
+
+/// Reader which reads from a blocking reader (a file, by default) in chunks in a pool (the
+/// file IO pool, by default). This should only be used from within a task.
+pub struct PoolReader<T = File> {
+    pool: CpuPool,
+    file: Option<T>,
+    chunk: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    pending: Option<CpuFuture<(T, Vec<u8>), io::Error>>,
+}
+
+impl<T: Read + Send + 'static> PoolReader<T> {
+    /// Read from the given blocking reader in the file IO pool.
+    pub fn new(inner: T) -> Self {
+        PoolReader::with_pool(inner, FILE_POOL.clone())
+    }
+
+    /// Read from the given blocking reader in its own thread. This is for readers which
+    /// could block indefinitely (say, a pipe of a child process), so that they don't
+    /// hold up the pool.
+    pub fn with_thread(inner: T) -> Self {
+        PoolReader::with_pool(inner, CpuPool::new(1))
+    }
+
+    fn with_pool(inner: T, pool: CpuPool) -> Self {
+        PoolReader {
+            pool,
+            file: Some(inner),
+            chunk: Vec::with_capacity(BUFFER_SIZE),
+            pos: 0,
+            eof: false,
+            pending: None,
+        }
+    }
+}
+
+impl PoolReader {
+    /// Open the file at the given path for reading from the given offset.
+    pub fn open(path: PathBuf, offset: u64) -> ClusterFuture<Self> {
+        run(move || -> io::Result<_> {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            Ok(PoolReader::new(file))
+        })
+    }
+}
+
+impl PoolReader<ExtentReader<File>> {
+    /// Open the file at the given path for reading the given extents.
+    pub fn open_extents(path: PathBuf, extents: Vec<Extent>) -> ClusterFuture<Self> {
+        run(move || -> io::Result<_> {
+            Ok(PoolReader::new(ExtentReader::new(File::open(path)?, extents)))
+        })
+    }
+}
+
+impl<T: Read + Send + 'static> Read for PoolReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let len = cmp::min(buf.len(), self.chunk.len() - self.pos);
+                buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len)
+            }
+
+            if self.eof {
+                return Ok(0)
+            }
+
+            if self.pending.is_none() {
+                let mut file = self.file.take().expect("reading after failure");
+                let mut chunk = mem::replace(&mut self.chunk, vec![]);
+                // Read as much as the caller wants (so that larger buffers get larger reads).
+                let len = cmp::max(buf.len(), BUFFER_SIZE);
+                self.pending = Some(self.pool.spawn_fn(move || {
+                    chunk.resize(len, 0);
+                    let len = file.read(&mut chunk)?;
+                    chunk.truncate(len);
+                    Ok((file, chunk))
+                }));
+            }
+
+            match self.pending.as_mut().unwrap().poll()? {
+                Async::Ready((file, chunk)) => {
+                    self.pending = None;
+                    self.file = Some(file);
+                    self.eof = chunk.is_empty();
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Async::NotReady => return Err(would_block()),
+            }
+        }
+    }
+}
+
+/// Writer which writes to a blocking writer (a file, by default) in chunks in a pool (the
+/// file IO pool, by default). This should only be used from within a task, and it should
+/// be flushed before dropping (or the unwritten bytes will be lost).
+pub struct PoolWriter<T = File> {
+    pool: CpuPool,
+    file: Option<T>,
+    chunk: Vec<u8>,
+    pending: Option<CpuFuture<T, io::Error>>,
+}
+
+impl<T: Write + Send + 'static> PoolWriter<T> {
+    /// Write to the given blocking writer in the file IO pool.
+    pub fn new(inner: T) -> Self {
+        PoolWriter::with_pool(inner, FILE_POOL.clone())
+    }
+
+    /// Write to the given blocking writer in its own thread. This is for writers which
+    /// could block indefinitely (say, stdout or a pipe of a child process), so that they
+    /// don't hold up the pool.
+    pub fn with_thread(inner: T) -> Self {
+        PoolWriter::with_pool(inner, CpuPool::new(1))
+    }
+
+    fn with_pool(inner: T, pool: CpuPool) -> Self {
+        PoolWriter {
+            pool,
+            file: Some(inner),
+            chunk: Vec::with_capacity(BUFFER_SIZE),
+            pending: None,
+        }
+    }
+
+}
+
+impl PoolWriter {
+    /// Open (or create) the file at the given path for writing. The file is truncated
+    /// to the given offset, and the writes are appended from there. The blocks up to
+    /// the given size are allocated upfront.
+    pub fn open(path: PathBuf, offset: u64, size: u64) -> ClusterFuture<Self> {
+        run(move || -> io::Result<_> {
+            let mut file = OpenOptions::new().write(true).create(true).open(path)?;
+            file.set_len(offset)?;
+            if size > offset {
+                sparse::allocate(&file, &[Extent { offset, len: size - offset }])?;
+            }
+
+            file.seek(SeekFrom::End(0))?;
+            Ok(PoolWriter::new(file))
+        })
+    }
+}
+
+impl PoolWriter<ExtentWriter<File>> {
+    /// Open (or create) the file at the given path for writing into the given extents (which
+    /// should come after the offset). The file is truncated to the given offset, and the
+    /// blocks for the extents are allocated upfront.
+    pub fn open_extents(path: PathBuf, offset: u64, extents: Vec<Extent>) -> ClusterFuture<Self> {
+        run(move || -> io::Result<_> {
+            let file = OpenOptions::new().write(true).create(true).open(path)?;
+            file.set_len(offset)?;
+            sparse::allocate(&file, &extents)?;
+            Ok(PoolWriter::new(ExtentWriter::new(file, extents)))
+        })
+    }
+}
+
+impl<T: Write + Send + 'static> PoolWriter<T> {
+    /// Check whether the previous write has completed.
+    fn poll_pending(&mut self) -> io::Result<()> {
+        let file = match self.pending {
+            Some(ref mut f) => match f.poll()? {
+                Async::Ready(file) => file,
+                Async::NotReady => return Err(would_block()),
+            },
+            None => return Ok(()),
+        };
+
+        self.pending = None;
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Write the current chunk in the pool.
+    fn write_chunk(&mut self) {
+        let mut file = self.file.take().expect("writing after failure");
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(BUFFER_SIZE));
+        self.pending = Some(self.pool.spawn_fn(move || {
+            file.write_all(&chunk).and_then(|_| file.flush()).map(|_| file)
+        }));
+    }
+}
+
+impl<T: Write + Send + 'static> Write for PoolWriter<T> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.poll_pending()?;
+        self.chunk.extend_from_slice(bytes);
+        if self.chunk.len() >= BUFFER_SIZE {
+            self.write_chunk();
+        }
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.poll_pending()?;
+        if !self.chunk.is_empty() {
+            self.write_chunk();
+            self.poll_pending()?;
+        }
+
+        Ok(())
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Future, future};
+    use rand::{self, RngCore};
+    use super::{PoolReader, PoolWriter};
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{ErrorKind, Read, Write};
+
+    #[test]
+    fn test_pooled_reads_and_writes() {
+        let mut rng = rand::thread_rng();
+        let mut content = vec![0; 100 * 1024 + 7];
+        rng.fill_bytes(&mut content);
+        let path = env::temp_dir().join(format!("rcluster-pool-{}", rng.next_u64()));
+        File::create(&path).unwrap().write_all(&content[..1024]).unwrap();
+
+        // Append everything after 1 KB, one byte at a time.
+        let mut writer = PoolWriter::open(path.clone(), 1024, content.len() as u64).wait().unwrap();
+        let mut written = 1024;
+        future::poll_fn(|| {
+            while written < content.len() {
+                match writer.write(&content[written..written + 1]) {
+                    Ok(n) => written += n,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            match writer.flush() {
+                Ok(()) => Ok(Async::Ready(())),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(Async::NotReady),
+                Err(e) => Err(e),
+            }
+        }).wait().unwrap();
+
+        let mut reader = PoolReader::open(path.clone(), 7).wait().unwrap();
+        let mut out = vec![];
+        future::poll_fn(|| {
+            let mut buf = [0; 1000];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => return Ok(Async::Ready(())),
+                    Ok(n) => out.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                    Err(e) => return Err(e),
+                }
+            }
+        }).wait().unwrap();
+
+        assert_eq!(&out[..], &content[7..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,534 @@
+use connection::{Connection, ConnectionFlag};
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use fs_ops;
+use futures::Future;
+use glob::Pattern;
+use libc;
+use pool;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Processes spawned by the slave (for executing the master's commands), along with their
+    /// commands. Each of them leads a process group of its own, and so their descendants can
+    /// be found (and signalled) with them.
+    static ref SPAWNED: Mutex<HashMap<u32, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Track a process spawned by the slave (until it's been awaited).
+pub fn track(pid: u32, args: &[String]) {
+    SPAWNED.lock().unwrap().insert(pid, args.to_vec());
+}
+
+/// Stop tracking a process once it's been awaited.
+pub fn untrack(pid: u32) {
+    SPAWNED.lock().unwrap().remove(&pid);
+}
+
+fn spawned_groups() -> HashSet<u32> {
+    SPAWNED.lock().unwrap().keys().cloned().collect()
+}
+
+/// Wrapper for managing the processes in the slave. The master sends the operation's flag,
+/// followed by two arguments (one per line) and a byte for its option. For listing, those
+/// are the name pattern and the user (either of them could be empty), and whether only the
+/// processes spawned by the slave should be listed. For signalling, those are the signal
+/// number and the target, and whether the process groups of the targets should be signalled.
+/// The slave replies like it does for the filesystem operations (see `FsOps`).
+pub struct Processes<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
+
+/// Filters for listing the processes (a process should match all of them).
+#[derive(Clone, Debug, Default)]
+pub struct ProcessFilter {
+    /// Glob pattern for the name of the process (or the file name of its executable).
+    pub name: Option<String>,
+    /// User (name or UID) owning the process.
+    pub user: Option<String>,
+    /// List only the processes spawned by the slave (and their descendants).
+    pub spawned: bool,
+}
+
+/// Processes to be signalled.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProcessTarget {
+    Pids(Vec<u32>),
+    /// Processes whose names match the glob pattern (like `ProcessFilter`).
+    Name(String),
+    /// Commands spawned by the slave (along with their descendants).
+    Spawned,
+}
+
+impl ProcessTarget {
+    fn to_line(&self) -> String {
+        match *self {
+            ProcessTarget::Pids(ref pids) => {
+                let pids = pids.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                format!("pids {}", pids.join(" "))
+            },
+            ProcessTarget::Name(ref pattern) => format!("name {}", pattern),
+            ProcessTarget::Spawned => String::from("spawned"),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next()?, parts.next()) {
+            ("pids", Some(pids)) => {
+                pids.split(' ').map(|p| p.parse().ok()).collect::<Option<_>>().map(ProcessTarget::Pids)
+            },
+            ("name", Some(pattern)) => Some(ProcessTarget::Name(pattern.to_owned())),
+            ("spawned", None) => Some(ProcessTarget::Spawned),
+            _ => None,
+        }
+    }
+}
+
+/// Information about a process in the slave.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    /// Process group.
+    pub pgid: u32,
+    pub uid: u32,
+    /// Name of the user (or the UID, if it doesn't have one).
+    pub user: String,
+    /// State (`R` for running, `S` for sleeping, `Z` for zombie, etc.)
+    pub state: char,
+    /// Resident memory (in bytes).
+    pub rss: u64,
+    /// Whether this has been spawned by the slave (or by one of those processes).
+    pub spawned: bool,
+    /// Name (as shown by `ps`, which is truncated to 15 bytes).
+    pub name: String,
+    /// Command line (empty for kernel threads and zombies).
+    pub args: Vec<String>,
+}
+
+impl ProcessInfo {
+    fn to_line(&self) -> String {
+        // Arguments could have spaces, but they can't have nulls.
+        let mut line = format!("{} {} {} {} {} {} {} {} {}", self.pid, self.ppid, self.pgid,
+                               self.uid, self.state, self.rss, self.spawned as u8, self.user,
+                               self.name);
+        for arg in &self.args {
+            line.push('\0');
+            line.push_str(arg);
+        }
+
+        line.replace('\n', " ")
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(9, ' ');
+        let (pid, ppid, pgid, uid) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?,
+                                      parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+        let state = parts.next()?.chars().next()?;
+        let rss = parts.next()?.parse().ok()?;
+        let spawned = parts.next()? == "1";
+        let user = parts.next()?.to_owned();
+        let mut rest = parts.next()?.split('\0');
+        Some(ProcessInfo {
+            pid, ppid, pgid, uid, state, rss, spawned, user,
+            name: rest.next()?.to_owned(),
+            args: rest.map(String::from).collect(),
+        })
+    }
+
+    fn matches(&self, name: Option<&Pattern>, user: Option<&str>) -> bool {
+        let exe = self.args.first().and_then(|a| Path::new(a).file_name())
+                                   .map(|n| n.to_string_lossy().into_owned());
+        let name_matches = name.map(|p| {
+            p.matches(&self.name) || exe.map(|e| p.matches(&e)).unwrap_or(false)
+        }).unwrap_or(true);
+        let user_matches = user.map(|u| u == self.user || u == self.uid.to_string()).unwrap_or(true);
+        name_matches && user_matches
+    }
+}
+
+/// Result of signalling a process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignalResult {
+    pub pid: u32,
+    /// Error (if the process couldn't be signalled).
+    pub error: Option<String>,
+}
+
+impl SignalResult {
+    fn to_line(&self) -> String {
+        match self.error {
+            Some(ref e) => format!("{} {}", self.pid, e),
+            None => self.pid.to_string(),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(2, ' ');
+        Some(SignalResult {
+            pid: parts.next()?.parse().ok()?,
+            error: parts.next().map(String::from),
+        })
+    }
+}
+
+/// Get the number of a signal from its name (with or without the `SIG` prefix) or number.
+pub fn signal_number(signal: &str) -> Option<i32> {
+    if let Ok(n) = signal.parse() {
+        return Some(n)
+    }
+
+    let name = signal.to_uppercase();
+    let number = match name.trim_start_matches("SIG") {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "STOP" => libc::SIGSTOP,
+        "CONT" => libc::SIGCONT,
+        _ => return None,
+    };
+
+    Some(number)
+}
+
+impl<R, W> Processes<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    /// (Master) List the processes in the other end which match the filter.
+    pub fn list(self, filter: &ProcessFilter)
+               -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<ProcessInfo>>)> {
+        let args = [filter.name.clone().unwrap_or_default(), filter.user.clone().unwrap_or_default()];
+        let async_list = self.request(&args, filter.spawned).map(|(c, result)| {
+            let procs = result.and_then(|lines| {
+                lines.iter().map(|l| ProcessInfo::from_line(l)).collect::<Option<Vec<_>>>()
+                     .ok_or(ClusterError::InvalidReport)
+            });
+
+            (c, procs)
+        });
+
+        Box::new(async_list) as ClusterFuture<_>
+    }
+
+    /// (Master) Send the signal to the target processes in the other end (or their process
+    /// groups, if `group` is set).
+    pub fn signal(self, target: &ProcessTarget, signal: i32,
+                  group: bool) -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<SignalResult>>)> {
+        let args = [signal.to_string(), target.to_line()];
+        let async_signal = self.request(&args, group).map(|(c, result)| {
+            let results = result.and_then(|lines| {
+                lines.iter().map(|l| SignalResult::from_line(l)).collect::<Option<Vec<_>>>()
+                     .ok_or(ClusterError::InvalidReport)
+            });
+
+            (c, results)
+        });
+
+        Box::new(async_signal) as ClusterFuture<_>
+    }
+
+    fn request(self, args: &[String; 2],
+               option: bool) -> ClusterFuture<(Connection<R, W>, ClusterResult<Vec<String>>)> {
+        let bytes = format!("{}\n{}\n", args[0], args[1]).into_bytes();
+        let async_request = self.0.write_bytes(bytes)
+            .and_then(move |c| c.write_bytes([option as u8]))
+            .and_then(|c| c.flush())
+            .and_then(fs_ops::read_result);
+
+        Box::new(async_request) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request for the operation (with the given flag), perform it, and send
+    /// the result (or the error).
+    pub fn serve(self, flag: ConnectionFlag) -> ClusterFuture<Connection<R, W>> {
+        let async_read = self.0.read_line()
+            .and_then(|(c, first)| c.read_line().map(move |(c, second)| (c, first, second)))
+            .and_then(|(c, first, second)| {
+                c.read_bytes([0; 1]).map(move |(c, option)| (c, first, second, option[0] != 0))
+            });
+
+        let async_serve = async_read.and_then(move |(c, first, second, option)| {
+            pool::run(move || perform(flag, &first, &second, option)).then(|result| {
+                if let Err(ref e) = result {
+                    info!("Process operation failed: {}", e);
+                }
+
+                fs_ops::write_result(c, result)
+            })
+        });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+}
+
+/// Perform the operation (with the given flag), and get its result lines.
+fn perform(flag: ConnectionFlag, first: &str, second: &str,
+           option: bool) -> ClusterResult<Vec<String>> {
+    match flag {
+        ConnectionFlag::MasterListsProcesses => {
+            let filter = ProcessFilter {
+                name: Some(first).filter(|n| !n.is_empty()).map(String::from),
+                user: Some(second).filter(|u| !u.is_empty()).map(String::from),
+                spawned: option,
+            };
+
+            Ok(list(&filter)?.iter().map(|p| p.to_line()).collect())
+        },
+        ConnectionFlag::MasterSignalsProcesses => {
+            let signal = first.parse().map_err(|_| ClusterError::InvalidReport)?;
+            let target = ProcessTarget::from_line(second).ok_or(ClusterError::InvalidReport)?;
+            Ok(signal_processes(&target, signal, option)?.iter().map(|r| r.to_line()).collect())
+        },
+        _ => Err(ClusterError::UnknownFlag),
+    }
+}
+
+/// List the processes (from `/proc`) which match the filter, sorted by their PIDs.
+pub fn list(filter: &ProcessFilter) -> ClusterResult<Vec<ProcessInfo>> {
+    let name = match filter.name {
+        Some(ref n) => Some(Pattern::new(n)?),
+        None => None,
+    };
+
+    let spawned = spawned_groups();
+    let mut users = HashMap::new();
+    let mut procs = vec![];
+    for entry in fs::read_dir("/proc")? {
+        let pid = match entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        // The process could've exited by now.
+        let mut info = match read_process(pid) {
+            Ok(info) => info,
+            Err(_) => continue,
+        };
+
+        info.spawned = spawned.contains(&info.pgid);
+        info.user = users.entry(info.uid).or_insert_with(|| user_name(info.uid)).clone();
+        let user = filter.user.as_ref().map(|u| u.as_str());
+        if (filter.spawned && !info.spawned) || !info.matches(name.as_ref(), user) {
+            continue
+        }
+
+        procs.push(info);
+    }
+
+    procs.sort_by_key(|p| p.pid);
+    Ok(procs)
+}
+
+/// Read the information of a process (except for its user name, and whether it's been spawned).
+fn read_process(pid: u32) -> io::Result<ProcessInfo> {
+    let dir = Path::new("/proc").join(pid.to_string());
+    let uid = fs::metadata(&dir)?.uid();
+    let stat = fs::read(dir.join("stat"))?;
+    let mut info = parse_stat(&String::from_utf8_lossy(&stat)).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "malformed stat")
+    })?;
+
+    let cmdline = fs::read(dir.join("cmdline"))?;
+    info.args = cmdline.split(|&b| b == 0).filter(|a| !a.is_empty())
+                       .map(|a| String::from_utf8_lossy(a).into_owned()).collect();
+    info.uid = uid;
+    Ok(info)
+}
+
+/// Parse the PID, name, state, parent, process group and resident memory from the content
+/// of `/proc/<pid>/stat`.
+fn parse_stat(stat: &str) -> Option<ProcessInfo> {
+    // Name is in parentheses, and it could have anything (including parentheses).
+    let (open, close) = (stat.find('(')?, stat.rfind(')')?);
+    let fields = stat.get(close + 1..)?.split_whitespace().collect::<Vec<_>>();
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Some(ProcessInfo {
+        pid: stat[..open].trim().parse().ok()?,
+        name: stat.get(open + 1..close)?.to_owned(),
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        pgid: fields.get(2)?.parse().ok()?,
+        // Field 24 (RSS in pages) comes 21 fields after the state.
+        rss: fields.get(21)?.parse::<u64>().ok()? * page_size,
+        uid: 0,
+        user: String::new(),
+        spawned: false,
+        args: vec![],
+    })
+}
+
+/// Name of the user with the given UID (or the UID itself, if there's no such user).
+fn user_name(uid: u32) -> String {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+
+    if ret != 0 || result.is_null() {
+        return uid.to_string()
+    }
+
+    unsafe { CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned()
+}
+
+/// Send the signal to the target processes (or their process groups). The slave itself
+/// is never signalled.
+pub fn signal_processes(target: &ProcessTarget, signal: i32,
+                        group: bool) -> ClusterResult<Vec<SignalResult>> {
+    let (pids, group) = match *target {
+        ProcessTarget::Pids(ref pids) => (pids.clone(), group),
+        ProcessTarget::Name(ref pattern) => {
+            let filter = ProcessFilter { name: Some(pattern.clone()), ..ProcessFilter::default() };
+            let matched = list(&filter)?.into_iter();
+            if group {
+                // The matches needn't lead their groups, so signal the groups they are in.
+                // Kernel threads are in the group 0, and init leads the group 1.
+                let mut pgids = matched.map(|p| p.pgid).filter(|&g| g > 1).collect::<Vec<_>>();
+                pgids.sort();
+                pgids.dedup();
+                (pgids, true)
+            } else {
+                (matched.map(|p| p.pid).collect(), false)
+            }
+        },
+        ProcessTarget::Spawned => {
+            let mut pids = spawned_groups().into_iter().collect::<Vec<_>>();
+            pids.sort();
+            (pids, true)
+        },
+    };
+
+    let own = process::id();
+    let own_group = unsafe { libc::getpgrp() } as u32;
+    let results = pids.into_iter().map(|pid| {
+        let error = if pid == own || (group && pid == own_group) {
+            Some(String::from("refusing to signal the slave"))
+        } else if pid == 0 || (group && pid == 1) || pid > i32::max_value() as u32 {
+            // These would signal the slave's group, or every process we can reach.
+            Some(String::from("refusing to signal special process IDs"))
+        } else {
+            // Negative PID is the process group.
+            let target = if group { -(pid as i32) } else { pid as i32 };
+            match unsafe { libc::kill(target, signal) } {
+                0 => {
+                    info!("Sent signal {} to {}{}", signal, if group { "group " } else { "" }, pid);
+                    None
+                },
+                _ => Some(io::Error::last_os_error().to_string()),
+            }
+        };
+
+        SignalResult { pid, error }
+    }).collect();
+
+    Ok(results)
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, RngCore};
+    use super::{ProcessFilter, ProcessInfo, ProcessTarget, list, parse_stat, signal_number};
+    use super::signal_processes;
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::{self, Command};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stat_and_lines() {
+        let stat = "4242 (tmux: server) S 1 4242 4242 0 -1 4194560 1 0 0 0 3 5 0 0 20 0 1 0 \
+                    100 25280512 3 18446744073709551615 1 1 0 0 0 0 0 0 0 0 0 0 17 2 0 0 0 0 0";
+        let mut info = parse_stat(stat).unwrap();
+        assert_eq!((info.pid, info.ppid, info.pgid, info.state), (4242, 1, 4242, 'S'));
+        assert_eq!(info.name, "tmux: server");
+        assert!(info.rss >= 3 * 4096);
+
+        info.user = String::from("root");
+        info.args = vec![String::from("tmux"), String::from("new -s foo")];
+        assert_eq!(ProcessInfo::from_line(&info.to_line()), Some(info));
+
+        for target in &[ProcessTarget::Pids(vec![1, 2]), ProcessTarget::Name(String::from("foo *")),
+                        ProcessTarget::Spawned] {
+            assert_eq!(ProcessTarget::from_line(&target.to_line()).as_ref(), Some(target));
+        }
+
+        assert_eq!(signal_number("SIGTERM"), Some(15));
+        assert_eq!(signal_number("kill"), Some(9));
+        assert_eq!(signal_number("foo"), None);
+    }
+
+    #[test]
+    fn test_list_and_signal() {
+        let own = list(&ProcessFilter::default()).unwrap();
+        assert!(own.iter().any(|p| p.pid == process::id() && !p.args.is_empty()));
+
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let filter = ProcessFilter { name: Some(String::from("sle?p")), ..ProcessFilter::default() };
+        assert!(list(&filter).unwrap().iter().any(|p| p.pid == child.id()));
+        let filter = ProcessFilter { spawned: true, ..filter };
+        assert!(list(&filter).unwrap().iter().all(|p| p.pid != child.id()));
+
+        let results = signal_processes(&ProcessTarget::Pids(vec![child.id(), process::id()]),
+                                       9, false).unwrap();
+        assert_eq!(results[0].error, None);
+        assert!(results[1].error.is_some());
+        assert_eq!(child.wait().unwrap().signal(), Some(9));
+
+        // Special IDs would signal the slave's group, or everything.
+        let results = signal_processes(&ProcessTarget::Pids(vec![0, 1, u32::max_value()]),
+                                       0, true).unwrap();
+        assert!(results.iter().all(|r| r.error.is_some()));
+        let results = signal_processes(&ProcessTarget::Pids(vec![0]), 0, false).unwrap();
+        assert!(results[0].error.is_some());
+    }
+
+    #[test]
+    fn test_signal_group_by_name() {
+        // The matched process is a child of the group leader, under a name of its own.
+        let name = format!("rcluster-child-{}", rand::thread_rng().next_u64());
+        let link = env::temp_dir().join(&name);
+        symlink("/bin/sleep", &link).unwrap();
+        let mut leader = Command::new("sh").arg("-c").arg("\"$0\" 30 & wait").arg(&link)
+            .process_group(0).spawn().unwrap();
+
+        let filter = ProcessFilter { name: Some(name.clone()), ..ProcessFilter::default() };
+        let mut matched = list(&filter).unwrap();
+        for _ in 0..100 {
+            if !matched.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            matched = list(&filter).unwrap();
+        }
+        fs::remove_file(&link).unwrap();
+        assert_eq!(matched.len(), 1);
+        assert!(matched[0].pid != leader.id());
+        assert_eq!(matched[0].pgid, leader.id());
+
+        let results = signal_processes(&ProcessTarget::Name(name), 9, true).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!((results[0].pid, results[0].error.as_ref()), (leader.id(), None));
+        assert_eq!(leader.wait().unwrap().signal(), Some(9));
+    }
+}
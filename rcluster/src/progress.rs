@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Minimum interval between reports in the middle of a file.
+const REPORT_INTERVAL_MS: u64 = 100;
+
+/// Progress of a transfer.
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    /// Bytes sent so far (including the bytes skipped while resuming).
+    pub bytes_done: u64,
+    /// Total bytes that should be sent.
+    pub bytes_total: u64,
+    /// Path (relative to the source's parent) that's currently being sent.
+    pub current_file: PathBuf,
+    /// Average rate (bytes per second) of the bytes that have actually been sent.
+    pub rate: f64,
+    /// Whether the transfer has completed.
+    pub finished: bool,
+}
+
+impl Progress {
+    /// Estimated time for sending the remaining bytes (based on the average rate).
+    pub fn eta(&self) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None
+        }
+
+        let secs = self.bytes_total.saturating_sub(self.bytes_done) as f64 / self.rate;
+        Some(Duration::from_millis((secs * 1000.0) as u64))
+    }
+}
+
+struct TrackerState {
+    progress: Progress,
+    /// Bytes that have actually been sent (i.e., not skipped).
+    transferred: u64,
+    started: Instant,
+    last_report: Instant,
+    callback: Box<FnMut(&Progress) + Send>,
+}
+
+impl TrackerState {
+    fn report(&mut self) {
+        let elapsed = self.started.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        if secs > 0.0 {
+            self.progress.rate = self.transferred as f64 / secs;
+        }
+
+        self.last_report = Instant::now();
+        (self.callback)(&self.progress);
+    }
+}
+
+/// Tracker which updates the progress of a transfer and reports it to a callback.
+/// The default tracker doesn't track anything.
+#[derive(Clone, Default)]
+pub struct ProgressTracker(Option<Arc<Mutex<TrackerState>>>);
+
+impl ProgressTracker {
+    /// Create a tracker which reports the progress to the given callback.
+    pub fn new<F>(callback: F) -> Self
+        where F: FnMut(&Progress) + Send + 'static
+    {
+        let now = Instant::now();
+        ProgressTracker(Some(Arc::new(Mutex::new(TrackerState {
+            progress: Progress::default(),
+            transferred: 0,
+            started: now,
+            last_report: now,
+            callback: Box::new(callback),
+        }))))
+    }
+
+    /// Whether this tracker reports anything.
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Set the total bytes of the transfer.
+    pub fn set_total(&self, bytes: u64) {
+        if let Some(ref state) = self.0 {
+            state.lock().unwrap().progress.bytes_total = bytes;
+        }
+    }
+
+    /// Mark the beginning of a file. Skipped bytes (if any) are the ones which
+    /// the destination already has.
+    pub fn start_file(&self, path: &Path, skipped: u64) {
+        if let Some(ref state) = self.0 {
+            let mut state = state.lock().unwrap();
+            state.progress.current_file = PathBuf::from(path);
+            state.progress.bytes_done += skipped;
+            state.report();
+        }
+    }
+
+    /// Account for the bytes that have been sent.
+    pub fn advance(&self, bytes: u64) {
+        if let Some(ref state) = self.0 {
+            let mut state = state.lock().unwrap();
+            state.progress.bytes_done += bytes;
+            state.transferred += bytes;
+            if state.last_report.elapsed() >= Duration::from_millis(REPORT_INTERVAL_MS) {
+                state.report();
+            }
+        }
+    }
+
+    /// Mark the end of the transfer.
+    pub fn finish(&self) {
+        if let Some(ref state) = self.0 {
+            let mut state = state.lock().unwrap();
+            state.progress.finished = true;
+            state.report();
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use errors::{ClusterError, ClusterFuture};
+use futures::{Async, Future, future};
+use tokio_timer::{self, Sleep, Timer};
+
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Granularity of the delays imposed by the limiters.
+const TICK_MS: u64 = 10;
+
+lazy_static! {
+    /// Timer for the delays (this runs in its own thread).
+    static ref TIMER: Timer = tokio_timer::wheel().tick_duration(Duration::from_millis(TICK_MS))
+                                                  .build();
+}
+
+/// Token bucket, which refills at `rate` bytes per second (and holds
+/// at most a second's worth of tokens).
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second (`None` if it's unlimited).
+    rate: Option<u64>,
+    /// Available tokens - this goes negative when bytes are borrowed from the future.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Take tokens for the given number of bytes, and get the duration for which
+    /// the caller should wait before the bytes can be sent.
+    fn take(&mut self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    /// Same as `take`, but with the given time for refilling the bucket.
+    fn take_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        let rate = match self.rate {
+            Some(r) => r as f64,
+            None => return Duration::from_secs(0),
+        };
+
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0)
+        }
+
+        let wait = -self.tokens / rate;
+        Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+    }
+}
+
+/// A rate limiter (for bytes) shared between its clones, so that it can be used for
+/// limiting an operation, a connection or all connections of the master.
+#[derive(Clone, Debug)]
+pub struct RateLimiter(Arc<Mutex<TokenBucket>>);
+
+impl Default for RateLimiter {
+    fn default() -> Self { RateLimiter::new(None) }
+}
+
+impl RateLimiter {
+    /// Create a limiter for the given rate (in bytes per second). The limiter is
+    /// unlimited if the rate is `None`.
+    pub fn new(rate: Option<u64>) -> Self {
+        RateLimiter(Arc::new(Mutex::new(TokenBucket {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last_refill: Instant::now(),
+        })))
+    }
+
+    /// Change the rate of this limiter (and all its clones).
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate.unwrap_or(0) as f64);
+    }
+}
+
+/// Chain of rate limiters, all of which are applied to the bytes being written.
+#[derive(Clone, Debug, Default)]
+pub struct Limiters(Vec<RateLimiter>);
+
+impl Limiters {
+    /// Get a new chain with the given limiter appended to this chain.
+    pub fn with(&self, limiter: RateLimiter) -> Self {
+        let mut limiters = self.0.clone();
+        limiters.push(limiter);
+        Limiters(limiters)
+    }
+
+    /// Account for the given number of bytes, and get the duration for which the
+    /// caller should wait (so that all the limiters allow them).
+    fn take(&self, bytes: usize) -> Duration {
+        let wait = self.0.iter().map(|l| l.0.lock().unwrap().take(bytes)).max();
+        wait.unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Account for the given number of bytes, and get a future which resolves once
+    /// all the limiters allow them.
+    pub fn delay(&self, bytes: usize) -> ClusterFuture<()> {
+        let wait = self.take(bytes);
+        if wait == Duration::from_secs(0) {
+            return Box::new(future::ok(()))
+        }
+
+        let async_sleep = TIMER.sleep(wait).map_err(|e| {
+            ClusterError::from(io::Error::new(ErrorKind::Other, e))
+        });
+
+        Box::new(async_sleep) as ClusterFuture<()>
+    }
+}
+
+/// Writer which throttles the bytes written to the inner writer. Once the limiters
+/// impose a delay, writes fail with `WouldBlock` until the delay has elapsed (and the
+/// current task is notified), and so this should only be used from within a task.
+pub struct Throttled<W: Write> {
+    inner: W,
+    limiters: Limiters,
+    delay: Option<Sleep>,
+}
+
+impl<W: Write> Throttled<W> {
+    pub fn new(inner: W, limiters: Limiters) -> Self {
+        Throttled { inner, limiters, delay: None }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if let Some(mut delay) = self.delay.take() {
+            match delay.poll() {
+                Ok(Async::NotReady) => {
+                    self.delay = Some(delay);
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "throttled"))
+                },
+                Ok(Async::Ready(())) => (),
+                Err(e) => return Err(io::Error::new(ErrorKind::Other, e)),
+            }
+        }
+
+        let written = self.inner.write(bytes)?;
+        let wait = self.limiters.take(written);
+        if wait > Duration::from_secs(0) {
+            self.delay = Some(TIMER.sleep(wait));
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use futures::{Async, Future, future};
+    use super::{Limiters, RateLimiter, Throttled, TokenBucket};
+
+    use std::cmp;
+    use std::io::{ErrorKind, Write};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_bucket_refills() {
+        // 64 KB/s - the first second's worth of bytes go through immediately.
+        let start = Instant::now();
+        let mut bucket = TokenBucket {
+            rate: Some(64 * 1024),
+            tokens: 64.0 * 1024.0,
+            last_refill: start,
+        };
+        assert_eq!(bucket.take_at(64 * 1024, start), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(32 * 1024, start), Duration::from_millis(500));
+        // Borrowed tokens are refilled first.
+        assert_eq!(bucket.take_at(0, start + Duration::from_millis(250)), Duration::from_millis(250));
+        assert_eq!(bucket.take_at(0, start + Duration::from_millis(500)), Duration::from_secs(0));
+        // ... and the bucket holds at most a second's worth of tokens.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(64 * 1024, later), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(16 * 1024, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_limiter_delays() {
+        let limiter = RateLimiter::new(Some(64 * 1024));
+        let limiters = Limiters::default().with(RateLimiter::default()).with(limiter.clone());
+        assert_eq!(limiters.take(64 * 1024), Duration::from_secs(0));
+        // The wait depends on how long it took to get here (see `test_bucket_refills`).
+        let wait = limiters.take(32 * 1024);
+        assert!(wait <= Duration::from_millis(500));
+        limiters.delay(0).wait().unwrap();
+
+        limiter.set_rate(None);
+        assert_eq!(limiters.take(1024 * 1024), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_throttled_writes() {
+        let limiters = Limiters::default().with(RateLimiter::new(Some(64 * 1024)));
+        let mut buf = vec![];
+        let start = Instant::now();
+        {
+            let mut writer = Throttled::new(&mut buf, limiters);
+            let mut written = 0;
+            let content = [0; 96 * 1024];
+            future::poll_fn(|| {
+                while written < content.len() {
+                    let end = cmp::min(written + 8 * 1024, content.len());
+                    match writer.write(&content[written..end]) {
+                        Ok(n) => written += n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                Ok(Async::Ready(()))
+            }).wait().unwrap();
+        }
+
+        // The last 32 KB should've been delayed, except for the final delay (which isn't
+        // awaited, since there's nothing more to write).
+        assert!(start.elapsed() >= Duration::from_millis(350));
+        assert_eq!(buf.len(), 96 * 1024);
+    }
+}
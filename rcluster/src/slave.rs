@@ -1,4 +1,5 @@
-use connection::Connection;
+use buffered::BufferSize;
+use connection::{Connection, ConnectionSettings};
 use config::SERVER_CONFIG;
 use errors::{ClusterError, ClusterResult};
 use futures::{Future, Stream};
@@ -7,11 +8,13 @@ use tokio_core::reactor::Core;
 use tokio_rustls::ServerConfigExt;
 
 use std::net::SocketAddr;
+use std::path::Path;
 
 /// A slave represents a server that can be connected only by the master.
 /// (Ideally, the master has the right signed cert).
 pub struct Slave {
     address: SocketAddr,
+    settings: ConnectionSettings,
 }
 
 impl Slave {
@@ -19,8 +22,31 @@ impl Slave {
     pub fn new(addr: SocketAddr) -> Self {
         Slave {
             address: addr,
+            settings: ConnectionSettings::default(),
         }
     }
+
+    /// Set the size of the buffers for the incoming connections (and the transfers over them).
+    pub fn set_buffer_size(&mut self, size: BufferSize) {
+        self.settings.buffer_size = size;
+    }
+
+    /// Restrict the filesystem operations (from master) to the given directories. This covers
+    /// the syncs (and pulls) along with the other operations on paths. By default, there are
+    /// no restrictions.
+    pub fn set_roots<P: AsRef<Path>>(&mut self, roots: &[P]) -> ClusterResult<()> {
+        self.settings.roots = roots.iter().map(|r| r.as_ref().canonicalize())
+                                   .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Allow the commands (from master) to be run in cgroups of their own, which are created
+    /// in the given cgroup (v2) directory. This should be delegated to the slave (i.e., it
+    /// should be writable, and it shouldn't have any processes of its own).
+    pub fn set_cgroup_root<P: AsRef<Path>>(&mut self, path: P) -> ClusterResult<()> {
+        self.settings.cgroup_root = Some(path.as_ref().canonicalize()?);
+        Ok(())
+    }
 }
 
 impl Slave {
@@ -32,12 +58,12 @@ impl Slave {
         let listener = TcpListener::bind(&self.address, &handle).unwrap();
         let listen = listener.incoming().for_each(|(stream, addr)| {
             info!("Incoming stream from {:?}", addr);
+            let settings = self.settings.clone();
             handle.spawn({
                 SERVER_CONFIG.accept_async(stream)
                     .map_err(ClusterError::from)
-                    .and_then(|stream| Connection::create_for_stream(stream, true))
-                    .and_then(|c| c.handle_flags())
-                    .map(|_| ())
+                    .and_then(move |stream| Connection::create_for_stream(stream, true, settings))
+                    .and_then(|c| c.serve())
                     .map_err(move |e| error!("Error in stream from {}: {:?}", addr, e))
             });
 
@@ -0,0 +1,352 @@
+use buffered::BUFFER_SIZE;
+use libc;
+
+use std::cmp;
+use std::fs::{File, Metadata};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+/// Maximum number of extents accepted for a file (so that the other end can't make us
+/// allocate arbitrary amounts of memory).
+pub const MAX_EXTENTS: usize = 1 << 20;
+/// Size of the blocks which are checked for zeros while writing sparse files.
+const ZERO_BLOCK_SIZE: usize = 4096;
+
+/// Range of a file which has data (i.e., it's not a hole).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+/// Check whether the file could have holes (i.e., it has fewer blocks than its size).
+pub fn maybe_sparse(metadata: &Metadata) -> bool {
+    metadata.is_file() && metadata.blocks() * 512 < metadata.len()
+}
+
+/// Get the data extents of the file, if it has holes. This returns `None` if the file
+/// doesn't have any holes, or if the filesystem can't tell where they are.
+#[cfg(target_os = "linux")]
+pub fn data_extents(file: &File) -> io::Result<Option<Vec<Extent>>> {
+    let metadata = file.metadata()?;
+    if !maybe_sparse(&metadata) {
+        return Ok(None)
+    }
+
+    let size = metadata.len();
+    let mut extents = vec![];
+    let mut pos = 0;
+    while pos < size {
+        let start = match seek(file, pos, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // There's no more data after this position.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(ref e) if is_unsupported(e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // There's always a hole at the end of the file.
+        let end = cmp::min(seek(file, start, libc::SEEK_HOLE)?, size);
+        extents.push(Extent { offset: start, len: end - start });
+        pos = end;
+    }
+
+    let data = extents.iter().map(|e| e.len).sum::<u64>();
+    Ok(if data < size { Some(extents) } else { None })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_extents(_file: &File) -> io::Result<Option<Vec<Extent>>> {
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+        -1 => Err(io::Error::last_os_error()),
+        pos => Ok(pos as u64),
+    }
+}
+
+/// Whether the error means that the filesystem doesn't support the operation.
+fn is_unsupported(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => code == libc::EINVAL || code == libc::ENOSYS || code == libc::EOPNOTSUPP,
+        None => false,
+    }
+}
+
+/// Get the parts of the extents which come after the given offset.
+pub fn clip(extents: &[Extent], offset: u64) -> Vec<Extent> {
+    extents.iter().filter(|e| e.end() > offset).map(|e| {
+        let start = cmp::max(e.offset, offset);
+        Extent { offset: start, len: e.end() - start }
+    }).collect()
+}
+
+/// Check that the extents are in order (without overlaps), and that they're within
+/// the given range of a file.
+pub fn are_valid(extents: &[Extent], offset: u64, size: u64) -> bool {
+    let mut pos = offset;
+    for e in extents {
+        // Extents come from the other end, so their ends could overflow.
+        match e.offset.checked_add(e.len) {
+            Some(end) if e.offset >= pos && end <= size => pos = end,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Allocate the blocks for the given extents upfront (without changing the file size),
+/// so that the file isn't fragmented, and so that we run out of space before receiving
+/// the contents (rather than halfway through). This does nothing if the filesystem
+/// doesn't support it.
+#[cfg(target_os = "linux")]
+pub fn allocate(file: &File, extents: &[Extent]) -> io::Result<()> {
+    for e in extents.iter().filter(|e| e.len > 0) {
+        let result = unsafe {
+            libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE,
+                            e.offset as libc::off_t, e.len as libc::off_t)
+        };
+
+        if result != 0 {
+            let err = io::Error::last_os_error();
+            return if is_unsupported(&err) { Ok(()) } else { Err(err) }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allocate(_file: &File, _extents: &[Extent]) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reader which reads the given extents of a file, one after the other.
+pub struct ExtentReader<T: Read + Seek> {
+    inner: T,
+    extents: Vec<Extent>,
+    /// Index of the current extent.
+    current: usize,
+    /// Bytes left to be read in the current extent.
+    remaining: u64,
+}
+
+impl<T: Read + Seek> ExtentReader<T> {
+    pub fn new(inner: T, extents: Vec<Extent>) -> Self {
+        ExtentReader { inner, extents, current: 0, remaining: 0 }
+    }
+}
+
+impl<T: Read + Seek> Read for ExtentReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let extent = match self.extents.get(self.current) {
+                Some(e) => *e,
+                None => return Ok(0),
+            };
+
+            self.inner.seek(SeekFrom::Start(extent.offset))?;
+            self.remaining = extent.len;
+            self.current += 1;
+        }
+
+        let len = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            // File has shrunk since its extents were found.
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "extent ended early"))
+        }
+
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Writer which writes the content into the given extents of a file, one after the other.
+pub struct ExtentWriter<T: Write + Seek> {
+    inner: T,
+    extents: Vec<Extent>,
+    current: usize,
+    remaining: u64,
+}
+
+impl<T: Write + Seek> ExtentWriter<T> {
+    pub fn new(inner: T, extents: Vec<Extent>) -> Self {
+        ExtentWriter { inner, extents, current: 0, remaining: 0 }
+    }
+}
+
+impl<T: Write + Seek> Write for ExtentWriter<T> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if bytes.is_empty() {
+            return Ok(0)
+        }
+
+        while self.remaining == 0 {
+            let extent = match self.extents.get(self.current) {
+                Some(e) => *e,
+                None => return Err(io::Error::new(ErrorKind::InvalidData,
+                                                  "content is larger than the extents")),
+            };
+
+            self.inner.seek(SeekFrom::Start(extent.offset))?;
+            self.remaining = extent.len;
+            self.current += 1;
+        }
+
+        let len = cmp::min(bytes.len() as u64, self.remaining) as usize;
+        let written = self.inner.write(&bytes[..len])?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copy the reader into the file, while leaving holes for the blocks which only have zeros.
+/// This is for content whose holes we don't know about (say, sparse entries in archives).
+pub fn copy_sparse<R: Read>(reader: &mut R, file: &mut File) -> io::Result<u64> {
+    let mut buf = [0; BUFFER_SIZE];
+    let mut len = 0;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for block in buf[..read].chunks(ZERO_BLOCK_SIZE) {
+            if block.iter().all(|&b| b == 0) {
+                file.seek(SeekFrom::Current(block.len() as i64))?;
+            } else {
+                file.write_all(block)?;
+            }
+        }
+
+        len += read as u64;
+    }
+
+    // Trailing holes aren't there until the file is extended.
+    file.set_len(len)?;
+    Ok(len)
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, RngCore};
+    use super::{Extent, ExtentReader, ExtentWriter};
+
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_extents_of_sparse_file() {
+        let mut rng = rand::thread_rng();
+        let path = env::temp_dir().join(format!("rcluster-sparse-{}", rng.next_u64()));
+        let mut file = File::create(&path).unwrap();
+        let mut data = vec![0; 8192];
+        rng.fill_bytes(&mut data);
+        // Hole, data, hole, data and a trailing hole.
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(&data).unwrap();
+        file.seek(SeekFrom::Start(4 << 20)).unwrap();
+        file.write_all(&data).unwrap();
+        file.set_len(8 << 20).unwrap();
+        file.sync_all().unwrap();
+
+        let file = File::open(&path).unwrap();
+        let extents = super::data_extents(&file).unwrap().expect("sparse file");
+        // Filesystems could allocate larger blocks, but the data should be covered.
+        assert_eq!(extents.len(), 2);
+        assert!(extents[0].offset <= 1 << 20 && extents[0].end() >= (1 << 20) + 8192);
+        assert!(extents[1].offset <= 4 << 20 && extents[1].end() >= (4 << 20) + 8192);
+        assert!(extents[1].end() < 8 << 20);
+
+        let clipped = super::clip(&extents, (1 << 20) + 100);
+        assert_eq!(clipped[0].offset, (1 << 20) + 100);
+        assert_eq!(clipped[1], extents[1]);
+        assert!(super::are_valid(&clipped, 1 << 20, 8 << 20));
+        assert!(!super::are_valid(&extents, (1 << 20) + 100, 8 << 20));
+        assert!(!super::are_valid(&extents, 0, 4 << 20));
+        let overflowing = [Extent { offset: 1 << 20, len: u64::max_value() }];
+        assert!(!super::are_valid(&overflowing, 0, u64::max_value()));
+
+        // Dense files don't have extents.
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_eq!(super::data_extents(&File::open(&path).unwrap()).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_extents_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let mut content = vec![0; 64 * 1024];
+        rng.fill_bytes(&mut content[1000..5000]);
+        rng.fill_bytes(&mut content[30000..40000]);
+        let extents = vec![Extent { offset: 1000, len: 4000 }, Extent { offset: 30000, len: 10000 }];
+
+        let mut data = vec![];
+        ExtentReader::new(Cursor::new(content.clone()), extents.clone())
+                     .read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 14000);
+
+        let path = env::temp_dir().join(format!("rcluster-extents-{}", rng.next_u64()));
+        {
+            let file = OpenOptions::new().write(true).create(true).open(&path).unwrap();
+            super::allocate(&file, &extents).unwrap();
+            let mut writer = ExtentWriter::new(file, extents);
+            for chunk in data.chunks(999) {
+                writer.write_all(chunk).unwrap();
+            }
+
+            // There's no room for anything else.
+            assert!(writer.write(b"x").is_err());
+        }
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(content.len() as u64).unwrap();
+        let mut out = vec![];
+        File::open(&path).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, content);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_copy_sparse_leaves_holes() {
+        let mut rng = rand::thread_rng();
+        let mut content = vec![0; 1 << 20];
+        rng.fill_bytes(&mut content[..100]);
+        let path = env::temp_dir().join(format!("rcluster-copy-sparse-{}", rng.next_u64()));
+        let mut file = File::create(&path).unwrap();
+        let len = super::copy_sparse(&mut Cursor::new(content.clone()), &mut file).unwrap();
+        assert_eq!(len, content.len() as u64);
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.len(), content.len() as u64);
+        assert!(metadata.blocks() * 512 < metadata.len());
+        let mut out = vec![];
+        File::open(&path).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, content);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use buffered::{BUFFER_SIZE, StreamingBuffer};
+use compression::Codec;
+use connection::Connection;
+use errors::{ClusterError, ClusterFuture, ClusterResult};
+use fs_ops;
+use pool;
+use futures::{Future, future};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Number of lines (from the end of the file) to begin with.
+const INITIAL_LINES: usize = 10;
+/// Interval for checking a followed file for new content (and rotation).
+const POLL_INTERVAL_MS: u64 = 250;
+/// Status byte for a file which has been opened (followed by the content).
+const STATUS_OK: u8 = 0;
+/// Status byte for a file which couldn't be opened (followed by the error message).
+const STATUS_FAILED: u8 = 1;
+
+/// Wrapper for tailing files. The master sends the path (a line) and a byte for following
+/// it. The slave replies with a status byte, followed by the error message (a line) or the
+/// content (the last few lines of the file, and the bytes appended to it, if it's being
+/// followed), ending with the magic. A followed file doesn't end, and so its content ends
+/// only when the connection is dropped.
+pub struct Tail<R: AsyncRead, W: AsyncWrite>(pub Connection<R, W>);
+
+/// Sets the flag when it's dropped (along with the future which holds it).
+pub struct StopOnDrop(pub Arc<AtomicBool>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Reader for the content of a file, which waits for more content (once it reaches the end)
+/// if the file is being followed. If the file is replaced by another file (say, by log
+/// rotation), then the new file is read once the old one has been drained, and if it's
+/// truncated, then it's read again from the start.
+struct TailReader {
+    path: PathBuf,
+    file: File,
+    pos: u64,
+    follow: bool,
+    /// Set once nobody's interested in the content anymore.
+    stopped: Arc<AtomicBool>,
+}
+
+impl TailReader {
+    /// Open the file, and seek to the last few lines.
+    fn open(path: PathBuf, follow: bool) -> io::Result<Self> {
+        let mut file = File::open(&path)?;
+        if file.metadata()?.is_dir() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Is a directory"))
+        }
+
+        let pos = start_offset(&mut file, INITIAL_LINES)?;
+        file.seek(SeekFrom::Start(pos))?;
+        Ok(TailReader { path, file, pos, follow, stopped: Arc::new(AtomicBool::new(false)) })
+    }
+
+    /// Check whether the file has been truncated (or replaced by another file), and rewind
+    /// (or reopen) it.
+    fn check_rotation(&mut self) -> io::Result<bool> {
+        let current = self.file.metadata()?;
+        if current.len() < self.pos {
+            info!("{} has been truncated", self.path.display());
+            self.pos = self.file.seek(SeekFrom::Start(0))?;
+            return Ok(true)
+        }
+
+        // The file could be missing for a while (until it's created again).
+        match fs::metadata(&self.path) {
+            Ok(ref m) if (m.dev(), m.ino()) != (current.dev(), current.ino()) => {
+                info!("{} has been replaced, reopening", self.path.display());
+                self.file = File::open(&self.path)?;
+                self.pos = 0;
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Read for TailReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.file.read(buf)?;
+            if len > 0 || !self.follow {
+                self.pos += len as u64;
+                return Ok(len)
+            }
+
+            if self.check_rotation()? {
+                continue
+            }
+
+            if self.stopped.load(Ordering::SeqCst) {
+                return Err(io::Error::new(ErrorKind::Other, "stopped following"))
+            }
+
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+}
+
+/// Get the offset of the last few lines of the file (a trailing newline doesn't count).
+fn start_offset(file: &mut File, lines: usize) -> io::Result<u64> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if lines == 0 {
+        return Ok(len)
+    }
+
+    let mut chunk = vec![0; BUFFER_SIZE];
+    let (mut end, mut found) = (len, 0);
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let size = (end - start) as usize;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk[..size])?;
+        for i in (0..size).rev() {
+            let next = start + i as u64 + 1;
+            if chunk[i] == b'\n' && next != len {
+                found += 1;
+                if found == lines {
+                    return Ok(next)
+                }
+            }
+        }
+
+        end = start;
+    }
+
+    Ok(0)
+}
+
+impl<R, W> Tail<R, W>
+    where R: AsyncRead + 'static, W: AsyncWrite + 'static
+{
+    /// (Master) Request the content of the file (whose flag has already been sent), and
+    /// stream it to the given writer. If the file is being followed, this resolves only
+    /// when the connection is dropped.
+    pub fn request<P, O>(self, path: P, follow: bool,
+                         output: O) -> ClusterFuture<(Connection<R, W>, ClusterResult<()>)>
+        where P: AsRef<Path>, O: Write + Send + 'static
+    {
+        let mut bytes = path.as_ref().to_string_lossy().into_owned().into_bytes();
+        bytes.push(b'\n');
+        bytes.push(follow as u8);
+        let async_tail = self.0.write_bytes(bytes)
+            .and_then(|c| c.flush())
+            .and_then(|c| c.read_bytes([0; 1]))
+            .and_then(move |(c, status)| -> ClusterFuture<_> {
+                if status[0] != STATUS_OK {
+                    return Box::new(c.read_line().map(|(c, msg)| (c, Err(ClusterError::Remote(msg)))))
+                }
+
+                let (r, w, m, s) = c.into();
+                let async_stream = StreamingBuffer::stream_to_blocking(r, &m, output, s.buffer_size)
+                    .with_codec(Codec::Decode(s.compression))
+                    .stream()
+                    .map(move |(r, _)| (Connection::from((r, w, m, s)), Ok(())));
+                Box::new(async_stream)
+            });
+
+        Box::new(async_tail) as ClusterFuture<_>
+    }
+
+    /// (Slave) Read the request, and stream the file's content (only if it's inside the roots).
+    pub fn serve(self) -> ClusterFuture<Connection<R, W>> {
+        let roots = self.0.settings().roots.clone();
+        let async_serve = self.0.read_line()
+            .and_then(|(c, path)| c.read_bytes([0; 1]).map(move |(c, f)| (c, path, f[0] != 0)))
+            .and_then(move |(c, path, follow)| {
+                let requested = path.clone();
+                pool::run(move || -> ClusterResult<_> {
+                    let path = fs_ops::check_roots(&roots, Path::new(&requested))?;
+                    Ok(TailReader::open(path, follow)?)
+                }).then(move |reader| Ok((c, path, follow, reader)))
+            }).and_then(|(c, path, follow, reader)| -> ClusterFuture<_> {
+                let reader = match reader {
+                    Ok(r) => r,
+                    Err(e) => {
+                        info!("Cannot tail {}: {}", path, e);
+                        let mut bytes = vec![STATUS_FAILED];
+                        bytes.extend_from_slice(fs_ops::error_message(&e).as_bytes());
+                        bytes.push(b'\n');
+                        return c.write_bytes(bytes)
+                    },
+                };
+
+                info!("Tailing {}{}", path, if follow { " (following)" } else { "" });
+                let guard = StopOnDrop(reader.stopped.clone());
+                let async_stream = c.write_bytes([STATUS_OK]).and_then(move |c| {
+                    let (r, w, m, s) = c.into();
+                    StreamingBuffer::blocking_to_stream(reader, w, s.buffer_size)
+                        .with_codec(Codec::Encode(s.compression))
+                        .with_limiters(s.limiters.clone())
+                        .with_idle_flush(true)
+                        .stream()
+                        .and_then(move |(_, w)| Connection::from((r, w, m, s)).write_magic())
+                });
+
+                // The reader is stopped once the connection is dropped.
+                Box::new(async_stream.then(move |result| {
+                    drop(guard);
+                    future::result(result)
+                }))
+            });
+
+        Box::new(async_serve) as ClusterFuture<_>
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use connection::{loopback, response};
+    use futures::Future;
+    use rand::{self, RngCore};
+    use super::{Tail, TailReader};
+
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// Writer whose content can be checked after it's been moved.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> ::std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read_some(reader: &mut TailReader) -> String {
+        let mut buf = [0; 64];
+        let len = reader.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn test_tail_exchange() {
+        let path = env::temp_dir().join(format!("rcluster-tail-{}", rand::thread_rng().next_u64()));
+        let mut content = String::new();
+        for i in 0..15 {
+            content.push_str(&format!("line {}\n", i));
+        }
+
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        let magic = [42; 16];
+        let mut request = format!("{}\n", path.display()).into_bytes();
+        request.push(0);
+        let conn = Tail(loopback(request, magic)).serve().wait().unwrap();
+
+        let output = Shared::default();
+        let (_, result) = Tail(loopback(response(conn), magic))
+            .request(&path, false, output.clone())
+            .wait().unwrap();
+        result.unwrap();
+        let expected = (5..15).map(|i| format!("line {}\n", i)).collect::<String>();
+        assert_eq!(String::from_utf8(output.0.lock().unwrap().clone()).unwrap(), expected);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_follow_rotation() {
+        let path = env::temp_dir().join(format!("rcluster-follow-{}", rand::thread_rng().next_u64()));
+        File::create(&path).unwrap().write_all(b"foo\n").unwrap();
+        let mut reader = TailReader::open(path.clone(), true).unwrap();
+        assert_eq!(read_some(&mut reader), "foo\n");
+
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"bar\n").unwrap();
+        assert_eq!(read_some(&mut reader), "bar\n");
+
+        // Bytes written to the old file (after it's been moved) come before the new file.
+        let rotated = path.with_extension("1");
+        fs::rename(&path, &rotated).unwrap();
+        File::create(&path).unwrap().write_all(b"new\n").unwrap();
+        OpenOptions::new().append(true).open(&rotated).unwrap().write_all(b"old\n").unwrap();
+        assert_eq!(read_some(&mut reader), "old\n");
+        assert_eq!(read_some(&mut reader), "new\n");
+
+        // Truncated file is read again from the start.
+        File::create(&path).unwrap().write_all(b"z\n").unwrap();
+        assert_eq!(read_some(&mut reader), "z\n");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
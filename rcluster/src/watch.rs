@@ -0,0 +1,281 @@
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use path_sync::SyncOptions;
+use walkdir::WalkDir;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the buffer for reading the events (each event has a name, which is at most 255 bytes).
+const EVENT_BUFFER_SIZE: usize = 64 * 1024;
+/// Batches are collected for at most this many debounce periods (so that a file which keeps
+/// changing doesn't hold back the others forever).
+const MAX_DEBOUNCE_PERIODS: u32 = 10;
+
+/// Change of a path in the watched tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Event {
+    Changed,
+    Removed,
+    /// Events have been dropped by the kernel, and so anything could've changed.
+    Overflowed,
+}
+
+/// Changes in the watched source (since the previous batch). Paths are relative to `root`
+/// (the parent of the source), like the paths in the stream.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    pub root: PathBuf,
+    /// Files and directories which have been created or modified. Directories include
+    /// everything in them.
+    pub changed: Vec<PathBuf>,
+    /// Paths which have been removed (or moved away).
+    pub removed: Vec<PathBuf>,
+}
+
+/// Watches the source tree (with inotify), and gives the changed paths in batches.
+pub struct Watcher {
+    source: PathBuf,
+    root: PathBuf,
+    debounce: Duration,
+    events: Receiver<io::Result<(PathBuf, Event)>>,
+}
+
+impl Watcher {
+    /// Start watching the source (a file or a directory, along with everything in it, except
+    /// the excluded paths and symlinks). A batch ends once there aren't any events for the
+    /// `debounce` period.
+    pub fn new<P>(source: P, options: &SyncOptions, debounce: Duration) -> io::Result<Watcher>
+        where P: AsRef<Path>
+    {
+        let source = source.as_ref().canonicalize()?;
+        let root = source.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let mut state = WatchState {
+            inotify: Inotify::init()?,
+            watches: HashMap::new(),
+            file: None,
+            root: root.clone(),
+            options: options.clone(),
+        };
+
+        // Files can't be watched for creation (or removal), and so we watch their parents.
+        if source.is_dir() {
+            state.add_watches(&source)?;
+        } else {
+            state.add_watch(&root)?;
+            state.file = Some(source.clone());
+        }
+
+        let (sender, events) = mpsc::channel();
+        // The thread stays blocked on the events (even if this watcher has been dropped)
+        // until the next event comes in.
+        thread::spawn(move || state.send_events(sender));
+        Ok(Watcher { source, root, debounce, events })
+    }
+
+    /// Wait for the next batch of changes.
+    pub fn next_batch(&mut self) -> io::Result<Changes> {
+        let stopped = || io::Error::new(ErrorKind::BrokenPipe, "Watcher has stopped");
+        let mut pending = BTreeMap::new();
+        let (path, event) = self.events.recv().map_err(|_| stopped())??;
+        pending.insert(path, event);
+
+        let start = Instant::now();
+        while start.elapsed() < self.debounce * MAX_DEBOUNCE_PERIODS {
+            match self.events.recv_timeout(self.debounce) {
+                Ok(event) => {
+                    let (path, event) = event?;
+                    pending.insert(path, event);
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(stopped()),
+            }
+        }
+
+        let mut changes = Changes { root: self.root.clone(), ..Changes::default() };
+        if pending.values().any(|e| *e == Event::Overflowed) {
+            info!("Too many events, resending {}", self.source.display());
+            changes.changed.push(self.source.strip_prefix(&self.root).unwrap().to_path_buf());
+            return Ok(changes)
+        }
+
+        // Paths are sorted, and so ancestors come before their descendants, which needn't
+        // be sent (or removed) separately.
+        for (path, event) in pending {
+            let paths = match event {
+                Event::Changed => &mut changes.changed,
+                _ => &mut changes.removed,
+            };
+
+            if !paths.iter().any(|p| path.starts_with(p)) {
+                paths.push(path);
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+/// State of the thread which reads the events.
+struct WatchState {
+    inotify: Inotify,
+    /// Directories being watched.
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    /// Source file (if the source isn't a directory).
+    file: Option<PathBuf>,
+    root: PathBuf,
+    options: SyncOptions,
+}
+
+impl WatchState {
+    fn add_watch(&mut self, dir: &Path) -> io::Result<()> {
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::ATTRIB | WatchMask::CREATE |
+                   WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO;
+        let wd = self.inotify.add_watch(dir, mask)?;
+        self.watches.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Watch the directory, along with all the directories in it.
+    fn add_watches(&mut self, dir: &Path) -> io::Result<()> {
+        let mut entries = WalkDir::new(dir).into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                continue
+            }
+
+            if self.options.is_excluded(entry.path().strip_prefix(&self.root).unwrap()) {
+                entries.skip_current_dir();
+                continue
+            }
+
+            self.add_watch(entry.path())?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the events and send them (until the receiver goes away).
+    fn send_events(mut self, sender: Sender<io::Result<(PathBuf, Event)>>) {
+        let mut buf = vec![0; EVENT_BUFFER_SIZE];
+        loop {
+            let mut new_dirs = vec![];
+            {
+                let events = match self.inotify.read_events_blocking(&mut buf) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return
+                    },
+                };
+
+                for event in events {
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        if sender.send(Ok((self.root.clone(), Event::Overflowed))).is_err() {
+                            return
+                        }
+
+                        continue
+                    }
+
+                    if event.mask.contains(EventMask::IGNORED) {
+                        self.watches.remove(&event.wd);
+                        continue
+                    }
+
+                    let path = match (self.watches.get(&event.wd), event.name) {
+                        (Some(dir), Some(name)) => dir.join(name),
+                        _ => continue,
+                    };
+
+                    if self.file.as_ref().map(|f| *f != path).unwrap_or(false) {
+                        continue
+                    }
+
+                    let rel_path = path.strip_prefix(&self.root).unwrap().to_path_buf();
+                    if self.options.is_excluded(&rel_path) {
+                        continue
+                    }
+
+                    let is_dir = event.mask.contains(EventMask::ISDIR);
+                    let kind = if event.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                        Event::Removed
+                    } else if is_dir && event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                        new_dirs.push(path);
+                        Event::Changed
+                    } else if is_dir || event.mask == EventMask::CREATE {
+                        // Directory attributes aren't synced, and new files are sent
+                        // once they've been written.
+                        continue
+                    } else {
+                        Event::Changed
+                    };
+
+                    debug!("{:?}: {}", kind, rel_path.display());
+                    if sender.send(Ok((rel_path, kind))).is_err() {
+                        return
+                    }
+                }
+            }
+
+            // Files created in the new directories before the watches are added are
+            // covered by sending the directories.
+            for dir in new_dirs {
+                if let Err(e) = self.add_watches(&dir) {
+                    info!("Cannot watch {}: {}", dir.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use path_sync::SyncOptions;
+    use rand::{self, RngCore};
+    use super::Watcher;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_watch_batches() {
+        let dir = env::temp_dir().join(format!("rcluster-watch-{}", rand::thread_rng().next_u64()));
+        fs::create_dir_all(dir.join("old")).unwrap();
+        File::create(dir.join("old/foo")).unwrap();
+        File::create(dir.join("bar")).unwrap();
+        let mut options = SyncOptions::default();
+        options.exclude("*.swp").unwrap();
+        let mut watcher = Watcher::new(&dir, &options, Duration::from_millis(100)).unwrap();
+
+        File::create(dir.join("bar")).unwrap().write_all(b"bar").unwrap();
+        File::create(dir.join(".bar.swp")).unwrap().write_all(b"bar").unwrap();
+        fs::create_dir(dir.join("new")).unwrap();
+        File::create(dir.join("new/baz")).unwrap().write_all(b"baz").unwrap();
+        fs::remove_file(dir.join("old/foo")).unwrap();
+        fs::remove_dir(dir.join("old")).unwrap();
+
+        let name = PathBuf::from(dir.file_name().unwrap());
+        let changes = watcher.next_batch().unwrap();
+        assert_eq!(changes.root, dir.parent().unwrap());
+        assert_eq!(changes.changed, [name.join("bar"), name.join("new")]);
+        assert_eq!(changes.removed, [name.join("old")]);
+
+        // Files in the new directories are watched too.
+        File::create(dir.join("new/baz")).unwrap().write_all(b"foobar").unwrap();
+        let changes = watcher.next_batch().unwrap();
+        assert_eq!(changes.changed, [name.join("new/baz")]);
+        assert!(changes.removed.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
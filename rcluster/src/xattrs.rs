@@ -0,0 +1,227 @@
+use libc;
+
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Maximum number of attributes accepted for a path.
+pub const MAX_XATTRS: usize = 1024;
+/// Maximum size of an attribute's value (Linux doesn't allow anything larger).
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+/// Prefix of the attributes which hold the POSIX ACLs (access and default).
+const ACL_PREFIX: &str = "system.posix_acl_";
+/// Namespaces of the attributes which are synced (other than ACLs). SELinux labels
+/// and file capabilities belong to `security`.
+const NAMESPACES: [&str; 3] = ["user.", "security.", "trusted."];
+/// Attribute used for checking whether a filesystem supports attributes at all.
+const PROBE_NAME: &str = "user.rcluster.probe";
+
+/// Extended attribute of a path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Check whether the attribute holds an ACL.
+pub fn is_acl(name: &str) -> bool {
+    name.starts_with(ACL_PREFIX)
+}
+
+/// Check whether the attribute (which isn't an ACL) belongs to a namespace that's synced.
+pub fn is_syncable(name: &str) -> bool {
+    NAMESPACES.iter().any(|n| name.starts_with(n))
+}
+
+/// Whether the error means that the filesystem (or the platform) doesn't support attributes.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+/// Check whether attributes can be set on paths in the given directory. Paths are always
+/// checked for reading (the way `getfattr` does), which doesn't need any permissions.
+pub fn is_supported(dir: &Path) -> bool {
+    match get(dir, PROBE_NAME) {
+        Ok(_) => true,
+        Err(ref e) => !is_unsupported(e),
+    }
+}
+
+/// Get the attributes of the path (without following symlinks), sorted by name. Attributes
+/// whose names aren't valid UTF-8 are ignored, and so are the ones which disappear meanwhile.
+pub fn read(path: &Path) -> io::Result<Vec<Xattr>> {
+    let mut attrs = vec![];
+    for name in list(path)? {
+        match get(path, &name) {
+            Ok(value) => attrs.push(Xattr { name, value }),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODATA) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    attrs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(attrs)
+}
+
+#[cfg(target_os = "linux")]
+fn list(path: &Path) -> io::Result<Vec<String>> {
+    let path = c_path(path)?;
+    loop {
+        let size = match unsafe { libc::llistxattr(path.as_ptr(), ::std::ptr::null_mut(), 0) } {
+            -1 => return Err(io::Error::last_os_error()),
+            size => size as usize,
+        };
+
+        let mut buf = vec![0u8; size];
+        match unsafe { libc::llistxattr(path.as_ptr(), buf.as_mut_ptr() as *mut _, size) } {
+            // Attributes have been added in the meantime.
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            len => buf.truncate(len as usize),
+        }
+
+        // Names are separated by null bytes.
+        return Ok(buf.split(|&b| b == 0)
+                     .filter(|n| !n.is_empty())
+                     .filter_map(|n| String::from_utf8(n.to_vec()).ok())
+                     .collect())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    let (path, name) = (c_path(path)?, c_name(name)?);
+    loop {
+        let size = match unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), ::std::ptr::null_mut(), 0)
+        } {
+            -1 => return Err(io::Error::last_os_error()),
+            size => size as usize,
+        };
+
+        let mut buf = vec![0u8; size];
+        match unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut _, size)
+        } {
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            len => buf.truncate(len as usize),
+        }
+
+        return Ok(buf)
+    }
+}
+
+/// Set the attribute on the path (without following symlinks).
+#[cfg(target_os = "linux")]
+pub fn set(path: &Path, attr: &Xattr) -> io::Result<()> {
+    let (path, name) = (c_path(path)?, c_name(&attr.name)?);
+    let value = attr.value.as_ptr() as *const _;
+    match unsafe { libc::lsetxattr(path.as_ptr(), name.as_ptr(), value, attr.value.len(), 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Remove the attribute from the path (without following symlinks).
+#[cfg(target_os = "linux")]
+pub fn remove(path: &Path, name: &str) -> io::Result<()> {
+    let (path, name) = (c_path(path)?, c_name(name)?);
+    match unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn list(_path: &Path) -> io::Result<Vec<String>> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get(_path: &Path, _name: &str) -> io::Result<Vec<u8>> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set(_path: &Path, _attr: &Xattr) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn remove(_path: &Path, _name: &str) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+/// Make the path's attributes (the ones for which `wanted` returns true) the same as the
+/// given attributes (others are ignored) - missing ones are set, and the extra ones are
+/// removed. This carries on after failures, and returns the attributes which couldn't
+/// be set (or removed).
+pub fn apply<F>(path: &Path, attrs: &[Xattr], wanted: F) -> io::Result<Vec<(String, io::Error)>>
+    where F: Fn(&str) -> bool
+{
+    let existing = read(path)?;
+    let mut failed = vec![];
+    for attr in existing.iter().filter(|a| wanted(&a.name)) {
+        if !attrs.iter().any(|a| a.name == attr.name) {
+            if let Err(e) = remove(path, &attr.name) {
+                failed.push((attr.name.clone(), e));
+            }
+        }
+    }
+
+    for attr in attrs.iter().filter(|a| wanted(&a.name) && !existing.contains(a)) {
+        if let Err(e) = set(path, attr) {
+            failed.push((attr.name.clone(), e));
+        }
+    }
+
+    Ok(failed)
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+/* Tests */
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, RngCore};
+    use super::{Xattr, apply, is_supported, read};
+
+    use std::env;
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_apply_attributes() {
+        let dir = env::temp_dir();
+        // tmpfs in some kernels doesn't support user attributes.
+        if !is_supported(&dir) {
+            return
+        }
+
+        let path = dir.join(format!("rcluster-xattrs-{}", rand::thread_rng().next_u64()));
+        File::create(&path).unwrap();
+        let attr = |name: &str, value: &[u8]| Xattr { name: name.into(), value: value.to_vec() };
+        let failed = apply(&path, &[attr("user.foo", b"bar"), attr("user.baz", b"")],
+                           |_| true).unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(read(&path).unwrap(), [attr("user.baz", b""), attr("user.foo", b"bar")]);
+
+        // Extra attributes are removed, unless they're not wanted.
+        let failed = apply(&path, &[attr("user.foo", b"foobar")], |n| n != "user.baz").unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(read(&path).unwrap(), [attr("user.baz", b""), attr("user.foo", b"foobar")]);
+        let failed = apply(&path, &[], |_| true).unwrap();
+        assert!(failed.is_empty());
+        assert!(read(&path).unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -8,7 +8,18 @@ use std::env;
 
 fn start_listening() -> ClusterResult<()> {
     let addr = env::var("ADDRESS").unwrap_or(DEFAULT_ADDRESS.to_string()).parse()?;
-    Slave::new(addr).start_listening()?;
+    let mut slave = Slave::new(addr);
+    // Directories (separated by colons) to which the filesystem operations are restricted.
+    if let Some(roots) = env::var_os("ROOTS") {
+        slave.set_roots(&env::split_paths(&roots).collect::<Vec<_>>())?;
+    }
+
+    // Cgroup (v2) directory in which the commands can be isolated.
+    if let Some(root) = env::var_os("CGROUP_ROOT") {
+        slave.set_cgroup_root(root)?;
+    }
+
+    slave.start_listening()?;
     Ok(())
 }
 